@@ -97,6 +97,8 @@
 //! # Ok::<(), Error>(())
 //! ```
 use failure::Fallible;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
 #[cfg(unix)]
 mod unix;
@@ -108,6 +110,24 @@ mod windows;
 #[cfg(windows)]
 pub use crate::windows::*;
 
+/// `HandleType` classifies the kind of object that a `FileDescriptor`
+/// refers to.  It is returned by `FileDescriptor::handle_type` and is
+/// primarily useful for diagnostics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HandleType {
+    /// A character device, such as a terminal
+    Char,
+    /// A regular file
+    Disk,
+    /// A pipe or FIFO
+    Pipe,
+    /// A socket
+    Socket,
+    /// Something else, or the type could not be determined
+    #[default]
+    Unknown,
+}
+
 /// `AsRawFileDescriptor` is a platform independent trait for returning
 /// a non-owning reference to the underlying platform file descriptor
 /// type.
@@ -128,6 +148,9 @@ pub trait IntoRawFileDescriptor {
 /// to indicate that care must be taken by the caller to ensure that it
 /// is used appropriately.
 pub trait FromRawFileDescriptor {
+    /// # Safety
+    /// The caller must own `fd` and must not use or close it after
+    /// handing it over; the returned instance takes over its lifetime.
     unsafe fn from_raw_file_descriptor(fd: RawFileDescriptor) -> Self;
 }
 
@@ -138,6 +161,9 @@ pub trait IntoRawSocketDescriptor {
     fn into_socket_descriptor(self) -> SocketDescriptor;
 }
 pub trait FromRawSocketDescriptor {
+    /// # Safety
+    /// The caller must own `fd` and must not use or close it after
+    /// handing it over; the returned instance takes over its lifetime.
     unsafe fn from_socket_descriptor(fd: SocketDescriptor) -> Self;
 }
 
@@ -250,6 +276,80 @@ impl FileDescriptor {
     pub fn as_stdio(&self) -> Fallible<std::process::Stdio> {
         self.as_stdio_impl()
     }
+
+    /// Returns the kind of object that the descriptor refers to
+    pub fn handle_type(&self) -> HandleType {
+        self.handle_type_impl()
+    }
+
+    /// Set or clear the flag that determines whether the descriptor
+    /// is inherited by child processes.  This can be used to pass an
+    /// existing descriptor to a child that is about to be spawned.
+    /// On Windows this is `HANDLE_FLAG_INHERIT`; on Unix systems it is
+    /// the inverse of `FD_CLOEXEC`.
+    pub fn set_inheritable(&self, inheritable: bool) -> Fallible<()> {
+        self.handle.set_inheritable(inheritable)
+    }
+
+    /// Create a pipe, returning its readable and writable ends, in
+    /// that order.  The ends are not inherited by child processes,
+    /// which is usually what is wanted when building a pipeline: the
+    /// ends are explicitly passed to each child as its stdio.
+    ///
+    /// ```
+    /// use filedescriptor::FileDescriptor;
+    /// use std::io::{Read,Write};
+    /// use failure::Error;
+    ///
+    /// let (mut read, mut write) = FileDescriptor::pipe()?;
+    /// write.write(b"hello")?;
+    /// drop(write);
+    ///
+    /// let mut s = String::new();
+    /// read.read_to_string(&mut s)?;
+    /// assert_eq!(s, "hello");
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn pipe() -> Fallible<(FileDescriptor, FileDescriptor)> {
+        let pipe = Pipe::new()?;
+        Ok((pipe.read, pipe.write))
+    }
+
+    /// Create a pipe whose ends are inherited by child processes.
+    /// This is useful when a child process needs to find the pipe
+    /// at a descriptor number other than its stdio streams.
+    pub fn pipe_inheritable() -> Fallible<(FileDescriptor, FileDescriptor)> {
+        let (read, write) = Self::pipe()?;
+        read.handle.set_inheritable(true)?;
+        write.handle.set_inheritable(true)?;
+        Ok((read, write))
+    }
+
+    /// Wait until at least one of `fds` is ready to read, or for
+    /// `duration` to elapse, and return a flag for each descriptor,
+    /// in order, indicating whether it is readable.  A descriptor
+    /// whose peer has closed is considered readable, as reading it
+    /// will not block.  This is a convenience wrapper around `poll`,
+    /// so the same platform restrictions apply: on Windows only
+    /// sockets can be polled.
+    pub fn poll_readable(
+        fds: &[&FileDescriptor],
+        duration: Option<Duration>,
+    ) -> Fallible<Vec<bool>> {
+        let mut poll_array: Vec<pollfd> = fds
+            .iter()
+            .map(|fd| pollfd {
+                fd: fd.as_socket_descriptor(),
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect();
+        poll(&mut poll_array, duration)?;
+        Ok(poll_array
+            .iter()
+            .map(|pfd| pfd.revents & (POLLIN | POLLHUP | POLLERR) != 0)
+            .collect())
+    }
 }
 
 /// Represents the readable and writable ends of a pair of descriptors
@@ -276,6 +376,72 @@ pub struct Pipe {
     pub write: FileDescriptor,
 }
 
+/// Where a descriptor in an `FdTable` comes from
+#[derive(Clone, Debug)]
+enum FdSource {
+    /// A descriptor owned by the table
+    Descriptor(Arc<FileDescriptor>),
+    /// The descriptor with this number that the child inherits
+    /// from its parent
+    Inherited(usize),
+}
+
+/// `FdTable` maps the descriptor numbers seen by a child process to
+/// the `FileDescriptor`s that they should refer to.  It is assembled
+/// in the parent by applying redirections in order, and then applied
+/// to the `Command` that spawns the child.  On Unix systems the child
+/// rearranges its descriptors between fork and exec, while on Windows,
+/// which can only pass the standard handles, they become the stdin,
+/// stdout and stderr of the `Command`.
+///
+/// ```
+/// use filedescriptor::{FdTable, FileDescriptor};
+/// use failure::Error;
+/// use std::process::Command;
+///
+/// let (_read, write) = FileDescriptor::pipe()?;
+/// let mut table = FdTable::new();
+/// // Equivalent to `>pipe 2>&1`
+/// table.set(1, write);
+/// table.dup(1, 2);
+/// table.apply(&mut Command::new("ls"))?;
+/// # Ok::<(), Error>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FdTable {
+    entries: BTreeMap<usize, FdSource>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `fd_number` refer to `fd` in the child
+    pub fn set(&mut self, fd_number: usize, fd: FileDescriptor) {
+        self.entries
+            .insert(fd_number, FdSource::Descriptor(Arc::new(fd)));
+    }
+
+    /// Make `dest` refer to whatever `src` currently refers to, in the
+    /// same way as `dest>&src`.  If `src` hasn't been set then `dest`
+    /// refers to the `src` descriptor inherited from the parent.
+    /// Later changes to `src` don't affect `dest`.
+    pub fn dup(&mut self, src: usize, dest: usize) {
+        let source = self
+            .entries
+            .get(&src)
+            .cloned()
+            .unwrap_or(FdSource::Inherited(src));
+        self.entries.insert(dest, source);
+    }
+
+    /// Returns the descriptor numbers that have been set, in order
+    pub fn fd_numbers(&self) -> impl Iterator<Item = usize> + '_ {
+        self.entries.keys().cloned()
+    }
+}
+
 use std::time::Duration;
 
 /// Examines a set of FileDescriptors to see if some of them are ready for I/O,
@@ -315,3 +481,82 @@ pub fn poll(pfd: &mut [pollfd], duration: Option<Duration>) -> Fallible<usize> {
 pub fn socketpair() -> Fallible<(FileDescriptor, FileDescriptor)> {
     socketpair_impl()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn pipe() -> Fallible<()> {
+        let (mut read, mut write) = FileDescriptor::pipe()?;
+        assert!(!read.handle.is_inheritable()?);
+        assert!(!write.handle.is_inheritable()?);
+
+        write.write_all(b"hello")?;
+        drop(write);
+        let mut s = String::new();
+        read.read_to_string(&mut s)?;
+        assert_eq!(s, "hello");
+
+        let (read, write) = FileDescriptor::pipe_inheritable()?;
+        assert!(read.handle.is_inheritable()?);
+        assert!(write.handle.is_inheritable()?);
+        Ok(())
+    }
+
+    #[test]
+    fn poll_readable() -> Fallible<()> {
+        let (a, mut b) = socketpair()?;
+        let (c, _d) = socketpair()?;
+        let wait = Some(Duration::from_millis(20));
+
+        assert_eq!(
+            FileDescriptor::poll_readable(&[&a, &c], wait)?,
+            vec![false, false]
+        );
+
+        b.write_all(b"hello")?;
+        assert_eq!(
+            FileDescriptor::poll_readable(&[&a, &c], wait)?,
+            vec![true, false]
+        );
+        assert_eq!(
+            FileDescriptor::poll_readable(&[&c, &a], wait)?,
+            vec![false, true]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_inheritable() -> Fallible<()> {
+        let (a, _b) = socketpair()?;
+        assert!(!a.handle.is_inheritable()?);
+        a.set_inheritable(true)?;
+        assert!(a.handle.is_inheritable()?);
+        a.set_inheritable(false)?;
+        assert!(!a.handle.is_inheritable()?);
+        Ok(())
+    }
+
+    #[test]
+    fn handle_type() -> Fallible<()> {
+        let (read, write) = FileDescriptor::pipe()?;
+        assert_eq!(read.handle_type(), HandleType::Pipe);
+        assert_eq!(write.handle_type(), HandleType::Pipe);
+
+        let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))?;
+        assert_eq!(FileDescriptor::new(file).handle_type(), HandleType::Disk);
+        Ok(())
+    }
+
+    #[test]
+    fn write_to_closed_pipe() -> Fallible<()> {
+        let (read, mut write) = FileDescriptor::pipe()?;
+        drop(read);
+        assert_eq!(write.write(b"hello")?, 0);
+        let err = write.write_all(b"hello").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WriteZero);
+        Ok(())
+    }
+}
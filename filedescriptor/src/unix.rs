@@ -1,11 +1,11 @@
 use crate::{
-    AsRawFileDescriptor, AsRawSocketDescriptor, FileDescriptor, FromRawFileDescriptor,
-    FromRawSocketDescriptor, IntoRawFileDescriptor, IntoRawSocketDescriptor, OwnedHandle, Pipe,
+    AsRawFileDescriptor, AsRawSocketDescriptor, FdSource, FdTable, FileDescriptor,
+    FromRawFileDescriptor, FromRawSocketDescriptor, HandleType, IntoRawFileDescriptor,
+    IntoRawSocketDescriptor, OwnedHandle, Pipe,
 };
 use failure::{bail, Fallible};
 use std::os::unix::prelude::*;
-
-pub(crate) type HandleType = ();
+use std::process::Command;
 
 /// `RawFileDescriptor` is a platform independent type alias for the
 /// underlying platform file descriptor type.  It is primarily useful
@@ -79,14 +79,20 @@ impl FromRawFd for OwnedHandle {
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
         Self {
             handle: fd,
-            handle_type: (),
+            handle_type: Self::probe_handle_type(fd),
         }
     }
 }
 
 impl OwnedHandle {
     /// Helper function to set the close-on-exec flag for a raw descriptor
-    fn cloexec(&mut self) -> Fallible<()> {
+    fn cloexec(&self) -> Fallible<()> {
+        self.set_inheritable(false)
+    }
+
+    /// Clear or set the close-on-exec flag, which determines whether
+    /// the descriptor is inherited by child processes
+    pub(crate) fn set_inheritable(&self, inheritable: bool) -> Fallible<()> {
         let flags = unsafe { libc::fcntl(self.handle, libc::F_GETFD) };
         if flags == -1 {
             bail!(
@@ -94,7 +100,12 @@ impl OwnedHandle {
                 std::io::Error::last_os_error()
             );
         }
-        let result = unsafe { libc::fcntl(self.handle, libc::F_SETFD, flags | libc::FD_CLOEXEC) };
+        let flags = if inheritable {
+            flags & !libc::FD_CLOEXEC
+        } else {
+            flags | libc::FD_CLOEXEC
+        };
+        let result = unsafe { libc::fcntl(self.handle, libc::F_SETFD, flags) };
         if result == -1 {
             bail!(
                 "fcntl to set CLOEXEC failed: {:?}",
@@ -104,6 +115,19 @@ impl OwnedHandle {
         Ok(())
     }
 
+    /// Returns true if the descriptor will be inherited by child processes
+    #[cfg(test)]
+    pub(crate) fn is_inheritable(&self) -> Fallible<bool> {
+        let flags = unsafe { libc::fcntl(self.handle, libc::F_GETFD) };
+        if flags == -1 {
+            bail!(
+                "fcntl to read flags failed: {:?}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(flags & libc::FD_CLOEXEC == 0)
+    }
+
     fn non_atomic_dup(fd: RawFd) -> Fallible<Self> {
         let duped = unsafe { libc::dup(fd) };
         if duped == -1 {
@@ -113,9 +137,9 @@ impl OwnedHandle {
                 std::io::Error::last_os_error()
             )
         } else {
-            let mut owned = OwnedHandle {
+            let owned = OwnedHandle {
                 handle: duped,
-                handle_type: (),
+                handle_type: HandleType::Unknown,
             };
             owned.cloexec()?;
             Ok(owned)
@@ -134,7 +158,7 @@ impl OwnedHandle {
             if let Some(libc::EINVAL) = err.raw_os_error() {
                 // We may be running on eg: WSL or an old kernel that
                 // doesn't support F_DUPFD_CLOEXEC; fall back.
-                return Self::non_atomic_dup(fd);
+                Self::non_atomic_dup(fd)
             } else {
                 bail!("dup of fd {} failed: {:?}", fd, err)
             }
@@ -146,8 +170,19 @@ impl OwnedHandle {
         }
     }
 
-    pub(crate) fn probe_handle_type(_handle: RawFileDescriptor) -> HandleType {
-        ()
+    /// Classify the descriptor by the file type reported by `fstat`
+    pub(crate) fn probe_handle_type(handle: RawFileDescriptor) -> HandleType {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(handle, &mut stat) } != 0 {
+            return HandleType::Unknown;
+        }
+        match stat.st_mode & libc::S_IFMT {
+            libc::S_IFIFO => HandleType::Pipe,
+            libc::S_IFSOCK => HandleType::Socket,
+            libc::S_IFREG => HandleType::Disk,
+            libc::S_IFCHR => HandleType::Char,
+            _ => HandleType::Unknown,
+        }
     }
 }
 
@@ -166,7 +201,16 @@ impl std::io::Write for FileDescriptor {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
         let size = unsafe { libc::write(self.handle.handle, buf.as_ptr() as *const _, buf.len()) };
         if size == -1 {
-            Err(std::io::Error::last_os_error())
+            let err = std::io::Error::last_os_error();
+            // The reader has gone away; SIGPIPE is ignored by the Rust
+            // runtime, so report that nothing was written rather than
+            // an error, in the same way that reading a broken pipe
+            // reports EOF on Windows.
+            if err.raw_os_error() == Some(libc::EPIPE) {
+                Ok(0)
+            } else {
+                Err(err)
+            }
         } else {
             Ok(size as usize)
         }
@@ -204,6 +248,13 @@ impl FileDescriptor {
         let stdio = unsafe { std::process::Stdio::from_raw_fd(fd) };
         Ok(stdio)
     }
+
+    pub(crate) fn handle_type_impl(&self) -> HandleType {
+        match self.handle.handle_type {
+            HandleType::Unknown => OwnedHandle::probe_handle_type(self.handle.handle),
+            t => t,
+        }
+    }
 }
 
 impl Pipe {
@@ -220,13 +271,13 @@ impl Pipe {
             let read = FileDescriptor {
                 handle: OwnedHandle {
                     handle: fds[0],
-                    handle_type: (),
+                    handle_type: HandleType::Pipe,
                 },
             };
             let write = FileDescriptor {
                 handle: OwnedHandle {
                     handle: fds[1],
-                    handle_type: (),
+                    handle_type: HandleType::Pipe,
                 },
             };
             Ok(Pipe { read, write })
@@ -243,16 +294,16 @@ impl Pipe {
                 std::io::Error::last_os_error()
             )
         } else {
-            let mut read = FileDescriptor {
+            let read = FileDescriptor {
                 handle: OwnedHandle {
                     handle: fds[0],
-                    handle_type: (),
+                    handle_type: HandleType::Pipe,
                 },
             };
-            let mut write = FileDescriptor {
+            let write = FileDescriptor {
                 handle: OwnedHandle {
                     handle: fds[1],
-                    handle_type: (),
+                    handle_type: HandleType::Pipe,
                 },
             };
             read.handle.cloexec()?;
@@ -283,13 +334,13 @@ pub fn socketpair_impl() -> Fallible<(FileDescriptor, FileDescriptor)> {
         let read = FileDescriptor {
             handle: OwnedHandle {
                 handle: fds[0],
-                handle_type: (),
+                handle_type: HandleType::Socket,
             },
         };
         let write = FileDescriptor {
             handle: OwnedHandle {
                 handle: fds[1],
-                handle_type: (),
+                handle_type: HandleType::Socket,
             },
         };
         Ok((read, write))
@@ -307,16 +358,16 @@ pub fn socketpair_impl() -> Fallible<(FileDescriptor, FileDescriptor)> {
             std::io::Error::last_os_error()
         )
     } else {
-        let mut read = FileDescriptor {
+        let read = FileDescriptor {
             handle: OwnedHandle {
                 handle: fds[0],
-                handle_type: (),
+                handle_type: HandleType::Socket,
             },
         };
-        let mut write = FileDescriptor {
+        let write = FileDescriptor {
             handle: OwnedHandle {
                 handle: fds[1],
-                handle_type: (),
+                handle_type: HandleType::Socket,
             },
         };
         read.handle.cloexec()?;
@@ -325,6 +376,85 @@ pub fn socketpair_impl() -> Fallible<(FileDescriptor, FileDescriptor)> {
     }
 }
 
+impl FdSource {
+    fn raw_fd(&self) -> RawFd {
+        match self {
+            FdSource::Descriptor(fd) => fd.as_raw_fd(),
+            FdSource::Inherited(fd_number) => *fd_number as RawFd,
+        }
+    }
+}
+
+impl FdTable {
+    /// Arrange for `command` to see the descriptors of the table.  They
+    /// are rearranged in the child between fork and exec, after any
+    /// `pre_exec` closures that were registered on `command` earlier.
+    pub fn apply(&self, command: &mut Command) -> Fallible<()> {
+        let mut table = self.clone();
+        // Safe because apply_in_child only makes system calls and
+        // does not allocate memory
+        unsafe {
+            command.pre_exec(move || table.apply_in_child());
+        }
+        Ok(())
+    }
+
+    /// Rearrange the descriptors of the current process to match the
+    /// table.  This is called in the child process, between fork and
+    /// exec, so it avoids allocating memory.
+    ///
+    /// Assigning the descriptors in order could clobber a source
+    /// before it is used: `1>&2 2>file` needs the original stderr to
+    /// be copied to 1 before 2 is replaced.  To avoid that, any source
+    /// that is also the destination of some other entry is first
+    /// moved above the highest destination.  A source that already has
+    /// the number of its destination is left in place, but its
+    /// close-on-exec flag is cleared so that the child inherits it.
+    fn apply_in_child(&mut self) -> std::io::Result<()> {
+        let max_dest = match self.entries.keys().next_back() {
+            Some(&max_dest) => max_dest,
+            None => return Ok(()),
+        };
+
+        for dest in 0..=max_dest {
+            let raw = match self.entries.get(&dest) {
+                Some(source) => source.raw_fd(),
+                None => continue,
+            };
+            if raw as usize == dest || !self.entries.contains_key(&(raw as usize)) {
+                continue;
+            }
+            let moved = unsafe { libc::fcntl(raw, libc::F_DUPFD_CLOEXEC, max_dest + 1) };
+            if moved == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if let Some(source) = self.entries.get_mut(&dest) {
+                // Leak the original rather than freeing memory here;
+                // it is close-on-exec in any case
+                std::mem::forget(std::mem::replace(
+                    source,
+                    FdSource::Inherited(moved as usize),
+                ));
+            }
+        }
+
+        for (&dest, source) in &self.entries {
+            let raw = source.raw_fd();
+            if raw as usize == dest {
+                let flags = unsafe { libc::fcntl(raw, libc::F_GETFD) };
+                if flags == -1
+                    || unsafe { libc::fcntl(raw, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } == -1
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+            } else if unsafe { libc::dup2(raw, dest as RawFd) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
 pub use libc::{pollfd, POLLERR, POLLHUP, POLLIN, POLLOUT};
 use std::time::Duration;
 
@@ -344,3 +474,134 @@ pub fn poll_impl(pfd: &mut [pollfd], duration: Option<Duration>) -> Fallible<usi
         Ok(poll_result as usize)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn probe_handle_type() -> Fallible<()> {
+        let pipe = Pipe::new()?;
+        assert_eq!(
+            OwnedHandle::probe_handle_type(pipe.read.as_raw_fd()),
+            HandleType::Pipe
+        );
+        assert_eq!(
+            OwnedHandle::probe_handle_type(pipe.write.as_raw_fd()),
+            HandleType::Pipe
+        );
+
+        let (a, _b) = socketpair_impl()?;
+        assert_eq!(
+            OwnedHandle::probe_handle_type(a.as_raw_fd()),
+            HandleType::Socket
+        );
+
+        let dir = tempdir::TempDir::new("filedescriptor")?;
+        let file = std::fs::File::create(dir.path().join("file"))?;
+        assert_eq!(
+            OwnedHandle::probe_handle_type(file.as_raw_fd()),
+            HandleType::Disk
+        );
+
+        let null = std::fs::File::open("/dev/null")?;
+        assert_eq!(
+            OwnedHandle::probe_handle_type(null.as_raw_fd()),
+            HandleType::Char
+        );
+        Ok(())
+    }
+
+    /// Run `script` with `table` applied, returning what it wrote to
+    /// its original stdout and to the pipe in the table
+    fn run_with_table(script: &str, table: FdTable, pipe: Pipe) -> Fallible<(String, String)> {
+        use std::io::Read;
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(script)
+            .stdout(std::process::Stdio::piped());
+        table.apply(&mut cmd)?;
+        drop(table);
+        let child = cmd.spawn()?;
+        drop(cmd);
+        let output = child.wait_with_output()?;
+
+        let mut piped = String::new();
+        let mut read = pipe.read;
+        drop(pipe.write);
+        read.read_to_string(&mut piped)?;
+        Ok((String::from_utf8(output.stdout)?, piped))
+    }
+
+    #[test]
+    fn fd_table_redirect_then_dup() -> Fallible<()> {
+        // >pipe 2>&1
+        let pipe = Pipe::new()?;
+        let mut table = FdTable::new();
+        table.set(1, pipe.write.try_clone()?);
+        table.dup(1, 2);
+        let (stdout, piped) = run_with_table("echo out; echo err >&2", table, pipe)?;
+        assert_eq!(stdout, "");
+        assert_eq!(piped, "out\nerr\n");
+        Ok(())
+    }
+
+    #[test]
+    fn fd_table_dup_then_redirect() -> Fallible<()> {
+        // 2>&1 >pipe: the source of the dup is the destination of the
+        // later redirect, so it must not be clobbered before use
+        let pipe = Pipe::new()?;
+        let mut table = FdTable::new();
+        table.dup(1, 2);
+        table.set(1, pipe.write.try_clone()?);
+        let (stdout, piped) = run_with_table("echo out; echo err >&2", table, pipe)?;
+        assert_eq!(stdout, "err\n");
+        assert_eq!(piped, "out\n");
+        Ok(())
+    }
+
+    #[test]
+    fn fd_table_swap() -> Fallible<()> {
+        // 3>&1 1>&2 2>&3 swaps stdout and stderr
+        let pipe = Pipe::new()?;
+        let mut table = FdTable::new();
+        table.set(2, pipe.write.try_clone()?);
+        table.dup(1, 3);
+        table.dup(2, 1);
+        table.dup(3, 2);
+        let (stdout, piped) =
+            run_with_table("echo out; echo err >&2; echo three >&3", table, pipe)?;
+        assert_eq!(stdout, "err\nthree\n");
+        assert_eq!(piped, "out\n");
+        Ok(())
+    }
+
+    #[test]
+    fn fd_table_source_already_in_place() -> Fallible<()> {
+        // The pipe is close-on-exec in this process; keeping it at the
+        // same number must still make it available to the child
+        let pipe = Pipe::new()?;
+        let raw = pipe.write.as_raw_fd();
+        let mut table = FdTable::new();
+        table.dup(raw as usize, raw as usize);
+        let (stdout, piped) = run_with_table(&format!("echo direct >&{}", raw), table, pipe)?;
+        assert_eq!(stdout, "");
+        assert_eq!(piped, "direct\n");
+        Ok(())
+    }
+
+    #[test]
+    fn handle_type_is_recorded() -> Fallible<()> {
+        let (a, _b) = socketpair_impl()?;
+        assert_eq!(a.handle.handle_type, HandleType::Socket);
+        let duped = a.try_clone()?;
+        assert_eq!(duped.handle.handle_type, HandleType::Socket);
+
+        let dir = tempdir::TempDir::new("filedescriptor")?;
+        let file = FileDescriptor::new(std::fs::File::create(dir.path().join("file"))?);
+        assert_eq!(file.handle.handle_type, HandleType::Disk);
+        assert_eq!(file.handle_type(), HandleType::Disk);
+        Ok(())
+    }
+}
@@ -1,13 +1,16 @@
 use crate::{
-    AsRawFileDescriptor, AsRawSocketDescriptor, FileDescriptor, FromRawFileDescriptor,
-    FromRawSocketDescriptor, IntoRawFileDescriptor, IntoRawSocketDescriptor, OwnedHandle, Pipe,
+    AsRawFileDescriptor, AsRawSocketDescriptor, FdSource, FdTable, FileDescriptor,
+    FromRawFileDescriptor, FromRawSocketDescriptor, HandleType, IntoRawFileDescriptor,
+    IntoRawSocketDescriptor, OwnedHandle, Pipe,
 };
 use failure::{bail, Fallible};
 use std::io::{self, Error as IoError};
 use std::os::windows::prelude::*;
+use std::process::{Command, Stdio};
 use std::ptr;
 use std::sync::Once;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use winapi::shared::winerror::WSAEWOULDBLOCK;
 use winapi::shared::ws2def::AF_INET;
 use winapi::shared::ws2def::INADDR_LOOPBACK;
 use winapi::shared::ws2def::SOCKADDR_IN;
@@ -16,11 +19,12 @@ use winapi::um::handleapi::*;
 use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
 use winapi::um::namedpipeapi::{CreatePipe, GetNamedPipeInfo};
 use winapi::um::processthreadsapi::*;
-use winapi::um::winbase::{FILE_TYPE_CHAR, FILE_TYPE_DISK, FILE_TYPE_PIPE};
+use winapi::um::winbase::{FILE_TYPE_CHAR, FILE_TYPE_DISK, FILE_TYPE_PIPE, HANDLE_FLAG_INHERIT};
 use winapi::um::winnt::HANDLE;
 use winapi::um::winsock2::{
-    accept, bind, closesocket, connect, getsockname, htonl, listen, WSAPoll, WSASocketW,
-    WSAStartup, INVALID_SOCKET, SOCKET, SOCK_STREAM, WSADATA, WSA_FLAG_NO_HANDLE_INHERIT,
+    accept, bind, closesocket, connect, getpeername, getsockname, htonl, ioctlsocket, listen,
+    u_long, WSAGetLastError, WSAPoll, WSASocketW, WSAStartup, FIONBIO, INVALID_SOCKET, SOCKET,
+    SOCK_STREAM, WSADATA, WSA_FLAG_NO_HANDLE_INHERIT,
 };
 pub use winapi::um::winsock2::{POLLERR, POLLHUP, POLLIN, POLLOUT, WSAPOLLFD as pollfd};
 
@@ -34,21 +38,6 @@ pub type RawFileDescriptor = RawHandle;
 /// for avoiding using `cfg` blocks in platform independent code.
 pub type SocketDescriptor = SOCKET;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) enum HandleType {
-    Char,
-    Disk,
-    Pipe,
-    Socket,
-    Unknown,
-}
-
-impl Default for HandleType {
-    fn default() -> Self {
-        HandleType::Unknown
-    }
-}
-
 impl<T: AsRawHandle> AsRawFileDescriptor for T {
     fn as_raw_file_descriptor(&self) -> RawFileDescriptor {
         self.as_raw_handle()
@@ -151,6 +140,26 @@ impl FromRawHandle for OwnedHandle {
 }
 
 impl OwnedHandle {
+    /// Set or clear the flag that determines whether the handle is
+    /// inherited by child processes
+    pub(crate) fn set_inheritable(&self, inheritable: bool) -> Fallible<()> {
+        let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+        if unsafe { SetHandleInformation(self.handle as _, HANDLE_FLAG_INHERIT, flags) } == 0 {
+            bail!("SetHandleInformation failed: {}", IoError::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns true if the handle will be inherited by child processes
+    #[cfg(test)]
+    pub(crate) fn is_inheritable(&self) -> Fallible<bool> {
+        let mut flags = 0;
+        if unsafe { GetHandleInformation(self.handle as _, &mut flags) } == 0 {
+            bail!("GetHandleInformation failed: {}", IoError::last_os_error());
+        }
+        Ok(flags & HANDLE_FLAG_INHERIT != 0)
+    }
+
     #[inline]
     pub(crate) fn dup_impl<F: AsRawFileDescriptor>(
         f: &F,
@@ -212,6 +221,10 @@ impl FileDescriptor {
         let stdio = unsafe { std::process::Stdio::from_raw_handle(handle) };
         Ok(stdio)
     }
+
+    pub(crate) fn handle_type_impl(&self) -> HandleType {
+        OwnedHandle::probe_handle_type_if_unknown(self.handle.handle, self.handle.handle_type)
+    }
 }
 
 impl IntoRawHandle for FileDescriptor {
@@ -296,7 +309,12 @@ impl io::Write for FileDescriptor {
             )
         };
         if ok == 0 {
-            Err(IoError::last_os_error())
+            let err = IoError::last_os_error();
+            if err.kind() == std::io::ErrorKind::BrokenPipe {
+                Ok(0)
+            } else {
+                Err(err)
+            }
         } else {
             Ok(num_wrote as usize)
         }
@@ -308,6 +326,8 @@ impl io::Write for FileDescriptor {
 
 impl Pipe {
     pub fn new() -> Fallible<Pipe> {
+        // The ends are not inheritable; FileDescriptor::pipe_inheritable
+        // relies on this when it opts in to inheritance
         let mut sa = SECURITY_ATTRIBUTES {
             nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
             lpSecurityDescriptor: ptr::null_mut(),
@@ -335,6 +355,37 @@ impl Pipe {
     }
 }
 
+impl FdTable {
+    /// Make descriptors 0, 1 and 2 of the table the standard input,
+    /// output and error of `command`.  Those that haven't been set are
+    /// left as configured on `command`.  Windows has no way to pass
+    /// other descriptor numbers to a child, so they are an error.
+    pub fn apply(&self, command: &mut Command) -> Fallible<()> {
+        if let Some(fd_number) = self.entries.keys().find(|&&fd_number| fd_number > 2) {
+            bail!("fd {} cannot be passed to a child process", fd_number);
+        }
+
+        for (&fd_number, source) in &self.entries {
+            let stdio = match source {
+                FdSource::Descriptor(fd) => fd.as_stdio()?,
+                FdSource::Inherited(src) if *src == fd_number => Stdio::inherit(),
+                FdSource::Inherited(0) => FileDescriptor::dup(&io::stdin())?.as_stdio()?,
+                FdSource::Inherited(1) => FileDescriptor::dup(&io::stdout())?.as_stdio()?,
+                FdSource::Inherited(2) => FileDescriptor::dup(&io::stderr())?.as_stdio()?,
+                FdSource::Inherited(src) => {
+                    bail!("fd {} cannot be passed to a child process", src)
+                }
+            };
+            match fd_number {
+                0 => command.stdin(stdio),
+                1 => command.stdout(stdio),
+                _ => command.stderr(stdio),
+            };
+        }
+        Ok(())
+    }
+}
+
 fn init_winsock() {
     static START: Once = Once::new();
     START.call_once(|| unsafe {
@@ -369,6 +420,109 @@ fn socket(af: i32, sock_type: i32, proto: i32) -> Fallible<FileDescriptor> {
     })
 }
 
+/// Accept a connection on the listening socket.  The new socket is
+/// owned by the returned FileDescriptor from the moment that it is
+/// created.  On failure, no socket was created and there is nothing
+/// to close; `listener` remains owned by the caller.
+fn accept_socket(listener: &FileDescriptor) -> Fallible<FileDescriptor> {
+    let server = unsafe {
+        accept(
+            listener.as_raw_handle() as _,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    if server == INVALID_SOCKET {
+        bail!("accept failed: {}", IoError::last_os_error());
+    }
+    Ok(FileDescriptor {
+        handle: OwnedHandle {
+            handle: server as _,
+            handle_type: HandleType::Socket,
+        },
+    })
+}
+
+/// Return the local address of `sock`, or the address of its peer
+/// when `peer` is true.
+fn socket_address(sock: &FileDescriptor, peer: bool) -> Fallible<SOCKADDR_IN> {
+    let mut addr: SOCKADDR_IN = unsafe { std::mem::zeroed() };
+    let mut addr_len = std::mem::size_of_val(&addr) as i32;
+    let result = unsafe {
+        if peer {
+            getpeername(
+                sock.as_raw_handle() as _,
+                std::mem::transmute(&mut addr),
+                &mut addr_len,
+            )
+        } else {
+            getsockname(
+                sock.as_raw_handle() as _,
+                std::mem::transmute(&mut addr),
+                &mut addr_len,
+            )
+        }
+    };
+    if result != 0 {
+        bail!(
+            "{} failed: {}",
+            if peer { "getpeername" } else { "getsockname" },
+            IoError::last_os_error()
+        );
+    }
+    Ok(addr)
+}
+
+fn same_address(a: &SOCKADDR_IN, b: &SOCKADDR_IN) -> bool {
+    a.sin_port == b.sin_port && unsafe { *a.sin_addr.S_un.S_addr() == *b.sin_addr.S_un.S_addr() }
+}
+
+/// The longest that `socketpair_impl` waits for the loopback
+/// connection to be established before giving up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn set_nonblocking(sock: &FileDescriptor, non_blocking: bool) -> Fallible<()> {
+    let mut mode: u_long = if non_blocking { 1 } else { 0 };
+    if unsafe { ioctlsocket(sock.as_raw_handle() as _, FIONBIO, &mut mode) } != 0 {
+        bail!("ioctlsocket failed: {}", IoError::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wait until `sock` reports one of `events`, failing if that
+/// doesn't happen before `deadline`.  Returns the reported events.
+fn wait_for_socket(sock: &FileDescriptor, events: i16, deadline: Instant) -> Fallible<i16> {
+    loop {
+        let now = Instant::now();
+        let remaining = if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_millis(0)
+        };
+        let mut pfd = [pollfd {
+            fd: sock.as_raw_handle() as _,
+            events,
+            revents: 0,
+        }];
+        if poll_impl(&mut pfd, Some(remaining))? > 0 && pfd[0].revents != 0 {
+            return Ok(pfd[0].revents);
+        }
+        if remaining == Duration::from_millis(0) {
+            bail!("socketpair handshake timed out");
+        }
+    }
+}
+
+/// Emulate socketpair(2) with a pair of connected loopback sockets.
+/// Every socket is wrapped in a FileDescriptor as soon as it is
+/// created, so an early return from a failed `bind`, `listen`,
+/// `connect` or `accept` closes those that were created before it.
+/// The handshake is performed with non-blocking sockets so that it
+/// fails after `HANDSHAKE_TIMEOUT` rather than hanging if, for
+/// example, a firewall interferes with the loopback connection.
+/// The listener is bound to an ephemeral port on 127.0.0.1 and any
+/// accepted connection whose peer isn't our client socket is closed,
+/// so another local process can't hijack the pair by connecting first.
 #[doc(hidden)]
 pub fn socketpair_impl() -> Fallible<(FileDescriptor, FileDescriptor)> {
     init_winsock();
@@ -377,6 +531,7 @@ pub fn socketpair_impl() -> Fallible<(FileDescriptor, FileDescriptor)> {
 
     let mut in_addr: SOCKADDR_IN = unsafe { std::mem::zeroed() };
     in_addr.sin_family = AF_INET as _;
+    in_addr.sin_port = 0;
     unsafe {
         *in_addr.sin_addr.S_un.S_addr_mut() = htonl(INADDR_LOOPBACK);
     }
@@ -392,18 +547,7 @@ pub fn socketpair_impl() -> Fallible<(FileDescriptor, FileDescriptor)> {
         }
     }
 
-    let mut addr_len = std::mem::size_of_val(&in_addr) as i32;
-
-    unsafe {
-        if getsockname(
-            s.as_raw_handle() as _,
-            std::mem::transmute(&mut in_addr),
-            &mut addr_len,
-        ) != 0
-        {
-            bail!("getsockname failed: {}", IoError::last_os_error());
-        }
-    }
+    let in_addr = socket_address(&s, false)?;
 
     unsafe {
         if listen(s.as_raw_handle() as _, 1) != 0 {
@@ -411,30 +555,42 @@ pub fn socketpair_impl() -> Fallible<(FileDescriptor, FileDescriptor)> {
         }
     }
 
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    set_nonblocking(&s, true)?;
+
     let client = socket(AF_INET, SOCK_STREAM, 0)?;
+    set_nonblocking(&client, true)?;
 
     unsafe {
         if connect(
             client.as_raw_handle() as _,
             std::mem::transmute(&in_addr),
-            addr_len,
+            std::mem::size_of_val(&in_addr) as _,
         ) != 0
+            && WSAGetLastError() != WSAEWOULDBLOCK as i32
         {
             bail!("connect failed: {}", IoError::last_os_error());
         }
     }
 
-    let server = unsafe { accept(s.as_raw_handle() as _, ptr::null_mut(), ptr::null_mut()) };
-    if server == INVALID_SOCKET {
-        bail!("socket failed: {}", IoError::last_os_error());
+    if wait_for_socket(&client, POLLOUT, deadline)? & (POLLERR | POLLHUP) != 0 {
+        bail!("connect failed");
     }
-    let server = FileDescriptor {
-        handle: OwnedHandle {
-            handle: server as _,
-            handle_type: HandleType::Socket,
-        },
+    let client_addr = socket_address(&client, false)?;
+
+    let server = loop {
+        wait_for_socket(&s, POLLIN, deadline)?;
+        let server = accept_socket(&s)?;
+        if same_address(&socket_address(&server, true)?, &client_addr) {
+            break server;
+        }
+        // Someone else connected; dropping `server` closes it
     };
 
+    // The accepted socket inherits the non-blocking mode of the listener
+    set_nonblocking(&server, false)?;
+    set_nonblocking(&client, false)?;
+
     Ok((server, client))
 }
 
@@ -458,8 +614,124 @@ pub fn poll_impl(pfd: &mut [pollfd], duration: Option<Duration>) -> Fallible<usi
 
 #[cfg(test)]
 mod test {
+    use super::*;
     use std::io::{Read, Write};
 
+    #[test]
+    fn accept_failure_closes_listener() {
+        init_winsock();
+        let listener = socket(AF_INET, SOCK_STREAM, 0).unwrap();
+        let raw = listener.as_raw_handle();
+
+        // The socket isn't listening, so accept fails
+        assert!(accept_socket(&listener).is_err());
+
+        // Dropping the listener on the error path closes it, so
+        // closing the raw socket a second time fails
+        drop(listener);
+        assert_ne!(unsafe { closesocket(raw as _) }, 0);
+    }
+
+    #[test]
+    fn handshake_is_quick() {
+        let start = Instant::now();
+        super::socketpair_impl().unwrap();
+        assert!(start.elapsed() < HANDSHAKE_TIMEOUT / 10);
+    }
+
+    #[test]
+    fn handshake_times_out() {
+        init_winsock();
+        let listener = socket(AF_INET, SOCK_STREAM, 0).unwrap();
+        let mut in_addr: SOCKADDR_IN = unsafe { std::mem::zeroed() };
+        in_addr.sin_family = AF_INET as _;
+        unsafe {
+            *in_addr.sin_addr.S_un.S_addr_mut() = htonl(INADDR_LOOPBACK);
+            assert_eq!(
+                bind(
+                    listener.as_raw_handle() as _,
+                    std::mem::transmute(&in_addr),
+                    std::mem::size_of_val(&in_addr) as _,
+                ),
+                0
+            );
+            assert_eq!(listen(listener.as_raw_handle() as _, 1), 0);
+        }
+
+        // Nothing connects, so the listener never becomes readable
+        let err = wait_for_socket(&listener, POLLIN, Instant::now()).unwrap_err();
+        assert_eq!(err.to_string(), "socketpair handshake timed out");
+    }
+
+    #[test]
+    fn socketpair_is_paired() {
+        let (mut server, mut client) = super::socketpair_impl().unwrap();
+        let (mut other_server, mut other_client) = super::socketpair_impl().unwrap();
+        for (a, b) in &[(&server, &client), (&other_server, &other_client)] {
+            assert!(same_address(
+                &socket_address(a, true).unwrap(),
+                &socket_address(b, false).unwrap()
+            ));
+            assert!(same_address(
+                &socket_address(b, true).unwrap(),
+                &socket_address(a, false).unwrap()
+            ));
+        }
+        assert!(!same_address(
+            &socket_address(&server, true).unwrap(),
+            &socket_address(&other_client, false).unwrap()
+        ));
+
+        client.write(b"one").unwrap();
+        server.write(b"two").unwrap();
+        let mut buf = [0u8; 3];
+        assert_eq!(server.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"one");
+        assert_eq!(client.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"two");
+
+        // Nothing was written to the other pair
+        let mut pfd = [pollfd {
+            fd: other_server.as_raw_handle() as _,
+            events: POLLIN,
+            revents: 0,
+        }];
+        assert_eq!(
+            poll_impl(&mut pfd, Some(Duration::from_millis(50))).unwrap(),
+            0
+        );
+        other_client.write(b"three").unwrap();
+        let mut buf = [0u8; 5];
+        assert_eq!(other_server.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"three");
+    }
+
+    #[test]
+    fn fd_table_command() {
+        // >pipe 2>&1
+        let pipe = Pipe::new().unwrap();
+        let mut table = FdTable::new();
+        table.set(1, pipe.write);
+        table.dup(1, 2);
+
+        let mut cmd = Command::new("cmd");
+        cmd.args(&["/C", "echo out&& echo err>&2"]);
+        table.apply(&mut cmd).unwrap();
+        assert!(cmd.status().unwrap().success());
+        // Close the remaining copies of the write end
+        drop(cmd);
+        drop(table);
+
+        let mut output = String::new();
+        let mut read = pipe.read;
+        read.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "out\r\nerr\r\n");
+
+        let mut table = FdTable::new();
+        table.set(3, FileDescriptor::pipe().unwrap().0);
+        assert!(table.apply(&mut Command::new("cmd")).is_err());
+    }
+
     #[test]
     fn socketpair() {
         let (mut a, mut b) = super::socketpair_impl().unwrap();
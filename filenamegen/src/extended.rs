@@ -0,0 +1,356 @@
+//! Extended glob patterns, as enabled by the `extglob` shell option.
+//! In addition to `*`, `?` and `[...]`, a pattern may contain these
+//! groups, where `list` is one or more patterns separated by `|`:
+//!
+//! * `?(list)` matches zero or one occurrence of the patterns
+//! * `*(list)` matches zero or more occurrences
+//! * `+(list)` matches one or more occurrences
+//! * `@(list)` matches exactly one of the patterns
+//! * `!(list)` matches anything except one of the patterns
+//!
+//! The negated form cannot be expressed using the regex crate, so
+//! extended patterns are evaluated by a backtracking matcher.
+use failure::{format_err, Fallible};
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GroupKind {
+    ZeroOrOne,
+    ZeroOrMore,
+    OneOrMore,
+    ExactlyOne,
+    Not,
+}
+
+#[derive(Debug)]
+enum Element {
+    Literal(char),
+    /// `?`
+    Any,
+    /// `*`
+    ZeroOrMore,
+    /// A `[...]` bracket expression, compiled to match a single char
+    Class(Regex),
+    Group(GroupKind, Vec<Vec<Element>>),
+}
+
+/// A compiled extended glob pattern.  Unlike pathname expansion,
+/// the wildcards match any character, including `/` and a leading `.`.
+#[derive(Debug)]
+pub struct ExtendedPattern {
+    elements: Vec<Element>,
+}
+
+impl ExtendedPattern {
+    /// Compile `pattern`.  A `\` quotes the character that follows it.
+    /// A `[` or group opener that has no matching close is treated
+    /// literally.
+    pub fn new(pattern: &str) -> Fallible<Self> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut idx = 0;
+        let elements = parse_sequence(&chars, &mut idx, false)?;
+        Ok(Self { elements })
+    }
+
+    /// Returns true if `pattern` contains any of the extended
+    /// group operators, and thus requires this matcher
+    pub fn is_extended(pattern: &str) -> bool {
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '?' | '*' | '+' | '@' | '!' if chars.peek() == Some(&'(') => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Returns true if the pattern begins with a literal `.`, which
+    /// is required to match a hidden file name
+    pub fn starts_with_dot(&self) -> bool {
+        matches!(self.elements.first(), Some(Element::Literal('.')))
+    }
+
+    /// Returns true if the entirety of `text` is matched
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        match_sequence(&self.elements, &text, 0, &|end| end == text.len())
+    }
+}
+
+fn group_kind(c: char) -> Option<GroupKind> {
+    match c {
+        '?' => Some(GroupKind::ZeroOrOne),
+        '*' => Some(GroupKind::ZeroOrMore),
+        '+' => Some(GroupKind::OneOrMore),
+        '@' => Some(GroupKind::ExactlyOne),
+        '!' => Some(GroupKind::Not),
+        _ => None,
+    }
+}
+
+/// Returns the index of the `)` that closes the group whose `(`
+/// is at `open`
+fn find_group_end(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut idx = open;
+    while idx < chars.len() {
+        match chars[idx] {
+            '\\' => idx += 1,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => {}
+        }
+        idx += 1;
+    }
+    None
+}
+
+/// Parse elements until the end of the input or, when `in_group`
+/// is true, until an unquoted `|` or `)`, which is not consumed.
+fn parse_sequence(chars: &[char], idx: &mut usize, in_group: bool) -> Fallible<Vec<Element>> {
+    let mut elements = vec![];
+    while *idx < chars.len() {
+        let c = chars[*idx];
+        if in_group && (c == '|' || c == ')') {
+            break;
+        }
+        *idx += 1;
+        match c {
+            '\\' if *idx < chars.len() => {
+                elements.push(Element::Literal(chars[*idx]));
+                *idx += 1;
+            }
+            c if group_kind(c).is_some() && chars.get(*idx) == Some(&'(') => {
+                match find_group_end(chars, *idx) {
+                    Some(end) => {
+                        let mut alternatives = vec![];
+                        *idx += 1;
+                        loop {
+                            alternatives.push(parse_sequence(&chars[..end], idx, true)?);
+                            if *idx >= end {
+                                break;
+                            }
+                            // Skip the `|` separator
+                            *idx += 1;
+                        }
+                        *idx = end + 1;
+                        elements.push(Element::Group(group_kind(c).unwrap(), alternatives));
+                    }
+                    None => elements.push(Element::Literal(c)),
+                }
+            }
+            '*' => elements.push(Element::ZeroOrMore),
+            '?' => elements.push(Element::Any),
+            '[' => match bracket_expression(&chars[*idx..])? {
+                Some((class, len)) => {
+                    elements.push(Element::Class(class));
+                    *idx += len;
+                }
+                None => elements.push(Element::Literal('[')),
+            },
+            c => elements.push(Element::Literal(c)),
+        }
+    }
+    Ok(elements)
+}
+
+/// Compile the body of a bracket expression; `chars` starts just
+/// after the opening `[`.  Returns the compiled class and the number
+/// of chars consumed, including the closing `]`.
+fn bracket_expression(chars: &[char]) -> Fallible<Option<(Regex, usize)>> {
+    let mut class = String::from("^(?s:[");
+    let mut idx = 0;
+
+    if let Some('!') | Some('^') = chars.get(idx) {
+        class.push('^');
+        idx += 1;
+    }
+
+    // A `]` in the first position is literal
+    if let Some(']') = chars.get(idx) {
+        class.push_str("\\]");
+        idx += 1;
+    }
+
+    while idx < chars.len() {
+        match chars[idx] {
+            ']' => {
+                class.push_str("])$");
+                let regex = Regex::new(&class)
+                    .map_err(|e| format_err!("error compiling class: {}: {}", class, e))?;
+                return Ok(Some((regex, idx + 1)));
+            }
+            '[' if chars.get(idx + 1) == Some(&':') => {
+                // A character class name such as `[:alpha:]`
+                let rest: String = chars[idx..].iter().collect();
+                let end = match rest[2..].find(":]") {
+                    Some(end) => end + 2,
+                    None => return Ok(None),
+                };
+                class.push_str(&rest[..end + 2]);
+                idx += rest[..end + 2].chars().count();
+                continue;
+            }
+            '\\' if idx + 1 < chars.len() => {
+                idx += 1;
+                class.push_str(&regex::escape(&chars[idx].to_string()));
+            }
+            c @ '[' | c @ '&' | c @ '~' | c @ '^' => {
+                class.push('\\');
+                class.push(c);
+            }
+            c => class.push(c),
+        }
+        idx += 1;
+    }
+
+    Ok(None)
+}
+
+/// Attempt to match `elements` against `text` starting at `pos`.
+/// `next` is called with each position at which the elements could
+/// end, and determines whether the overall match succeeds; this
+/// allows backtracking into the choices made by earlier elements.
+fn match_sequence(
+    elements: &[Element],
+    text: &[char],
+    pos: usize,
+    next: &dyn Fn(usize) -> bool,
+) -> bool {
+    let (element, rest) = match elements.split_first() {
+        Some(split) => split,
+        None => return next(pos),
+    };
+    let rest_matches = |end: usize| match_sequence(rest, text, end, next);
+
+    match element {
+        Element::Literal(c) => text.get(pos) == Some(c) && rest_matches(pos + 1),
+        Element::Any => pos < text.len() && rest_matches(pos + 1),
+        Element::ZeroOrMore => (pos..=text.len()).any(rest_matches),
+        Element::Class(class) => match text.get(pos) {
+            Some(c) => class.is_match(&c.to_string()) && rest_matches(pos + 1),
+            None => false,
+        },
+        Element::Group(kind, alternatives) => {
+            let any_alternative = |start: usize, then: &dyn Fn(usize) -> bool| {
+                alternatives
+                    .iter()
+                    .any(|alt| match_sequence(alt, text, start, then))
+            };
+            match kind {
+                GroupKind::ExactlyOne => any_alternative(pos, &rest_matches),
+                GroupKind::ZeroOrOne => rest_matches(pos) || any_alternative(pos, &rest_matches),
+                GroupKind::ZeroOrMore => repeat(alternatives, text, pos, &rest_matches),
+                GroupKind::OneOrMore => any_alternative(pos, &|end| {
+                    end > pos && repeat(alternatives, text, end, &rest_matches)
+                }),
+                GroupKind::Not => (pos..=text.len()).any(|end| {
+                    !any_alternative(pos, &|alt_end| alt_end == end) && rest_matches(end)
+                }),
+            }
+        }
+    }
+}
+
+/// Match zero or more occurrences of any of the alternatives.
+/// Each occurrence must consume at least one character so that
+/// the recursion terminates.
+fn repeat(
+    alternatives: &[Vec<Element>],
+    text: &[char],
+    pos: usize,
+    next: &dyn Fn(usize) -> bool,
+) -> bool {
+    next(pos)
+        || alternatives.iter().any(|alt| {
+            match_sequence(alt, text, pos, &|end| {
+                end > pos && repeat(alternatives, text, end, next)
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        ExtendedPattern::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn zero_or_one() {
+        assert!(matches("foo?(.rs)", "foo"));
+        assert!(matches("foo?(.rs)", "foo.rs"));
+        assert!(!matches("foo?(.rs)", "foo.rs.rs"));
+        assert!(matches("?(a|b)c", "bc"));
+    }
+
+    #[test]
+    fn zero_or_more() {
+        assert!(matches("*(ab)", ""));
+        assert!(matches("*(ab)", "ababab"));
+        assert!(!matches("*(ab)", "aba"));
+        assert!(matches("x*(a|bc)y", "xabcay"));
+        assert!(matches("*(*)", "anything"));
+    }
+
+    #[test]
+    fn one_or_more() {
+        assert!(!matches("+(ab)", ""));
+        assert!(matches("+(ab)", "ab"));
+        assert!(matches("+(a|b)", "abba"));
+        assert!(!matches("+(a|b)", "abc"));
+        assert!(matches("+([0-9]).txt", "123.txt"));
+    }
+
+    #[test]
+    fn exactly_one() {
+        assert!(matches("@(foo|bar).c", "foo.c"));
+        assert!(matches("@(foo|bar).c", "bar.c"));
+        assert!(!matches("@(foo|bar).c", "foobar.c"));
+        assert!(!matches("@(foo|bar).c", ".c"));
+        assert!(matches("@(f*|b?r)", "fizz"));
+    }
+
+    #[test]
+    fn not() {
+        assert!(matches("!(foo)", "bar"));
+        assert!(matches("!(foo)", ""));
+        assert!(!matches("!(foo)", "foo"));
+        // Only the text as a whole is compared with the excluded pattern
+        assert!(matches("!(foo)", "foobar"));
+        assert!(matches("!(*.o|*.a).c", "main.c"));
+        assert!(!matches("*.!(o|a)", "lib.o"));
+        assert!(matches("*.!(o|a)", "lib.rs"));
+        assert!(matches("!(+(ab))", "aba"));
+        assert!(!matches("!(+(ab))", "abab"));
+    }
+
+    #[test]
+    fn nested_and_literal() {
+        assert!(matches("@(a|+(b|c))d", "bcbd"));
+        assert!(matches("a\\*(b)", "a*(b)"));
+        assert!(!matches("a\\*(b)", "ab"));
+        // An unclosed group is literal
+        assert!(matches("@(ab", "@(ab"));
+        assert!(matches("[@!](x)", "!(x)"));
+    }
+
+    #[test]
+    fn detection() {
+        assert!(ExtendedPattern::is_extended("*.!(o)"));
+        assert!(ExtendedPattern::is_extended("@(a|b)"));
+        assert!(!ExtendedPattern::is_extended("*.rs"));
+        assert!(!ExtendedPattern::is_extended("\\@(a)"));
+        assert!(!ExtendedPattern::is_extended("foo(1)"));
+    }
+}
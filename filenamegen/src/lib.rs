@@ -4,15 +4,17 @@
 //! * Can generate paths relative to a specified dir rather than
 //!   assuming the current working dir
 
-use failure::Fallible;
+use failure::{ensure, Fallible};
 use std::collections::VecDeque;
 use std::path::{Component, Path, PathBuf};
 
+mod extended;
 mod node;
 mod nodewalker;
 mod parser;
 mod recursivewalker;
 mod token;
+pub use extended::ExtendedPattern;
 use node::Node;
 use nodewalker::NodeWalker;
 use parser::parse;
@@ -28,6 +30,20 @@ use recursivewalker::RecursiveWalker;
 #[derive(Debug)]
 pub struct Glob {
     nodes: Vec<Node>,
+    options: MatchOptions,
+}
+
+/// Options that adjust how the wildcards in a pattern are matched.
+/// The defaults follow the unix shell conventions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MatchOptions {
+    /// Allow wildcards to match a leading `.` in a directory entry name
+    pub dot_files: bool,
+    /// Match without regard to case.  This is always the case on Windows.
+    pub case_insensitive: bool,
+    /// Recognize the `?(...)`, `*(...)`, `+(...)`, `@(...)` and `!(...)`
+    /// extended patterns; see `ExtendedPattern`
+    pub extended: bool,
 }
 
 impl Glob {
@@ -49,8 +65,17 @@ impl Glob {
     ///
     /// `{foo,bar}.rs` matches both `foo.rs` and `bar.rs`.  The curly braces
     ///    define an alternation regex.
+    ///
+    /// When enabled by `MatchOptions::extended`, the extended patterns
+    /// described by `ExtendedPattern` may also be used.
     pub fn new(pattern: &str) -> Fallible<Glob> {
-        let mut nodes = vec![];
+        Self::with_options(pattern, MatchOptions::default())
+    }
+
+    /// Compile pattern into a `Glob` that is matched according
+    /// to the supplied options
+    pub fn with_options(pattern: &str, options: MatchOptions) -> Fallible<Glob> {
+        let mut nodes: Vec<Node> = vec![];
         for comp in Path::new(pattern).components() {
             let token = match comp {
                 Component::Prefix(s) => {
@@ -69,7 +94,16 @@ impl Glob {
                     // Let's see if this component contains a pattern
                     match s {
                         "**" => Node::RecursiveMatch,
-                        _ => parse(s)?,
+                        _ if options.extended && ExtendedPattern::is_extended(s) => {
+                            // The recursive walker matches the remainder of
+                            // the path using a single regex
+                            ensure!(
+                                !nodes.iter().any(Node::is_recursive),
+                                "extended patterns cannot follow `**`"
+                            );
+                            Node::Extended(ExtendedPattern::new(s)?, !options.dot_files)
+                        }
+                        _ => parse(s, options)?,
                     }
                 }
             };
@@ -84,14 +118,14 @@ impl Glob {
             }
         }
 
-        Ok(Glob { nodes })
+        Ok(Glob { nodes, options })
     }
 
     /// Walk the filesystem starting at `path` and execute the glob.
     /// Returns all matching entries in sorted order.  The entries are
     /// relative to `path`.
     pub fn walk<P: AsRef<Path>>(&self, path: P) -> Vec<PathBuf> {
-        let walker = Walker::new(path.as_ref(), &self.nodes);
+        let walker = Walker::new(path.as_ref(), &self.nodes, self.options);
 
         let mut results: Vec<PathBuf> = walker.collect();
         results.sort();
@@ -101,8 +135,12 @@ impl Glob {
 }
 
 /// Disable unicode mode so that we can match non-utf8 filenames
-fn new_binary_pattern_string() -> String {
-    String::from(if cfg!(windows) { "^(?i-u)" } else { "^(?-u)" })
+fn new_binary_pattern_string(options: MatchOptions) -> String {
+    String::from(if cfg!(windows) || options.case_insensitive {
+        "^(?i-u)"
+    } else {
+        "^(?-u)"
+    })
 }
 
 /// This is triply gross because the string needs to be translated
@@ -132,17 +170,19 @@ fn normalize_slashes(path: PathBuf) -> PathBuf {
 /// before the recursive walkers.
 struct Walker<'a> {
     root: &'a Path,
+    options: MatchOptions,
     stack: VecDeque<NodeWalker<'a>>,
     recursive: VecDeque<RecursiveWalker>,
 }
 
 impl<'a> Walker<'a> {
-    fn new(root: &'a Path, nodes: &'a [Node]) -> Self {
+    fn new(root: &'a Path, nodes: &'a [Node], options: MatchOptions) -> Self {
         let route = NodeWalker::new(nodes);
         let mut stack = VecDeque::new();
         stack.push_back(route);
         Self {
             root,
+            options,
             stack,
             recursive: VecDeque::new(),
         }
@@ -220,13 +260,13 @@ mod test {
 
     #[test]
     fn non_utf8_node_match() -> Fallible<()> {
-        let node = parse("*.rs")?;
+        let node = parse("*.rs", MatchOptions::default())?;
         use bstr::B;
         let pound = B(b"\xa3.rs");
 
         eprintln!("pound is {:?}", pound);
         eprintln!("node is {:?}", node);
-        assert_eq!(node.is_match(&pound), true);
+        assert_eq!(node.is_match(pound), true);
 
         Ok(())
     }
@@ -234,7 +274,7 @@ mod test {
     #[test]
     #[cfg(windows)]
     fn case_insensitive() -> Fallible<()> {
-        let node = parse("foo/bar.rs")?;
+        let node = parse("foo/bar.rs", MatchOptions::default())?;
         use bstr::B;
         let upper = B(b"FOO/bAr.rs");
 
@@ -249,7 +289,7 @@ mod test {
         let root = make_fixture()?;
         let pound = B(b"\xa3.rs").to_path()?;
         // on macos, the system won't allow us to create invalid utf8 names
-        touch_file(root.path().join(&pound))?;
+        touch_file(root.path().join(pound))?;
         let glob = Glob::new("*.rs")?;
         assert_eq!(glob.walk(root), vec![pound.to_path_buf()]);
         Ok(())
@@ -339,6 +379,74 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn dot_files() -> Fallible<()> {
+        let root = make_fixture()?;
+        touch_files_in(&root, &["foo.rs", ".foo.rs", ".hidden/bar.rs"])?;
+        let glob = Glob::new("*.rs")?;
+        assert_eq!(glob.walk(&root), vec![PathBuf::from("foo.rs")]);
+
+        let options = MatchOptions {
+            dot_files: true,
+            ..Default::default()
+        };
+        let glob = Glob::with_options("*.rs", options)?;
+        assert_eq!(
+            glob.walk(&root),
+            vec![PathBuf::from(".foo.rs"), PathBuf::from("foo.rs")]
+        );
+        let glob = Glob::with_options("**/*.rs", options)?;
+        assert_eq!(glob.walk(&root), vec![PathBuf::from(".hidden/bar.rs")]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn case_insensitive_option() -> Fallible<()> {
+        let root = make_fixture()?;
+        touch_files_in(&root, &["README.md", "notes.MD"])?;
+        let glob = Glob::new("*.md")?;
+        assert_eq!(glob.walk(&root), vec![PathBuf::from("README.md")]);
+
+        let options = MatchOptions {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let glob = Glob::with_options("*.md", options)?;
+        assert_eq!(
+            glob.walk(&root),
+            vec![PathBuf::from("README.md"), PathBuf::from("notes.MD")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn extended() -> Fallible<()> {
+        let root = make_fixture()?;
+        touch_files_in(&root, &["main.c", "main.o", ".hidden.c", "src/lib.c"])?;
+        let options = MatchOptions {
+            extended: true,
+            ..Default::default()
+        };
+        let glob = Glob::with_options("!(*.o)", options)?;
+        assert_eq!(
+            glob.walk(&root),
+            vec![PathBuf::from("main.c"), PathBuf::from("src")]
+        );
+        let glob = Glob::with_options("*/@(lib|main).c", options)?;
+        assert_eq!(glob.walk(&root), vec![PathBuf::from("src/lib.c")]);
+
+        // Without the option the parentheses are literal
+        let glob = Glob::new("!(*.o)")?;
+        assert_eq!(glob.walk(&root), Vec::<PathBuf>::new());
+
+        assert_eq!(
+            format!("{}", Glob::with_options("**/!(*.o)", options).unwrap_err()),
+            "extended patterns cannot follow `**`"
+        );
+        Ok(())
+    }
+
     #[test]
     fn alternative() -> Fallible<()> {
         let root = make_fixture()?;
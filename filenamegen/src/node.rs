@@ -1,4 +1,5 @@
 use crate::token::Token;
+use crate::{ExtendedPattern, MatchOptions};
 use bstr::BStr;
 #[cfg(windows)]
 use bstr::BString;
@@ -10,6 +11,9 @@ pub enum Node {
     LiteralComponents(PathBuf),
     RecursiveMatch,
     Regex(RegexAndTokens),
+    /// An extended pattern, and whether it is prevented from
+    /// matching a leading `.` by wildcards
+    Extended(ExtendedPattern, bool),
 }
 
 #[derive(Debug)]
@@ -56,33 +60,41 @@ impl Node {
             }
             Node::RecursiveMatch => true,
             Node::Regex(RegexAndTokens { regex, .. }) => regex.is_match(s.as_bytes()),
+            Node::Extended(pattern, hide_dot) => {
+                if *hide_dot && s.starts_with(b".") && !pattern.starts_with_dot() {
+                    return false;
+                }
+                pattern.is_match(&s.to_str_lossy())
+            }
         }
     }
 
     /// Convenience for testing whether Node is RecursiveMatch
     pub fn is_recursive(&self) -> bool {
-        match self {
-            Node::RecursiveMatch => true,
-            _ => false,
-        }
+        matches!(self, Node::RecursiveMatch)
     }
 
     /// Append a regex representation of Node to the supplied pattern string
-    pub fn append_regex(&self, pattern: &mut String) {
+    pub fn append_regex(&self, pattern: &mut String, options: MatchOptions) {
         match self {
             Node::LiteralComponents(path) => pattern.push_str(&regex::escape(
                 path.to_str()
                     .expect("pattern to be convertible back to String"),
             )),
             #[cfg(windows)]
+            Node::RecursiveMatch if options.dot_files => pattern.push_str("([^/\\\\]+[/\\\\]?)*"),
+            #[cfg(windows)]
             Node::RecursiveMatch => pattern.push_str("([^./\\\\][^/\\\\]*[/\\\\]?)*"),
             #[cfg(not(windows))]
+            Node::RecursiveMatch if options.dot_files => pattern.push_str("([^/]+/?)*"),
+            #[cfg(not(windows))]
             Node::RecursiveMatch => pattern.push_str("([^./][^/]*/?)*"),
             Node::Regex(RegexAndTokens { tokens, .. }) => {
                 for (i, token) in tokens.iter().enumerate() {
-                    token.append_regex(pattern, i == 0);
+                    token.append_regex(pattern, i == 0 && !options.dot_files);
                 }
             }
+            Node::Extended(..) => unreachable!("extended patterns are rejected after `**`"),
         }
     }
 }
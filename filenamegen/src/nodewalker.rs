@@ -55,7 +55,7 @@ impl<'a> NodeWalker<'a> {
 
     // Advance to the next directory component
     fn next_candidate_path(&mut self) -> Option<&'a Node> {
-        while let Some(node) = self.node.next() {
+        for node in self.node.by_ref() {
             match node {
                 Node::LiteralComponents(literal) => {
                     self.current_dir = self.current_dir.join(literal);
@@ -75,13 +75,13 @@ impl<'a> NodeWalker<'a> {
                     let file_name = entry.path();
                     let base_name = file_name.file_name().unwrap();
                     if let Some(bstr) = BStr::from_os_str(base_name) {
-                        if self.node_to_match.as_ref().unwrap().is_match(&bstr) {
+                        if self.node_to_match.as_ref().unwrap().is_match(bstr) {
                             let is_leaf = self.node.peek().is_none();
                             if is_leaf {
                                 return Some(normalize_slashes(
                                     entry
                                         .path()
-                                        .strip_prefix(&walker.root)
+                                        .strip_prefix(walker.root)
                                         .expect("entry path always has walker.root as a prefix")
                                         .to_path_buf(),
                                 ));
@@ -96,7 +96,7 @@ impl<'a> NodeWalker<'a> {
                 }
             }
         }
-        return None;
+        None
     }
 
     pub(crate) fn next(&mut self, walker: &mut Walker<'a>) -> Option<PathBuf> {
@@ -113,16 +113,16 @@ impl<'a> NodeWalker<'a> {
                 node => node,
             };
 
-            if self.node_to_match.as_ref().unwrap().is_recursive() {
-                if self.node.peek().is_some() {
-                    walker.recursive.push_back(RecursiveWalker::new(
-                        self.node.clone(),
-                        walker.root.join(&self.current_dir),
-                    ));
-                    return None;
-                }
-                // Otherwise: a leaf recursive match is equivalent to ZeroOrMore
+            if self.node_to_match.as_ref().unwrap().is_recursive() && self.node.peek().is_some() {
+                walker.recursive.push_back(RecursiveWalker::new(
+                    self.node.clone(),
+                    walker.root.join(&self.current_dir),
+                    walker.options,
+                ));
+                return None;
             }
+            // Otherwise: a leaf recursive match (or a non-recursive one)
+            // is read from the current directory like ZeroOrMore
 
             let name = walker.root.join(&self.current_dir);
             match std::fs::read_dir(&name) {
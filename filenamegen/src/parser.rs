@@ -1,6 +1,7 @@
 use crate::new_binary_pattern_string;
 use crate::node::{Node, RegexAndTokens};
 use crate::token::Token;
+use crate::MatchOptions;
 use failure::{ensure, format_err, Fallible};
 use regex::bytes::Regex;
 
@@ -80,10 +81,10 @@ impl<'a> Parser<'a> {
         Some(s)
     }
 
-    fn compile_to_regex(&mut self) -> Fallible<Regex> {
-        let mut pattern = new_binary_pattern_string();
+    fn compile_to_regex(&mut self, options: MatchOptions) -> Fallible<Regex> {
+        let mut pattern = new_binary_pattern_string(options);
         for (i, token) in self.tokens.iter().enumerate() {
-            token.append_regex(&mut pattern, i == 0);
+            token.append_regex(&mut pattern, i == 0 && !options.dot_files);
         }
         pattern.push('$');
         Regex::new(&pattern).map_err(|e| format_err!("error compiling regex: {}: {}", pattern, e))
@@ -95,12 +96,12 @@ impl<'a> Parser<'a> {
 
     #[allow(unused)]
     fn peek(&mut self) -> Option<char> {
-        self.chars.peek().map(|&ch| ch)
+        self.chars.peek().copied()
     }
 }
 
 /// Parse a pattern string into a Node.
-pub fn parse(pattern: &str) -> Fallible<Node> {
+pub fn parse(pattern: &str, options: MatchOptions) -> Fallible<Node> {
     let mut parser = Parser {
         chars: pattern.chars().peekable(),
         tokens: vec![],
@@ -114,7 +115,7 @@ pub fn parse(pattern: &str) -> Fallible<Node> {
         Ok(Node::LiteralComponents(literal.into()))
     } else {
         Ok(Node::Regex(RegexAndTokens::new(
-            parser.compile_to_regex()?,
+            parser.compile_to_regex(options)?,
             parser.tokens,
         )))
     }
@@ -1,5 +1,5 @@
 use crate::node::Node;
-use crate::{new_binary_pattern_string, normalize_slashes, Walker};
+use crate::{new_binary_pattern_string, normalize_slashes, MatchOptions, Walker};
 use bstr::BStr;
 use regex::bytes::Regex;
 use std::path::{Path, PathBuf};
@@ -15,15 +15,16 @@ impl RecursiveWalker {
     pub fn new<'a>(
         nodes: std::iter::Peekable<std::slice::Iter<'a, Node>>,
         walk_root: PathBuf,
+        options: MatchOptions,
     ) -> Self {
-        let mut pattern = new_binary_pattern_string();
-        Node::RecursiveMatch.append_regex(&mut pattern);
+        let mut pattern = new_binary_pattern_string(options);
+        Node::RecursiveMatch.append_regex(&mut pattern, options);
         for node in nodes {
             #[cfg(not(windows))]
             pattern.push('/');
             #[cfg(windows)]
             pattern.push_str("[/\\\\]");
-            node.append_regex(&mut pattern);
+            node.append_regex(&mut pattern, options);
         }
         pattern.push('$');
         let regex = Regex::new(&pattern).expect("regex to compile");
@@ -54,7 +55,7 @@ impl RecursiveWalker {
                     return Some(normalize_slashes(
                         entry
                             .path()
-                            .strip_prefix(&walker.root)
+                            .strip_prefix(walker.root)
                             .expect("walk is always relative to walker.root")
                             .to_path_buf(),
                     ));
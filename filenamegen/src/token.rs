@@ -21,14 +21,15 @@ pub enum Token {
 }
 
 impl Token {
-    /// Append a regex representation of Token to the supplied pattern string
-    pub fn append_regex(&self, pattern: &mut String, is_first_in_component: bool) {
+    /// Append a regex representation of Token to the supplied pattern string.
+    /// If `hide_dot` is true, wildcards will not match a leading `.`.
+    pub fn append_regex(&self, pattern: &mut String, hide_dot: bool) {
         match self {
             Token::Literal(c) => pattern.push_str(&regex::escape(&c.to_string())),
             // `?` matches any single character, except for `.` at the start of
             // a filename.
             Token::Any => {
-                if is_first_in_component {
+                if hide_dot {
                     #[cfg(not(windows))]
                     pattern.push_str("[^./]");
                     #[cfg(windows)]
@@ -43,7 +44,7 @@ impl Token {
             // `*` matches 0 or more of any character,
             // except for `.` at the start of a filename.
             Token::ZeroOrMore => {
-                if is_first_in_component {
+                if hide_dot {
                     #[cfg(not(windows))]
                     pattern.push_str("[^./][^/]*");
                     #[cfg(windows)]
@@ -1,4 +1,4 @@
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsStr;
 use std::path::PathBuf;
 
 #[cfg(unix)]
@@ -60,10 +60,5 @@ pub type PathSearcher<'a> = windows::WindowsPathSearcher<'a>;
 pub fn find_executable_in_path(command: &OsStr) -> Option<PathBuf> {
     let path = std::env::var_os("PATH");
     let path_ext = std::env::var_os("PATHEXT");
-    PathSearcher::new(
-        command,
-        path.as_ref().map(OsString::as_os_str),
-        path_ext.as_ref().map(OsString::as_os_str),
-    )
-    .next()
+    PathSearcher::new(command, path.as_deref(), path_ext.as_deref()).next()
 }
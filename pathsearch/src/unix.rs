@@ -54,7 +54,7 @@ impl<'a> Iterator for ExecutablePathSearcher<'a> {
 
     /// Returns the next candidate executable file
     fn next(&mut self) -> Option<PathBuf> {
-        while let Some(candidate) = self.0.next() {
+        for candidate in self.0.by_ref() {
             if let Ok(true) = is_executable(&candidate) {
                 return Some(candidate);
             }
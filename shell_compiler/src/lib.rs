@@ -1,10 +1,14 @@
 #![allow(dead_code, unused_imports)]
 use failure::{bail, err_msg, Fallible};
-use shell_lexer::{Assignment, ParamExpr, ParamOper, WordComponent, WordComponentKind};
-use shell_parser::{Command, CommandType, CompoundList, Redirection};
+use shell_lexer::{
+    Assignment, Lexer, ParamExpr, ParamOper, Quoting, Token, WordComponent, WordComponentKind,
+};
+use shell_parser::{Command, CommandType, CompoundList, ConditionalExpr, Parser, Redirection};
 pub use shell_vm::*;
 use std::cell::Cell;
 use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
 use std::thread::JoinHandle;
 
 mod registeralloc;
@@ -29,10 +33,33 @@ impl std::ops::DerefMut for FrameCompiler {
     }
 }
 
+/// Tracks the state required to compile `break` and `continue`
+/// for an enclosing loop.
+#[derive(Debug)]
+struct LoopContext {
+    /// The address that `continue` jumps to
+    continue_address: usize,
+    /// The addresses of the Jump instructions emitted by `break`.
+    /// These are patched to target the end of the loop once its
+    /// address is known.
+    break_jumps: Vec<usize>,
+    /// The depth of the cleanup stack when the loop was entered
+    cleanup_depth: usize,
+    /// The frame relative register holding the status of the loop
+    status: usize,
+}
+
 #[derive(Default, Debug)]
 pub struct Compiler {
     program: Vec<Operation>,
     frames: VecDeque<FrameCompiler>,
+    /// The operations required to unwind the frame, io and environment
+    /// stacks back to the start of the program.  `break` and `continue`
+    /// emit a suffix of these when jumping out of nested commands.
+    cleanup: Vec<Operation>,
+    loops: Vec<LoopContext>,
+    /// Emit TraceExpansion operations from word_expand
+    trace_expansion: bool,
 }
 
 impl Compiler {
@@ -40,6 +67,21 @@ impl Compiler {
         Default::default()
     }
 
+    /// When enabled, the compiled program prints the result of each
+    /// stage of word expansion to stderr as it runs.
+    pub fn set_trace_expansion(&mut self, trace: bool) {
+        self.trace_expansion = trace;
+    }
+
+    fn trace(&mut self, stage: &str, slot: usize) {
+        if self.trace_expansion {
+            self.push(op::TraceExpansion {
+                stage: stage.to_owned(),
+                value: Operand::FrameRelative(slot),
+            });
+        }
+    }
+
     pub fn finish(mut self) -> Fallible<Vec<Operation>> {
         self.push(op::Exit {
             value: Operand::LastWaitStatus,
@@ -58,6 +100,7 @@ impl Compiler {
         // Use a dummy frame size; we'll patch this once we know the
         // full frame size.
         self.push(op::PushFrame { size: 0 });
+        self.cleanup.push(op::PopFrame {}.into());
         self.frames.push_back(FrameCompiler {
             allocator: RegisterAllocator::new(),
             frame_start_program_address,
@@ -78,6 +121,7 @@ impl Compiler {
             size: frame.frame_size(),
         }
         .into();
+        self.cleanup.pop();
         self.push(op::PopFrame {});
         Ok(())
     }
@@ -167,11 +211,62 @@ impl Compiler {
     }
 
     fn parameter_expand(&mut self, target_string: usize, expr: &ParamExpr) -> Fallible<()> {
+        // `${!NAME[@]}` expands to the keys of the array rather
+        // than being an indirect reference
+        let keys = expr.indirect && expr.is_all_elements();
+        // For `${!NAME}` the value of NAME is the name of the
+        // parameter to be expanded
+        let name = if expr.indirect && !keys {
+            let name_slot = self.frame()?.allocate();
+            self.push(op::GetEnv {
+                name: Operand::Immediate(expr.name.as_str().into()),
+                target: Operand::FrameRelative(name_slot),
+            });
+            self.push(op::CheckBound {
+                name: Operand::Immediate(expr.name.as_str().into()),
+                value: Operand::FrameRelative(name_slot),
+            });
+            Operand::FrameRelative(name_slot)
+        } else {
+            Operand::Immediate(expr.name.as_str().into())
+        };
         let slot = self.frame()?.allocate();
-        self.push(op::GetEnv {
-            name: Operand::Immediate(expr.name.as_str().into()),
-            target: Operand::FrameRelative(slot),
-        });
+        match &expr.subscript {
+            Some(_) if keys => self.push(op::GetArrayKeys {
+                name: name.clone(),
+                target: Operand::FrameRelative(slot),
+            }),
+            Some(subscript) => {
+                let subscript = self.expand_subscript(subscript)?;
+                self.push(op::GetArrayElement {
+                    name: name.clone(),
+                    subscript: Operand::FrameRelative(subscript),
+                    target: Operand::FrameRelative(slot),
+                });
+                self.frame()?.free(subscript);
+            }
+            None => self.push(op::GetEnv {
+                name: name.clone(),
+                target: Operand::FrameRelative(slot),
+            }),
+        }
+        match expr.kind {
+            ParamOper::Get
+            | ParamOper::StringLength
+            | ParamOper::Uppercase { .. }
+            | ParamOper::Lowercase { .. }
+            | ParamOper::ReplaceFirst
+            | ParamOper::ReplaceAll
+            | ParamOper::ReplacePrefix
+            | ParamOper::ReplaceSuffix
+            | ParamOper::QuoteValue
+            | ParamOper::ExpandEscapes
+            | ParamOper::AssignmentForm => self.push(op::CheckBound {
+                name: name.clone(),
+                value: Operand::FrameRelative(slot),
+            }),
+            _ => {}
+        }
         match expr.kind {
             ParamOper::Get => self.push(op::Copy {
                 source: Operand::FrameRelative(slot),
@@ -241,7 +336,7 @@ impl Compiler {
                         });
                         me.frame()?.free(argv);
                         me.push(op::SetEnv {
-                            name: Operand::Immediate(expr.name.as_str().into()),
+                            name: name.clone(),
                             value: Operand::FrameRelative(target_string),
                         });
 
@@ -351,10 +446,322 @@ impl Compiler {
             | ParamOper::RemoveLargestSuffixPattern
             | ParamOper::RemoveSmallestPrefixPattern
             | ParamOper::RemoveLargestPrefixPattern => bail!("TODO {:?} not implemented", expr),
+            ParamOper::Uppercase { all } => {
+                self.change_case(slot, target_string, &expr.word, true, all)?
+            }
+            ParamOper::Lowercase { all } => {
+                self.change_case(slot, target_string, &expr.word, false, all)?
+            }
+            ParamOper::ReplaceFirst => {
+                self.replace_pattern(slot, target_string, expr, ReplaceMode::First)?
+            }
+            ParamOper::ReplaceAll => {
+                self.replace_pattern(slot, target_string, expr, ReplaceMode::All)?
+            }
+            ParamOper::ReplacePrefix => {
+                self.replace_pattern(slot, target_string, expr, ReplaceMode::Prefix)?
+            }
+            ParamOper::ReplaceSuffix => {
+                self.replace_pattern(slot, target_string, expr, ReplaceMode::Suffix)?
+            }
+            ParamOper::QuoteValue | ParamOper::ExpandEscapes | ParamOper::AssignmentForm => {
+                if !expr.word.is_empty() {
+                    bail!("${{{}@...}}: bad substitution", expr.name);
+                }
+                let transform = match expr.kind {
+                    ParamOper::QuoteValue => Transform::Quote,
+                    ParamOper::ExpandEscapes => Transform::Escape,
+                    _ => Transform::Assignment,
+                };
+                self.push(op::TransformValue {
+                    string: Operand::FrameRelative(slot),
+                    name,
+                    transform,
+                    destination: Operand::FrameRelative(target_string),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand the word of a parameter expansion, such as the `pat`
+    /// in `${NAME^^pat}`, into a pattern string.  Quoted portions of
+    /// the word are escaped so that they match literally.
+    fn param_pattern(&mut self, words: &[Vec<WordComponent>]) -> Fallible<usize> {
+        let pattern = self.allocate_string()?;
+        for (idx, word) in words.iter().enumerate() {
+            if idx > 0 {
+                self.push(op::StringAppend {
+                    source: Operand::Immediate(" ".into()),
+                    destination: Operand::FrameRelative(pattern),
+                });
+            }
+            self.conditional_word(pattern, word, ConditionalOperand::Pattern)?;
+        }
+        Ok(pattern)
+    }
+
+    fn replace_pattern(
+        &mut self,
+        slot: usize,
+        target_string: usize,
+        expr: &ParamExpr,
+        mode: ReplaceMode,
+    ) -> Fallible<()> {
+        let (pattern_words, replacement_words) = expr.split_replacement();
+        let pattern = self.param_pattern(&pattern_words)?;
+
+        let argv = self.allocate_list()?;
+        for w in &replacement_words {
+            self.word_expand(argv, w)?;
+        }
+        let replacement = self.allocate_string()?;
+        self.push(op::JoinList {
+            list: Operand::FrameRelative(argv),
+            destination: Operand::FrameRelative(replacement),
+        });
+        self.frame()?.free(argv);
+
+        self.push(op::ReplacePattern {
+            string: Operand::FrameRelative(slot),
+            pattern: Operand::FrameRelative(pattern),
+            replacement: Operand::FrameRelative(replacement),
+            mode,
+            destination: Operand::FrameRelative(target_string),
+        });
+        self.frame()?.free(replacement);
+        self.frame()?.free(pattern);
+        Ok(())
+    }
+
+    fn change_case(
+        &mut self,
+        slot: usize,
+        target_string: usize,
+        words: &[Vec<WordComponent>],
+        upper: bool,
+        all: bool,
+    ) -> Fallible<()> {
+        let pattern = self.param_pattern(words)?;
+        self.push(op::ChangeCase {
+            string: Operand::FrameRelative(slot),
+            pattern: Operand::FrameRelative(pattern),
+            upper,
+            all,
+            destination: Operand::FrameRelative(target_string),
+        });
+        self.frame()?.free(pattern);
+        Ok(())
+    }
+
+    /// Compile a `[[ ]]` expression; the result is left in the
+    /// last wait status.
+    fn conditional_expr(&mut self, expr: &ConditionalExpr) -> Fallible<()> {
+        match expr {
+            ConditionalExpr::Not(expr) => {
+                self.conditional_expr(expr)?;
+                self.if_then_else(
+                    Operand::LastWaitStatus,
+                    |me| {
+                        me.push(op::SetLastWaitStatus {
+                            value: Operand::Immediate(Value::Integer(1)),
+                        });
+                        Ok(())
+                    },
+                    |me| {
+                        me.push(op::SetLastWaitStatus {
+                            value: Operand::Immediate(Value::Integer(0)),
+                        });
+                        Ok(())
+                    },
+                )
+            }
+            ConditionalExpr::And(a, b) => {
+                self.conditional_expr(a)?;
+                self.if_then_else(
+                    Operand::LastWaitStatus,
+                    |me| me.conditional_expr(b),
+                    |_| Ok(()),
+                )
+            }
+            ConditionalExpr::Or(a, b) => {
+                self.conditional_expr(a)?;
+                self.if_then_else(
+                    Operand::LastWaitStatus,
+                    |_| Ok(()),
+                    |me| me.conditional_expr(b),
+                )
+            }
+            ConditionalExpr::Word(word) => {
+                self.conditional_test("-n", &[(word, ConditionalOperand::String)])
+            }
+            ConditionalExpr::Unary { operator, operand } => {
+                self.conditional_test(operator, &[(operand, ConditionalOperand::String)])
+            }
+            ConditionalExpr::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let kind = match operator.as_str() {
+                    "==" | "=" | "!=" => ConditionalOperand::Pattern,
+                    "=~" => ConditionalOperand::Regex,
+                    _ => ConditionalOperand::String,
+                };
+                self.conditional_test(
+                    operator,
+                    &[(left, ConditionalOperand::String), (right, kind)],
+                )
+            }
+        }
+    }
+
+    fn conditional_test(
+        &mut self,
+        operator: &str,
+        words: &[(&Vec<WordComponent>, ConditionalOperand)],
+    ) -> Fallible<()> {
+        let operands = self.allocate_list()?;
+        for (word, kind) in words {
+            let expanded = self.allocate_string()?;
+            self.conditional_word(expanded, word, *kind)?;
+            self.push(op::ListAppend {
+                value: Operand::FrameRelative(expanded),
+                list: Operand::FrameRelative(operands),
+                split: false,
+                glob: false,
+                remove_backslash: false,
+            });
+            self.frame()?.free(expanded);
+        }
+        self.push(op::ConditionalTest {
+            operator: Operand::Immediate(operator.into()),
+            operands: Operand::FrameRelative(operands),
+        });
+        self.frame()?.free(operands);
+        Ok(())
+    }
+
+    /// Expand an operand of a `[[ ]]` expression into a single
+    /// string.  There is no field splitting or pathname expansion.
+    /// When the operand is a pattern or regex, its quoted portions
+    /// are escaped so that they match literally.
+    fn conditional_word(
+        &mut self,
+        expanded_word: usize,
+        word: &[WordComponent],
+        kind: ConditionalOperand,
+    ) -> Fallible<()> {
+        for component in word {
+            let quoted = component.is_quoted();
+            match &component.kind {
+                WordComponentKind::Literal(literal) => {
+                    let literal = match kind {
+                        ConditionalOperand::String if !quoted && component.remove_backslash => {
+                            remove_backslash_escapes(literal)
+                        }
+                        ConditionalOperand::Pattern if quoted => escape_pattern(literal, false),
+                        ConditionalOperand::Regex if quoted => escape_pattern(literal, true),
+                        _ => literal.to_owned(),
+                    };
+                    self.push(op::StringAppend {
+                        source: Operand::Immediate(Value::String(literal)),
+                        destination: Operand::FrameRelative(expanded_word),
+                    });
+                }
+                WordComponentKind::TildeExpand(name) => {
+                    let expanded = self.allocate_string()?;
+                    self.push(op::TildeExpand {
+                        name: Operand::Immediate(
+                            name.as_ref().map(|s| s.into()).unwrap_or(Value::None),
+                        ),
+                        destination: Operand::FrameRelative(expanded),
+                    });
+                    self.push(op::StringAppend {
+                        source: Operand::FrameRelative(expanded),
+                        destination: Operand::FrameRelative(expanded_word),
+                    });
+                    self.frame()?.free(expanded);
+                }
+                WordComponentKind::ParamExpand(expr) => {
+                    let expanded = self.allocate_string()?;
+                    self.parameter_expand(expanded, expr)?;
+                    self.join_positional(expanded, expr);
+                    if quoted && kind != ConditionalOperand::String {
+                        self.push(op::EscapePattern {
+                            value: Operand::FrameRelative(expanded),
+                            destination: Operand::FrameRelative(expanded),
+                            regex: kind == ConditionalOperand::Regex,
+                        });
+                    }
+                    self.push(op::StringAppend {
+                        source: Operand::FrameRelative(expanded),
+                        destination: Operand::FrameRelative(expanded_word),
+                    });
+                    self.frame()?.free(expanded);
+                }
+                WordComponentKind::CommandSubstitution(tokens) => {
+                    let expanded = self.allocate_string()?;
+                    self.command_substitution(expanded, tokens)?;
+                    if quoted && kind != ConditionalOperand::String {
+                        self.push(op::EscapePattern {
+                            value: Operand::FrameRelative(expanded),
+                            destination: Operand::FrameRelative(expanded),
+                            regex: kind == ConditionalOperand::Regex,
+                        });
+                    }
+                    self.push(op::StringAppend {
+                        source: Operand::FrameRelative(expanded),
+                        destination: Operand::FrameRelative(expanded_word),
+                    });
+                    self.frame()?.free(expanded);
+                }
+            }
         }
         Ok(())
     }
 
+    /// Compile the tokens of a `$(command)` into a separate program
+    /// whose output is stored into target_string when it is run
+    fn command_substitution(&mut self, target_string: usize, tokens: &[Token]) -> Fallible<()> {
+        let mut parser = Parser::from_tokens("command substitution", tokens.to_vec());
+        let command = parser.parse()?;
+        let mut compiler = Self::new();
+        compiler.trace_expansion = self.trace_expansion;
+        compiler.compile_command(&command)?;
+        self.push(op::CommandSubstitution {
+            program: Program::new(compiler.finish()?),
+            destination: Operand::FrameRelative(target_string),
+        });
+        Ok(())
+    }
+
+    /// Expand the words of an array subscript into a single string,
+    /// returning the frame slot that holds it
+    fn expand_subscript(&mut self, subscript: &[WordComponent]) -> Fallible<usize> {
+        let expanded = self.allocate_list()?;
+        self.word_expand(expanded, &subscript.to_vec())?;
+        self.push(op::JoinList {
+            list: Operand::FrameRelative(expanded),
+            destination: Operand::FrameRelative(expanded),
+        });
+        Ok(expanded)
+    }
+
+    /// When `$*` is expanded as part of a larger string, the positional
+    /// parameters are joined using the first character of IFS; a space
+    /// if IFS is unset, or nothing at all if IFS is empty.
+    /// The elements of `${NAME[@]}` and `${NAME[*]}` are joined likewise.
+    fn join_positional(&mut self, expanded: usize, expr: &ParamExpr) {
+        let all = (expr.name == "*" && expr.subscript.is_none()) || expr.is_all_elements();
+        if all && expr.kind == ParamOper::Get {
+            self.push(op::JoinList {
+                list: Operand::FrameRelative(expanded),
+                destination: Operand::FrameRelative(expanded),
+            });
+        }
+    }
+
     /// Perform word expansion on word.
     /// Word is a list of components that are logically all part of the
     /// same field and thus are emitted into a string value together.
@@ -364,19 +771,24 @@ impl Compiler {
     fn word_expand(&mut self, argv: usize, word: &Vec<WordComponent>) -> Fallible<()> {
         // Hideous "special parameters" special casing
         if word.len() == 1 {
+            let quoted = word[0].is_quoted();
             if let WordComponentKind::ParamExpand(ParamExpr {
                 name,
                 word,
                 kind: ParamOper::Get,
+                indirect: false,
+                subscript: None,
             }) = &word[0].kind
             {
-                if word.is_empty() && (name == "@" || name == "*") {
+                // A quoted "$*" is a single field; it is joined below
+                if word.is_empty() && (name == "@" || (name == "*" && !quoted)) {
                     let positional = self.allocate_string()?;
                     self.push(op::GetEnv {
                         name: Operand::Immediate(name.to_owned().into()),
                         target: Operand::FrameRelative(positional),
                     });
 
+                    self.trace("parameter", positional);
                     self.push(op::ListAppendList {
                         src_list: Operand::FrameRelative(positional),
                         dest_list: Operand::FrameRelative(argv),
@@ -386,22 +798,67 @@ impl Compiler {
                     return Ok(());
                 }
             }
+
+            // Likewise `${NAME[@]}` produces a field per element, as
+            // does an unquoted `${NAME[*]}`
+            if let WordComponentKind::ParamExpand(expr) = &word[0].kind {
+                let all_fields = match expr.subscript.as_deref() {
+                    Some(
+                        [WordComponent {
+                            kind: WordComponentKind::Literal(literal),
+                            ..
+                        }],
+                    ) => literal == "@" || !quoted,
+                    _ => false,
+                };
+                if expr.kind == ParamOper::Get
+                    && expr.is_all_elements()
+                    && expr.word.is_empty()
+                    && all_fields
+                {
+                    let elements = self.allocate_string()?;
+                    self.parameter_expand(elements, expr)?;
+                    self.trace("parameter", elements);
+                    self.push(op::ListAppendList {
+                        src_list: Operand::FrameRelative(elements),
+                        dest_list: Operand::FrameRelative(argv),
+                    });
+                    self.frame()?.free(elements);
+
+                    return Ok(());
+                }
+            }
         }
 
         let expanded_word = self.allocate_string()?;
 
+        // Only the unquoted portions of a word are subject to pathname
+        // expansion.  When the word is globbed, the pattern characters
+        // of its quoted portions are escaped so that they match
+        // literally, and the escapes are removed again if nothing matches.
+        let glob = word.iter().any(|c| c.splittable && !c.is_quoted());
+
         let mut split = true;
         let mut remove_backslash = true;
         for component in word {
             if !component.splittable {
                 split = false;
             }
+            let quoted = component.is_quoted();
             match &component.kind {
                 WordComponentKind::Literal(literal) => {
-                    if !component.remove_backslash {
-                        remove_backslash = false;
-                    }
-                    let literal = literal.to_owned();
+                    let literal = if glob && (quoted || !component.remove_backslash) {
+                        if component.remove_backslash {
+                            escape_pattern(&remove_backslash_escapes(literal), false)
+                        } else {
+                            escape_pattern(literal, false)
+                        }
+                    } else {
+                        if !component.remove_backslash {
+                            remove_backslash = false;
+                        }
+                        literal.to_owned()
+                    };
                     self.push(op::StringAppend {
                         source: Operand::Immediate(Value::String(literal)),
                         destination: Operand::FrameRelative(expanded_word),
@@ -415,6 +872,7 @@ impl Compiler {
                         ),
                         destination: Operand::FrameRelative(expanded),
                     });
+                    self.trace("tilde", expanded);
                     self.push(op::StringAppend {
                         source: Operand::FrameRelative(expanded),
                         destination: Operand::FrameRelative(expanded_word),
@@ -424,35 +882,112 @@ impl Compiler {
                 WordComponentKind::ParamExpand(expr) => {
                     let expanded = self.allocate_string()?;
                     self.parameter_expand(expanded, expr)?;
+                    self.join_positional(expanded, expr);
+                    self.trace("parameter", expanded);
+                    if glob && quoted {
+                        self.push(op::EscapePattern {
+                            value: Operand::FrameRelative(expanded),
+                            destination: Operand::FrameRelative(expanded),
+                            regex: false,
+                        });
+                    }
+                    self.push(op::StringAppend {
+                        source: Operand::FrameRelative(expanded),
+                        destination: Operand::FrameRelative(expanded_word),
+                    });
+                    self.frame()?.free(expanded);
+                }
+                WordComponentKind::CommandSubstitution(tokens) => {
+                    // Whether the output is field split is determined
+                    // by the quoting of the word, as for parameters
+                    let expanded = self.allocate_string()?;
+                    self.command_substitution(expanded, tokens)?;
+                    self.trace("command", expanded);
+                    if glob && quoted {
+                        self.push(op::EscapePattern {
+                            value: Operand::FrameRelative(expanded),
+                            destination: Operand::FrameRelative(expanded),
+                            regex: false,
+                        });
+                    }
                     self.push(op::StringAppend {
                         source: Operand::FrameRelative(expanded),
                         destination: Operand::FrameRelative(expanded_word),
                     });
                     self.frame()?.free(expanded);
                 }
-                WordComponentKind::CommandSubstitution(_) => bail!("command subst not implemented"),
             }
         }
 
-        let glob = split;
+        if !self.trace_expansion {
+            self.push(op::ListAppend {
+                value: Operand::FrameRelative(expanded_word),
+                list: Operand::FrameRelative(argv),
+                split,
+                glob,
+                remove_backslash,
+            });
+            self.frame()?.free(expanded_word);
+            return Ok(());
+        }
 
+        // Field splitting and pathname expansion are performed by a
+        // single ListAppend; when tracing, split into a scratch list
+        // first so that the fields can be shown before globbing.
+        self.trace("word", expanded_word);
+        if split {
+            let fields = self.allocate_list()?;
+            self.push(op::ListAppend {
+                value: Operand::FrameRelative(expanded_word),
+                list: Operand::FrameRelative(fields),
+                split,
+                glob: false,
+                remove_backslash: false,
+            });
+            self.trace("split", fields);
+            self.frame()?.free(fields);
+        }
+        let fields = self.allocate_list()?;
         self.push(op::ListAppend {
             value: Operand::FrameRelative(expanded_word),
-            list: Operand::FrameRelative(argv),
+            list: Operand::FrameRelative(fields),
             split,
             glob,
             remove_backslash,
         });
-
+        if glob {
+            self.trace("glob", fields);
+        }
+        self.push(op::ListAppendList {
+            src_list: Operand::FrameRelative(fields),
+            dest_list: Operand::FrameRelative(argv),
+        });
+        self.frame()?.free(fields);
         self.frame()?.free(expanded_word);
         Ok(())
     }
 
+    /// Compile a program that expands words, as for the arguments of
+    /// a simple command, and exits with the list of resultant fields.
+    pub fn compile_expansion(&mut self, words: &[Vec<WordComponent>]) -> Fallible<()> {
+        self.reserve_frame();
+        let fields = self.allocate_list()?;
+        for word in words {
+            self.word_expand(fields, word)?;
+        }
+        self.push(op::Exit {
+            value: Operand::FrameRelative(fields),
+        });
+        self.frame()?.free(fields);
+        self.commit_frame()
+    }
+
     fn apply_redirection(&mut self, redir: &Vec<Redirection>) -> Fallible<bool> {
         if redir.is_empty() {
             return Ok(false);
         }
         self.push(op::PushIo {});
+        self.cleanup.push(op::PopIo {}.into());
 
         for r in redir {
             match r {
@@ -475,6 +1010,20 @@ impl Compiler {
                         dest_fd: f.dest_fd_number,
                     });
                 }
+                Redirection::HereString(h) => {
+                    let word = unsplittable_word(&h.word);
+                    let text = self.allocate_list()?;
+                    self.word_expand(text, &word)?;
+                    self.push(op::JoinList {
+                        list: Operand::FrameRelative(text),
+                        destination: Operand::FrameRelative(text),
+                    });
+                    self.push(op::HereString {
+                        value: Operand::FrameRelative(text),
+                        fd_number: h.fd_number,
+                    });
+                    self.frame()?.free(text);
+                }
             }
         }
 
@@ -483,22 +1032,90 @@ impl Compiler {
 
     fn pop_redirection(&mut self, do_pop: bool) {
         if do_pop {
+            self.cleanup.pop();
             self.push(op::PopIo {});
         }
     }
 
-    fn process_assignments(&mut self, assignments: &Vec<Assignment>) -> Fallible<()> {
+    /// Assign the variables.  When `export` is true the assignments
+    /// prefix a command and are exported to it.
+    fn process_assignments(&mut self, assignments: &Vec<Assignment>, export: bool) -> Fallible<()> {
         for a in assignments {
+            if let Some(words) = &a.array {
+                // `NAME=(word...)` expands each word into elements,
+                // subject to field splitting and pathname expansion
+                let elements = self.allocate_list()?;
+                for word in words {
+                    self.word_expand(elements, word)?;
+                }
+                self.push(op::SetArray {
+                    name: Operand::Immediate(a.name.as_str().into()),
+                    value: Operand::FrameRelative(elements),
+                    append: a.append,
+                });
+                self.frame()?.free(elements);
+                continue;
+            }
             let value = self.allocate_list()?;
-            self.word_expand(value, &a.value)?;
+            self.word_expand(value, &unsplittable_word(&a.value))?;
             self.push(op::JoinList {
                 list: Operand::FrameRelative(value),
                 destination: Operand::FrameRelative(value),
             });
-            self.push(op::SetEnv {
-                name: Operand::Immediate(a.name.as_str().into()),
-                value: Operand::FrameRelative(value),
-            });
+            if let Some(subscript) = &a.subscript {
+                // `NAME[subscript]=value` assigns a single element
+                let subscript = self.expand_subscript(subscript)?;
+                if a.append {
+                    let current = self.frame()?.allocate();
+                    self.push(op::GetArrayElement {
+                        name: Operand::Immediate(a.name.as_str().into()),
+                        subscript: Operand::FrameRelative(subscript),
+                        target: Operand::FrameRelative(current),
+                    });
+                    self.push(op::StringAppend {
+                        source: Operand::FrameRelative(value),
+                        destination: Operand::FrameRelative(current),
+                    });
+                    self.push(op::Copy {
+                        source: Operand::FrameRelative(current),
+                        destination: Operand::FrameRelative(value),
+                    });
+                    self.frame()?.free(current);
+                }
+                self.push(op::SetArrayElement {
+                    name: Operand::Immediate(a.name.as_str().into()),
+                    subscript: Operand::FrameRelative(subscript),
+                    value: Operand::FrameRelative(value),
+                });
+                self.frame()?.free(subscript);
+            } else if a.append {
+                // `NAME+=value` appends to the current value, which
+                // may be unset, in which case it is a plain assignment
+                let current = self.frame()?.allocate();
+                self.push(op::GetEnv {
+                    name: Operand::Immediate(a.name.as_str().into()),
+                    target: Operand::FrameRelative(current),
+                });
+                self.push(op::StringAppend {
+                    source: Operand::FrameRelative(value),
+                    destination: Operand::FrameRelative(current),
+                });
+                self.push(op::SetEnv {
+                    name: Operand::Immediate(a.name.as_str().into()),
+                    value: Operand::FrameRelative(current),
+                });
+                self.frame()?.free(current);
+            } else {
+                self.push(op::SetEnv {
+                    name: Operand::Immediate(a.name.as_str().into()),
+                    value: Operand::FrameRelative(value),
+                });
+            }
+            if export {
+                self.push(op::ExportEnv {
+                    name: Operand::Immediate(a.name.as_str().into()),
+                });
+            }
 
             self.frame()?.free(value);
         }
@@ -506,6 +1123,14 @@ impl Compiler {
     }
 
     pub fn compile_command(&mut self, command: &Command) -> Fallible<()> {
+        self.compile_command_as(command, command.asynchronous)
+    }
+
+    /// Compile command, running it in the background rather than
+    /// waiting for it if `asynchronous` is true.  The stages of a
+    /// pipeline other than the last are run this way, so that they
+    /// run alongside the stages that read their output.
+    fn compile_command_as(&mut self, command: &Command, asynchronous: bool) -> Fallible<()> {
         self.reserve_frame();
         let pop_outer_redir = self.apply_redirection(&command.redirects)?;
 
@@ -518,6 +1143,7 @@ impl Compiler {
                     // Assignments are applicable only to the command we're
                     // setting up here, so push a new context.
                     self.push(op::PushEnvironment {});
+                    self.cleanup.push(op::PopEnvironment {}.into());
                     true
                 } else {
                     // Either there are no assignments, or they are supposed
@@ -525,25 +1151,42 @@ impl Compiler {
                     false
                 };
 
-                self.process_assignments(&simple.assignments)?;
+                self.process_assignments(&simple.assignments, pop_env)?;
 
                 for word in &simple.words {
                     self.word_expand(argv, word)?;
                 }
 
-                let status = self.frame()?.allocate();
-                self.push(op::SpawnCommand {
-                    argv: Operand::FrameRelative(argv),
-                    status: Operand::FrameRelative(status),
-                });
-                if !command.asynchronous {
-                    self.push(op::Wait {
+                if is_special_builtin(&simple.words, "return") {
+                    // `return` needs to unwind the function, so it is
+                    // handled by the vm rather than being spawned.
+                    self.push(op::Return {
+                        argv: Operand::FrameRelative(argv),
+                    });
+                } else if is_special_builtin(&simple.words, "break") && !self.loops.is_empty() {
+                    self.loop_control(argv, &simple.words, true)?;
+                } else if is_special_builtin(&simple.words, "continue") && !self.loops.is_empty() {
+                    self.loop_control(argv, &simple.words, false)?;
+                } else {
+                    let status = self.frame()?.allocate();
+                    self.push(op::SpawnCommand {
+                        argv: Operand::FrameRelative(argv),
                         status: Operand::FrameRelative(status),
                     });
+                    if asynchronous {
+                        self.push(op::RecordBackgroundPid {
+                            status: Operand::FrameRelative(status),
+                        });
+                    } else {
+                        self.push(op::Wait {
+                            status: Operand::FrameRelative(status),
+                        });
+                    }
+                    self.frame()?.free(status);
                 }
-                self.frame()?.free(status);
 
                 if pop_env {
+                    self.cleanup.pop();
                     self.push(op::PopEnvironment {});
                 }
                 self.pop_redirection(pop_redir);
@@ -560,26 +1203,78 @@ impl Compiler {
                         Ok(())
                     },
                     |me| {
-                        if let Some(false_part) = &cmd.false_part {
-                            me.compound_list(false_part)?;
+                        match &cmd.false_part {
+                            // An if clause without an else part has
+                            // a status of 0 if no branch is taken
+                            Some(false_part) if false_part.commands.is_empty() => {
+                                me.push(op::SetLastWaitStatus {
+                                    value: Operand::Immediate(Value::Integer(0)),
+                                });
+                            }
+                            Some(false_part) => me.compound_list(false_part)?,
+                            None => {}
                         }
                         Ok(())
                     },
                 )?;
             }
+            CommandType::WhileLoop(cmd) => {
+                self.conditional_loop(&cmd.condition, &cmd.body, true)?;
+            }
+            CommandType::UntilLoop(cmd) => {
+                self.conditional_loop(&cmd.condition, &cmd.body, false)?;
+            }
+            CommandType::ForEach(cmd) => {
+                self.wordlist_loop(&cmd.name, &cmd.wordlist, &cmd.body, false)?;
+            }
+            CommandType::Select(cmd) => {
+                self.wordlist_loop(&cmd.name, &cmd.wordlist, &cmd.body, true)?;
+            }
+            CommandType::Conditional(expr) => {
+                self.conditional_expr(expr)?;
+            }
+            CommandType::Arithmetic(expression) => {
+                // The exit status is 0 if the expression evaluates
+                // to a non-zero value, 1 otherwise.
+                let result = self.frame()?.allocate();
+                self.push(op::Arithmetic {
+                    expression: Operand::Immediate(expression.as_str().into()),
+                    destination: Operand::FrameRelative(result),
+                });
+                self.if_then_else(
+                    Operand::FrameRelative(result),
+                    |me| {
+                        me.push(op::SetLastWaitStatus {
+                            value: Operand::Immediate(Value::Integer(0)),
+                        });
+                        Ok(())
+                    },
+                    |me| {
+                        me.push(op::SetLastWaitStatus {
+                            value: Operand::Immediate(Value::Integer(1)),
+                        });
+                        Ok(())
+                    },
+                )?;
+                self.frame()?.free(result);
+            }
             CommandType::Program(list) | CommandType::BraceGroup(list) => {
                 self.compound_list(list)?;
             }
             CommandType::Pipeline(pipeline) => {
+                if pipeline.timed {
+                    self.push(op::StartTimer {});
+                }
                 let num_commands = pipeline.commands.len();
                 if num_commands <= 1 {
                     // Nothing to pipe together, so just emit the command
                     for cmd in &pipeline.commands {
-                        self.compile_command(&cmd)?;
+                        self.compile_command(cmd)?;
                     }
                 } else {
                     for (i, cmd) in pipeline.commands.iter().enumerate() {
                         self.push(op::PushIo {});
+                        self.cleanup.push(op::PopIo {}.into());
                         let first = i == 0;
                         if !first {
                             // Connect the read pipe from the prior iteration
@@ -589,8 +1284,29 @@ impl Compiler {
                         if !last {
                             // Set up the write pipe for the next iteration
                             self.push(op::PushPipe {});
+                            self.compile_command_as(cmd, true)?;
+                        } else {
+                            // The last stage runs in the current shell
+                            // environment only if the lastpipe option
+                            // applies, which is decided at run time
+                            let isolated = self.frame()?.allocate();
+                            self.push(op::PushPipelineEnvironment {
+                                isolated: Operand::FrameRelative(isolated),
+                            });
+                            self.cleanup.push(
+                                op::PopPipelineEnvironment {
+                                    isolated: Operand::FrameRelative(isolated),
+                                }
+                                .into(),
+                            );
+                            self.compile_command(cmd)?;
+                            self.cleanup.pop();
+                            self.push(op::PopPipelineEnvironment {
+                                isolated: Operand::FrameRelative(isolated),
+                            });
+                            self.frame()?.free(isolated);
                         }
-                        self.compile_command(cmd)?;
+                        self.cleanup.pop();
                         self.push(op::PopIo {});
                     }
                 }
@@ -598,11 +1314,14 @@ impl Compiler {
                 if pipeline.inverted {
                     self.push(op::InvertLastWait {});
                 }
+                if pipeline.timed {
+                    self.push(op::ReportTime {});
+                }
             }
 
             CommandType::FunctionDefinition { name, body } => {
                 let mut compiler = Self::new();
-                compiler.compile_command(&*body)?;
+                compiler.compile_command(body)?;
                 let program = Program::new(compiler.finish()?);
                 self.push(op::DefineFunction {
                     name: name.to_string(),
@@ -618,15 +1337,354 @@ impl Compiler {
         Ok(())
     }
 
-    fn compound_list(&mut self, list: &CompoundList) -> Fallible<()> {
-        for command in &list.commands {
-            self.compile_command(command)?;
-        }
-        Ok(())
+    /// Compile a while or until loop
+    fn conditional_loop(
+        &mut self,
+        condition: &CompoundList,
+        body: &CompoundList,
+        is_while: bool,
+    ) -> Fallible<()> {
+        self.compile_loop(
+            |me| {
+                me.compound_list(condition)?;
+                Ok(if is_while {
+                    op::JumpIfZero {
+                        condition: Operand::LastWaitStatus,
+                        target: InstructionAddress::Absolute(0),
+                    }
+                    .into()
+                } else {
+                    op::JumpIfNonZero {
+                        condition: Operand::LastWaitStatus,
+                        target: InstructionAddress::Absolute(0),
+                    }
+                    .into()
+                })
+            },
+            |me| me.compound_list(body),
+        )
     }
-}
 
-#[cfg(test)]
+    /// Compile a for or select loop.  The words in the wordlist are
+    /// expanded up front.  A for loop assigns each of them to `name`
+    /// in turn, whereas a select loop prompts the user to choose one.
+    fn wordlist_loop(
+        &mut self,
+        name: &str,
+        wordlist: &[Vec<WordComponent>],
+        body: &CompoundList,
+        select: bool,
+    ) -> Fallible<()> {
+        let list = self.allocate_list()?;
+        for word in wordlist {
+            self.word_expand(list, word)?;
+        }
+        let item = self.frame()?.allocate();
+        let exhausted = self.frame()?.allocate();
+
+        self.compile_loop(
+            |me| {
+                if select {
+                    me.push(op::SelectMenu {
+                        list: Operand::FrameRelative(list),
+                        destination: Operand::FrameRelative(item),
+                    });
+                } else {
+                    me.push(op::ListPopFront {
+                        list: Operand::FrameRelative(list),
+                        destination: Operand::FrameRelative(item),
+                    });
+                }
+                me.push(op::IsNone {
+                    source: Operand::FrameRelative(item),
+                    destination: Operand::FrameRelative(exhausted),
+                });
+                Ok(op::JumpIfNonZero {
+                    condition: Operand::FrameRelative(exhausted),
+                    target: InstructionAddress::Absolute(0),
+                }
+                .into())
+            },
+            |me| {
+                me.push(op::SetEnv {
+                    name: Operand::Immediate(name.into()),
+                    value: Operand::FrameRelative(item),
+                });
+                me.compound_list(body)
+            },
+        )?;
+
+        self.frame()?.free(exhausted);
+        self.frame()?.free(item);
+        self.frame()?.free(list);
+        Ok(())
+    }
+
+    /// Emit a loop.  The `head` closure emits the code that determines
+    /// whether to terminate the loop and returns the conditional jump
+    /// that does so; the jump target is patched to the end of the loop.
+    /// The `body` closure emits the loop body.
+    ///
+    /// ```norun
+    ///   Copy 0 -> STATUS
+    /// .CONTINUE
+    ///   {HEAD}
+    ///   JumpIf... .DONE
+    ///   {BODY}
+    ///   Copy LastWaitStatus -> STATUS
+    ///   Jump .CONTINUE
+    /// .DONE
+    ///   SetLastWaitStatus STATUS
+    /// ```
+    ///
+    /// The status of the loop is that of the last command executed
+    /// in the body, or 0 if the body was never executed.
+    fn compile_loop<
+        HEAD: FnOnce(&mut Compiler) -> Fallible<Operation>,
+        BODY: FnOnce(&mut Compiler) -> Fallible<()>,
+    >(
+        &mut self,
+        head: HEAD,
+        body: BODY,
+    ) -> Fallible<()> {
+        let status = self.frame()?.allocate();
+        self.push(op::Copy {
+            source: Operand::Immediate(Value::Integer(0)),
+            destination: Operand::FrameRelative(status),
+        });
+
+        let continue_address = self.program.len();
+        self.loops.push(LoopContext {
+            continue_address,
+            break_jumps: vec![],
+            cleanup_depth: self.cleanup.len(),
+            status,
+        });
+
+        let exit_jump = head(self)?;
+        let exit_jump_address = self.program.len();
+        self.program.push(exit_jump);
+
+        body(self)?;
+        self.push(op::Copy {
+            source: Operand::LastWaitStatus,
+            destination: Operand::FrameRelative(status),
+        });
+        self.push(op::Jump {
+            target: InstructionAddress::Absolute(continue_address),
+        });
+
+        let done = self.program.len();
+        let context = self
+            .loops
+            .pop()
+            .ok_or_else(|| err_msg("loop context underflow"))?;
+        self.patch_jump(exit_jump_address, done)?;
+        for addr in context.break_jumps {
+            self.patch_jump(addr, done)?;
+        }
+
+        self.push(op::SetLastWaitStatus {
+            value: Operand::FrameRelative(status),
+        });
+        self.frame()?.free(status);
+        Ok(())
+    }
+
+    /// Patch the target of the jump instruction at `addr`
+    fn patch_jump(&mut self, addr: usize, target: usize) -> Fallible<()> {
+        match self.program.get_mut(addr) {
+            Some(Operation::Jump(op::Jump { target: t }))
+            | Some(Operation::JumpIfZero(op::JumpIfZero { target: t, .. }))
+            | Some(Operation::JumpIfNonZero(op::JumpIfNonZero { target: t, .. })) => {
+                *t = InstructionAddress::Absolute(target);
+                Ok(())
+            }
+            _ => bail!("opcode mismatch while patching jump"),
+        }
+    }
+
+    /// Compile `break [n]` or `continue [n]` for the enclosing loop(s).
+    /// argv holds the expanded words of the command.  The loop count
+    /// is only known at run time, so a LoopControl operation selects
+    /// the code that leaves the corresponding number of loops.  If it
+    /// is larger than the number of enclosing loops, the outermost
+    /// loop is targeted.
+    fn loop_control(
+        &mut self,
+        argv: usize,
+        words: &[Vec<WordComponent>],
+        is_break: bool,
+    ) -> Fallible<()> {
+        if words.len() == 1 {
+            return self.leave_loops(self.loops.len() - 1, is_break);
+        }
+
+        let control = self.program.len();
+        self.push(op::LoopControl {
+            argv: Operand::FrameRelative(argv),
+            targets: vec![],
+        });
+        // An invalid loop count leaves the loops alone
+        let invalid = self.program.len();
+        self.push(op::Jump {
+            target: InstructionAddress::Absolute(0),
+        });
+
+        let mut targets = vec![];
+        for idx in (0..self.loops.len()).rev() {
+            targets.push(InstructionAddress::Absolute(self.program.len()));
+            self.leave_loops(idx, is_break)?;
+        }
+        match self.program.get_mut(control) {
+            Some(Operation::LoopControl(op)) => op.targets = targets,
+            _ => bail!("opcode mismatch while patching loop control"),
+        }
+        let done = self.program.len();
+        self.patch_jump(invalid, done)
+    }
+
+    /// Emit the code for `break` or `continue` to leave the loops
+    /// nested within the loop at index `idx` of the loop stack
+    fn leave_loops(&mut self, idx: usize, is_break: bool) -> Fallible<()> {
+        // Unwind any frames, io and environment contexts that were
+        // pushed since the target loop was entered
+        let unwind: Vec<Operation> = self.cleanup[self.loops[idx].cleanup_depth..]
+            .iter()
+            .rev()
+            .cloned()
+            .collect();
+        self.program.extend(unwind);
+
+        if is_break {
+            self.push(op::Copy {
+                source: Operand::Immediate(Value::Integer(0)),
+                destination: Operand::FrameRelative(self.loops[idx].status),
+            });
+            let addr = self.program.len();
+            self.push(op::Jump {
+                target: InstructionAddress::Absolute(0),
+            });
+            self.loops[idx].break_jumps.push(addr);
+        } else {
+            self.push(op::Jump {
+                target: InstructionAddress::Absolute(self.loops[idx].continue_address),
+            });
+        }
+        Ok(())
+    }
+
+    fn compound_list(&mut self, list: &CompoundList) -> Fallible<()> {
+        for command in &list.commands {
+            self.compile_command(command)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes how an operand of a `[[ ]]` expression is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConditionalOperand {
+    String,
+    /// The right hand side of `==` and `!=`
+    Pattern,
+    /// The right hand side of `=~`
+    Regex,
+}
+
+/// Expand `input` as though it were the arguments of a simple command,
+/// returning the resultant fields.  This is for embedders and for
+/// builtins that need to expand text outside of a command.
+///
+/// The same expansions as for command arguments are performed, in
+/// the same order: tilde expansion, parameter expansion and command
+/// substitution, then field splitting of the unquoted results and
+/// pathname expansion, which includes braces, of the unquoted fields,
+/// and finally quote removal.  Words of the form `NAME=value` are
+/// expanded as ordinary words rather than being assigned.
+///
+/// `env` is updated with any assignments that the expansion makes,
+/// such as by `${NAME:=word}`.  `host` is used to look up home
+/// directories and to run command substitutions.
+pub fn expand(
+    input: &str,
+    env: &mut Environment,
+    cwd: &Path,
+    host: Arc<dyn ShellHost>,
+) -> Fallible<Vec<Value>> {
+    let mut lexer = Lexer::new("expand", input.as_bytes());
+    let mut words = vec![];
+    loop {
+        match lexer.next_token()? {
+            Token::Word(word) => words.push(word),
+            Token::Assignment(assign) => words.push((&assign).into()),
+            Token::Newline(_) => {}
+            Token::Eof(_) => break,
+            token => bail!("expand: unexpected {}", token.describe()),
+        }
+    }
+
+    let mut compiler = Compiler::new();
+    compiler.compile_expansion(&words)?;
+    let program = Program::new(compiler.finish()?);
+
+    let mut machine = Machine::new(&program, Some(env.clone()), cwd)?;
+    machine.set_host(host);
+    let status = machine.run()?;
+    *env = machine.top_environment().1;
+
+    match status {
+        Status::Complete(Value::List(fields)) => Ok(fields),
+        status => bail!("expand: expansion did not complete: {:?}", status),
+    }
+}
+
+/// Resolve the backslash escapes in an unquoted literal
+fn remove_backslash_escapes(literal: &str) -> String {
+    let mut result = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(c) = chars.next() {
+                result.push(c);
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Returns true if the first word of a simple command is the unquoted
+/// literal `name`.  This is used to recognize the special builtins
+/// that are implemented by the compiler rather than the shell host.
+fn is_special_builtin(words: &[Vec<WordComponent>], name: &str) -> bool {
+    match words.first().map(Vec::as_slice) {
+        Some(
+            [WordComponent {
+                kind: WordComponentKind::Literal(word),
+                quoting: Quoting::Unquoted,
+                ..
+            }],
+        ) => word == name,
+        _ => false,
+    }
+}
+
+/// Returns a copy of `word` that is not subject to field splitting
+/// or pathname expansion, as for the value of an assignment or the
+/// word of a here-string
+fn unsplittable_word(word: &[WordComponent]) -> Vec<WordComponent> {
+    word.iter()
+        .cloned()
+        .map(|mut component| {
+            component.splittable = false;
+            component
+        })
+        .collect()
+}
+
+#[cfg(test)]
 mod test {
     use super::*;
     use failure::{Error, ResultExt};
@@ -661,6 +1719,21 @@ mod test {
             self.environment.set(key, value);
             self
         }
+
+        fn set_array(mut self, key: &str, values: &[&str]) -> Self {
+            self.environment
+                .assign_array(key, values.iter().map(OsString::from))
+                .unwrap();
+            self
+        }
+
+        fn set_associative(mut self, key: &str, pairs: &[(&str, &str)]) -> Self {
+            self.environment.declare_associative(key).unwrap();
+            for (k, v) in pairs {
+                self.environment.assign_key(key, *k, *v).unwrap();
+            }
+            self
+        }
     }
 
     #[derive(Default, Debug)]
@@ -682,7 +1755,7 @@ mod test {
     }
 
     struct ThreadStatus {
-        state: Cell<ThreadState>,
+        state: Mutex<ThreadState>,
     }
 
     impl std::fmt::Debug for ThreadStatus {
@@ -694,20 +1767,20 @@ mod test {
     impl ThreadStatus {
         pub fn new(handle: JoinHandle<isize>) -> ThreadStatus {
             Self {
-                state: Cell::new(ThreadState::Running(handle)),
+                state: Mutex::new(ThreadState::Running(handle)),
             }
         }
     }
 
     impl WaitForStatus for ThreadStatus {
         fn wait(&self) -> Option<Status> {
-            let state = self.state.replace(ThreadState::Done(10));
-            let state = match state {
+            let mut state = self.state.lock().unwrap();
+            let status = match std::mem::replace(&mut *state, ThreadState::Done(10)) {
                 ThreadState::Running(thread) => thread.join().unwrap(),
-                ThreadState::Done(state) => state,
+                ThreadState::Done(status) => status,
             };
-            self.state.set(ThreadState::Done(state));
-            Some(Status::Complete(state.into()))
+            *state = ThreadState::Done(status);
+            Some(Status::Complete(status.into()))
         }
 
         // We don't support non-blocking here
@@ -758,13 +1831,17 @@ mod test {
 
         fn spawn_command(
             &self,
-            argv: &Vec<Value>,
+            argv: &[Value],
             environment: &mut Environment,
             current_directory: &mut PathBuf,
             io_env: &IoEnvironment,
         ) -> Fallible<WaitableStatus> {
+            if argv.is_empty() {
+                return Ok(Status::Complete(0.into()).into());
+            }
+
             let command = argv
-                .get(0)
+                .first()
                 .ok_or_else(|| err_msg("argv0 is missing"))?
                 .as_os_str()
                 .ok_or_else(|| err_msg("argv0 is not a string"))?;
@@ -772,19 +1849,22 @@ mod test {
             if let Some(prog) = self.lookup_function(command.to_str().unwrap()) {
                 // Execute the function.
                 // This is blocking and not subjectable to job control.
-                let mut machine =
-                    Machine::new(&prog, Some(environment.clone()), &current_directory)?;
+                let mut func_env = environment.clone();
+                func_env.push_scope();
+                let mut machine = Machine::new(&prog, Some(func_env), current_directory)?;
                 machine.set_host(Arc::new(TestHost {
                     funcs: Arc::clone(&self.funcs),
                     spawn_log: Arc::clone(&self.spawn_log),
                 }));
+                *machine.io_env_mut()? = io_env.clone();
 
                 print_prog(prog.opcodes());
-                machine.set_positional(argv.clone());
+                machine.set_positional(argv.to_vec());
 
                 let status = machine.run();
 
-                let (new_cwd, new_env) = machine.top_environment();
+                let (new_cwd, mut new_env) = machine.top_environment();
+                new_env.pop_scope();
                 *current_directory = new_cwd;
                 *environment = new_env;
 
@@ -806,7 +1886,46 @@ mod test {
                         write!(stdout, "{}", s).context("echo: write")?;
                     }
                 }
-                write!(stdout, "\n")?;
+                writeln!(stdout)?;
+                Status::Complete(0.into()).into()
+            } else if command == "local" {
+                // Minimal `local NAME[=VALUE]` for testing function scopes
+                for arg in argv.iter().skip(1) {
+                    let arg = arg.as_str().ok_or_else(|| err_msg("local: non-string"))?;
+                    let split: Vec<&str> = arg.splitn(2, '=').collect();
+                    environment.declare_local(split[0])?;
+                    match split.get(1) {
+                        Some(value) => environment.assign(split[0], *value)?,
+                        None => environment.unset(split[0])?,
+                    }
+                }
+                Status::Complete(0.into()).into()
+            } else if command == "readonly" {
+                // Minimal `readonly NAME[=VALUE]` for testing enforcement
+                for arg in argv.iter().skip(1) {
+                    let arg = arg
+                        .as_str()
+                        .ok_or_else(|| err_msg("readonly: non-string"))?;
+                    let split: Vec<&str> = arg.splitn(2, '=').collect();
+                    if let Some(value) = split.get(1) {
+                        environment.assign(split[0], *value)?;
+                    }
+                    environment.set_readonly(split[0]);
+                }
+                Status::Complete(0.into()).into()
+            } else if command == "read" {
+                // Minimal `read NAME` that assigns the first line of stdin
+                let name = argv
+                    .get(1)
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| err_msg("read: missing name"))?;
+                let mut stdin = io_env.stdin();
+                let mut line = vec![];
+                let mut buf = [0u8; 1];
+                while stdin.read(&mut buf)? == 1 && buf[0] != b'\n' {
+                    line.push(buf[0]);
+                }
+                environment.assign(name, String::from_utf8(line)?)?;
                 Status::Complete(0.into()).into()
             } else if command == "false" {
                 // false is explicitly non-zero
@@ -823,7 +1942,7 @@ mod test {
             };
 
             log.push(SpawnEntry {
-                argv: argv.clone(),
+                argv: argv.to_vec(),
                 environment: environment.clone(),
                 current_directory: current_directory.clone(),
             });
@@ -838,7 +1957,7 @@ mod test {
     }
 
     fn compile(prog: &str) -> Fallible<Vec<Operation>> {
-        let mut parser = Parser::new(prog.as_bytes());
+        let mut parser = Parser::new("test", prog.as_bytes());
         let command = parser.parse()?;
         let mut compiler = Compiler::new();
         compiler.compile_command(&command)?;
@@ -885,13 +2004,16 @@ mod test {
 
     fn run_with_log_and_output(
         prog: Vec<Operation>,
+    ) -> Fallible<(Status, Vec<SpawnEntry>, String, String)> {
+        run_with_env_and_output(prog, Environment::new_empty())
+    }
+
+    fn run_with_env_and_output(
+        prog: Vec<Operation>,
+        env: Environment,
     ) -> Fallible<(Status, Vec<SpawnEntry>, String, String)> {
         print_prog(&prog);
-        let mut machine = Machine::new(
-            &Program::new(prog),
-            Some(Environment::new_empty()),
-            &std::env::current_dir()?,
-        )?;
+        let mut machine = Machine::new(&Program::new(prog), Some(env), &std::env::current_dir()?)?;
 
         let host = TestHost::default();
         let log = Arc::clone(&host.spawn_log);
@@ -1020,6 +2142,44 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_if_clause() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("if false; then echo a; else echo b; fi")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![
+                    SpawnEntry::new(vec!["false".into()]),
+                    SpawnEntry::new(vec!["echo".into(), "b".into()]),
+                ]
+            )
+        );
+
+        // The status is 0 if no branch is taken
+        assert_eq!(
+            run_with_log(compile("if false; then echo a; fi")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["false".into()])]
+            )
+        );
+
+        assert_eq!(
+            run_with_log(compile(
+                "if false; then echo a; elif true; then false; else echo c; fi"
+            )?)?,
+            (
+                Status::Complete(1.into()),
+                vec![
+                    SpawnEntry::new(vec!["false".into()]),
+                    SpawnEntry::new(vec!["true".into()]),
+                    SpawnEntry::new(vec!["false".into()]),
+                ]
+            )
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_param_get() -> Fallible<()> {
         assert_eq!(
@@ -1049,6 +2209,77 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_quoted_field_splitting() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("echo \"a b\"")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "a b".into()]),]
+            )
+        );
+
+        assert_eq!(
+            run_with_log(compile("foo='a b' echo \"$foo\"")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "a b".into()]).set_env("foo", "a b"),]
+            )
+        );
+
+        assert_eq!(
+            run_with_log(compile("foo='a b' echo $foo")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "a".into(), "b".into()])
+                    .set_env("foo", "a b"),]
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assignment_no_split() -> Fallible<()> {
+        // The value of an assignment is neither field split nor globbed
+        assert_eq!(
+            run_with_log(compile("foo='a   b'\nbar=$foo\necho \"$bar\"")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "a   b".into()])
+                    .set_env("foo", "a   b")
+                    .set_env("bar", "a   b")]
+            )
+        );
+        assert_eq!(
+            run_with_log(compile("bar=*\necho \"$bar\"")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "*".into()]).set_env("bar", "*")]
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_locale_quoting() -> Fallible<()> {
+        // Without translation, `$"..."` expands as a double quoted string
+        for prog in &[
+            "name='a b' echo $\"hello $name\"",
+            "name='a b' echo \"hello $name\"",
+        ] {
+            assert_eq!(
+                run_with_log(compile(prog)?)?,
+                (
+                    Status::Complete(0.into()),
+                    vec![SpawnEntry::new(vec!["echo".into(), "hello a b".into()])
+                        .set_env("name", "a b"),]
+                )
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_param_get_default() -> Fallible<()> {
         assert_eq!(
@@ -1125,6 +2356,42 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_append_assignment() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("foo=bar\nfoo+=\" baz\"\necho \"$foo\"")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "bar baz".into()])
+                    .set_env("foo", "bar baz")]
+            )
+        );
+        // Appending to an unset variable is a plain assignment
+        assert_eq!(
+            run_with_log(compile("foo+=bar\necho $foo")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "bar".into()]).set_env("foo", "bar")]
+            )
+        );
+        assert_eq!(
+            run_with_log(compile("foo+=\necho \"[$foo]\"")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "[]".into()]).set_env("foo", "")]
+            )
+        );
+        // Outside of command position, it is an ordinary word
+        assert_eq!(
+            run_with_log(compile("echo a+=b")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "a+=b".into()])]
+            )
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_param_assign_default() -> Fallible<()> {
         assert_eq!(
@@ -1183,56 +2450,247 @@ mod test {
     }
 
     #[test]
-    fn test_param_check_set() -> Fallible<()> {
+    fn test_param_indirect() -> Fallible<()> {
         assert_eq!(
-            run_with_log_and_output(compile("echo ${foo:?bar}")?)?,
+            run_with_log(compile("ref=foo\nfoo=bar\necho ${!ref}")?)?,
             (
-                Status::Complete(1.into()),
-                vec![],
-                String::new(),
-                String::from("bar")
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "bar".into()])
+                    .set_env("ref", "foo")
+                    .set_env("foo", "bar")]
             )
         );
         assert_eq!(
-            run_with_log_and_output(compile("echo ${foo:?}")?)?,
+            run_with_log(compile("echo ${!ref} ${!ref:-default}")?)?,
             (
-                Status::Complete(1.into()),
-                vec![],
-                String::new(),
-                String::from("parameter foo is not set")
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "default".into()])]
             )
         );
         Ok(())
     }
 
     #[test]
-    fn test_param_alternative_value() -> Fallible<()> {
+    fn test_param_nounset() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.options_mut().set("nounset", true)?;
+
         assert_eq!(
-            run_with_log(compile("echo ${foo:+bar}")?)?,
+            run_with_env_and_output(compile("echo ${!ref}")?, env.clone())?,
             (
-                Status::Complete(0.into()),
-                vec![SpawnEntry::new(vec!["echo".into()]),]
+                Status::Complete(1.into()),
+                vec![],
+                String::new(),
+                String::from("wzsh: ref: unbound variable\n")
             )
         );
-
         assert_eq!(
-            run_with_log(compile("foo=1 echo ${foo:+bar}")?)?,
+            run_with_env_and_output(compile("ref=foo\necho ${!ref}")?, env.clone())?,
             (
-                Status::Complete(0.into()),
-                vec![SpawnEntry::new(vec!["echo".into(), "bar".into()]).set_env("foo", "1"),]
+                Status::Complete(1.into()),
+                vec![],
+                String::new(),
+                String::from("wzsh: foo: unbound variable\n")
             )
         );
-
+        let (status, _, stdout, stderr) =
+            run_with_env_and_output(compile("echo ${foo-unset}")?, env)?;
         assert_eq!(
-            run_with_log(compile("foo= echo ${foo:+bar}")?)?,
-            (
-                Status::Complete(0.into()),
-                vec![SpawnEntry::new(vec!["echo".into()]).set_env("foo", ""),]
-            )
+            (status, stdout.as_str(), stderr.as_str()),
+            (Status::Complete(0.into()), "unset\n", "")
         );
+        Ok(())
+    }
+
+    #[test]
+    fn test_xtrace() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.options_mut().set("xtrace", true)?;
+        let traced = |prog: &str, env: &Environment| -> Fallible<String> {
+            let (_, _, _, stderr) = run_with_env_and_output(compile(prog)?, env.clone())?;
+            Ok(stderr)
+        };
 
         assert_eq!(
-            run_with_log(compile("foo= echo ${foo+bar}")?)?,
+            traced("v='a b'\necho $v \"$v\" it\\'s", &env)?,
+            "+ echo a b 'a b' 'it'\\''s'\n"
+        );
+        assert_eq!(
+            traced("echo $(echo inner)", &env)?,
+            "++ echo inner\n+ echo inner\n"
+        );
+        assert_eq!(
+            traced("n=1\nPS4='[$n] '\necho $(echo x)", &env)?,
+            "[[1] echo x\n[1] echo x\n"
+        );
+
+        env.options_mut().set("xtrace", false)?;
+        assert_eq!(traced("echo quiet", &env)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_param_change_case() -> Fallible<()> {
+        let echoed = |prog: &str| -> Fallible<Vec<Value>> {
+            let (_, log) = run_with_log(compile(prog)?)?;
+            Ok(log[0].argv.clone())
+        };
+        assert_eq!(
+            echoed("v=hello\necho ${v^} ${v^^}")?,
+            vec!["echo".into(), "Hello".into(), "HELLO".into()]
+        );
+        assert_eq!(
+            echoed("v=HELLO\necho ${v,} ${v,,}")?,
+            vec!["echo".into(), "hELLO".into(), "hello".into()]
+        );
+        // The pattern restricts which characters are converted
+        assert_eq!(
+            echoed("v=hello\necho ${v^^[lo]} ${v^l}")?,
+            vec!["echo".into(), "heLLO".into(), "hello".into()]
+        );
+        assert_eq!(
+            echoed("v=straße\necho ${v^^}")?,
+            vec!["echo".into(), "STRASSE".into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_param_transform() -> Fallible<()> {
+        let echoed = |prog: &str| -> Fallible<Vec<Value>> {
+            let (_, log) = run_with_log(compile(prog)?)?;
+            Ok(log[0].argv.clone())
+        };
+        assert_eq!(
+            echoed("v='a b'\necho \"${v@Q}\" \"${v@A}\"")?,
+            vec!["echo".into(), "'a b'".into(), "v='a b'".into()]
+        );
+        assert_eq!(
+            echoed("v='1\\t2'\necho \"${v@E}\"")?,
+            vec!["echo".into(), "1\t2".into()]
+        );
+        assert_eq!(
+            echoed("echo \"${unset@Q}\"")?,
+            vec!["echo".into(), "".into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_param_replace() -> Fallible<()> {
+        let echoed = |prog: &str| -> Fallible<Vec<Value>> {
+            let (_, log) = run_with_log(compile(prog)?)?;
+            Ok(log[0].argv.clone())
+        };
+        assert_eq!(
+            echoed("v=banana\necho ${v/an/AN} ${v//an/AN}")?,
+            vec!["echo".into(), "bANana".into(), "bANANa".into()]
+        );
+        assert_eq!(
+            echoed("v=banana\necho ${v/#b/B} ${v/#a/A} ${v/%a/A}")?,
+            vec![
+                "echo".into(),
+                "Banana".into(),
+                "banana".into(),
+                "bananA".into()
+            ]
+        );
+        // An empty or absent replacement deletes the match
+        assert_eq!(
+            echoed("v=banana\necho ${v//a/} ${v//n}")?,
+            vec!["echo".into(), "bnn".into(), "baaa".into()]
+        );
+        // The pattern may be a glob, and its quoted portions are literal
+        assert_eq!(
+            echoed("v=a*b*c\necho ${v//\"*\"/-} ${v/a*b/x}")?,
+            vec!["echo".into(), "a-b-c".into(), "x*c".into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn command_substitution() -> Fallible<()> {
+        let echoed = |prog: &str| -> Fallible<Vec<Value>> {
+            let (_, log) = run_with_log(compile(prog)?)?;
+            Ok(log.last().unwrap().argv.clone())
+        };
+        let f = "f() { echo a  b\necho\necho c }\n";
+
+        // Quoted, internal newlines are preserved and only the
+        // trailing newline is removed
+        assert_eq!(
+            echoed(&format!("{}echo \"$(f)\"", f))?,
+            vec!["echo".into(), "a b\n\nc".into()]
+        );
+        // Unquoted, the output is split into fields
+        assert_eq!(
+            echoed(&format!("{}echo $(f)", f))?,
+            vec!["echo".into(), "a".into(), "b".into(), "c".into()]
+        );
+        assert_eq!(
+            echoed(&format!("{}echo x\"$(f)\"y", f))?,
+            vec!["echo".into(), "xa b\n\ncy".into()]
+        );
+
+        // Assignments made by the command do not persist
+        assert_eq!(
+            echoed("v=1\necho $(v=2\necho $v) $v")?,
+            vec!["echo".into(), "2".into(), "1".into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_param_check_set() -> Fallible<()> {
+        assert_eq!(
+            run_with_log_and_output(compile("echo ${foo:?bar}")?)?,
+            (
+                Status::Complete(1.into()),
+                vec![],
+                String::new(),
+                String::from("bar")
+            )
+        );
+        assert_eq!(
+            run_with_log_and_output(compile("echo ${foo:?}")?)?,
+            (
+                Status::Complete(1.into()),
+                vec![],
+                String::new(),
+                String::from("parameter foo is not set")
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_param_alternative_value() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("echo ${foo:+bar}")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into()]),]
+            )
+        );
+
+        assert_eq!(
+            run_with_log(compile("foo=1 echo ${foo:+bar}")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "bar".into()]).set_env("foo", "1"),]
+            )
+        );
+
+        assert_eq!(
+            run_with_log(compile("foo= echo ${foo:+bar}")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into()]).set_env("foo", ""),]
+            )
+        );
+
+        assert_eq!(
+            run_with_log(compile("foo= echo ${foo+bar}")?)?,
             (
                 Status::Complete(0.into()),
                 vec![SpawnEntry::new(vec!["echo".into(), "bar".into()]).set_env("foo", ""),]
@@ -1284,6 +2742,63 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_timed_pipeline() -> Fallible<()> {
+        let (status, log, stdout, stderr) =
+            run_with_log_and_output(compile("time ! echo a | uppercase")?)?;
+        assert_eq!(status, Status::Complete(1.into()));
+        assert_eq!(log.len(), 2);
+        assert_eq!(stdout, "A\n");
+        assert!(stderr.starts_with("\nreal\t0m"), "stderr: {:?}", stderr);
+        assert!(stderr.contains("\nuser\t0m"));
+        assert!(stderr.contains("\nsys\t0m"));
+        Ok(())
+    }
+
+    #[test]
+    fn here_string() -> Fallible<()> {
+        assert_eq!(
+            run_with_log_and_output(compile("bar='a  b*'; uppercase <<< $bar")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["uppercase".into()]).set_env("bar", "a  b*")],
+                "A  B*\n".to_owned(),
+                "".to_owned(),
+            )
+        );
+
+        let (status, _log, stdout, _stderr) =
+            run_with_log_and_output(compile("bar=hello; uppercase <<< \"$bar world\"")?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "HELLO WORLD\n");
+        Ok(())
+    }
+
+    #[test]
+    fn trace_expansion() -> Fallible<()> {
+        let mut parser = Parser::new("test", "dir=src; echo ~ $dir/*.rs".as_bytes());
+        let command = parser.parse()?;
+        let mut compiler = Compiler::new();
+        compiler.set_trace_expansion(true);
+        compiler.compile_command(&command)?;
+        let (status, _log, _stdout, stderr) = run_with_log_and_output(compiler.finish()?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            stderr,
+            "+ expand word: 'src'\n\
+             + expand word: 'echo'\n\
+             + expand split: 'echo'\n\
+             + expand glob: 'echo'\n\
+             + expand tilde: '/home/wez'\n\
+             + expand word: '/home/wez'\n\
+             + expand parameter: 'src'\n\
+             + expand word: 'src/*.rs'\n\
+             + expand split: 'src/*.rs'\n\
+             + expand glob: 'src/lib.rs' 'src/registeralloc.rs'\n"
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_pipeline() -> Fallible<()> {
         assert_eq!(
@@ -1301,6 +2816,68 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn public_expand() -> Fallible<()> {
+        let cwd = std::env::current_dir()?;
+        let host: Arc<dyn ShellHost> = Arc::new(TestHost::default());
+        let mut env = Environment::new_empty();
+        env.set("HOME", "/home/wez");
+        env.set("DIR", "a b");
+        let strings = |fields: Vec<Value>| -> Vec<String> {
+            fields
+                .iter()
+                .map(|v| v.as_os_str().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        assert_eq!(
+            strings(expand(
+                "\"$HOME/foo\" ~/bar $DIR \"$DIR\"",
+                &mut env,
+                &cwd,
+                Arc::clone(&host)
+            )?),
+            vec!["/home/wez/foo", "/home/wez/bar", "a", "b", "a b"]
+        );
+
+        // This is sensitive to the names of the files in this crate
+        let mut globbed = strings(expand(
+            "src/{lib,registeralloc}.rs",
+            &mut env,
+            &cwd,
+            Arc::clone(&host),
+        )?);
+        globbed.sort();
+        assert_eq!(globbed, vec!["src/lib.rs", "src/registeralloc.rs"]);
+
+        // Assignments made during expansion persist
+        assert_eq!(
+            strings(expand("X=${X:=5}", &mut env, &cwd, Arc::clone(&host))?),
+            vec!["X=5"]
+        );
+        assert_eq!(env.get_str("X")?, Some("5"));
+
+        assert!(expand("echo a | b", &mut env, &cwd, host).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lastpipe() -> Fallible<()> {
+        // By default, the last stage runs as though in a subshell
+        let (status, _log, stdout, _stderr) =
+            run_with_log_and_output(compile("v=old; echo x | read v; echo $v")?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "old\n");
+
+        let mut env = Environment::new_empty();
+        env.options_mut().set("lastpipe", true)?;
+        let (status, _log, stdout, _stderr) =
+            run_with_env_and_output(compile("v=old; echo x | read v; echo $v")?, env)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "x\n");
+        Ok(())
+    }
+
     #[test]
     fn test_glob() -> Fallible<()> {
         assert_eq!(
@@ -1329,6 +2906,17 @@ mod test {
                 "".to_owned(),
             )
         );
+
+        // The unquoted portions of a partly quoted word are globbed,
+        // while pattern characters in the quoted portions are literal
+        let mut env = Environment::new_empty();
+        env.set("DIR", "src");
+        env.set("STAR", "*");
+        let (_status, _log, stdout, _stderr) = run_with_env_and_output(
+            compile("echo \"$DIR\"/*.rs \"s\"r* \"$STAR\"* '*'.rs 'a\\'*")?,
+            env,
+        )?;
+        assert_eq!(stdout, "src/lib.rs src/registeralloc.rs src ** *.rs a\\*\n");
         Ok(())
     }
 
@@ -1360,8 +2948,9 @@ mod test {
             run_with_log_and_output(compile("echo \\**/*.rs")?)?,
             (
                 Status::Complete(0.into()),
-                vec![SpawnEntry::new(vec!["echo".into(),]),],
-                "\n".to_owned(),
+                // Nothing matches, so the word is retained
+                vec![SpawnEntry::new(vec!["echo".into(), "**/*.rs".into()]),],
+                "**/*.rs\n".to_owned(),
                 "".to_owned(),
             )
         );
@@ -1394,6 +2983,106 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn positional_star_joined() -> Fallible<()> {
+        // IFS is unset: join with a space
+        assert_eq!(
+            run_with_log(compile("f() { echo \"$*\" $* \"$@\" }\nf a '' c")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![echo(&["a  c", "a", "", "c", "a", "", "c"])]
+            )
+        );
+        // The first character of IFS is used
+        assert_eq!(
+            run_with_log(compile("IFS=:-\nf() { echo \"<$*>\" }\nf a '' c")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![echo(&["<a::c>"]).set_env("IFS", ":-")]
+            )
+        );
+        // An empty IFS joins without a separator
+        assert_eq!(
+            run_with_log(compile("IFS=\nf() { echo \"$*\" }\nf a '' c")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![echo(&["ac"]).set_env("IFS", "")]
+            )
+        );
+        // Without positional parameters "$*" is a single empty field
+        assert_eq!(
+            run_with_log(compile("f() { echo \"$*\" }\nf")?)?,
+            (Status::Complete(0.into()), vec![echo(&[""])])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn indexed_arrays() -> Fallible<()> {
+        let arr = ["a", "b c", "x"];
+
+        // Element access, including from the end, and the element count
+        assert_eq!(
+            run_with_log(compile(
+                "arr=(a 'b c' d)\narr[2]=x\necho $arr \"${arr[1]}\" ${arr[-1]} ${#arr[@]}"
+            )?)?,
+            (
+                Status::Complete(0.into()),
+                vec![echo(&["a", "b c", "x", "3"]).set_array("arr", &arr)]
+            )
+        );
+        // `@` produces a field per element, while a quoted `*` joins them
+        assert_eq!(
+            run_with_log(compile(
+                "arr=(a 'b c')\narr+=(x)\necho \"${arr[@]}\" \"<${arr[*]}>\" ${arr[5]}"
+            )?)?,
+            (
+                Status::Complete(0.into()),
+                vec![echo(&["a", "b c", "x", "<a b c x>"]).set_array("arr", &arr)]
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn associative_arrays() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.declare_associative("map")?;
+        let (status, log, _out, _err) = run_with_env_and_output(
+            compile(
+                "map[one]=1\nk='two words'\nmap[$k]=2\n\
+                 echo \"${map[$k]}\" ${map[one]} \"${!map[@]}\" ${#map[@]}",
+            )?,
+            env,
+        )?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log,
+            vec![SpawnEntry::new(vec![
+                "echo".into(),
+                "2".into(),
+                "1".into(),
+                "one".into(),
+                "two words".into(),
+                "2".into()
+            ])
+            .set_env("k", "two words")
+            .set_associative("map", &[("one", "1"), ("two words", "2")])]
+        );
+
+        // Without a declaration the subscript is arithmetic, and
+        // the name becomes an indexed array
+        assert_eq!(
+            run_with_log(compile("arr[foo]=x\necho ${!arr[@]} ${arr[foo]}")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "0".into(), "x".into()])
+                    .set_array("arr", &["x"])]
+            )
+        );
+        Ok(())
+    }
+
     #[test]
     fn param_n() -> Fallible<()> {
         assert_eq!(
@@ -1441,6 +3130,206 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn local_variables() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile(
+                "foo=global\nf() { local foo=inner\necho $foo }\nf\necho $foo"
+            )?)?,
+            (
+                Status::Complete(0.into()),
+                vec![
+                    SpawnEntry::new(vec!["local".into(), "foo=inner".into()])
+                        .set_env("foo", "inner"),
+                    SpawnEntry::new(vec!["echo".into(), "inner".into()]).set_env("foo", "inner"),
+                    SpawnEntry::new(vec!["echo".into(), "global".into()]).set_env("foo", "global"),
+                ]
+            )
+        );
+
+        // Variables that were not previously set are unset again
+        assert_eq!(
+            run_with_log(compile("f() { local foo=inner }\nf\necho $foo")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![
+                    SpawnEntry::new(vec!["local".into(), "foo=inner".into()])
+                        .set_env("foo", "inner"),
+                    SpawnEntry::new(vec!["echo".into()]),
+                ]
+            )
+        );
+
+        // Non-local assignments made by the function persist
+        assert_eq!(
+            run_with_log(compile("f() { foo=inner }\nf\necho $foo")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "inner".into()]).set_env("foo", "inner"),]
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn readonly_variables() -> Fallible<()> {
+        let err = run_with_log(compile("readonly foo=1\nfoo=2\necho $foo")?).unwrap_err();
+        assert!(err.to_string().ends_with("foo: readonly variable"));
+
+        // Neither can a readonly variable be unset by declaring it local
+        let err = run_with_log(compile("readonly foo=1\nf() { local foo }\nf")?).unwrap_err();
+        assert!(err.to_string().ends_with("foo: readonly variable"));
+        Ok(())
+    }
+
+    #[test]
+    fn return_from_function() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("f() { echo a\nreturn 3\necho b }\nf")?)?,
+            (
+                Status::Complete(3.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "a".into()]),]
+            )
+        );
+
+        // Without an argument, the status of the last command is returned
+        assert_eq!(
+            run_with_log(compile("f() { false\nreturn\necho b }\nf")?)?,
+            (
+                Status::Complete(1.into()),
+                vec![SpawnEntry::new(vec!["false".into()]),]
+            )
+        );
+
+        assert_eq!(
+            run_with_log_and_output(compile("return 3\necho b")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["echo".into(), "b".into()]),],
+                "b\n".to_owned(),
+                "wzsh: return: can only `return' from a function\n".to_owned(),
+            )
+        );
+        Ok(())
+    }
+
+    fn echo(args: &[&str]) -> SpawnEntry {
+        let mut argv = vec!["echo".into()];
+        argv.extend(args.iter().map(|s| (*s).into()));
+        SpawnEntry::new(argv)
+    }
+
+    #[test]
+    fn for_loop() -> Fallible<()> {
+        let (status, log) = run_with_log(compile("for i in a b c; do echo $i; done")?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["a"]).argv, echo(&["b"]).argv, echo(&["c"]).argv,]
+        );
+
+        // The loop status is that of the last body command
+        let (status, _log) = run_with_log(compile("for i in a; do false; done")?)?;
+        assert_eq!(status, Status::Complete(1.into()));
+        Ok(())
+    }
+
+    #[test]
+    fn while_loop() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("while false; do echo a; done")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["false".into()])]
+            )
+        );
+        assert_eq!(
+            run_with_log(compile("until true; do echo a; done")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["true".into()])]
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn loop_break() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("while true; do echo a; break; echo b; done")?)?,
+            (
+                Status::Complete(0.into()),
+                vec![SpawnEntry::new(vec!["true".into()]), echo(&["a"])]
+            )
+        );
+
+        // Break from within nested commands unwinds their frames
+        let (status, log) = run_with_log(compile(
+            "for i in a b; do { echo $i && break; }; echo no; done\necho after",
+        )?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["a"]).argv, echo(&["after"]).argv]
+        );
+
+        let (status, log) = run_with_log(compile(
+            "for i in a b; do for j in c d; do echo $i$j; break 2; done; echo no; done",
+        )?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["ac"]).argv]
+        );
+
+        // The loop count is expanded at run time
+        let (status, log) = run_with_log(compile(
+            "n=2; for i in a b; do for j in c d; do echo $i$j; break $n; done; echo no; done",
+        )?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["ac"]).argv]
+        );
+
+        // An invalid count is reported and the loop carries on
+        let (status, log, _stdout, stderr) =
+            run_with_log_and_output(compile("for i in a b; do echo $i; break 0; done")?)?;
+        assert_eq!(status, Status::Complete(1.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["a"]).argv, echo(&["b"]).argv]
+        );
+        assert_eq!(
+            stderr,
+            "wzsh: break: 0: loop count out of range\n\
+             wzsh: break: 0: loop count out of range\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn loop_continue() -> Fallible<()> {
+        let (status, log) = run_with_log(compile(
+            "for i in a b; do echo $i; continue; echo no; done",
+        )?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["a"]).argv, echo(&["b"]).argv]
+        );
+
+        let (status, log) = run_with_log(compile(
+            "for i in a b; do for j in c d; do echo $i$j; continue 2; done; echo no; done",
+        )?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["ac"]).argv, echo(&["bc"]).argv]
+        );
+        Ok(())
+    }
+
     #[test]
     fn positional_len() -> Fallible<()> {
         assert_eq!(
@@ -1452,4 +3341,147 @@ mod test {
         );
         Ok(())
     }
+
+    #[test]
+    fn arithmetic_command() -> Fallible<()> {
+        assert_eq!(
+            run_with_log(compile("((1 < 2))")?)?,
+            (Status::Complete(0.into()), vec![])
+        );
+        assert_eq!(
+            run_with_log(compile("((0))")?)?,
+            (Status::Complete(1.into()), vec![])
+        );
+        assert_eq!(
+            run_with_log(compile("((2 * 3 - 6)) || echo zero")?)?,
+            (Status::Complete(0.into()), vec![echo(&["zero"])])
+        );
+
+        let (status, log) = run_with_log(compile("i=1\n((i++))\necho $i")?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["2"]).argv]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_glob() -> Fallible<()> {
+        for (text, status) in &[
+            ("[[ foo.rs == *.rs ]]", 0),
+            ("[[ foo.rs = f?o.* ]]", 0),
+            ("[[ foo.rs == \"*.rs\" ]]", 1),
+            ("[[ '*.rs' == \"*.rs\" ]]", 0),
+            ("p='*.rs'\n[[ foo.rs == $p ]]", 0),
+            ("p='*.rs'\n[[ foo.rs == \"$p\" ]]", 1),
+            ("[[ abc != a* ]]", 1),
+            ("[[ 1 -lt 2 && ! ( a > b ) ]]", 0),
+            ("[[ 1 -gt 2 || -z '' ]]", 0),
+        ] {
+            assert_eq!(
+                run_with_log(compile(text)?)?,
+                (Status::Complete((*status).into()), vec![]),
+                "{}",
+                text
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_regex() -> Fallible<()> {
+        let (status, log) = run_with_log(compile(
+            "[[ abc123 =~ ^([a-z]+)([0-9]+)$ ]] && echo $BASH_REMATCH $BASH_REMATCH_1 $BASH_REMATCH_2",
+        )?)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(
+            log.into_iter().map(|e| e.argv).collect::<Vec<_>>(),
+            vec![echo(&["abc123", "abc", "123"]).argv]
+        );
+
+        // Quoted portions of the regex match literally
+        assert_eq!(
+            run_with_log(compile("[[ abc =~ \"a.c\" ]]")?)?,
+            (Status::Complete(1.into()), vec![])
+        );
+        assert_eq!(
+            run_with_log(compile("[[ a.c =~ ^\"a.c\"$ ]]")?)?,
+            (Status::Complete(0.into()), vec![])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_no_split() -> Fallible<()> {
+        // Unlike `test`, the operands are not subject to field splitting,
+        // and an empty expansion is still a single operand
+        for (text, status) in &[
+            ("x='a b'\n[[ $x == 'a b' ]]", 0),
+            ("x='a b'\n[[ $x ]]", 0),
+            ("[[ -n $unset ]]", 1),
+            ("[[ -z $unset ]]", 0),
+            ("[[ $unset ]]", 1),
+        ] {
+            assert_eq!(
+                run_with_log(compile(text)?)?,
+                (Status::Complete((*status).into()), vec![]),
+                "{}",
+                text
+            );
+        }
+        Ok(())
+    }
+
+    /// Spawns commands without running them, and reports that the
+    /// user has interrupted the shell once `limit` have been spawned
+    #[derive(Debug)]
+    struct InterruptingHost {
+        spawned: Mutex<usize>,
+        limit: usize,
+    }
+
+    impl ShellHost for InterruptingHost {
+        fn lookup_homedir(&self, _user: Option<&str>) -> Fallible<OsString> {
+            Ok("/home".into())
+        }
+
+        fn spawn_command(
+            &self,
+            _argv: &[Value],
+            _environment: &mut Environment,
+            _current_directory: &mut PathBuf,
+            _io_env: &IoEnvironment,
+        ) -> Fallible<WaitableStatus> {
+            *self.spawned.lock().unwrap() += 1;
+            Ok(Status::Complete(0.into()).into())
+        }
+
+        fn define_function(&self, _name: &str, _program: &Arc<Program>) -> Fallible<()> {
+            Ok(())
+        }
+
+        fn interrupted(&self) -> bool {
+            *self.spawned.lock().unwrap() >= self.limit
+        }
+    }
+
+    #[test]
+    fn test_interrupt_stops_loop() -> Fallible<()> {
+        let prog = compile("while true; do true; done")?;
+        let mut machine = Machine::new(
+            &Program::new(prog),
+            Some(Environment::new_empty()),
+            &std::env::current_dir()?,
+        )?;
+        let host = Arc::new(InterruptingHost {
+            spawned: Mutex::new(0),
+            limit: 5,
+        });
+        machine.set_host(Arc::clone(&host) as Arc<dyn ShellHost>);
+
+        assert_eq!(machine.run()?, Status::Complete(130.into()));
+        assert_eq!(*host.spawned.lock().unwrap(), 5);
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use crate::position::Span;
+use crate::position::{Pos, Span};
 use failure::Fail;
 
 #[derive(Debug, Clone, Copy, Fail)]
@@ -11,12 +11,22 @@ pub enum LexErrorKind {
     EofDuringSingleQuotedString,
     #[fail(display = "EOF while lexing double quoted string")]
     EofDuringDoubleQuotedString,
+    #[fail(display = "EOF while lexing $'...' string")]
+    EofDuringAnsiCQuotedString,
     #[fail(display = "EOF while lexing parameter expansion")]
     EofDuringParameterExpansion,
     #[fail(display = "EOF while lexing assignment word")]
     EofDuringAssignmentWord,
     #[fail(display = "EOF while lexing command substitution")]
     EofDuringCommandSubstitution,
+    #[fail(display = "EOF while lexing arithmetic command")]
+    EofDuringArithmeticCommand,
+    #[fail(display = "EOF while lexing extended pattern")]
+    EofDuringExtendedPattern,
+    #[fail(display = "unmatched `)` in arithmetic command")]
+    UnmatchedParenInArithmeticCommand,
+    #[fail(display = "NUL byte in input")]
+    NulByte,
     #[fail(display = "IO Error")]
     IoError,
 }
@@ -32,10 +42,38 @@ impl LexErrorKind {
 pub struct LexError {
     pub kind: LexErrorKind,
     pub span: Span,
+    /// The name of the input that was being lexed; a filename,
+    /// or a label such as "stdin"
+    pub source: String,
+    /// For an error at the end of the input, the position of the
+    /// quote or other delimiter that began the unterminated construct
+    pub opened_at: Option<Pos>,
 }
 
 impl LexError {
     pub fn new(kind: LexErrorKind, span: Span) -> Self {
-        Self { kind, span }
+        Self {
+            kind,
+            span,
+            source: String::new(),
+            opened_at: None,
+        }
+    }
+
+    /// Record the position of the delimiter that began the construct
+    /// that was left unterminated
+    pub fn opened_at(self, pos: Pos) -> Self {
+        Self {
+            opened_at: Some(pos),
+            ..self
+        }
+    }
+
+    /// Associate the error with the named source
+    pub fn in_source(self, source: &str) -> Self {
+        Self {
+            source: source.to_owned(),
+            ..self
+        }
     }
 }
@@ -0,0 +1,101 @@
+//! Backslash escape sequences, as decoded within `$'...'` quoting
+
+/// Expand the backslash escape sequences that are recognized in
+/// `$'...'` quoting: `\a`, `\b`, `\e`, `\E`, `\f`, `\n`, `\r`, `\t`,
+/// `\v`, `\\`, `\'`, `\"`, `\?`, `\NNN` (octal), `\xHH`, `\uHHHH`,
+/// `\UHHHHHHHH` and `\cX` (control character).  Any other sequence
+/// is left as-is.
+pub fn expand_backslash_escapes(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    let digits = |chars: &mut std::iter::Peekable<std::str::Chars>, radix, max| {
+        let mut value = 0u32;
+        let mut count = 0;
+        while count < max {
+            match chars.peek().and_then(|c| c.to_digit(radix)) {
+                Some(digit) => {
+                    value = value * radix + digit;
+                    chars.next();
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        (value, count)
+    };
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some(escaped) => escaped,
+            None => {
+                result.push('\\');
+                break;
+            }
+        };
+        match escaped {
+            'a' => result.push('\x07'),
+            'b' => result.push('\x08'),
+            'e' | 'E' => result.push('\x1b'),
+            'f' => result.push('\x0c'),
+            'n' => result.push('\n'),
+            'r' => result.push('\r'),
+            't' => result.push('\t'),
+            'v' => result.push('\x0b'),
+            '\\' | '\'' | '"' | '?' => result.push(escaped),
+            '0'..='7' => {
+                let (rest, count) = digits(&mut chars, 8, 2);
+                let value = escaped.to_digit(8).unwrap() * 8u32.pow(count) + rest;
+                result.push(std::char::from_u32(value & 0xff).unwrap_or('?'));
+            }
+            'x' | 'u' | 'U' => {
+                let max = match escaped {
+                    'x' => 2,
+                    'u' => 4,
+                    _ => 8,
+                };
+                match digits(&mut chars, 16, max) {
+                    (_, 0) => {
+                        result.push('\\');
+                        result.push(escaped);
+                    }
+                    (value, _) => result.push(std::char::from_u32(value).unwrap_or('?')),
+                }
+            }
+            'c' => match chars.next() {
+                Some(control) => {
+                    let value = control.to_ascii_uppercase() as u32 ^ 0x40;
+                    result.push(std::char::from_u32(value).unwrap_or('?'));
+                }
+                None => result.push_str("\\c"),
+            },
+            other => {
+                result.push('\\');
+                result.push(other);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            expand_backslash_escapes("\\a\\b\\e\\f\\n\\r\\t\\v"),
+            "\x07\x08\x1b\x0c\n\r\t\x0b"
+        );
+        assert_eq!(expand_backslash_escapes("\\\\ \\' \\\" \\?"), "\\ ' \" ?");
+        assert_eq!(expand_backslash_escapes("\\101\\0\\x41\\u00e9"), "A\0Aé");
+        assert_eq!(expand_backslash_escapes("\\U0001F600"), "\u{1F600}");
+        assert_eq!(expand_backslash_escapes("\\ca\\c?"), "\x01\x7f");
+        assert_eq!(expand_backslash_escapes("\\q \\x \\"), "\\q \\x \\");
+    }
+}
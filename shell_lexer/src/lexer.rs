@@ -1,6 +1,7 @@
 /// With reference to https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html
 /// This module implements a lexer for the Shell Command Language.
-use crate::errors::LexErrorKind;
+use crate::errors::{LexError, LexErrorKind};
+use crate::escapes::expand_backslash_escapes;
 use crate::position::{Pos, Span};
 use crate::reader::{CharReader, Next, PositionedChar};
 use crate::tokenenum::MatchResult;
@@ -13,9 +14,12 @@ use std::io::Read;
 lazy_static! {
     static ref TILE_EXPAND_RE: Regex =
         Regex::new(r"^~([a-zA-Z_][a-zA-Z0-9_]+)?(/|$)").expect("failed to compile TILE_EXPAND_RE");
-    static ref PARAM_RE: Regex = Regex::new(r"^([0-9]+|[@*#?$!-]|[a-zA-Z_][a-zA-Z0-9_]+)")
+    static ref PARAM_RE: Regex = Regex::new(r"^([0-9]+|[@*#?$!-]|[a-zA-Z_][a-zA-Z0-9_]*)")
         .expect("failed to compile PARAM_RE");
-    static ref OPER_RE: Regex = Regex::new(r"^[%#:]?[%#-=?+]").expect("failed to compile OPER_RE");
+    static ref OPER_RE: Regex = Regex::new(r"^(\^\^|\^|,,|,|/[/#%]?|@[QEA]|[%#:]?[%#-=?+])")
+        .expect("failed to compile OPER_RE");
+    static ref ARITH_COMMAND_RE: Regex =
+        Regex::new(r"^\(\(").expect("failed to compile ARITH_COMMAND_RE");
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -32,19 +36,113 @@ impl WordComponentKind {
     }
 }
 
+/// Records the quoting context from which a word component
+/// was lexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quoting {
+    Unquoted,
+    Single,
+    Double,
+    /// `$'...'`, whose backslash escapes are decoded by the lexer
+    AnsiC,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct WordComponent {
     pub kind: WordComponentKind,
     pub span: Span,
     pub splittable: bool,
     pub remove_backslash: bool,
+    pub quoting: Quoting,
+}
+
+impl WordComponent {
+    /// Returns true if this component was produced from within
+    /// single or double quotes.
+    pub fn is_quoted(&self) -> bool {
+        self.quoting != Quoting::Unquoted
+    }
+}
+
+/// Returns a compact description of a word for debugging purposes;
+/// literal components are shown quoted, and expansions are shown in
+/// shell syntax
+pub fn describe_word(word: &[WordComponent]) -> String {
+    word.iter()
+        .map(|component| match &component.kind {
+            WordComponentKind::Literal(s) => format!("{:?}", s),
+            WordComponentKind::TildeExpand(name) => {
+                format!("~{}", name.as_deref().unwrap_or_default())
+            }
+            WordComponentKind::ParamExpand(expr) => describe_param(expr),
+            WordComponentKind::CommandSubstitution(tokens) => {
+                let tokens = tokens
+                    .iter()
+                    .map(describe_token)
+                    .filter(|token| !token.is_empty())
+                    .collect::<Vec<_>>();
+                format!("$({})", tokens.join(" "))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Describes a parameter expansion as `$NAME`, or in the `${...}`
+/// form when it has an operator, subscript or indirection
+fn describe_param(expr: &ParamExpr) -> String {
+    if expr.kind == ParamOper::Get && !expr.indirect && expr.subscript.is_none() {
+        return format!("${}", expr.name);
+    }
+
+    let mut result = String::from("${");
+    if expr.kind == ParamOper::StringLength {
+        result.push('#');
+    }
+    if expr.indirect {
+        result.push('!');
+    }
+    result.push_str(&expr.name);
+    if let Some(subscript) = &expr.subscript {
+        result.push_str(&format!("[{}]", describe_word(subscript)));
+    }
+    result.push_str(expr.kind.operator());
+    let word = expr
+        .word
+        .iter()
+        .map(|word| describe_word(word))
+        .collect::<Vec<_>>();
+    result.push_str(&word.join(" "));
+    result.push('}');
+    result
+}
+
+/// Describes a token from within a command substitution
+fn describe_token(token: &Token) -> String {
+    match token {
+        Token::Word(word) => describe_word(word),
+        Token::Operator(operator, _) => operator.to_string(),
+        Token::Newline(_) => ";".to_string(),
+        Token::IoNumber(number, _) => number.to_string(),
+        Token::Assignment(assignment) => describe_word(&Vec::<WordComponent>::from(assignment)),
+        Token::ArithmeticCommand(expr, _) => format!("(({}))", expr),
+        Token::EndCommandSubst(_) | Token::EndParamSubst(_) | Token::Eof(_) => String::new(),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Assignment {
     pub name: String,
+    /// `NAME[SUBSCRIPT]=value` assigns a single element of an array
+    pub subscript: Option<Vec<WordComponent>>,
+    /// True for `NAME+=value`, which appends to the existing value
+    pub append: bool,
     pub span: Span,
     pub value: Vec<WordComponent>,
+    /// `NAME=(WORD...)` assigns the words to the elements of an
+    /// array, in which case value is empty.  The words are filled
+    /// in by the parser.
+    pub array: Option<Vec<Vec<WordComponent>>>,
 }
 
 impl From<&Assignment> for Vec<WordComponent> {
@@ -54,12 +152,22 @@ impl From<&Assignment> for Vec<WordComponent> {
     /// find in the RHS.
     fn from(assignment: &Assignment) -> Vec<WordComponent> {
         let mut components = vec![];
-        components.push(WordComponent {
-            kind: WordComponentKind::Literal(format!("{}=", assignment.name)),
+        let oper = if assignment.append { "+=" } else { "=" };
+        let literal = |text: String| WordComponent {
+            kind: WordComponentKind::Literal(text),
             span: assignment.span,
             splittable: true,
             remove_backslash: false,
-        });
+            quoting: Quoting::Unquoted,
+        };
+        match &assignment.subscript {
+            Some(subscript) => {
+                components.push(literal(format!("{}[", assignment.name)));
+                components.extend(subscript.iter().cloned());
+                components.push(literal(format!("]{}", oper)));
+            }
+            None => components.push(literal(format!("{}{}", assignment.name, oper))),
+        }
 
         for comp in &assignment.value {
             match comp {
@@ -75,6 +183,7 @@ impl From<&Assignment> for Vec<WordComponent> {
                     span: *span,
                     splittable: true,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                 }),
                 comp => components.push(comp.clone()),
             }
@@ -94,6 +203,8 @@ pub enum Token {
     Assignment(Assignment),
     EndCommandSubst(Pos),
     EndParamSubst(Pos),
+    /// The expression text from an arithmetic command `((expression))`
+    ArithmeticCommand(String, Span),
 }
 
 impl Token {
@@ -110,6 +221,16 @@ impl Token {
         None
     }
 
+    /// If the token is a word, returns true if any portion of it
+    /// was quoted.  This is useful to determine whether a word such
+    /// as `"if"` or `'EOF'` should be treated literally.
+    pub fn is_quoted(&self) -> bool {
+        match self {
+            Token::Word(word) => word.iter().any(WordComponent::is_quoted),
+            _ => false,
+        }
+    }
+
     pub fn is_reserved_word(&self, reserved: ReservedWord) -> bool {
         if let Some(literal) = self.as_single_literal_word_string() {
             if let Some(word) = RESERVED_WORDS.lookup(literal) {
@@ -121,7 +242,7 @@ impl Token {
 
     pub fn is_any_reserved_word(&self) -> bool {
         if let Some(literal) = self.as_single_literal_word_string() {
-            if let Some(_) = RESERVED_WORDS.lookup(literal) {
+            if RESERVED_WORDS.lookup(literal).is_some() {
                 return true;
             }
         }
@@ -134,13 +255,37 @@ impl Token {
             Token::Word(list) => list[0].span,
             Token::Operator(_, span)
             | Token::Assignment(Assignment { span, .. })
-            | Token::IoNumber(_, span) => *span,
+            | Token::IoNumber(_, span)
+            | Token::ArithmeticCommand(_, span) => *span,
             Token::Newline(pos)
             | Token::EndCommandSubst(pos)
             | Token::EndParamSubst(pos)
             | Token::Eof(pos) => Span::new(*pos, *pos),
         }
     }
+
+    /// Returns a single line description of the token, its kind
+    /// and span, as printed by `wzsh --dump-tokens`
+    pub fn describe(&self) -> String {
+        let (kind, detail) = match self {
+            Token::Word(word) => ("Word", describe_word(word)),
+            Token::Operator(operator, _) => ("Operator", format!("{:?}", operator)),
+            Token::Eof(_) => ("Eof", String::new()),
+            Token::Newline(_) => ("Newline", String::new()),
+            Token::IoNumber(number, _) => ("IoNumber", number.to_string()),
+            Token::Assignment(assignment) => ("Assignment", format!("{:?}", assignment.name)),
+            Token::EndCommandSubst(_) => ("EndCommandSubst", String::new()),
+            Token::EndParamSubst(_) => ("EndParamSubst", String::new()),
+            Token::ArithmeticCommand(expr, _) => ("ArithmeticCommand", format!("{:?}", expr)),
+        };
+        let span = self.span();
+        format!(
+            "{} {}:{}-{}:{} {}",
+            kind, span.start.line, span.start.col, span.end.line, span.end.col, detail
+        )
+        .trim_end()
+        .to_owned()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -181,14 +326,156 @@ pub enum ParamOper {
     /// $NAME shall be expanded with the largest portion of the prefix
     /// matched by the pattern deleted.
     RemoveLargestPrefixPattern,
+    /// `${NAME^word}` or `${NAME^^word}`.  word is expanded and
+    /// treated as a pattern that matches a single character.  The
+    /// first (or, if all is true, every) character of $NAME that is
+    /// matched by the pattern is converted to upper case.  An empty
+    /// pattern matches every character.
+    Uppercase { all: bool },
+    /// `${NAME,word}` or `${NAME,,word}`.  As Uppercase, but
+    /// converts the matched characters to lower case.
+    Lowercase { all: bool },
+    /// `${NAME/pattern/string}`.  The longest match of pattern in
+    /// $NAME is replaced by string.  If string is empty, the
+    /// `/` that precedes it may be omitted and the match is deleted.
+    ReplaceFirst,
+    /// `${NAME//pattern/string}`.  As ReplaceFirst, but replaces
+    /// every match.
+    ReplaceAll,
+    /// `${NAME/#pattern/string}`.  As ReplaceFirst, but pattern must
+    /// match at the start of $NAME.
+    ReplacePrefix,
+    /// `${NAME/%pattern/string}`.  As ReplaceFirst, but pattern must
+    /// match at the end of $NAME.
+    ReplaceSuffix,
+    /// `${NAME@Q}`.  Expands to the value of $NAME quoted so that
+    /// it can be reused as input to the shell.
+    QuoteValue,
+    /// `${NAME@E}`.  Expands to the value of $NAME with backslash
+    /// escape sequences expanded, as in `$'...'` quoting.
+    ExpandEscapes,
+    /// `${NAME@A}`.  Expands to an assignment statement that would
+    /// recreate $NAME with its current value.
+    AssignmentForm,
+}
+
+impl ParamOper {
+    /// Returns the operator text that follows the name in `${NAME...}`
+    fn operator(&self) -> &'static str {
+        match self {
+            ParamOper::Get | ParamOper::StringLength => "",
+            ParamOper::GetDefault { allow_null: false } => ":-",
+            ParamOper::GetDefault { allow_null: true } => "-",
+            ParamOper::AssignDefault { allow_null: false } => ":=",
+            ParamOper::AssignDefault { allow_null: true } => "=",
+            ParamOper::CheckSet { allow_null: false } => ":?",
+            ParamOper::CheckSet { allow_null: true } => "?",
+            ParamOper::AlternativeValue { allow_null: false } => ":+",
+            ParamOper::AlternativeValue { allow_null: true } => "+",
+            ParamOper::RemoveSmallestSuffixPattern => "%",
+            ParamOper::RemoveLargestSuffixPattern => "%%",
+            ParamOper::RemoveSmallestPrefixPattern => "#",
+            ParamOper::RemoveLargestPrefixPattern => "##",
+            ParamOper::Uppercase { all: false } => "^",
+            ParamOper::Uppercase { all: true } => "^^",
+            ParamOper::Lowercase { all: false } => ",",
+            ParamOper::Lowercase { all: true } => ",,",
+            ParamOper::ReplaceFirst => "/",
+            ParamOper::ReplaceAll => "//",
+            ParamOper::ReplacePrefix => "/#",
+            ParamOper::ReplaceSuffix => "/%",
+            ParamOper::QuoteValue => "@Q",
+            ParamOper::ExpandEscapes => "@E",
+            ParamOper::AssignmentForm => "@A",
+        }
+    }
 }
 
 /// Represents a parameter expansion expression
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ParamExpr {
     pub kind: ParamOper,
+    /// `${!NAME}`: NAME holds the name of the parameter to expand
+    pub indirect: bool,
     pub name: String,
     pub word: Vec<Vec<WordComponent>>,
+    /// `${NAME[SUBSCRIPT]}` expands an element of an array, while
+    /// a subscript of `@` or `*` expands all of its elements
+    pub subscript: Option<Vec<WordComponent>>,
+}
+
+impl ParamExpr {
+    /// Returns true for `${NAME[@]}` and `${NAME[*]}`, which
+    /// expand to all of the elements of the array
+    pub fn is_all_elements(&self) -> bool {
+        match self.subscript.as_deref() {
+            Some(
+                [WordComponent {
+                    kind: WordComponentKind::Literal(literal),
+                    quoting: Quoting::Unquoted,
+                    ..
+                }],
+            ) => literal == "@" || literal == "*",
+            _ => false,
+        }
+    }
+
+    /// Split the word of a `${NAME/pattern/string}` expansion at its
+    /// first unquoted, unescaped `/`, returning the words of the
+    /// pattern and of the replacement string.
+    pub fn split_replacement(&self) -> (Vec<Vec<WordComponent>>, Vec<Vec<WordComponent>>) {
+        let mut pattern = vec![];
+        let mut words = self.word.iter();
+        while let Some(word) = words.next() {
+            let mut before = vec![];
+            let mut components = word.iter();
+            while let Some(component) = components.next() {
+                let literal = match (&component.kind, component.quoting) {
+                    (WordComponentKind::Literal(literal), Quoting::Unquoted) => literal,
+                    _ => {
+                        before.push(component.clone());
+                        continue;
+                    }
+                };
+                let mut escaped = false;
+                let slash = literal.char_indices().find(|&(_, c)| {
+                    let found = c == '/' && !escaped;
+                    escaped = !escaped && c == '\\' && component.remove_backslash;
+                    found
+                });
+                let idx = match slash {
+                    Some((idx, _)) => idx,
+                    None => {
+                        before.push(component.clone());
+                        continue;
+                    }
+                };
+
+                let mut head = component.clone();
+                head.kind = WordComponentKind::Literal(literal[..idx].to_owned());
+                if idx > 0 {
+                    before.push(head);
+                }
+                pattern.push(before);
+
+                let mut after = vec![];
+                if idx + 1 < literal.len() {
+                    let mut tail = component.clone();
+                    tail.kind = WordComponentKind::Literal(literal[idx + 1..].to_owned());
+                    after.push(tail);
+                }
+                after.extend(components.cloned());
+                let mut replacement = vec![];
+                if !after.is_empty() {
+                    replacement.push(after);
+                }
+                replacement.extend(words.cloned());
+                return (pattern, replacement);
+            }
+            pattern.push(before);
+        }
+        (pattern, vec![])
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -208,14 +495,19 @@ struct LexState {
 }
 
 pub struct Lexer<R: Read> {
+    /// The name of the input, used to label errors
+    source: String,
     reader: CharReader<R>,
     stack: Vec<LexState>,
     last_token: Option<Token>,
 }
 
 impl<R: Read> Lexer<R> {
-    pub fn new(stream: R) -> Self {
+    /// Create a lexer that reads from stream.  `source` names the
+    /// input (eg: a filename) and is recorded in any LexError.
+    pub fn new(source: &str, stream: R) -> Self {
         Self {
+            source: source.to_owned(),
             reader: CharReader::new(stream),
             last_token: None,
             stack: vec![LexState {
@@ -227,12 +519,29 @@ impl<R: Read> Lexer<R> {
     }
 
     pub fn next_token(&mut self) -> Fallible<Token> {
-        match self.state().state {
+        let result = match self.state().state {
             State::CommandSubstitution(_)
             | State::Top
             | State::AssignmentWord
             | State::ParamExprWord => self.top(),
             State::DoubleQuotes => bail!("invalid state for next_token {:?}", self.state().state),
+        };
+        result.map_err(|err| match err.downcast::<LexError>() {
+            Ok(err) => err.in_source(&self.source).into(),
+            Err(err) => err,
+        })
+    }
+
+    /// Lex the remainder of the input, returning the description
+    /// of each token up to and including the Eof token
+    pub fn dump_tokens(&mut self) -> Fallible<Vec<String>> {
+        let mut tokens = vec![];
+        loop {
+            let token = self.next_token()?;
+            tokens.push(token.describe());
+            if let Token::Eof(_) = token {
+                return Ok(tokens);
+            }
         }
     }
 
@@ -259,7 +568,23 @@ impl<R: Read> Lexer<R> {
                 return Ok(token);
             }
 
-            if let MatchResult::Match(..) = self.reader.matches_literal(&OPERATORS)? {
+            if self.state().state == State::Top && self.state().current_word.is_none() {
+                let arith_start = self
+                    .reader
+                    .matches_regex(&ARITH_COMMAND_RE)?
+                    .map(|(_, pos)| pos);
+                if let Some(start) = arith_start {
+                    self.reader.fixup_matched_length(2);
+                    return self.arithmetic_command(start);
+                }
+            }
+
+            if let MatchResult::Match(op, _) = self.reader.matches_literal(&OPERATORS)? {
+                if op == Operator::LeftParen && self.word_ends_with_pattern_operator() {
+                    self.extended_pattern()?;
+                    continue;
+                }
+
                 if let Some(token) = self.delimit_current_word() {
                     return Ok(token);
                 }
@@ -291,11 +616,17 @@ impl<R: Read> Lexer<R> {
                 return Ok(Token::IoNumber(num, span));
             }
 
-            if self.reader.matches_assignment_word()? {
-                if let Some(token) = self.delimit_current_word() {
-                    return Ok(token);
-                }
-                let (name, span) = self.reader.next_assignment_word()?.unwrap();
+            if self.at_command_word_start() && self.reader.matches_assignment_word()? {
+                let (name, append, span) = self.reader.next_assignment_word()?.unwrap();
+                let (name, subscript) = match name.find('[') {
+                    Some(idx) => {
+                        let mut start = span.start;
+                        start.col += idx + 1;
+                        let subscript = lex_subscript(&name[idx + 1..name.len() - 1], start)?;
+                        (name[..idx].to_owned(), Some(subscript))
+                    }
+                    None => (name, None),
+                };
                 self.push_state(State::AssignmentWord);
                 let value = match self.top()? {
                     Token::Word(value) => value,
@@ -305,10 +636,17 @@ impl<R: Read> Lexer<R> {
                     }
                 };
                 self.pop_state();
-                return Ok(Token::Assignment(Assignment { name, span, value }));
+                return Ok(Token::Assignment(Assignment {
+                    name,
+                    subscript,
+                    append,
+                    span,
+                    value,
+                    array: None,
+                }));
             }
 
-            match self.reader.next_char() {
+            match self.next_char()? {
                 Next::Eof(pos) => {
                     if let Some(token) = self.delimit_current_word() {
                         return Ok(token);
@@ -334,6 +672,7 @@ impl<R: Read> Lexer<R> {
                                 span: Span::new(c.pos, c.pos),
                                 splittable: false,
                                 remove_backslash: false,
+                                quoting: Quoting::Unquoted,
                             }]));
                         }
                     } else if c.c == '\n' {
@@ -342,6 +681,8 @@ impl<R: Read> Lexer<R> {
                             return Ok(token);
                         }
                         return Ok(Token::Newline(c.pos));
+                    } else if c.c == '#' && self.at_command_word_start() {
+                        self.comment()?;
                     } else if c.c == '\'' {
                         self.single_quotes(c.pos)?;
                     } else if c.c == '"' {
@@ -411,14 +752,64 @@ impl<R: Read> Lexer<R> {
         }
     }
 
-    fn next_char_or_err(&mut self, err: LexErrorKind) -> Fallible<PositionedChar> {
+    /// A `#` introduces a comment, and `NAME=` an assignment, only
+    /// at the start of a word in command position; `a#b`, `x=#`,
+    /// `--opt=value` and `${x:-#}` are all literal
+    fn at_command_word_start(&mut self) -> bool {
+        let state = self.state();
+        state.current_word.is_none()
+            && matches!(state.state, State::Top | State::CommandSubstitution(_))
+    }
+
+    /// Discard the remainder of the line.  The newline itself is left
+    /// in the input, as it still terminates the command before it.
+    fn comment(&mut self) -> Fallible<()> {
+        loop {
+            match self.next_char()? {
+                Next::Char(c) => {
+                    if c.c == '\n' {
+                        self.reader.unget(c);
+                        return Ok(());
+                    }
+                }
+                Next::Eof(_) => return Ok(()),
+                Next::Error(err, pos) => return Err(err.context(pos).into()),
+            }
+        }
+    }
+
+    /// Read the next character.  A shell word cannot hold a NUL byte,
+    /// so encountering one means that the input is binary rather than
+    /// text, and it is reported as an error rather than lexed.
+    fn next_char(&mut self) -> Fallible<Next> {
         match self.reader.next_char() {
+            Next::Char(c) if c.c == '\0' => Err(LexErrorKind::NulByte.at(c.pos.into()).into()),
+            next => Ok(next),
+        }
+    }
+
+    fn next_char_or_err(&mut self, err: LexErrorKind) -> Fallible<PositionedChar> {
+        match self.next_char()? {
             Next::Char(b) => Ok(b),
             Next::Eof(pos) => Err(err.at(pos.into()).into()),
             Next::Error(e, pos) => Err(e.context(pos).into()),
         }
     }
 
+    /// As `next_char_or_err`, but an error at the end of the input
+    /// records `opened_at` as the start of the unterminated construct
+    fn next_char_or_unterminated(
+        &mut self,
+        err: LexErrorKind,
+        opened_at: Pos,
+    ) -> Fallible<PositionedChar> {
+        match self.next_char()? {
+            Next::Char(b) => Ok(b),
+            Next::Eof(pos) => Err(err.at(pos.into()).opened_at(opened_at).into()),
+            Next::Error(e, pos) => Err(e.context(pos).into()),
+        }
+    }
+
     fn state(&mut self) -> &mut LexState {
         self.stack.last_mut().unwrap()
     }
@@ -453,7 +844,8 @@ impl<R: Read> Lexer<R> {
         let mut accumulator = String::new();
         let mut end;
         loop {
-            let c = self.next_char_or_err(LexErrorKind::EofDuringSingleQuotedString)?;
+            let c =
+                self.next_char_or_unterminated(LexErrorKind::EofDuringSingleQuotedString, start)?;
             end = c.pos;
             if c.c == '\'' {
                 break;
@@ -466,16 +858,56 @@ impl<R: Read> Lexer<R> {
             span: Span::new(start, end),
             splittable: false,
             remove_backslash: false,
+            quoting: Quoting::Single,
         };
 
         self.add_to_word(word);
         Ok(())
     }
 
+    /// Lex a `$'...'` string, whose backslash escapes are decoded
+    /// here so that the result can be treated as a single quoted
+    /// literal.  A backslash prevents a quote from ending the string.
+    fn ansi_c_quotes(&mut self, start: Pos) -> Fallible<()> {
+        let mut text = String::new();
+        let end;
+        loop {
+            let c =
+                self.next_char_or_unterminated(LexErrorKind::EofDuringAnsiCQuotedString, start)?;
+            if c.c == '\'' {
+                end = c.pos;
+                break;
+            }
+            text.push(c.c);
+            if c.c == '\\' {
+                let quoted = self
+                    .next_char_or_unterminated(LexErrorKind::EofDuringAnsiCQuotedString, start)?;
+                text.push(quoted.c);
+            }
+        }
+
+        self.add_to_word(WordComponent {
+            kind: WordComponentKind::Literal(expand_backslash_escapes(&text)),
+            span: Span::new(start, end),
+            splittable: false,
+            remove_backslash: false,
+            quoting: Quoting::AnsiC,
+        });
+        Ok(())
+    }
+
     fn dollar(&mut self, start: Pos) -> Fallible<()> {
-        let c = self.next_char_or_err(LexErrorKind::EofDuringParameterExpansion)?;
-        if c.c == '(' {
-            let maybe_paren = self.next_char_or_err(LexErrorKind::EofDuringParameterExpansion)?;
+        let c = self.next_char_or_unterminated(LexErrorKind::EofDuringParameterExpansion, start)?;
+        if c.c == '\'' && self.state().state != State::DoubleQuotes {
+            self.ansi_c_quotes(start)
+        } else if c.c == '"' && self.state().state != State::DoubleQuotes {
+            // `$"..."` would be translated according to the locale,
+            // but without a message catalog it is an ordinary double
+            // quoted string
+            self.double_quotes(start)
+        } else if c.c == '(' {
+            let maybe_paren =
+                self.next_char_or_unterminated(LexErrorKind::EofDuringParameterExpansion, start)?;
             if maybe_paren.c == '(' {
                 self.arithmetic(start)
             } else {
@@ -509,6 +941,7 @@ impl<R: Read> Lexer<R> {
                 Token::Eof(pos) => {
                     return Err(LexErrorKind::EofDuringCommandSubstitution
                         .at(pos.into())
+                        .opened_at(start)
                         .into());
                 }
                 token => tokens.push(token),
@@ -520,12 +953,97 @@ impl<R: Read> Lexer<R> {
             span: Span::new(start, end),
             splittable: true,
             remove_backslash: true,
+            quoting: Quoting::Unquoted,
         };
 
         self.add_to_word(word);
         Ok(())
     }
 
+    /// Lex the expression portion of an arithmetic command.
+    /// The opening `((` has already been consumed.
+    fn arithmetic_command(&mut self, start: Pos) -> Fallible<Token> {
+        let mut expression = String::new();
+        let mut depth = 0;
+        loop {
+            let c =
+                self.next_char_or_unterminated(LexErrorKind::EofDuringArithmeticCommand, start)?;
+            match c.c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' => {
+                    let closer = self.next_char_or_unterminated(
+                        LexErrorKind::EofDuringArithmeticCommand,
+                        start,
+                    )?;
+                    if closer.c != ')' {
+                        return Err(LexErrorKind::UnmatchedParenInArithmeticCommand
+                            .at(c.pos.into())
+                            .into());
+                    }
+                    return Ok(Token::ArithmeticCommand(
+                        expression,
+                        Span::new(start, closer.pos),
+                    ));
+                }
+                _ => {}
+            }
+            expression.push(c.c);
+        }
+    }
+
+    /// Returns true if the current word ends with an unquoted `?`, `*`,
+    /// `+`, `@` or `!`, in which case a `(` that immediately follows
+    /// starts an extended pattern rather than being an operator.
+    fn word_ends_with_pattern_operator(&mut self) -> bool {
+        let current = match self.state().current_word.as_ref() {
+            Some(current) => current,
+            None => return false,
+        };
+        match current.last() {
+            Some(WordComponent {
+                kind: WordComponentKind::Literal(s),
+                quoting: Quoting::Unquoted,
+                remove_backslash: true,
+                ..
+            }) => {
+                let mut chars = s.chars().rev();
+                match (chars.next(), chars.next()) {
+                    (_, Some('\\')) => false,
+                    (Some(c), _) => "?*+@!".contains(c),
+                    (None, _) => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Add the parenthesized portion of an extended pattern, such as
+    /// `@(foo|bar)`, to the current word.  The pattern is kept intact,
+    /// including any `|` and whitespace, so that it can be matched once
+    /// the word has been expanded.
+    fn extended_pattern(&mut self) -> Fallible<()> {
+        let mut depth = 0;
+        loop {
+            let c = self.next_char_or_err(LexErrorKind::EofDuringExtendedPattern)?;
+            match c.c {
+                '\\' => {
+                    self.add_char_to_word(c);
+                    let quoted = self.next_char_or_err(LexErrorKind::EofDuringExtendedPattern)?;
+                    self.add_char_to_word(quoted);
+                    continue;
+                }
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            self.add_char_to_word(c);
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
     fn arithmetic(&mut self, _start: Pos) -> Fallible<()> {
         bail!("arithmetic not done");
     }
@@ -538,10 +1056,21 @@ impl<R: Read> Lexer<R> {
             true
         };
 
+        let mut indirect = false;
         let mut oper = if curlies {
-            let hash = self.next_char_or_err(LexErrorKind::EofDuringParameterExpansion)?;
+            let hash =
+                self.next_char_or_unterminated(LexErrorKind::EofDuringParameterExpansion, start)?;
             if hash.c == '#' {
                 Some(ParamOper::StringLength)
+            } else if hash.c == '!' {
+                // `${!name}` is an indirect reference, but `${!}` is
+                // the special parameter holding the last background pid
+                if self.reader.matches_regex(&PARAM_RE)?.is_some() {
+                    indirect = true;
+                } else {
+                    self.reader.unget(hash);
+                }
+                None
             } else {
                 self.reader.unget(hash);
                 None
@@ -553,7 +1082,9 @@ impl<R: Read> Lexer<R> {
         let (caps, name_pos) = match self.reader.matches_regex(&PARAM_RE)? {
             Some(tuple) => tuple,
             None => {
-                self.reader.unget(c);
+                if curlies {
+                    self.reader.unget(c);
+                }
                 self.add_char_to_word(PositionedChar { c: '$', pos: start });
                 return Ok(());
             }
@@ -564,6 +1095,8 @@ impl<R: Read> Lexer<R> {
         let mut end = name_pos;
         end.col += name.len() - 1;
 
+        let subscript = if curlies { self.subscript()? } else { None };
+
         if curlies && oper.is_none() {
             if let Some((caps, _oper_pos)) = self.reader.matches_regex(&OPER_RE)? {
                 let oper_len = caps.get(0).unwrap().as_str().len();
@@ -580,6 +1113,17 @@ impl<R: Read> Lexer<R> {
                     "%%" => ParamOper::RemoveLargestSuffixPattern,
                     "#" => ParamOper::RemoveSmallestPrefixPattern,
                     "##" => ParamOper::RemoveLargestPrefixPattern,
+                    "^" => ParamOper::Uppercase { all: false },
+                    "^^" => ParamOper::Uppercase { all: true },
+                    "," => ParamOper::Lowercase { all: false },
+                    ",," => ParamOper::Lowercase { all: true },
+                    "/" => ParamOper::ReplaceFirst,
+                    "//" => ParamOper::ReplaceAll,
+                    "/#" => ParamOper::ReplacePrefix,
+                    "/%" => ParamOper::ReplaceSuffix,
+                    "@Q" => ParamOper::QuoteValue,
+                    "@E" => ParamOper::ExpandEscapes,
+                    "@A" => ParamOper::AssignmentForm,
                     wat => bail!("unhandled operator type {}", wat),
                 });
                 self.reader.fixup_matched_length(oper_len);
@@ -600,6 +1144,7 @@ impl<R: Read> Lexer<R> {
                     Token::Eof(pos) => {
                         return Err(LexErrorKind::EofDuringParameterExpansion
                             .at(pos.into())
+                            .opened_at(start)
                             .into());
                     }
                     Token::Word(word) => tokens.push(word),
@@ -616,24 +1161,60 @@ impl<R: Read> Lexer<R> {
         self.add_to_word(WordComponent {
             kind: WordComponentKind::ParamExpand(ParamExpr {
                 kind: oper,
+                indirect,
                 name,
                 word,
+                subscript,
             }),
             span: Span::new(start, end),
             splittable: true,
             remove_backslash: false,
+            quoting: Quoting::Unquoted,
         });
 
         Ok(())
     }
 
+    /// Consume the `[SUBSCRIPT]` that may follow the name in a
+    /// `${NAME[SUBSCRIPT]}` expansion.  Brackets may nest within
+    /// the subscript, as in `${a[b[0]]}`.
+    fn subscript(&mut self) -> Fallible<Option<Vec<WordComponent>>> {
+        let open = match self.next_char()? {
+            Next::Char(c) if c.c == '[' => c,
+            Next::Char(c) => {
+                self.reader.unget(c);
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+
+        let mut text = String::new();
+        let mut depth = 0;
+        loop {
+            let c = self
+                .next_char_or_unterminated(LexErrorKind::EofDuringParameterExpansion, open.pos)?;
+            match c.c {
+                '[' => depth += 1,
+                ']' if depth == 0 => break,
+                ']' => depth -= 1,
+                _ => {}
+            }
+            text.push(c.c);
+        }
+
+        let mut start = open.pos;
+        start.col += 1;
+        Ok(Some(lex_subscript(&text, start)?))
+    }
+
     fn double_quotes(&mut self, start: Pos) -> Fallible<()> {
         self.push_state(State::DoubleQuotes);
 
         let mut backslash = false;
         let end;
         loop {
-            let c = self.next_char_or_err(LexErrorKind::EofDuringDoubleQuotedString)?;
+            let c =
+                self.next_char_or_unterminated(LexErrorKind::EofDuringDoubleQuotedString, start)?;
             if c.c == '"' && !backslash {
                 end = c.pos;
                 break;
@@ -658,6 +1239,7 @@ impl<R: Read> Lexer<R> {
             // Quoted strings are not subject to field splitting
             for mut component in word {
                 component.splittable = false;
+                component.quoting = Quoting::Double;
                 self.add_to_word(component);
             }
         }
@@ -685,6 +1267,7 @@ impl<R: Read> Lexer<R> {
                 span,
                 splittable: true,
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
             }) => {
                 s.push(c.c);
                 span.end = c.pos;
@@ -695,17 +1278,59 @@ impl<R: Read> Lexer<R> {
                     span: Span::new(c.pos, c.pos),
                     splittable: true,
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                 });
             }
         }
     }
 }
 
+/// Lex the text of an array subscript, which starts at `start`, into
+/// a word.  The subscript is subject to parameter expansion and command
+/// substitution, but not to field splitting, so any whitespace between
+/// its words is retained as a single space.
+fn lex_subscript(text: &str, start: Pos) -> Fallible<Vec<WordComponent>> {
+    let relocate = |pos: &mut Pos| {
+        if pos.line == 0 {
+            pos.col += start.col;
+        }
+        pos.line += start.line;
+    };
+
+    let mut lexer = Lexer::new("subscript", text.as_bytes());
+    let mut subscript: Vec<WordComponent> = vec![];
+    loop {
+        match lexer.next_token()? {
+            Token::Eof(_) => break,
+            Token::Word(word) => {
+                if let Some(last) = subscript.last() {
+                    subscript.push(WordComponent {
+                        kind: WordComponentKind::literal(" "),
+                        span: last.span,
+                        splittable: false,
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted,
+                    });
+                }
+                for mut component in word {
+                    relocate(&mut component.span.start);
+                    relocate(&mut component.span.end);
+                    component.splittable = false;
+                    subscript.push(component);
+                }
+            }
+            token => bail!("invalid token {} in array subscript", token.describe()),
+        }
+    }
+    Ok(subscript)
+}
+
 fn apply_single_tilde_expansion(word_idx: usize, words: &mut Vec<WordComponent>) {
     if let WordComponent {
         kind: WordComponentKind::Literal(first_word),
         splittable: true,
         remove_backslash: true,
+        quoting: Quoting::Unquoted,
         span,
     } = &words[word_idx]
     {
@@ -720,7 +1345,7 @@ fn apply_single_tilde_expansion(word_idx: usize, words: &mut Vec<WordComponent>)
             };
             let remainder = first_word[matched_len..].to_string();
             let name = caps.get(1).map(|cap| cap.as_str().to_owned());
-            let span = span.clone();
+            let span = *span;
 
             words.remove(word_idx);
 
@@ -734,6 +1359,7 @@ fn apply_single_tilde_expansion(word_idx: usize, words: &mut Vec<WordComponent>)
                     kind: WordComponentKind::TildeExpand(name),
                     splittable: false,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                     span: Span::new(start, end),
                 },
             );
@@ -750,6 +1376,7 @@ fn apply_single_tilde_expansion(word_idx: usize, words: &mut Vec<WordComponent>)
                         kind: WordComponentKind::Literal(remainder),
                         splittable: true,
                         remove_backslash: true,
+                        quoting: Quoting::Unquoted,
                         span: Span::new(rem_start, rem_end),
                     },
                 );
@@ -763,6 +1390,7 @@ fn split_first_word_by_unquoted_colons(words: &mut Vec<WordComponent>) -> usize
         kind: WordComponentKind::Literal(first_word),
         splittable: true,
         remove_backslash: true,
+        quoting: Quoting::Unquoted,
         span,
     } = &words[0]
     {
@@ -785,6 +1413,7 @@ fn split_first_word_by_unquoted_colons(words: &mut Vec<WordComponent>) -> usize
                         kind: WordComponentKind::literal(":"),
                         splittable: false,
                         remove_backslash: false,
+                        quoting: Quoting::Unquoted,
                         span: Span::new(start, start),
                     },
                 );
@@ -792,13 +1421,14 @@ fn split_first_word_by_unquoted_colons(words: &mut Vec<WordComponent>) -> usize
                 start.col += 1;
             }
             let mut end = start;
-            end.col += element.len() - 1;
+            end.col += element.len().saturating_sub(1);
             words.insert(
                 result,
                 WordComponent {
                     kind: WordComponentKind::literal(element),
                     splittable: true,
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                     span: Span::new(start, end),
                 },
             );
@@ -818,12 +1448,12 @@ mod test {
     use pretty_assertions::assert_eq;
 
     fn tokens(s: &str) -> Vec<Token> {
-        let mut lexer = Lexer::new(s.as_bytes());
+        let mut lexer = Lexer::new("test", s.as_bytes());
         let mut tokens = vec![];
         loop {
             match lexer.next_token().unwrap() {
                 Token::Eof(_) => break,
-                token @ _ => tokens.push(token),
+                token => tokens.push(token),
             }
         }
         eprintln!("tokens: {:#?}", tokens);
@@ -831,7 +1461,7 @@ mod test {
     }
 
     fn token_err(s: &str) -> String {
-        let mut lexer = Lexer::new(s.as_bytes());
+        let mut lexer = Lexer::new("test", s.as_bytes());
         loop {
             match lexer.next_token() {
                 Err(e) => return format!("{}", e),
@@ -880,7 +1510,8 @@ mod test {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 0, 4),
                     splittable: false,
-                    remove_backslash: false
+                    remove_backslash: false,
+                    quoting: Quoting::Single
                 },]),
                 Token::Newline(Pos::new(0, 5)),
                 Token::Operator(Operator::AndIf, Span::new_to(1, 0, 1))
@@ -888,6 +1519,66 @@ mod test {
         );
     }
 
+    #[test]
+    fn ansi_c_quotes() {
+        let literal = |text: &str, span| {
+            Token::Word(vec![WordComponent {
+                kind: WordComponentKind::literal(text),
+                span,
+                splittable: false,
+                remove_backslash: false,
+                quoting: Quoting::AnsiC,
+            }])
+        };
+        assert_eq!(
+            tokens("$'a\\tb'"),
+            vec![literal("a\tb", Span::new_to(0, 0, 6))]
+        );
+        assert_eq!(
+            tokens("$'\\x41'"),
+            vec![literal("A", Span::new_to(0, 0, 6))]
+        );
+        assert_eq!(
+            tokens("$'it\\'s'"),
+            vec![literal("it's", Span::new_to(0, 0, 7))]
+        );
+
+        // Within double quotes, `$'` has no special meaning
+        match &tokens("\"$'x'\"")[..] {
+            [Token::Word(word)] => assert_eq!(describe_word(word), "\"$'x'\""),
+            tokens => panic!("expected a single word, got {:?}", tokens),
+        }
+
+        let mut lexer = Lexer::new("test", "echo $'a\\'".as_bytes());
+        let err = lexer.dump_tokens().unwrap_err();
+        let err = err.downcast::<LexError>().unwrap();
+        assert!(matches!(err.kind, LexErrorKind::EofDuringAnsiCQuotedString));
+        assert_eq!(err.opened_at, Some(Pos::new(0, 5)));
+    }
+
+    #[test]
+    fn locale_quotes() {
+        match &tokens("$\"a $b\"")[..] {
+            [Token::Word(word)] => {
+                assert_eq!(word[0].span.start, Pos::new(0, 0));
+                assert_eq!(word.last().unwrap().span.end, Pos::new(0, 6));
+                assert!(word
+                    .iter()
+                    .all(|component| component.quoting == Quoting::Double));
+            }
+            tokens => panic!("expected a single word, got {:?}", tokens),
+        }
+
+        let mut lexer = Lexer::new("test", "echo $\"a".as_bytes());
+        let err = lexer.dump_tokens().unwrap_err();
+        let err = err.downcast::<LexError>().unwrap();
+        assert!(matches!(
+            err.kind,
+            LexErrorKind::EofDuringDoubleQuotedString
+        ));
+        assert_eq!(err.opened_at, Some(Pos::new(0, 5)));
+    }
+
     #[test]
     fn double_quotes() {
         assert_eq!(
@@ -897,18 +1588,63 @@ mod test {
                     kind: WordComponentKind::literal("fo\\o"),
                     span: Span::new_to(0, 0, 5),
                     splittable: false,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Double
                 },
                 WordComponent {
                     kind: WordComponentKind::literal("bar"),
                     span: Span::new_to(0, 6, 8),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 },
             ]),]
         );
     }
 
+    #[test]
+    fn quoting() {
+        assert!(!tokens("foo")[0].is_quoted());
+        assert!(tokens("'foo'")[0].is_quoted());
+        assert!(tokens("\"foo\"")[0].is_quoted());
+        assert!(tokens("foo\"bar\"")[0].is_quoted());
+        assert!(!tokens("~/foo")[0].is_quoted());
+    }
+
+    #[test]
+    fn extended_pattern() {
+        assert_eq!(
+            tokens("ls !(*.o|a b)x"),
+            vec![
+                Token::Word(vec![WordComponent {
+                    kind: WordComponentKind::literal("ls"),
+                    span: Span::new_to(0, 0, 1),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }]),
+                Token::Word(vec![WordComponent {
+                    kind: WordComponentKind::literal("!(*.o|a b)x"),
+                    span: Span::new_to(0, 3, 13),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }]),
+            ]
+        );
+        assert_eq!(
+            tokens("@(a|+(b))")[0].as_single_literal_word_string(),
+            Some("@(a|+(b))")
+        );
+        // A quoted or escaped operator does not start a pattern
+        assert_eq!(tokens("\\@(a)").len(), 4);
+        assert_eq!(tokens("'@'(a)").len(), 4);
+        assert_eq!(
+            token_err("@(a"),
+            "EOF while lexing extended pattern at line 0 column 3"
+        );
+    }
+
     #[test]
     fn backslash() {
         assert_eq!(
@@ -917,7 +1653,8 @@ mod test {
                 kind: WordComponentKind::literal("fo\\o"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             },]),]
         );
     }
@@ -933,7 +1670,8 @@ mod test {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 2, 4),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 },]),
             ]
         );
@@ -946,7 +1684,22 @@ mod test {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 3, 5),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                },]),
+            ]
+        );
+        assert_eq!(
+            tokens("1<<<foo"),
+            vec![
+                Token::IoNumber(1, Span::new_to(0, 0, 1)),
+                Token::Operator(Operator::TripleLess, Span::new_to(0, 1, 3)),
+                Token::Word(vec![WordComponent {
+                    kind: WordComponentKind::literal("foo"),
+                    span: Span::new_to(0, 4, 6),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 },]),
             ]
         );
@@ -959,7 +1712,8 @@ mod test {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 2, 4),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 },]),
             ]
         );
@@ -971,12 +1725,16 @@ mod test {
             tokens("FOO=bar"),
             vec![Token::Assignment(Assignment {
                 name: "FOO".to_owned(),
+                subscript: None,
+                array: None,
+                append: false,
                 span: Span::new_to(0, 0, 4),
                 value: vec![WordComponent {
                     kind: WordComponentKind::literal("bar"),
                     span: Span::new_to(0, 4, 6),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }]
             })]
         );
@@ -985,6 +1743,9 @@ mod test {
             tokens("FOO="),
             vec![Token::Assignment(Assignment {
                 name: "FOO".to_owned(),
+                subscript: None,
+                array: None,
+                append: false,
                 span: Span::new_to(0, 0, 4),
                 value: vec![],
             })]
@@ -995,6 +1756,9 @@ mod test {
             vec![
                 Token::Assignment(Assignment {
                     name: "FOO".to_owned(),
+                    subscript: None,
+                    array: None,
+                    append: false,
                     span: Span::new_to(0, 0, 4),
                     value: vec![],
                 }),
@@ -1007,6 +1771,9 @@ mod test {
             vec![
                 Token::Assignment(Assignment {
                     name: "FOO".to_owned(),
+                    subscript: None,
+                    array: None,
+                    append: false,
                     span: Span::new_to(0, 0, 4),
                     value: vec![],
                 }),
@@ -1014,16 +1781,49 @@ mod test {
             ]
         );
 
+        assert_eq!(
+            tokens("FOO+=bar"),
+            vec![Token::Assignment(Assignment {
+                name: "FOO".to_owned(),
+                subscript: None,
+                array: None,
+                append: true,
+                span: Span::new_to(0, 0, 5),
+                value: vec![WordComponent {
+                    kind: WordComponentKind::literal("bar"),
+                    span: Span::new_to(0, 5, 7),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }]
+            })]
+        );
+
+        // Only a whole word can be an assignment
+        let mut lexer = Lexer::new("test", "ls --color=auto".as_bytes());
+        assert_eq!(
+            lexer.dump_tokens().unwrap(),
+            vec![
+                "Word 0:0-0:1 \"ls\"",
+                "Word 0:3-0:14 \"--color=auto\"",
+                "Eof 0:15-0:15",
+            ]
+        );
+
         assert_eq!(
             tokens("FOO=~bar"),
             vec![Token::Assignment(Assignment {
                 name: "FOO".to_owned(),
+                subscript: None,
+                array: None,
+                append: false,
                 span: Span::new_to(0, 0, 4),
                 value: vec![WordComponent {
                     kind: WordComponentKind::TildeExpand(Some("bar".to_owned())),
                     span: Span::new_to(0, 4, 7),
                     splittable: false,
-                    remove_backslash: false
+                    remove_backslash: false,
+                    quoting: Quoting::Unquoted
                 }]
             })]
         );
@@ -1033,43 +1833,52 @@ mod test {
             tilde_tokens,
             vec![Token::Assignment(Assignment {
                 name: "FOO".to_owned(),
+                subscript: None,
+                array: None,
+                append: false,
                 span: Span::new_to(0, 0, 4),
                 value: vec![
                     WordComponent {
                         kind: WordComponentKind::TildeExpand(Some("bar".to_owned())),
                         span: Span::new_to(0, 4, 7),
                         splittable: false,
-                        remove_backslash: false
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted
                     },
                     WordComponent {
                         kind: WordComponentKind::literal(":"),
                         span: Span::new_to(0, 8, 8),
                         splittable: false,
                         remove_backslash: false,
+                        quoting: Quoting::Unquoted,
                     },
                     WordComponent {
                         kind: WordComponentKind::literal("/somewhere"),
                         span: Span::new_to(0, 9, 18),
                         splittable: true,
                         remove_backslash: true,
+                        quoting: Quoting::Unquoted,
                     },
                     WordComponent {
                         kind: WordComponentKind::literal(":"),
                         span: Span::new_to(0, 19, 19),
                         splittable: false,
                         remove_backslash: false,
+                        quoting: Quoting::Unquoted,
                     },
                     WordComponent {
                         kind: WordComponentKind::TildeExpand(Some("foo".to_owned())),
                         span: Span::new_to(0, 20, 23),
                         splittable: false,
-                        remove_backslash: false
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted
                     },
                     WordComponent {
                         kind: WordComponentKind::literal("/baz"),
                         span: Span::new_to(0, 24, 27),
                         splittable: true,
                         remove_backslash: true,
+                        quoting: Quoting::Unquoted,
                     },
                 ]
             })]
@@ -1084,43 +1893,50 @@ mod test {
                         kind: WordComponentKind::literal("FOO="),
                         span: Span::new_to(0, 0, 4),
                         splittable: true,
-                        remove_backslash: false
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted
                     },
                     WordComponent {
                         kind: WordComponentKind::literal("~bar"),
                         span: Span::new_to(0, 4, 7),
                         splittable: true,
-                        remove_backslash: false
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted
                     },
                     WordComponent {
                         kind: WordComponentKind::literal(":"),
                         span: Span::new_to(0, 8, 8),
                         splittable: false,
                         remove_backslash: false,
+                        quoting: Quoting::Unquoted,
                     },
                     WordComponent {
                         kind: WordComponentKind::literal("/somewhere"),
                         span: Span::new_to(0, 9, 18),
                         splittable: true,
                         remove_backslash: true,
+                        quoting: Quoting::Unquoted,
                     },
                     WordComponent {
                         kind: WordComponentKind::literal(":"),
                         span: Span::new_to(0, 19, 19),
                         splittable: false,
                         remove_backslash: false,
+                        quoting: Quoting::Unquoted,
                     },
                     WordComponent {
                         kind: WordComponentKind::literal("~foo"),
                         span: Span::new_to(0, 20, 23),
                         splittable: true,
-                        remove_backslash: false
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted
                     },
                     WordComponent {
                         kind: WordComponentKind::literal("/baz"),
                         span: Span::new_to(0, 24, 27),
                         splittable: true,
                         remove_backslash: true,
+                        quoting: Quoting::Unquoted,
                     },
                 ]
             );
@@ -1131,19 +1947,24 @@ mod test {
             vec![
                 Token::Assignment(Assignment {
                     name: "FOO".to_owned(),
+                    subscript: None,
+                    array: None,
+                    append: false,
                     span: Span::new_to(0, 0, 4),
                     value: vec![WordComponent {
                         kind: WordComponentKind::literal("bar"),
                         span: Span::new_to(0, 4, 6),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]
                 }),
                 Token::Word(vec![WordComponent {
                     kind: WordComponentKind::literal("baz"),
                     span: Span::new_to(0, 8, 10),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 },]),
             ]
         );
@@ -1152,12 +1973,16 @@ mod test {
             tokens("FOO=\"bar baz\""),
             vec![Token::Assignment(Assignment {
                 name: "FOO".to_owned(),
+                subscript: None,
+                array: None,
+                append: false,
                 span: Span::new_to(0, 0, 4),
                 value: vec![WordComponent {
                     kind: WordComponentKind::literal("bar baz"),
                     span: Span::new_to(0, 4, 12),
                     splittable: false,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Double
                 }]
             }),]
         );
@@ -1172,6 +1997,7 @@ mod test {
                 span: Span::new_to(0, 0, 0),
                 splittable: false,
                 remove_backslash: false,
+                quoting: Quoting::Unquoted,
             }])]
         );
         assert_eq!(
@@ -1181,6 +2007,7 @@ mod test {
                 span: Span::new_to(0, 0, 3),
                 splittable: false,
                 remove_backslash: false,
+                quoting: Quoting::Unquoted,
             }])]
         );
 
@@ -1192,12 +2019,14 @@ mod test {
                     span: Span::new_to(0, 0, 0),
                     splittable: false,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                 },
                 WordComponent {
                     kind: WordComponentKind::literal("/"),
                     span: Span::new_to(0, 1, 1),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 },
             ])]
         );
@@ -1209,12 +2038,14 @@ mod test {
                     span: Span::new_to(0, 0, 3),
                     splittable: false,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                 },
                 WordComponent {
                     kind: WordComponentKind::literal("/"),
                     span: Span::new_to(0, 4, 4),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 },
             ])]
         );
@@ -1225,7 +2056,8 @@ mod test {
                 kind: WordComponentKind::literal("~:"),
                 span: Span::new_to(0, 0, 1),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             },])]
         );
         assert_eq!(
@@ -1234,7 +2066,8 @@ mod test {
                 kind: WordComponentKind::literal("\\~"),
                 span: Span::new_to(0, 0, 1),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             },])]
         );
     }
@@ -1246,12 +2079,15 @@ mod test {
             vec![Token::Word(vec![WordComponent {
                 kind: WordComponentKind::ParamExpand(ParamExpr {
                     kind: ParamOper::Get,
+                    subscript: None,
+                    indirect: false,
                     name: "foo".to_owned(),
                     word: vec![]
                 }),
                 span: Span::new_to(0, 0, 5),
                 splittable: false,
                 remove_backslash: false,
+                quoting: Quoting::Double,
             }])]
         );
         assert_eq!(
@@ -1259,16 +2095,109 @@ mod test {
             vec![Token::Word(vec![WordComponent {
                 kind: WordComponentKind::ParamExpand(ParamExpr {
                     kind: ParamOper::Get,
+                    subscript: None,
+                    indirect: false,
                     name: "foo".to_owned(),
                     word: vec![]
                 }),
                 span: Span::new_to(0, 0, 7),
                 splittable: false,
                 remove_backslash: false,
+                quoting: Quoting::Double,
+            }])]
+        );
+    }
+
+    #[test]
+    fn paramexp_short_name() {
+        assert_eq!(
+            tokens("$i"),
+            vec![Token::Word(vec![WordComponent {
+                kind: WordComponentKind::ParamExpand(ParamExpr {
+                    kind: ParamOper::Get,
+                    subscript: None,
+                    indirect: false,
+                    name: "i".to_owned(),
+                    word: vec![]
+                }),
+                span: Span::new_to(0, 0, 1),
+                splittable: true,
+                remove_backslash: false,
+                quoting: Quoting::Unquoted,
             }])]
         );
     }
 
+    #[test]
+    fn array_subscripts() {
+        let subscript = |s: &str, col: usize| {
+            Some(vec![WordComponent {
+                kind: WordComponentKind::literal(s),
+                span: Span::new_to(0, col, col),
+                splittable: false,
+                remove_backslash: true,
+                quoting: Quoting::Unquoted,
+            }])
+        };
+        let param = |kind: ParamOper, subscript: Option<Vec<WordComponent>>, end: usize| {
+            vec![Token::Word(vec![WordComponent {
+                kind: WordComponentKind::ParamExpand(ParamExpr {
+                    kind,
+                    subscript,
+                    indirect: false,
+                    name: "arr".to_owned(),
+                    word: vec![],
+                }),
+                span: Span::new_to(0, 0, end),
+                splittable: true,
+                remove_backslash: false,
+                quoting: Quoting::Unquoted,
+            }])]
+        };
+
+        assert_eq!(
+            tokens("${arr[1]}"),
+            param(ParamOper::Get, subscript("1", 6), 8)
+        );
+        assert_eq!(
+            tokens("${#arr[@]}"),
+            param(ParamOper::StringLength, subscript("@", 7), 9)
+        );
+
+        assert_eq!(
+            tokens("arr[2]=x"),
+            vec![Token::Assignment(Assignment {
+                name: "arr".to_owned(),
+                subscript: subscript("2", 4),
+                array: None,
+                append: false,
+                span: Span::new_to(0, 0, 7),
+                value: vec![WordComponent {
+                    kind: WordComponentKind::literal("x"),
+                    span: Span::new_to(0, 7, 7),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }]
+            })]
+        );
+
+        // The subscript may hold expansions
+        match &tokens("${arr[$i]}")[0] {
+            Token::Word(word) => match &word[0].kind {
+                WordComponentKind::ParamExpand(expr) => {
+                    assert!(!expr.is_all_elements());
+                    match &expr.subscript.as_ref().unwrap()[0].kind {
+                        WordComponentKind::ParamExpand(index) => assert_eq!(index.name, "i"),
+                        kind => panic!("expected an expansion, got {:?}", kind),
+                    }
+                }
+                kind => panic!("expected an expansion, got {:?}", kind),
+            },
+            token => panic!("expected a word, got {:?}", token),
+        }
+    }
+
     #[test]
     fn paramexp() {
         assert_eq!(
@@ -1276,12 +2205,15 @@ mod test {
             vec![Token::Word(vec![WordComponent {
                 kind: WordComponentKind::ParamExpand(ParamExpr {
                     kind: ParamOper::Get,
+                    subscript: None,
+                    indirect: false,
                     name: "foo".to_owned(),
                     word: vec![]
                 }),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
                 remove_backslash: false,
+                quoting: Quoting::Unquoted,
             }])]
         );
         assert_eq!(
@@ -1289,12 +2221,15 @@ mod test {
             vec![Token::Word(vec![WordComponent {
                 kind: WordComponentKind::ParamExpand(ParamExpr {
                     kind: ParamOper::Get,
+                    subscript: None,
+                    indirect: false,
                     name: "foo".to_owned(),
                     word: vec![]
                 }),
                 span: Span::new_to(0, 0, 5),
                 splittable: true,
                 remove_backslash: false,
+                quoting: Quoting::Unquoted,
             }])]
         );
         assert_eq!(
@@ -1302,12 +2237,15 @@ mod test {
             vec![Token::Word(vec![WordComponent {
                 kind: WordComponentKind::ParamExpand(ParamExpr {
                     kind: ParamOper::StringLength,
+                    subscript: None,
+                    indirect: false,
                     name: "foo".to_owned(),
                     word: vec![]
                 }),
                 span: Span::new_to(0, 0, 6),
                 splittable: true,
                 remove_backslash: false,
+                quoting: Quoting::Unquoted,
             }])]
         );
         assert_eq!(
@@ -1316,18 +2254,22 @@ mod test {
                 WordComponent {
                     kind: WordComponentKind::ParamExpand(ParamExpr {
                         kind: ParamOper::Get,
+                        subscript: None,
+                        indirect: false,
                         name: "foo".to_owned(),
                         word: vec![]
                     }),
                     span: Span::new_to(0, 0, 5),
                     splittable: true,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                 },
                 WordComponent {
                     kind: WordComponentKind::literal("bar"),
                     span: Span::new_to(0, 6, 8),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }
             ])]
         );
@@ -1338,31 +2280,37 @@ mod test {
                 WordComponent {
                     kind: WordComponentKind::ParamExpand(ParamExpr {
                         kind: ParamOper::GetDefault { allow_null: false },
+                        subscript: None,
+                        indirect: false,
                         name: "foo".to_owned(),
                         word: vec![
                             vec![WordComponent {
                                 kind: WordComponentKind::literal("hello"),
                                 span: Span::new_to(0, 7, 11),
                                 splittable: true,
-                                remove_backslash: true
+                                remove_backslash: true,
+                                quoting: Quoting::Unquoted
                             }],
                             vec![WordComponent {
                                 kind: WordComponentKind::literal("there"),
                                 span: Span::new_to(0, 13, 17),
                                 splittable: true,
-                                remove_backslash: true
+                                remove_backslash: true,
+                                quoting: Quoting::Unquoted
                             }],
                         ]
                     }),
                     span: Span::new_to(0, 0, 18),
                     splittable: true,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                 },
                 WordComponent {
                     kind: WordComponentKind::literal("bar"),
                     span: Span::new_to(0, 19, 21),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }
             ])]
         );
@@ -1373,31 +2321,37 @@ mod test {
                 WordComponent {
                     kind: WordComponentKind::ParamExpand(ParamExpr {
                         kind: ParamOper::GetDefault { allow_null: false },
+                        subscript: None,
+                        indirect: false,
                         name: "foo".to_owned(),
                         word: vec![
                             vec![WordComponent {
                                 kind: WordComponentKind::literal("hello"),
                                 span: Span::new_to(0, 7, 11),
                                 splittable: true,
-                                remove_backslash: true
+                                remove_backslash: true,
+                                quoting: Quoting::Unquoted
                             }],
                             vec![WordComponent {
                                 kind: WordComponentKind::literal("there"),
                                 span: Span::new_to(1, 0, 4),
                                 splittable: true,
-                                remove_backslash: true
+                                remove_backslash: true,
+                                quoting: Quoting::Unquoted
                             }],
                         ]
                     }),
                     span: Span::new(Pos::new(0, 0), Pos::new(1, 5)),
                     splittable: true,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                 },
                 WordComponent {
                     kind: WordComponentKind::literal("bar"),
                     span: Span::new_to(1, 6, 8),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }
             ])]
         );
@@ -1408,27 +2362,34 @@ mod test {
                 WordComponent {
                     kind: WordComponentKind::ParamExpand(ParamExpr {
                         kind: ParamOper::GetDefault { allow_null: false },
+                        subscript: None,
+                        indirect: false,
                         name: "foo".to_owned(),
                         word: vec![vec![WordComponent {
                             kind: WordComponentKind::ParamExpand(ParamExpr {
                                 kind: ParamOper::Get,
+                                subscript: None,
+                                indirect: false,
                                 name: "nest".to_owned(),
                                 word: vec![]
                             }),
                             span: Span::new_to(0, 7, 13),
                             splittable: true,
                             remove_backslash: false,
+                            quoting: Quoting::Unquoted,
                         }],]
                     }),
                     span: Span::new_to(0, 0, 14),
                     splittable: true,
                     remove_backslash: false,
+                    quoting: Quoting::Unquoted,
                 },
                 WordComponent {
                     kind: WordComponentKind::literal("bar"),
                     span: Span::new_to(0, 15, 17),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }
             ])]
         );
@@ -1438,17 +2399,21 @@ mod test {
             vec![Token::Word(vec![WordComponent {
                 kind: WordComponentKind::ParamExpand(ParamExpr {
                     kind: ParamOper::GetDefault { allow_null: false },
+                    subscript: None,
+                    indirect: false,
                     name: "foo".to_owned(),
                     word: vec![vec![WordComponent {
                         kind: WordComponentKind::TildeExpand(Some("wez".to_owned())),
                         span: Span::new_to(0, 7, 10),
                         splittable: false,
-                        remove_backslash: false
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted
                     }],]
                 }),
                 span: Span::new_to(0, 0, 11),
                 splittable: true,
                 remove_backslash: false,
+                quoting: Quoting::Unquoted,
             },])]
         );
     }
@@ -1462,13 +2427,15 @@ mod test {
                     kind: WordComponentKind::literal("echo"),
                     span: Span::new_to(0, 0, 3),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }]),
                 Token::Word(vec![WordComponent {
                     kind: WordComponentKind::literal("hello"),
                     span: Span::new_to(0, 5, 9),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }]),
             ]
         );
@@ -1484,18 +2451,21 @@ mod test {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 2, 5),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                     Token::Word(vec![WordComponent {
                         kind: WordComponentKind::literal("hello"),
                         span: Span::new_to(0, 7, 11),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                 ]),
                 span: Span::new_to(0, 0, 12),
                 splittable: true,
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
             },])]
         );
 
@@ -1507,19 +2477,22 @@ mod test {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 2, 5),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                     Token::Newline(Pos::new(0, 6)),
                     Token::Word(vec![WordComponent {
                         kind: WordComponentKind::literal("hello"),
                         span: Span::new_to(1, 0, 4),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                 ]),
                 span: Span::new(Pos::new(0, 0), Pos::new(1, 5)),
                 splittable: true,
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
             },])]
         );
     }
@@ -1534,7 +2507,8 @@ mod test {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 2, 5),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                     Token::Word(vec![WordComponent {
                         kind: WordComponentKind::CommandSubstitution(vec![Token::Word(vec![
@@ -1542,17 +2516,20 @@ mod test {
                                 kind: WordComponentKind::literal("ls"),
                                 span: Span::new_to(0, 9, 10),
                                 splittable: true,
-                                remove_backslash: true
+                                remove_backslash: true,
+                                quoting: Quoting::Unquoted
                             }
                         ]),]),
                         span: Span::new_to(0, 7, 11),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                 ]),
                 span: Span::new_to(0, 0, 12),
                 splittable: true,
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
             },])]
         );
     }
@@ -1567,18 +2544,21 @@ mod test {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 1, 4),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                     Token::Word(vec![WordComponent {
                         kind: WordComponentKind::literal("hello"),
                         span: Span::new_to(0, 6, 10),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                 ]),
                 span: Span::new_to(0, 0, 11),
                 splittable: true,
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
             },])]
         );
     }
@@ -1593,7 +2573,8 @@ mod test {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 1, 4),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                     Token::Word(vec![WordComponent {
                         kind: WordComponentKind::CommandSubstitution(vec![Token::Word(vec![
@@ -1601,17 +2582,20 @@ mod test {
                                 kind: WordComponentKind::literal("ls"),
                                 span: Span::new_to(0, 8, 9),
                                 splittable: true,
-                                remove_backslash: true
+                                remove_backslash: true,
+                                quoting: Quoting::Unquoted
                             }
                         ]),]),
                         span: Span::new_to(0, 6, 10),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                 ]),
                 span: Span::new_to(0, 0, 11),
                 splittable: true,
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
             },])]
         );
     }
@@ -1626,7 +2610,8 @@ mod test {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 1, 4),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                     Token::Word(vec![WordComponent {
                         kind: WordComponentKind::CommandSubstitution(vec![Token::Word(vec![
@@ -1634,17 +2619,20 @@ mod test {
                                 kind: WordComponentKind::literal("ls"),
                                 span: Span::new_to(0, 8, 9),
                                 splittable: true,
-                                remove_backslash: true
+                                remove_backslash: true,
+                                quoting: Quoting::Unquoted
                             }
                         ]),]),
                         span: Span::new_to(0, 6, 11),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]),
                 ]),
                 span: Span::new_to(0, 0, 12),
                 splittable: true,
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
             },])]
         );
     }
@@ -1653,4 +2641,105 @@ mod test {
     fn arithmetic() {
         assert_eq!(token_err("$(($x-1))"), "arithmetic not done");
     }
+
+    #[test]
+    fn arithmetic_command() {
+        assert_eq!(
+            tokens("((i += (2 * 3)))"),
+            vec![Token::ArithmeticCommand(
+                "i += (2 * 3)".to_string(),
+                Span::new_to(0, 0, 15)
+            )]
+        );
+        assert_eq!(
+            token_err("((1 + 2"),
+            "EOF while lexing arithmetic command at line 0 column 7"
+        );
+        assert_eq!(
+            token_err("((1 + 2) "),
+            "unmatched `)` in arithmetic command at line 0 column 7"
+        );
+    }
+
+    #[test]
+    fn dump_tokens() {
+        let mut lexer = Lexer::new("test", "a | b".as_bytes());
+        assert_eq!(
+            lexer.dump_tokens().unwrap(),
+            vec![
+                "Word 0:0-0:0 \"a\"",
+                "Operator 0:2-0:2 Pipe",
+                "Word 0:4-0:4 \"b\"",
+                "Eof 0:5-0:5",
+            ]
+        );
+    }
+
+    #[test]
+    fn describe_expansions() {
+        let words = tokens("$x ${#x} ${!x} ${a[@]} ${x:-d} ${x//a/b} ~wez $(echo hi | cat)")
+            .iter()
+            .filter_map(|token| match token {
+                Token::Word(word) => Some(describe_word(word)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            words,
+            vec![
+                "$x",
+                "${#x}",
+                "${!x}",
+                "${a[\"@\"]}",
+                "${x:-\"d\"}",
+                "${x//\"a/b\"}",
+                "~wez",
+                "$(\"echo\" \"hi\" | \"cat\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let mut lexer = Lexer::new("test", "a#b # c d\n#e\nx=#y".as_bytes());
+        assert_eq!(
+            lexer.dump_tokens().unwrap(),
+            vec![
+                "Word 0:0-0:2 \"a#b\"",
+                "Newline 0:9-0:9",
+                "Newline 1:2-1:2",
+                "Assignment 2:0-2:2 \"x\"",
+                "Eof 2:4-2:4",
+            ]
+        );
+    }
+
+    #[test]
+    fn nul_byte() {
+        for source in &["echo a\0b\n", "echo 'a\0b'\n", "\0"] {
+            let mut lexer = Lexer::new("test", source.as_bytes());
+            let err = lexer.dump_tokens().unwrap_err();
+            let err = err.downcast::<LexError>().unwrap();
+            assert!(matches!(err.kind, LexErrorKind::NulByte));
+        }
+
+        let mut lexer = Lexer::new("test", "echo a\0b".as_bytes());
+        let err = lexer.dump_tokens().unwrap_err();
+        assert_eq!(err.to_string(), "NUL byte in input at line 0 column 6");
+    }
+
+    #[test]
+    fn unterminated_positions() {
+        let opened_at = |source: &str| {
+            let mut lexer = Lexer::new("test", source.as_bytes());
+            let err = lexer.dump_tokens().unwrap_err();
+            err.downcast::<LexError>().unwrap().opened_at
+        };
+        assert_eq!(opened_at("echo it's"), Some(Pos::new(0, 7)));
+        assert_eq!(opened_at("if a; then echo 'b; fi"), Some(Pos::new(0, 16)));
+        assert_eq!(opened_at("echo \"a\nb"), Some(Pos::new(0, 5)));
+        assert_eq!(opened_at("x ${y"), Some(Pos::new(0, 2)));
+        assert_eq!(opened_at("a $(b\nc"), Some(Pos::new(0, 2)));
+        assert_eq!(opened_at("echo \\"), None);
+    }
 }
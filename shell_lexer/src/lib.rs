@@ -1,4 +1,8 @@
+// failure_derive emits its impls inside an anonymous const block.
+#![allow(non_local_definitions)]
+
 mod errors;
+mod escapes;
 mod lexer;
 mod position;
 mod reader;
@@ -6,7 +10,11 @@ mod reader;
 mod tokenenum;
 
 pub use errors::{LexError, LexErrorKind};
-pub use lexer::{Assignment, Lexer, ParamExpr, ParamOper, Token, WordComponent, WordComponentKind};
+pub use escapes::expand_backslash_escapes;
+pub use lexer::{
+    describe_word, Assignment, Lexer, ParamExpr, ParamOper, Quoting, Token, WordComponent,
+    WordComponentKind,
+};
 pub use position::{Pos, Span};
 pub use reader::CharReader;
 pub use tokenenum::LiteralMatcher;
@@ -14,6 +22,7 @@ pub use tokenenum::LiteralMatcher;
 TokenEnum!(
     Operator,
     OPERATORS,
+    "<<<": TripleLess,
     "<<-": DoubleLessDash,
     "<<": DoubleLess,
     "<&": LessAnd,
@@ -23,7 +32,9 @@ TokenEnum!(
     ">&": GreatAnd,
     "&&": AndIf,
     "||": OrIf,
+    ";;&": DoubleSemicolonAnd,
     ";;": DoubleSemicolon,
+    ";&": SemicolonAnd,
     "<": Less,
     "&": Ampersand,
     "|": Pipe,
@@ -48,8 +59,12 @@ TokenEnum!(
     "while": While,
     "until": Until,
     "for": For,
+    "select": Select,
     "{": LeftBrace,
     "}": RightBrace,
     "!": Bang,
-    "in": In
+    "in": In,
+    "time": Time,
+    "[[": DoubleLeftBracket,
+    "]]": DoubleRightBracket
 );
@@ -9,7 +9,8 @@ lazy_static! {
     static ref IO_NUMBER_RE: Regex =
         Regex::new(r"^[0-9]+[<>]").expect("failed to compile IO_NUMBER_RE");
     static ref ASSIGNMENT_WORD_RE: Regex =
-        Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*=").expect("failed to compile ASSIGNMENT_WORD_RE");
+        Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*(\[[^\]\n]*\])?\+?=")
+            .expect("failed to compile ASSIGNMENT_WORD_RE");
 }
 
 pub struct CharReader<R: Read> {
@@ -58,7 +59,7 @@ impl<R: Read> CharReader<R> {
     ) -> Fallible<MatchResult<T>> {
         match self.check_and_fill_buffer() {
             Next::Eof(_) => Ok(MatchResult::No),
-            Next::Error(err, pos) => return Err(err.context(pos).into()),
+            Next::Error(err, pos) => Err(err.context(pos).into()),
             _ => Ok(matcher.matches(&self.line_buffer[self.line_idx..])),
         }
     }
@@ -79,10 +80,10 @@ impl<R: Read> CharReader<R> {
         }
     }
 
-    pub fn matches_regex(&mut self, regex: &Regex) -> Fallible<Option<(Captures, Pos)>> {
+    pub fn matches_regex(&mut self, regex: &Regex) -> Fallible<Option<(Captures<'_>, Pos)>> {
         match self.check_and_fill_buffer() {
             Next::Eof(_) => Ok(None),
-            Next::Error(err, pos) => return Err(err.context(pos).into()),
+            Next::Error(err, pos) => Err(err.context(pos).into()),
             _ => Ok(regex
                 .captures(&self.line_buffer[self.line_idx..])
                 .map(|c| (c, self.position))),
@@ -98,7 +99,7 @@ impl<R: Read> CharReader<R> {
     pub fn matches_io_number(&mut self) -> Fallible<bool> {
         match self.check_and_fill_buffer() {
             Next::Eof(_) => Ok(false),
-            Next::Error(err, pos) => return Err(err.context(pos).into()),
+            Next::Error(err, pos) => Err(err.context(pos).into()),
             _ => Ok(IO_NUMBER_RE.is_match(&self.line_buffer[self.line_idx..])),
         }
     }
@@ -106,12 +107,13 @@ impl<R: Read> CharReader<R> {
     pub fn next_io_number(&mut self) -> Fallible<Option<(usize, Span)>> {
         match self.check_and_fill_buffer() {
             Next::Eof(_) => Ok(None),
-            Next::Error(err, pos) => return Err(err.context(pos).into()),
+            Next::Error(err, pos) => Err(err.context(pos).into()),
             _ => {
                 if let Some(m) = IO_NUMBER_RE.find(&self.line_buffer[self.line_idx..]) {
                     let num_str = m.as_str();
                     let len = m.end() - 1;
-                    let num = usize::from_str_radix(&num_str[..len], 10)
+                    let num = num_str[..len]
+                        .parse::<usize>()
                         .expect("number to parse as number");
                     let start = self.position;
                     let end = Pos::new(start.line, start.col + len);
@@ -128,25 +130,29 @@ impl<R: Read> CharReader<R> {
     pub fn matches_assignment_word(&mut self) -> Fallible<bool> {
         match self.check_and_fill_buffer() {
             Next::Eof(_) => Ok(false),
-            Next::Error(err, pos) => return Err(err.context(pos).into()),
+            Next::Error(err, pos) => Err(err.context(pos).into()),
             _ => Ok(ASSIGNMENT_WORD_RE.is_match(&self.line_buffer[self.line_idx..])),
         }
     }
 
-    pub fn next_assignment_word(&mut self) -> Fallible<Option<(String, Span)>> {
+    /// Consume an assignment word, returning the name that it assigns,
+    /// which includes the subscript for the `NAME[SUBSCRIPT]=` form,
+    /// and whether it is of the `NAME+=` form that appends to the value
+    pub fn next_assignment_word(&mut self) -> Fallible<Option<(String, bool, Span)>> {
         match self.check_and_fill_buffer() {
             Next::Eof(_) => Ok(None),
-            Next::Error(err, pos) => return Err(err.context(pos).into()),
+            Next::Error(err, pos) => Err(err.context(pos).into()),
             _ => {
                 if let Some(m) = ASSIGNMENT_WORD_RE.find(&self.line_buffer[self.line_idx..]) {
-                    let num_str = m.as_str();
+                    let assign = &m.as_str()[..m.end() - 1];
+                    let append = assign.ends_with('+');
+                    let name = assign.trim_end_matches('+').to_string();
                     let len = m.end() - 1;
-                    let name = num_str[..len].to_string();
                     let start = self.position;
                     let end = Pos::new(start.line, start.col + len + 1);
                     self.line_idx += len + 1;
                     self.position.col += len + 1;
-                    Ok(Some((name, Span::new(start, end))))
+                    Ok(Some((name, append, Span::new(start, end))))
                 } else {
                     Ok(None)
                 }
@@ -182,7 +188,7 @@ impl<R: Read> CharReader<R> {
             fail @ Next::Eof(..) | fail @ Next::Error(..) => return fail,
             _ => {}
         }
-        match (&self.line_buffer[self.line_idx..]).chars().next() {
+        match self.line_buffer[self.line_idx..].chars().next() {
             Some(c) => {
                 let result = Next::Char(PositionedChar {
                     c,
@@ -60,7 +60,7 @@ impl<T: Copy> LiteralMatcher<T> {
             pattern.push_str(&regex::escape(lit.0));
             map.insert(lit.0, lit.1);
         }
-        pattern.push_str(")");
+        pattern.push(')');
 
         Self {
             re: Regex::new(&pattern).unwrap(),
@@ -69,7 +69,7 @@ impl<T: Copy> LiteralMatcher<T> {
     }
 
     pub fn lookup(&self, text: &str) -> Option<T> {
-        self.map.get(text).map(|v| *v)
+        self.map.get(text).copied()
     }
 
     pub fn matches(&self, text: &str) -> MatchResult<T> {
@@ -0,0 +1,250 @@
+//! Renders a parsed Command as an indented tree, as printed by
+//! `wzsh --dump-ast`.  This is a more readable alternative to the
+//! Debug representation, which includes the span of every word.
+use crate::types::*;
+use shell_lexer::{describe_word, WordComponent};
+use std::fmt::Write;
+
+/// Returns an indented, one node per line, rendering of command
+pub fn dump_ast(command: &Command) -> String {
+    let mut dumper = Dumper::default();
+    dumper.command(command);
+    dumper.output
+}
+
+#[derive(Default)]
+struct Dumper {
+    output: String,
+    depth: usize,
+}
+
+fn words(words: &[Vec<WordComponent>]) -> String {
+    words
+        .iter()
+        .map(|word| describe_word(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn redirection(redir: &Redirection) -> String {
+    match redir {
+        Redirection::File(f) => {
+            let mut flags = vec![];
+            for (flag, name) in &[
+                (f.input, "input"),
+                (f.output, "output"),
+                (f.clobber, "clobber"),
+                (f.append, "append"),
+            ] {
+                if *flag {
+                    flags.push(*name);
+                }
+            }
+            format!(
+                "Redirect {} {} {}",
+                f.fd_number,
+                describe_word(&f.file_name),
+                flags.join(" ")
+            )
+        }
+        Redirection::Fd(f) => format!("Redirect {}>&{}", f.dest_fd_number, f.src_fd_number),
+        Redirection::HereString(h) => {
+            format!("HereString {} {}", h.fd_number, describe_word(&h.word))
+        }
+    }
+}
+
+impl Dumper {
+    fn line(&mut self, text: &str) {
+        writeln!(
+            self.output,
+            "{:indent$}{}",
+            "",
+            text.trim_end(),
+            indent = self.depth * 2
+        )
+        .ok();
+    }
+
+    fn nested<F: FnOnce(&mut Self)>(&mut self, func: F) {
+        self.depth += 1;
+        func(self);
+        self.depth -= 1;
+    }
+
+    fn list(&mut self, label: &str, list: &CompoundList) {
+        self.line(label);
+        self.nested(|dumper| {
+            for command in &list.commands {
+                dumper.command(command);
+            }
+        });
+    }
+
+    fn command(&mut self, command: &Command) {
+        let suffix = if command.asynchronous { " &" } else { "" };
+        match &command.command {
+            CommandType::Pipeline(pipeline) => {
+                let mut label = "Pipeline".to_owned();
+                if pipeline.timed {
+                    label.push_str(" timed");
+                }
+                if pipeline.inverted {
+                    label.push_str(" inverted");
+                }
+                self.line(&format!("{}{}", label, suffix));
+                self.nested(|dumper| {
+                    for command in &pipeline.commands {
+                        dumper.command(command);
+                    }
+                });
+            }
+            CommandType::SimpleCommand(simple) => {
+                let mut label = "SimpleCommand".to_owned();
+                for a in &simple.assignments {
+                    let oper = if a.append { "+=" } else { "=" };
+                    write!(label, " {}", a.name).ok();
+                    if let Some(subscript) = &a.subscript {
+                        write!(label, "[{}]", describe_word(subscript)).ok();
+                    }
+                    match &a.array {
+                        Some(array) => write!(label, "{}({})", oper, words(array)).ok(),
+                        None => write!(label, "{}{}", oper, describe_word(&a.value)).ok(),
+                    };
+                }
+                if !simple.words.is_empty() {
+                    write!(label, " {}", words(&simple.words)).ok();
+                }
+                self.line(&format!("{}{}", label, suffix));
+                self.nested(|dumper| {
+                    for redir in &simple.redirects {
+                        dumper.line(&redirection(redir));
+                    }
+                });
+            }
+            CommandType::Program(list) => self.list(&format!("Program{}", suffix), list),
+            CommandType::BraceGroup(list) => self.list(&format!("BraceGroup{}", suffix), list),
+            CommandType::Subshell(list) => self.list(&format!("Subshell{}", suffix), list),
+            CommandType::ForEach(ForEach {
+                name,
+                wordlist,
+                body,
+            }) => {
+                self.line(&format!(
+                    "ForEach {} in {}{}",
+                    name,
+                    words(wordlist),
+                    suffix
+                ));
+                self.nested(|dumper| dumper.list("body:", body));
+            }
+            CommandType::Select(Select {
+                name,
+                wordlist,
+                body,
+            }) => {
+                self.line(&format!("Select {} in {}{}", name, words(wordlist), suffix));
+                self.nested(|dumper| dumper.list("body:", body));
+            }
+            CommandType::If(If {
+                condition,
+                true_part,
+                false_part,
+            }) => {
+                self.line(&format!("If{}", suffix));
+                self.nested(|dumper| {
+                    dumper.list("condition:", condition);
+                    if let Some(true_part) = true_part {
+                        dumper.list("then:", true_part);
+                    }
+                    // The empty else part of an if clause is omitted
+                    if let Some(false_part) = false_part {
+                        if !false_part.commands.is_empty() {
+                            dumper.list("else:", false_part);
+                        }
+                    }
+                });
+            }
+            CommandType::UntilLoop(UntilLoop { body, condition }) => {
+                self.line(&format!("UntilLoop{}", suffix));
+                self.nested(|dumper| {
+                    dumper.list("condition:", condition);
+                    dumper.list("body:", body);
+                });
+            }
+            CommandType::WhileLoop(WhileLoop { condition, body }) => {
+                self.line(&format!("WhileLoop{}", suffix));
+                self.nested(|dumper| {
+                    dumper.list("condition:", condition);
+                    dumper.list("body:", body);
+                });
+            }
+            CommandType::Case(Case { word, clauses }) => {
+                self.line(&format!("Case {}{}", describe_word(word), suffix));
+                self.nested(|dumper| {
+                    for clause in clauses {
+                        let patterns = clause
+                            .patterns
+                            .iter()
+                            .map(|pattern| describe_word(pattern))
+                            .collect::<Vec<_>>()
+                            .join(" | ");
+                        dumper.list(
+                            &format!("{}) {}", patterns, clause.terminator),
+                            &clause.body,
+                        );
+                    }
+                });
+            }
+            CommandType::Arithmetic(expr) => {
+                self.line(&format!("Arithmetic {:?}{}", expr, suffix));
+            }
+            CommandType::Conditional(expr) => {
+                self.line(&format!("Conditional{}", suffix));
+                self.nested(|dumper| dumper.conditional(expr));
+            }
+            CommandType::FunctionDefinition { name, body } => {
+                self.line(&format!("FunctionDefinition {}{}", name, suffix));
+                self.nested(|dumper| dumper.command(body));
+            }
+        }
+        self.nested(|dumper| {
+            for redir in &command.redirects {
+                dumper.line(&redirection(redir));
+            }
+        });
+    }
+
+    fn conditional(&mut self, expr: &ConditionalExpr) {
+        match expr {
+            ConditionalExpr::Word(word) => self.line(&format!("Word {}", describe_word(word))),
+            ConditionalExpr::Unary { operator, operand } => {
+                self.line(&format!("Unary {} {}", operator, describe_word(operand)))
+            }
+            ConditionalExpr::Binary {
+                operator,
+                left,
+                right,
+            } => self.line(&format!(
+                "Binary {} {} {}",
+                describe_word(left),
+                operator,
+                describe_word(right)
+            )),
+            ConditionalExpr::Not(expr) => {
+                self.line("Not");
+                self.nested(|dumper| dumper.conditional(expr));
+            }
+            ConditionalExpr::And(left, right) | ConditionalExpr::Or(left, right) => {
+                self.line(match expr {
+                    ConditionalExpr::And(..) => "And",
+                    _ => "Or",
+                });
+                self.nested(|dumper| {
+                    dumper.conditional(left);
+                    dumper.conditional(right);
+                });
+            }
+        }
+    }
+}
@@ -1,6 +1,11 @@
+// failure_derive emits its impls inside an anonymous const block.
+#![allow(non_local_definitions)]
+
+mod dump;
 mod parser;
 mod types;
 
+pub use dump::dump_ast;
 pub use parser::*;
 pub use types::*;
 
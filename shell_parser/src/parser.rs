@@ -1,6 +1,9 @@
 use crate::types::*;
 use failure::{bail, Error, Fail, Fallible};
-use shell_lexer::{Lexer, Operator, ReservedWord, Token};
+use shell_lexer::{
+    LexError, LexErrorKind, Lexer, Operator, ParamExpr, ParamOper, Pos, Quoting, ReservedWord,
+    Token, WordComponent, WordComponentKind,
+};
 use std::collections::VecDeque;
 use std::io::Read;
 
@@ -15,23 +18,63 @@ pub enum ParseErrorContext {
     FdRedirectionExpectsNumber,
     ExpectingRightBrace,
     ExpectingRightParen,
+    ExpectingDo,
+    ExpectingDone,
+    ExpectingThen,
+    ExpectingFi,
+    ExpectingLoopVariableName,
+    ExpectingConditionalExpression,
+    ExpectingDoubleRightBracket,
+    ExpectingCaseWord,
+    ExpectingIn,
+    ExpectingCasePattern,
+    ExpectingEsac,
 }
 
+/// Operators that take a single operand in a `[[ ]]` expression
+const CONDITIONAL_UNARY_OPERATORS: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-o", "-p", "-r", "-s", "-t", "-u",
+    "-v", "-w", "-x", "-z", "-G", "-L", "-N", "-O", "-S",
+];
+
+/// Operators that take two operands in a `[[ ]]` expression.
+/// `<` and `>` are lexed as redirection operators and are
+/// handled separately.
+const CONDITIONAL_BINARY_OPERATORS: &[&str] = &[
+    "==", "=", "!=", "=~", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
 #[derive(Debug, Clone, PartialEq, Eq, Fail)]
 pub enum ParseErrorKind {
+    /// The token, what was being parsed when it was encountered
+    /// and the name of the source being parsed
     #[fail(display = "Unexpected token {:?} while parsing {:?}", 0, 1)]
-    UnexpectedToken(Token, ParseErrorContext),
+    UnexpectedToken(Token, ParseErrorContext, String),
+}
+
+impl ParseErrorKind {
+    /// Returns the name of the source in which the error occurred
+    pub fn source(&self) -> &str {
+        match self {
+            ParseErrorKind::UnexpectedToken(_, _, source) => source,
+        }
+    }
 }
 
 pub struct Parser<R: Read> {
+    source: String,
     lexer: Lexer<R>,
     lookahead: VecDeque<Token>,
 }
 
 impl<R: Read> Parser<R> {
-    pub fn new(stream: R) -> Self {
-        let lexer = Lexer::new(stream);
+    /// Create a parser that reads from stream.  `source` names the
+    /// input, such as a filename or "stdin", and is recorded in any
+    /// errors so that they can be attributed to it.
+    pub fn new(source: &str, stream: R) -> Self {
+        let lexer = Lexer::new(source, stream);
         Self {
+            source: source.to_owned(),
             lexer,
             lookahead: VecDeque::new(),
         }
@@ -43,10 +86,76 @@ impl<R: Read> Parser<R> {
     }
 }
 
+impl<'a> Parser<&'a [u8]> {
+    /// Check the syntax of `text` without running it.  `source` names
+    /// the input, as for `Parser::new`.  Use `is_incomplete_input` to
+    /// tell whether an error is due to the input ending part way
+    /// through a command, rather than to a definite mistake.
+    pub fn validate(source: &str, text: &'a str) -> Fallible<()> {
+        Parser::new(source, text.as_bytes()).parse()?;
+        Ok(())
+    }
+}
+
+/// Returns true if a given error might be resolved by allowing
+/// the user to continue typing more text on a subsequent line.
+/// Most lex errors fall into that category.
+pub fn is_incomplete_input(e: &Error) -> bool {
+    if let Some(lex_err) = e.downcast_ref::<LexError>() {
+        match lex_err.kind {
+            LexErrorKind::EofDuringBackslash
+            | LexErrorKind::EofDuringComment
+            | LexErrorKind::EofDuringSingleQuotedString
+            | LexErrorKind::EofDuringDoubleQuotedString
+            | LexErrorKind::EofDuringAnsiCQuotedString
+            | LexErrorKind::EofDuringAssignmentWord
+            | LexErrorKind::EofDuringCommandSubstitution
+            | LexErrorKind::EofDuringParameterExpansion
+            | LexErrorKind::EofDuringArithmeticCommand
+            | LexErrorKind::EofDuringExtendedPattern => true,
+            LexErrorKind::UnmatchedParenInArithmeticCommand
+            | LexErrorKind::NulByte
+            | LexErrorKind::IoError => false,
+        }
+    } else if let Some(parse_err) = e.downcast_ref::<ParseErrorKind>() {
+        match parse_err {
+            // A line ending in `&&`, `||` or `|`, or part way through
+            // a compound command, continues on the next
+            ParseErrorKind::UnexpectedToken(
+                Token::Eof(..),
+                ParseErrorContext::ExpectingPipelineAfter(_)
+                | ParseErrorContext::PipeSequence
+                | ParseErrorContext::ExpectingRightBrace
+                | ParseErrorContext::ExpectingRightParen
+                | ParseErrorContext::ExpectingDo
+                | ParseErrorContext::ExpectingDone
+                | ParseErrorContext::ExpectingThen
+                | ParseErrorContext::ExpectingFi
+                | ParseErrorContext::ExpectingIn
+                | ParseErrorContext::ExpectingEsac,
+                _,
+            ) => true,
+            ParseErrorKind::UnexpectedToken(..) => false,
+        }
+    } else {
+        false
+    }
+}
+
+impl Parser<std::io::Empty> {
+    /// Create a parser for tokens that have already been lexed,
+    /// such as the body of a command substitution
+    pub fn from_tokens(source: &str, tokens: Vec<Token>) -> Self {
+        let mut parser = Self::new(source, std::io::empty());
+        parser.lookahead.extend(tokens);
+        parser
+    }
+}
+
 impl<R: Read> Parser<R> {
     fn unexpected_next_token(&mut self, context: ParseErrorContext) -> Error {
         match self.next_token() {
-            Ok(tok) => ParseErrorKind::UnexpectedToken(tok, context).into(),
+            Ok(tok) => ParseErrorKind::UnexpectedToken(tok, context, self.source.clone()).into(),
             Err(e) => e,
         }
     }
@@ -99,15 +208,14 @@ impl<R: Read> Parser<R> {
     /// Parse the top level program syntax, a potentially empty list
     /// of child commands.
     fn program(&mut self) -> Fallible<Command> {
+        // Blank and comment-only lines may precede the first command
+        self.linebreak()?;
         let mut cmd = match self.and_or()? {
             Some(cmd) => cmd,
             None => {
-                let tok = self.next_token()?;
-                if let Token::Eof(..) = &tok {
-                    // We parsed an empty program
-                    return Ok(CommandType::SimpleCommand(Default::default()).into());
-                }
-                return Err(self.unexpected_next_token(ParseErrorContext::List));
+                // We parsed an empty program
+                self.expect_eof()?;
+                return Ok(CommandType::SimpleCommand(Default::default()).into());
             }
         };
         cmd.asynchronous = self.separator_is_async()?;
@@ -117,6 +225,7 @@ impl<R: Read> Parser<R> {
             cmd.asynchronous = self.separator_is_async()?;
             commands.push(cmd);
         }
+        self.expect_eof()?;
 
         if commands.len() == 1 {
             Ok(commands.pop().unwrap())
@@ -129,54 +238,79 @@ impl<R: Read> Parser<R> {
         }
     }
 
+    /// The whole of the input must form the program; any token that
+    /// could not be parsed as part of it is an error, rather than
+    /// silently ending the program early
+    fn expect_eof(&mut self) -> Fallible<()> {
+        match self.next_token()? {
+            Token::Eof(..) => Ok(()),
+            tok => Err(ParseErrorKind::UnexpectedToken(
+                tok,
+                ParseErrorContext::List,
+                self.source.clone(),
+            )
+            .into()),
+        }
+    }
+
+    /// Parse a chain of pipelines joined by `&&` and `||`.  The
+    /// operators have equal precedence and associate to the left,
+    /// so `a && b || c` runs `c` if either `a` or `b` fails.
     fn and_or(&mut self) -> Fallible<Option<Command>> {
-        if let Some(pipeline) = self.pipeline()? {
-            match self.next_token_is_operator(&[Operator::AndIf, Operator::OrIf])? {
-                Some(Token::Operator(operator, ..)) => {
-                    self.pipeline_conditional(pipeline, operator)
-                }
-                _ => Ok(Some(pipeline.into())),
-            }
-        } else {
-            Ok(None)
+        let mut command: Command = match self.pipeline()? {
+            Some(pipeline) => pipeline.into(),
+            None => return Ok(None),
+        };
+        while let Some(Token::Operator(operator, ..)) =
+            self.next_token_is_operator(&[Operator::AndIf, Operator::OrIf])?
+        {
+            command = self.pipeline_conditional(command, operator)?;
         }
+        Ok(Some(command))
     }
 
-    fn pipeline_conditional(
-        &mut self,
-        condition: Pipeline,
-        op: Operator,
-    ) -> Fallible<Option<Command>> {
+    /// Parse the pipeline following an `&&` or `||` operator, which
+    /// may be on a subsequent line, and combine it with the commands
+    /// that precede the operator.
+    fn pipeline_conditional(&mut self, condition: Command, op: Operator) -> Fallible<Command> {
         self.linebreak()?;
 
         let then: CompoundList = Command::from(self.pipeline()?.ok_or_else(|| {
             self.unexpected_next_token(ParseErrorContext::ExpectingPipelineAfter(op))
         })?)
         .into();
-        let condition: CompoundList = Command::from(condition).into();
-
         let (true_part, false_part) = if op == Operator::AndIf {
             (Some(then), None)
         } else {
             (None, Some(then))
         };
 
-        Ok(Some(
-            CommandType::If(If {
-                condition: condition.into(),
-                true_part,
-                false_part,
-            })
-            .into(),
-        ))
+        Ok(CommandType::If(If {
+            condition: condition.into(),
+            true_part,
+            false_part,
+        })
+        .into())
     }
 
     fn pipeline(&mut self) -> Fallible<Option<Pipeline>> {
+        let timed = self.next_token_is_reserved_word(ReservedWord::Time)?;
         let inverted = self.next_token_is_reserved_word(ReservedWord::Bang)?;
         if let Some(commands) = self.pipe_sequence()? {
-            Ok(Some(Pipeline { inverted, commands }))
+            Ok(Some(Pipeline {
+                inverted,
+                timed,
+                commands,
+            }))
         } else if inverted {
             Err(self.unexpected_next_token(ParseErrorContext::PipelineStartingWithBang))
+        } else if timed {
+            // A bare `time` reports the time taken to do nothing
+            Ok(Some(Pipeline {
+                inverted,
+                timed,
+                commands: vec![],
+            }))
         } else {
             Ok(None)
         }
@@ -198,10 +332,6 @@ impl<R: Read> Parser<R> {
             }
         }
 
-        for cmd in commands.iter_mut().rev().skip(1) {
-            cmd.asynchronous = true;
-        }
-
         Ok(Some(commands))
     }
 
@@ -294,8 +424,23 @@ impl<R: Read> Parser<R> {
                 asynchronous: false,
                 redirects: vec![],
             }
+        } else if let Some(for_each) = self.for_clause()? {
+            CommandType::ForEach(for_each).into()
+        } else if let Some(select) = self.select_clause()? {
+            CommandType::Select(select).into()
+        } else if let Some(while_loop) = self.while_clause()? {
+            CommandType::WhileLoop(while_loop).into()
+        } else if let Some(until_loop) = self.until_clause()? {
+            CommandType::UntilLoop(until_loop).into()
+        } else if let Some(expression) = self.arithmetic_command()? {
+            CommandType::Arithmetic(expression).into()
+        } else if let Some(expression) = self.conditional_command()? {
+            CommandType::Conditional(expression).into()
+        } else if let Some(case) = self.case_clause()? {
+            CommandType::Case(case).into()
+        } else if let Some(if_clause) = self.if_clause()? {
+            CommandType::If(if_clause).into()
         } else {
-            // TODO: for_clause, case_clause, if_clause, while_clause, until_clause
             return Ok(None);
         };
 
@@ -303,6 +448,213 @@ impl<R: Read> Parser<R> {
         Ok(Some(command))
     }
 
+    fn arithmetic_command(&mut self) -> Fallible<Option<String>> {
+        let token = self.next_token()?;
+        if let Token::ArithmeticCommand(expression, _) = token {
+            Ok(Some(expression))
+        } else {
+            self.unget_token(token);
+            Ok(None)
+        }
+    }
+
+    fn conditional_command(&mut self) -> Fallible<Option<ConditionalExpr>> {
+        if !self.next_token_is_reserved_word(ReservedWord::DoubleLeftBracket)? {
+            return Ok(None);
+        }
+
+        let expr = self.conditional_or()?;
+
+        let tok = self.next_conditional_token()?;
+        if tok.is_reserved_word(ReservedWord::DoubleRightBracket) {
+            Ok(Some(expr))
+        } else {
+            self.unget_token(tok);
+            Err(self.unexpected_next_token(ParseErrorContext::ExpectingDoubleRightBracket))
+        }
+    }
+
+    /// Consume the next token from within a `[[ ]]` expression,
+    /// where newlines are not significant.
+    fn next_conditional_token(&mut self) -> Fallible<Token> {
+        loop {
+            match self.next_token()? {
+                Token::Newline(..) => continue,
+                tok => return Ok(tok),
+            }
+        }
+    }
+
+    fn conditional_or(&mut self) -> Fallible<ConditionalExpr> {
+        let mut expr = self.conditional_and()?;
+        loop {
+            let tok = self.next_conditional_token()?;
+            if let Token::Operator(Operator::OrIf, ..) = tok {
+                let rhs = self.conditional_and()?;
+                expr = ConditionalExpr::Or(Box::new(expr), Box::new(rhs));
+            } else {
+                self.unget_token(tok);
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn conditional_and(&mut self) -> Fallible<ConditionalExpr> {
+        let mut expr = self.conditional_not()?;
+        loop {
+            let tok = self.next_conditional_token()?;
+            if let Token::Operator(Operator::AndIf, ..) = tok {
+                let rhs = self.conditional_not()?;
+                expr = ConditionalExpr::And(Box::new(expr), Box::new(rhs));
+            } else {
+                self.unget_token(tok);
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn conditional_not(&mut self) -> Fallible<ConditionalExpr> {
+        let tok = self.next_conditional_token()?;
+        if tok.is_reserved_word(ReservedWord::Bang) {
+            return Ok(ConditionalExpr::Not(Box::new(self.conditional_not()?)));
+        }
+
+        if let Token::Operator(Operator::LeftParen, ..) = tok {
+            let expr = self.conditional_or()?;
+            let tok = self.next_conditional_token()?;
+            if let Token::Operator(Operator::RightParen, ..) = tok {
+                return Ok(expr);
+            }
+            self.unget_token(tok);
+            return Err(self.unexpected_next_token(ParseErrorContext::ExpectingRightParen));
+        }
+
+        self.unget_token(tok);
+        self.conditional_primary()
+    }
+
+    fn conditional_primary(&mut self) -> Fallible<ConditionalExpr> {
+        let first = match self.conditional_word()? {
+            Some(word) => word,
+            None => {
+                return Err(
+                    self.unexpected_next_token(ParseErrorContext::ExpectingConditionalExpression)
+                )
+            }
+        };
+
+        if let Some(operator) = unquoted_literal(&first) {
+            if CONDITIONAL_UNARY_OPERATORS.contains(&operator) {
+                // If there is no operand, then this is really
+                // just a test of the non-empty word, eg: `[[ -n ]]`
+                if let Some(operand) = self.conditional_word()? {
+                    return Ok(ConditionalExpr::Unary {
+                        operator: operator.to_owned(),
+                        operand,
+                    });
+                }
+            }
+        }
+
+        if let Some(operator) = self.conditional_binary_operator()? {
+            let right = if operator == "=~" {
+                self.conditional_regex()?
+            } else {
+                self.conditional_word()?
+            };
+            return match right {
+                Some(right) => Ok(ConditionalExpr::Binary {
+                    operator,
+                    left: first,
+                    right,
+                }),
+                None => {
+                    Err(self
+                        .unexpected_next_token(ParseErrorContext::ExpectingConditionalExpression))
+                }
+            };
+        }
+
+        Ok(ConditionalExpr::Word(first))
+    }
+
+    /// Consume a word operand from a `[[ ]]` expression
+    fn conditional_word(&mut self) -> Fallible<Option<Vec<WordComponent>>> {
+        let tok = self.next_conditional_token()?;
+        if tok.is_reserved_word(ReservedWord::DoubleRightBracket) {
+            self.unget_token(tok);
+            return Ok(None);
+        }
+        match tok {
+            Token::Word(word) => Ok(Some(word)),
+            Token::Assignment(assign) => Ok(Some((&assign).into())),
+            tok => {
+                self.unget_token(tok);
+                Ok(None)
+            }
+        }
+    }
+
+    fn conditional_binary_operator(&mut self) -> Fallible<Option<String>> {
+        let tok = self.next_conditional_token()?;
+        let operator = match &tok {
+            Token::Operator(Operator::Less, ..) => Some("<".to_owned()),
+            Token::Operator(Operator::Great, ..) => Some(">".to_owned()),
+            Token::Word(word) => unquoted_literal(word)
+                .filter(|op| CONDITIONAL_BINARY_OPERATORS.contains(op))
+                .map(str::to_owned),
+            _ => None,
+        };
+        if operator.is_none() {
+            self.unget_token(tok);
+        }
+        Ok(operator)
+    }
+
+    /// The right hand side of `=~` may contain unquoted parentheses
+    /// and pipes that the lexer treats as operators.  Glue together
+    /// any such tokens that are not separated by whitespace.
+    fn conditional_regex(&mut self) -> Fallible<Option<Vec<WordComponent>>> {
+        let mut regex: Vec<WordComponent> = vec![];
+        let mut end: Option<Pos> = None;
+        loop {
+            let tok = self.next_token()?;
+            let start = tok.span().start;
+            let adjacent = match end {
+                None => true,
+                Some(end) => start.line == end.line && start.col == end.col + 1,
+            };
+            if !adjacent || tok.is_reserved_word(ReservedWord::DoubleRightBracket) {
+                self.unget_token(tok);
+                break;
+            }
+            match tok {
+                Token::Word(word) => {
+                    end = word.last().map(|component| component.span.end);
+                    regex.extend(word);
+                }
+                Token::Operator(operator @ Operator::LeftParen, span)
+                | Token::Operator(operator @ Operator::RightParen, span)
+                | Token::Operator(operator @ Operator::Pipe, span) => {
+                    end = Some(span.end);
+                    regex.push(WordComponent {
+                        kind: WordComponentKind::Literal(operator.to_string()),
+                        span,
+                        splittable: false,
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted,
+                    });
+                }
+                tok => {
+                    self.unget_token(tok);
+                    break;
+                }
+            }
+        }
+
+        Ok(if regex.is_empty() { None } else { Some(regex) })
+    }
+
     fn subshell(&mut self) -> Fallible<Option<CompoundList>> {
         let left_paren = match self.next_token_is_operator(&[Operator::LeftParen])? {
             None => return Ok(None),
@@ -360,6 +712,257 @@ impl<R: Read> Parser<R> {
         }
     }
 
+    fn for_clause(&mut self) -> Fallible<Option<ForEach>> {
+        if !self.next_token_is_reserved_word(ReservedWord::For)? {
+            return Ok(None);
+        }
+
+        let (name, wordlist) = self.loop_name_and_wordlist()?;
+        let body = self.do_group()?;
+        Ok(Some(ForEach {
+            name,
+            wordlist,
+            body,
+        }))
+    }
+
+    fn select_clause(&mut self) -> Fallible<Option<Select>> {
+        if !self.next_token_is_reserved_word(ReservedWord::Select)? {
+            return Ok(None);
+        }
+
+        let (name, wordlist) = self.loop_name_and_wordlist()?;
+        let body = self.do_group()?;
+        Ok(Some(Select {
+            name,
+            wordlist,
+            body,
+        }))
+    }
+
+    /// Parses the `name [in word...]` portion of a for or select
+    /// clause, up to and including the separator that precedes the
+    /// do group.
+    fn loop_name_and_wordlist(&mut self) -> Fallible<(String, Vec<Vec<WordComponent>>)> {
+        let name_token = self.next_token()?;
+        let name = match name_token.as_single_literal_word_string() {
+            Some(name) if !name_token.is_any_reserved_word() => name.to_owned(),
+            _ => {
+                self.unget_token(name_token);
+                return Err(
+                    self.unexpected_next_token(ParseErrorContext::ExpectingLoopVariableName)
+                );
+            }
+        };
+        let span = match &name_token {
+            Token::Word(word) => word[0].span,
+            _ => bail!("impossible for loop name token {:?}", name_token),
+        };
+
+        self.linebreak()?;
+        let wordlist = if self.next_token_is_reserved_word(ReservedWord::In)? {
+            let mut wordlist = vec![];
+            loop {
+                let token = self.next_token()?;
+                match token {
+                    Token::Word(word) => wordlist.push(word),
+                    Token::Assignment(assign) => wordlist.push((&assign).into()),
+                    token => {
+                        self.unget_token(token);
+                        break;
+                    }
+                }
+            }
+            wordlist
+        } else {
+            // `for name do ...` iterates over "$@"
+            vec![vec![WordComponent {
+                kind: WordComponentKind::ParamExpand(ParamExpr {
+                    kind: ParamOper::Get,
+                    indirect: false,
+                    name: "@".to_owned(),
+                    word: vec![],
+                    subscript: None,
+                }),
+                span,
+                splittable: false,
+                remove_backslash: false,
+                quoting: Quoting::Double,
+            }]]
+        };
+        self.sequential_sep()?;
+
+        Ok((name, wordlist))
+    }
+
+    fn while_clause(&mut self) -> Fallible<Option<WhileLoop>> {
+        if !self.next_token_is_reserved_word(ReservedWord::While)? {
+            return Ok(None);
+        }
+        let condition = self.compound_list()?;
+        let body = self.do_group()?;
+        Ok(Some(WhileLoop { condition, body }))
+    }
+
+    fn until_clause(&mut self) -> Fallible<Option<UntilLoop>> {
+        if !self.next_token_is_reserved_word(ReservedWord::Until)? {
+            return Ok(None);
+        }
+        let condition = self.compound_list()?;
+        let body = self.do_group()?;
+        Ok(Some(UntilLoop { condition, body }))
+    }
+
+    fn if_clause(&mut self) -> Fallible<Option<If>> {
+        if !self.next_token_is_reserved_word(ReservedWord::If)? {
+            return Ok(None);
+        }
+        self.if_body().map(Some)
+    }
+
+    /// Parses the remainder of an if clause after the `if` or `elif`,
+    /// up to and including the `fi`.  Each `elif` is represented as an
+    /// `If` that forms the else part of the one before it.
+    fn if_body(&mut self) -> Fallible<If> {
+        let condition = self.compound_list()?;
+        if !self.next_token_is_reserved_word(ReservedWord::Then)? {
+            return Err(self.unexpected_next_token(ParseErrorContext::ExpectingThen));
+        }
+        let true_part = self.compound_list()?;
+
+        let false_part = if self.next_token_is_reserved_word(ReservedWord::Elif)? {
+            let command: Command = CommandType::If(self.if_body()?).into();
+            return Ok(If {
+                condition,
+                true_part: Some(true_part),
+                false_part: Some(command.into()),
+            });
+        } else if self.next_token_is_reserved_word(ReservedWord::Else)? {
+            self.compound_list()?
+        } else {
+            // The status is 0 if no branch is taken, unlike `a && b`,
+            // so the else part is present but empty
+            CompoundList { commands: vec![] }
+        };
+
+        if self.next_token_is_reserved_word(ReservedWord::Fi)? {
+            Ok(If {
+                condition,
+                true_part: Some(true_part),
+                false_part: Some(false_part),
+            })
+        } else {
+            Err(self.unexpected_next_token(ParseErrorContext::ExpectingFi))
+        }
+    }
+
+    fn do_group(&mut self) -> Fallible<CompoundList> {
+        if !self.next_token_is_reserved_word(ReservedWord::Do)? {
+            return Err(self.unexpected_next_token(ParseErrorContext::ExpectingDo));
+        }
+
+        let list = self.compound_list()?;
+
+        if self.next_token_is_reserved_word(ReservedWord::Done)? {
+            Ok(list)
+        } else {
+            Err(self.unexpected_next_token(ParseErrorContext::ExpectingDone))
+        }
+    }
+
+    fn case_clause(&mut self) -> Fallible<Option<Case>> {
+        if !self.next_token_is_reserved_word(ReservedWord::Case)? {
+            return Ok(None);
+        }
+
+        let word = match self.next_token()? {
+            Token::Word(word) => word,
+            Token::Assignment(assign) => (&assign).into(),
+            tok => {
+                self.unget_token(tok);
+                return Err(self.unexpected_next_token(ParseErrorContext::ExpectingCaseWord));
+            }
+        };
+
+        self.linebreak()?;
+        if !self.next_token_is_reserved_word(ReservedWord::In)? {
+            return Err(self.unexpected_next_token(ParseErrorContext::ExpectingIn));
+        }
+        self.linebreak()?;
+
+        let mut clauses = vec![];
+        loop {
+            if self.next_token_is_reserved_word(ReservedWord::Esac)? {
+                return Ok(Some(Case { word, clauses }));
+            }
+            let (clause, terminated) = self.case_item()?;
+            clauses.push(clause);
+            if !terminated {
+                // Only the final clause may omit its terminator
+                if self.next_token_is_reserved_word(ReservedWord::Esac)? {
+                    return Ok(Some(Case { word, clauses }));
+                }
+                return Err(self.unexpected_next_token(ParseErrorContext::ExpectingEsac));
+            }
+        }
+    }
+
+    /// Parses `[(] pattern [| pattern]...) compound_list [terminator]`,
+    /// along with any newlines that follow it.
+    /// Returns the clause and whether an explicit terminator was present.
+    fn case_item(&mut self) -> Fallible<(CaseClause, bool)> {
+        self.next_token_is_operator(&[Operator::LeftParen])?;
+
+        let mut patterns = vec![];
+        loop {
+            match self.next_token()? {
+                Token::Word(word) => patterns.push(word),
+                Token::Assignment(assign) => patterns.push((&assign).into()),
+                tok => {
+                    self.unget_token(tok);
+                    return Err(self.unexpected_next_token(ParseErrorContext::ExpectingCasePattern));
+                }
+            }
+            if self.next_token_is_operator(&[Operator::Pipe])?.is_none() {
+                break;
+            }
+        }
+
+        if self
+            .next_token_is_operator(&[Operator::RightParen])?
+            .is_none()
+        {
+            return Err(self.unexpected_next_token(ParseErrorContext::ExpectingRightParen));
+        }
+
+        let body = self.compound_list()?;
+
+        let (terminator, terminated) = match self.next_token_is_operator(&[
+            Operator::DoubleSemicolon,
+            Operator::SemicolonAnd,
+            Operator::DoubleSemicolonAnd,
+        ])? {
+            Some(Token::Operator(Operator::SemicolonAnd, ..)) => {
+                (CaseTerminator::FallThrough, true)
+            }
+            Some(Token::Operator(Operator::DoubleSemicolonAnd, ..)) => {
+                (CaseTerminator::Continue, true)
+            }
+            Some(_) => (CaseTerminator::Break, true),
+            None => (CaseTerminator::Break, false),
+        };
+        self.linebreak()?;
+
+        Ok((
+            CaseClause {
+                patterns,
+                body,
+                terminator,
+            },
+            terminated,
+        ))
+    }
+
     fn redirect_list(&mut self) -> Fallible<Vec<Redirection>> {
         let mut redirections = vec![];
         loop {
@@ -395,7 +998,8 @@ impl<R: Read> Parser<R> {
                 | Operator::GreatAnd
                 | Operator::DoubleGreat
                 | Operator::LessGreat
-                | Operator::Clobber => oper,
+                | Operator::Clobber
+                | Operator::TripleLess => oper,
                 _ => {
                     self.unget_token(t);
                     return Ok(None);
@@ -467,6 +1071,10 @@ impl<R: Read> Parser<R> {
                     clobber: true,
                     append: false,
                 }),
+                Operator::TripleLess => Redirection::HereString(HereString {
+                    fd_number: fd_number.unwrap_or(0),
+                    word: file_name,
+                }),
                 _ => bail!("impossible redirection oper {:?}", oper),
             }))
         } else {
@@ -489,14 +1097,22 @@ impl<R: Read> Parser<R> {
             let token = self.next_token()?;
             match &token {
                 Token::Assignment(assign) => {
+                    let mut assign = assign.clone();
+                    if assign.value.is_empty() {
+                        assign.array = self.array_words(assign.span.end)?;
+                    }
                     if words.is_empty() {
-                        assignments.push(assign.clone());
+                        assignments.push(assign);
                     } else {
-                        words.push(assign.into());
+                        words.push((&assign).into());
                     }
                 }
                 Token::Word(word) => {
-                    if token.is_reserved_word(ReservedWord::RightBrace) {
+                    if token.is_reserved_word(ReservedWord::RightBrace)
+                        || (words.is_empty() && token.is_any_reserved_word())
+                    {
+                        // A reserved word in command position terminates
+                        // the enclosing compound command
                         self.unget_token(token);
                         break;
                     }
@@ -523,6 +1139,34 @@ impl<R: Read> Parser<R> {
 }
 
 impl<R: Read> Parser<R> {
+    /// Parses the `(WORD...)` of a `NAME=(WORD...)` array assignment,
+    /// where the parenthesis immediately follows the `=` at `end`.
+    /// The words may be spread over several lines.
+    fn array_words(&mut self, end: Pos) -> Fallible<Option<Vec<Vec<WordComponent>>>> {
+        let tok = self.next_token()?;
+        match &tok {
+            Token::Operator(Operator::LeftParen, span) if span.start == end => {}
+            _ => {
+                self.unget_token(tok);
+                return Ok(None);
+            }
+        }
+
+        let mut words = vec![];
+        loop {
+            match self.next_token()? {
+                Token::Word(word) => words.push(word),
+                Token::Assignment(assign) => words.push((&assign).into()),
+                Token::Newline(..) => {}
+                Token::Operator(Operator::RightParen, ..) => return Ok(Some(words)),
+                tok => {
+                    self.unget_token(tok);
+                    return Err(self.unexpected_next_token(ParseErrorContext::ExpectingRightParen));
+                }
+            }
+        }
+    }
+
     /// Consumes an optional sequence of newline tokens.
     fn linebreak(&mut self) -> Fallible<()> {
         self.newline_list()?;
@@ -552,7 +1196,7 @@ impl<R: Read> Parser<R> {
     fn number(&mut self) -> Fallible<Option<usize>> {
         let t = self.next_token()?;
         if let Some(word) = t.as_single_literal_word_string() {
-            if let Ok(num) = usize::from_str_radix(word, 10) {
+            if let Ok(num) = word.parse::<usize>() {
                 return Ok(Some(num));
             }
         }
@@ -582,6 +1226,13 @@ impl<R: Read> Parser<R> {
         }
     }
 
+    /// Matches an optional `;` followed by optional newlines, or
+    /// a sequence of newlines.
+    fn sequential_sep(&mut self) -> Fallible<()> {
+        self.next_token_is_operator(&[Operator::Semicolon])?;
+        self.linebreak()
+    }
+
     /// Matches an optional separator, returning true if that separator
     /// is async, or false if it is sync or not present.
     fn separator_is_async(&mut self) -> Fallible<bool> {
@@ -594,3 +1245,15 @@ enum Separator {
     Sync,
     Async,
 }
+
+/// If word consists of a single unquoted literal, return it
+fn unquoted_literal(word: &[WordComponent]) -> Option<&str> {
+    match word {
+        [WordComponent {
+            kind: WordComponentKind::Literal(literal),
+            quoting: Quoting::Unquoted,
+            ..
+        }] => Some(literal.as_str()),
+        _ => None,
+    }
+}
@@ -1,10 +1,13 @@
 use super::*;
 use failure::Fallible;
 use pretty_assertions::assert_eq;
-use shell_lexer::{Assignment, Pos, Span, Token, WordComponent, WordComponentKind};
+use shell_lexer::{
+    Assignment, Operator, ParamExpr, ParamOper, Pos, Quoting, Span, Token, WordComponent,
+    WordComponentKind,
+};
 
 fn parse(text: &str) -> Fallible<Command> {
-    let mut parser = Parser::new(text.as_bytes());
+    let mut parser = Parser::new("test", text.as_bytes());
     parser.parse()
 }
 
@@ -17,22 +20,30 @@ fn test_assign() {
             assignments: vec![
                 Assignment {
                     name: "FOO".to_owned(),
+                    subscript: None,
+                    array: None,
+                    append: false,
                     span: Span::new_to(0, 0, 4),
                     value: vec![WordComponent {
                         kind: WordComponentKind::literal("bar"),
                         span: Span::new_to(0, 4, 6),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]
                 },
                 Assignment {
                     name: "BAR".to_owned(),
+                    subscript: None,
+                    array: None,
+                    append: false,
                     span: Span::new_to(0, 8, 12),
                     value: vec![WordComponent {
                         kind: WordComponentKind::literal("baz"),
                         span: Span::new_to(0, 12, 14),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }]
                 },
             ],
@@ -42,20 +53,23 @@ fn test_assign() {
                     kind: WordComponentKind::literal("echo"),
                     span: Span::new_to(0, 16, 19),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }],
                 vec![
                     WordComponent {
                         kind: WordComponentKind::literal("WOOT="),
                         span: Span::new_to(0, 21, 26),
                         splittable: true,
-                        remove_backslash: false
+                        remove_backslash: false,
+                        quoting: Quoting::Unquoted
                     },
                     WordComponent {
                         kind: WordComponentKind::literal("woot"),
                         span: Span::new_to(0, 26, 29),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     },
                 ],
             ]
@@ -76,19 +90,22 @@ fn test_parse() {
                     kind: WordComponentKind::literal("ls"),
                     span: Span::new_to(0, 0, 1),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }],
                 vec![WordComponent {
                     kind: WordComponentKind::literal("-l"),
                     span: Span::new_to(0, 3, 4),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }],
                 vec![WordComponent {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 6, 8),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }],
             ]
         }))
@@ -109,7 +126,8 @@ fn test_two_lines() {
                         kind: WordComponentKind::literal("false"),
                         span: Span::new_to(0, 0, 4),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }],]
                 })),
                 Command::from(CommandType::SimpleCommand(SimpleCommand {
@@ -119,7 +137,8 @@ fn test_two_lines() {
                         kind: WordComponentKind::literal("true"),
                         span: Span::new_to(1, 0, 3),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }],]
                 }))
             ]
@@ -140,6 +159,7 @@ fn redirect_out() {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 6, 8),
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                     splittable: true,
                 }],
                 input: false,
@@ -151,12 +171,56 @@ fn redirect_out() {
                 kind: WordComponentKind::literal("echo"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
 }
 
+#[test]
+fn here_string() {
+    let list = parse("grep foo <<< \"$bar\"").unwrap();
+    assert_eq!(
+        list,
+        Command::from(CommandType::SimpleCommand(SimpleCommand {
+            assignments: vec![],
+            redirects: vec![Redirection::HereString(HereString {
+                fd_number: 0,
+                word: vec![WordComponent {
+                    kind: WordComponentKind::ParamExpand(ParamExpr {
+                        kind: ParamOper::Get,
+                        subscript: None,
+                        indirect: false,
+                        name: "bar".to_owned(),
+                        word: vec![],
+                    }),
+                    span: Span::new_to(0, 13, 18),
+                    splittable: false,
+                    remove_backslash: false,
+                    quoting: Quoting::Double
+                }],
+            })],
+            words: vec![
+                vec![WordComponent {
+                    kind: WordComponentKind::literal("grep"),
+                    span: Span::new_to(0, 0, 3),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }],
+                vec![WordComponent {
+                    kind: WordComponentKind::literal("foo"),
+                    span: Span::new_to(0, 5, 7),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }],
+            ]
+        }))
+    );
+}
+
 #[test]
 fn redirect_append() {
     let list = parse("echo >>foo").unwrap();
@@ -170,6 +234,7 @@ fn redirect_append() {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 7, 9),
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                     splittable: true,
                 }],
                 input: false,
@@ -181,7 +246,8 @@ fn redirect_append() {
                 kind: WordComponentKind::literal("echo"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
@@ -200,6 +266,7 @@ fn redirect_clobber() {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 7, 9),
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                     splittable: true,
                 }],
                 input: false,
@@ -211,7 +278,8 @@ fn redirect_clobber() {
                 kind: WordComponentKind::literal("echo"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
@@ -230,6 +298,7 @@ fn redirect_input() {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 6, 8),
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                     splittable: true,
                 }],
                 input: true,
@@ -241,7 +310,8 @@ fn redirect_input() {
                 kind: WordComponentKind::literal("echo"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
@@ -262,7 +332,8 @@ fn redirect_stderr_stdout() {
                 kind: WordComponentKind::literal("echo"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
@@ -283,7 +354,8 @@ fn redirect_dup_for_input() {
                 kind: WordComponentKind::literal("echo"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
@@ -302,6 +374,7 @@ fn redirect_input_and_output() {
                     kind: WordComponentKind::literal("file"),
                     span: Span::new_to(0, 7, 10),
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                     splittable: true,
                 }],
                 input: true,
@@ -313,7 +386,8 @@ fn redirect_input_and_output() {
                 kind: WordComponentKind::literal("echo"),
                 span: Span::new_to(0, 0, 3),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
@@ -331,9 +405,28 @@ fn redirect_fd_not_number() {
                 kind: WordComponentKind::literal("file"),
                 span: Span::new_to(0, 7, 10),
                 remove_backslash: true,
+                quoting: Quoting::Unquoted,
                 splittable: true,
             }]),
-            ParseErrorContext::FdRedirectionExpectsNumber
+            ParseErrorContext::FdRedirectionExpectsNumber,
+            "test".to_owned()
+        )
+    );
+}
+
+#[test]
+fn trailing_tokens() {
+    // A token that cannot continue the program is an error rather
+    // than silently ending it after the first line
+    assert_eq!(
+        parse("x=1\n&& echo")
+            .unwrap_err()
+            .downcast::<ParseErrorKind>()
+            .unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Operator(Operator::AndIf, Span::new_to(1, 0, 1)),
+            ParseErrorContext::List,
+            "test".to_owned()
         )
     );
 }
@@ -347,7 +440,35 @@ fn bad_form_subshell() {
             .unwrap(),
         ParseErrorKind::UnexpectedToken(
             Token::Eof(Pos::new(0, 5)),
-            ParseErrorContext::ExpectingRightParen
+            ParseErrorContext::ExpectingRightParen,
+            "test".to_owned()
+        )
+    );
+}
+
+#[test]
+fn validate() {
+    assert!(Parser::validate("test", "if true; then echo yes; fi").is_ok());
+    assert!(Parser::validate("test", "while true; do echo yes; done").is_ok());
+    assert!(Parser::validate("test", "").is_ok());
+
+    let err = Parser::validate("test", "echo 'unterminated").unwrap_err();
+    assert!(is_incomplete_input(&err));
+    let err = Parser::validate("test", "true &&\n").unwrap_err();
+    assert!(is_incomplete_input(&err));
+    let err = Parser::validate("test", "if true; then\n echo yes\n").unwrap_err();
+    assert!(is_incomplete_input(&err));
+    let err = Parser::validate("test", "if true; then echo yes; done").unwrap_err();
+    assert!(!is_incomplete_input(&err));
+
+    let err = Parser::validate("test", "echo )").unwrap_err();
+    assert!(!is_incomplete_input(&err));
+    assert_eq!(
+        err.downcast::<ParseErrorKind>().unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Operator(Operator::RightParen, Span::new_to(0, 5, 5)),
+            ParseErrorContext::List,
+            "test".to_owned()
         )
     );
 }
@@ -365,13 +486,49 @@ fn subshell() {
                     kind: WordComponentKind::literal("echo"),
                     span: Span::new_to(0, 1, 4),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }],]
             }))]
         }))
     );
 }
 
+#[test]
+fn subshell_sep_newline() {
+    let word = |text: &str, span| {
+        Command::from(CommandType::SimpleCommand(SimpleCommand {
+            assignments: vec![],
+            redirects: vec![],
+            words: vec![vec![WordComponent {
+                kind: WordComponentKind::literal(text),
+                span,
+                splittable: true,
+                remove_backslash: true,
+                quoting: Quoting::Unquoted,
+            }]],
+        }))
+    };
+    assert_eq!(
+        parse("( a\n b )").unwrap(),
+        Command::from(CommandType::Subshell(CompoundList {
+            commands: vec![
+                word("a", Span::new_to(0, 2, 2)),
+                word("b", Span::new_to(1, 1, 1))
+            ]
+        }))
+    );
+    assert_eq!(
+        parse("(\n\ta\n\n\tb\n)").unwrap(),
+        Command::from(CommandType::Subshell(CompoundList {
+            commands: vec![
+                word("a", Span::new_to(1, 1, 1)),
+                word("b", Span::new_to(3, 1, 1))
+            ]
+        }))
+    );
+}
+
 #[test]
 fn subshell_redirected() {
     let list = parse("(echo)>foo").unwrap();
@@ -385,6 +542,7 @@ fn subshell_redirected() {
                     kind: WordComponentKind::literal("foo"),
                     span: Span::new_to(0, 7, 9),
                     remove_backslash: true,
+                    quoting: Quoting::Unquoted,
                     splittable: true,
                 }],
                 input: false,
@@ -400,7 +558,8 @@ fn subshell_redirected() {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 1, 4),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }],]
                 }))]
             })
@@ -425,7 +584,8 @@ fn brace_group_no_spaces() {
                 kind: WordComponentKind::literal("{echo}"),
                 span: Span::new_to(0, 0, 5),
                 splittable: true,
-                remove_backslash: true
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
             }],]
         }))
     );
@@ -440,7 +600,8 @@ fn bad_form_brace_group() {
             .unwrap(),
         ParseErrorKind::UnexpectedToken(
             Token::Eof(Pos::new(0, 6)),
-            ParseErrorContext::ExpectingRightBrace
+            ParseErrorContext::ExpectingRightBrace,
+            "test".to_owned()
         )
     );
 }
@@ -458,7 +619,8 @@ fn brace_group() {
                     kind: WordComponentKind::literal("echo"),
                     span: Span::new_to(0, 2, 5),
                     splittable: true,
-                    remove_backslash: true
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
                 }],]
             }))]
         }))
@@ -479,7 +641,8 @@ fn brace_group_sep_list() {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(0, 2, 5),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }],]
                 })),
                 Command::from(CommandType::SimpleCommand(SimpleCommand {
@@ -489,7 +652,8 @@ fn brace_group_sep_list() {
                         kind: WordComponentKind::literal("boo"),
                         span: Span::new_to(0, 9, 11),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }],]
                 })),
             ]
@@ -511,7 +675,8 @@ fn brace_group_sep_newlien() {
                         kind: WordComponentKind::literal("echo"),
                         span: Span::new_to(1, 1, 4),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }],]
                 })),
                 Command::from(CommandType::SimpleCommand(SimpleCommand {
@@ -521,10 +686,547 @@ fn brace_group_sep_newlien() {
                         kind: WordComponentKind::literal("boo"),
                         span: Span::new_to(2, 1, 3),
                         splittable: true,
-                        remove_backslash: true
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
                     }],]
                 })),
             ]
         }))
     );
 }
+
+#[test]
+fn for_loop() {
+    let list = parse("for i in a b; do echo; done").unwrap();
+    assert_eq!(
+        list,
+        Command::from(CommandType::ForEach(ForEach {
+            name: "i".to_owned(),
+            wordlist: vec![
+                vec![WordComponent {
+                    kind: WordComponentKind::literal("a"),
+                    span: Span::new_to(0, 9, 9),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }],
+                vec![WordComponent {
+                    kind: WordComponentKind::literal("b"),
+                    span: Span::new_to(0, 11, 11),
+                    splittable: true,
+                    remove_backslash: true,
+                    quoting: Quoting::Unquoted
+                }],
+            ],
+            body: CompoundList {
+                commands: vec![Command::from(CommandType::SimpleCommand(SimpleCommand {
+                    assignments: vec![],
+                    redirects: vec![],
+                    words: vec![vec![WordComponent {
+                        kind: WordComponentKind::literal("echo"),
+                        span: Span::new_to(0, 17, 20),
+                        splittable: true,
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
+                    }],]
+                }))]
+            }
+        }))
+    );
+}
+
+#[test]
+fn for_loop_positional() {
+    let list = parse("for i\ndo\necho\ndone").unwrap();
+    assert_eq!(
+        list,
+        Command::from(CommandType::ForEach(ForEach {
+            name: "i".to_owned(),
+            wordlist: vec![vec![WordComponent {
+                kind: WordComponentKind::ParamExpand(ParamExpr {
+                    kind: ParamOper::Get,
+                    subscript: None,
+                    indirect: false,
+                    name: "@".to_owned(),
+                    word: vec![],
+                }),
+                span: Span::new_to(0, 4, 4),
+                splittable: false,
+                remove_backslash: false,
+                quoting: Quoting::Double
+            }]],
+            body: CompoundList {
+                commands: vec![Command::from(CommandType::SimpleCommand(SimpleCommand {
+                    assignments: vec![],
+                    redirects: vec![],
+                    words: vec![vec![WordComponent {
+                        kind: WordComponentKind::literal("echo"),
+                        span: Span::new_to(2, 0, 3),
+                        splittable: true,
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
+                    }],]
+                }))]
+            }
+        }))
+    );
+}
+
+#[test]
+fn while_loop() {
+    let list = parse("while true; do echo; done").unwrap();
+    assert_eq!(
+        list,
+        Command::from(CommandType::WhileLoop(WhileLoop {
+            condition: CompoundList {
+                commands: vec![Command::from(CommandType::SimpleCommand(SimpleCommand {
+                    assignments: vec![],
+                    redirects: vec![],
+                    words: vec![vec![WordComponent {
+                        kind: WordComponentKind::literal("true"),
+                        span: Span::new_to(0, 6, 9),
+                        splittable: true,
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
+                    }],]
+                }))]
+            },
+            body: CompoundList {
+                commands: vec![Command::from(CommandType::SimpleCommand(SimpleCommand {
+                    assignments: vec![],
+                    redirects: vec![],
+                    words: vec![vec![WordComponent {
+                        kind: WordComponentKind::literal("echo"),
+                        span: Span::new_to(0, 15, 18),
+                        splittable: true,
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
+                    }],]
+                }))]
+            }
+        }))
+    );
+}
+
+#[test]
+fn while_loop_missing_done() {
+    assert_eq!(
+        parse("while true; do echo")
+            .unwrap_err()
+            .downcast::<ParseErrorKind>()
+            .unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Eof(Pos::new(0, 19)),
+            ParseErrorContext::ExpectingDone,
+            "test".to_owned()
+        )
+    );
+}
+
+#[test]
+fn if_clause() {
+    assert_eq!(
+        dump_ast(&parse("if a; then b; fi").unwrap()),
+        "If\n\
+         \x20 condition:\n\
+         \x20   SimpleCommand \"a\"\n\
+         \x20 then:\n\
+         \x20   SimpleCommand \"b\"\n"
+    );
+    assert_eq!(
+        dump_ast(&parse("if a\nthen b\nelif c; then d; else e; f\nfi").unwrap()),
+        "If\n\
+         \x20 condition:\n\
+         \x20   SimpleCommand \"a\"\n\
+         \x20 then:\n\
+         \x20   SimpleCommand \"b\"\n\
+         \x20 else:\n\
+         \x20   If\n\
+         \x20     condition:\n\
+         \x20       SimpleCommand \"c\"\n\
+         \x20     then:\n\
+         \x20       SimpleCommand \"d\"\n\
+         \x20     else:\n\
+         \x20       SimpleCommand \"e\"\n\
+         \x20       SimpleCommand \"f\"\n"
+    );
+}
+
+#[test]
+fn if_clause_errors() {
+    assert_eq!(
+        parse("if true; echo")
+            .unwrap_err()
+            .downcast::<ParseErrorKind>()
+            .unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Eof(Pos::new(0, 13)),
+            ParseErrorContext::ExpectingThen,
+            "test".to_owned()
+        )
+    );
+    assert_eq!(
+        parse("if true; then echo; else echo")
+            .unwrap_err()
+            .downcast::<ParseErrorKind>()
+            .unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Eof(Pos::new(0, 29)),
+            ParseErrorContext::ExpectingFi,
+            "test".to_owned()
+        )
+    );
+    assert!(parse("if true; then echo; fi fi").is_err());
+}
+
+#[test]
+fn comments_in_compound_commands() {
+    let body = "\
+        # leading comment\n\
+        \n\
+        for i in a b # the words\n\
+        # before do\n\
+        do\n\
+        \x20 # before the body\n\
+        \n\
+        \x20 echo $i\n\
+        # before done\n\
+        done\n\
+        while true\n\
+        \n\
+        # before do\n\
+        do\n\
+        \x20 { # inside a group\n\
+        \n\
+        \x20   echo\n\
+        \x20 }\n\
+        done # trailing\n\
+        if true # the condition\n\
+        \n\
+        then\n\
+        \x20 # before the body\n\
+        \x20 echo yes\n\
+        \n\
+        # before else\n\
+        else # no\n\
+        \x20 echo no\n\
+        # before fi\n\
+        fi\n";
+    assert_eq!(
+        dump_ast(&parse(body).unwrap()),
+        "Program\n\
+         \x20 ForEach i in \"a\" \"b\"\n\
+         \x20   body:\n\
+         \x20     SimpleCommand \"echo\" $i\n\
+         \x20 WhileLoop\n\
+         \x20   condition:\n\
+         \x20     SimpleCommand \"true\"\n\
+         \x20   body:\n\
+         \x20     BraceGroup\n\
+         \x20       SimpleCommand \"echo\"\n\
+         \x20 If\n\
+         \x20   condition:\n\
+         \x20     SimpleCommand \"true\"\n\
+         \x20   then:\n\
+         \x20     SimpleCommand \"echo\" \"yes\"\n\
+         \x20   else:\n\
+         \x20     SimpleCommand \"echo\" \"no\"\n"
+    );
+}
+
+#[test]
+fn select() {
+    let list = parse("select i in a; do echo; done").unwrap();
+    assert_eq!(
+        list,
+        Command::from(CommandType::Select(Select {
+            name: "i".to_owned(),
+            wordlist: vec![vec![WordComponent {
+                kind: WordComponentKind::literal("a"),
+                span: Span::new_to(0, 12, 12),
+                splittable: true,
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
+            }]],
+            body: CompoundList {
+                commands: vec![Command::from(CommandType::SimpleCommand(SimpleCommand {
+                    assignments: vec![],
+                    redirects: vec![],
+                    words: vec![vec![WordComponent {
+                        kind: WordComponentKind::literal("echo"),
+                        span: Span::new_to(0, 18, 21),
+                        splittable: true,
+                        remove_backslash: true,
+                        quoting: Quoting::Unquoted
+                    }],]
+                }))]
+            }
+        }))
+    );
+}
+
+#[test]
+fn arithmetic_command() {
+    let list = parse("((i += 2))").unwrap();
+    assert_eq!(
+        list,
+        Command::from(CommandType::Arithmetic("i += 2".to_owned()))
+    );
+}
+
+#[test]
+fn conditional_command() {
+    fn literal(s: &str, col: usize, endcol: usize) -> WordComponent {
+        WordComponent {
+            kind: WordComponentKind::literal(s),
+            span: Span::new_to(0, col, endcol),
+            splittable: true,
+            remove_backslash: true,
+            quoting: Quoting::Unquoted,
+        }
+    }
+    fn operator(s: &str, col: usize) -> WordComponent {
+        WordComponent {
+            kind: WordComponentKind::literal(s),
+            span: Span::new_to(0, col, col),
+            splittable: false,
+            remove_backslash: false,
+            quoting: Quoting::Unquoted,
+        }
+    }
+
+    let list = parse("[[ -n a || b =~ ^(c|d) ]]").unwrap();
+    assert_eq!(
+        list,
+        Command::from(CommandType::Conditional(ConditionalExpr::Or(
+            Box::new(ConditionalExpr::Unary {
+                operator: "-n".to_owned(),
+                operand: vec![literal("a", 6, 6)],
+            }),
+            Box::new(ConditionalExpr::Binary {
+                operator: "=~".to_owned(),
+                left: vec![literal("b", 11, 11)],
+                right: vec![
+                    literal("^", 16, 16),
+                    operator("(", 17),
+                    literal("c", 18, 18),
+                    operator("|", 19),
+                    literal("d", 20, 20),
+                    operator(")", 21),
+                ],
+            })
+        )))
+    );
+
+    assert_eq!(
+        parse("[[ ! ( a ) ]]").unwrap(),
+        Command::from(CommandType::Conditional(ConditionalExpr::Not(Box::new(
+            ConditionalExpr::Word(vec![literal("a", 7, 7)])
+        ))))
+    );
+
+    assert!(parse("[[ a == ]]").is_err());
+    assert!(parse("[[ a").is_err());
+}
+
+#[test]
+fn timed_pipeline() {
+    let command = parse("time ! true").unwrap();
+    match command.command {
+        CommandType::Pipeline(pipeline) => {
+            assert!(pipeline.timed);
+            assert!(pipeline.inverted);
+            assert_eq!(pipeline.commands.len(), 1);
+        }
+        _ => panic!("expected a pipeline, got {:?}", command),
+    }
+
+    // `time` is only reserved in command position
+    match parse("echo time").unwrap().command {
+        CommandType::SimpleCommand(cmd) => assert_eq!(cmd.words.len(), 2),
+        command => panic!("expected a simple command, got {:?}", command),
+    }
+}
+
+#[test]
+fn dump() {
+    assert_eq!(
+        dump_ast(&parse("if true; then echo hi; fi").unwrap()),
+        "If\n\
+         \x20 condition:\n\
+         \x20   SimpleCommand \"true\"\n\
+         \x20 then:\n\
+         \x20   SimpleCommand \"echo\" \"hi\"\n"
+    );
+    assert_eq!(
+        dump_ast(&parse("a || b | c > out &\nwhile [[ -n $x ]]; do x=; done").unwrap()),
+        "Program\n\
+         \x20 If &\n\
+         \x20   condition:\n\
+         \x20     SimpleCommand \"a\"\n\
+         \x20   else:\n\
+         \x20     Pipeline\n\
+         \x20       SimpleCommand \"b\"\n\
+         \x20       SimpleCommand \"c\"\n\
+         \x20         Redirect 1 \"out\" output\n\
+         \x20 WhileLoop\n\
+         \x20   condition:\n\
+         \x20     Conditional\n\
+         \x20       Unary -n $x\n\
+         \x20   body:\n\
+         \x20     SimpleCommand x=\n"
+    );
+}
+
+#[test]
+fn and_or_newline() {
+    // A newline may follow the operator, and a chain of operators
+    // associates to the left
+    assert_eq!(
+        dump_ast(&parse("true &&\nfalse ||\n\n\techo no").unwrap()),
+        "If\n\
+         \x20 condition:\n\
+         \x20   If\n\
+         \x20     condition:\n\
+         \x20       SimpleCommand \"true\"\n\
+         \x20     then:\n\
+         \x20       SimpleCommand \"false\"\n\
+         \x20 else:\n\
+         \x20   SimpleCommand \"echo\" \"no\"\n"
+    );
+
+    assert_eq!(
+        parse("true &&")
+            .unwrap_err()
+            .downcast::<ParseErrorKind>()
+            .unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Eof(Pos::new(0, 7)),
+            ParseErrorContext::ExpectingPipelineAfter(Operator::AndIf),
+            "test".to_owned()
+        )
+    );
+}
+
+#[test]
+fn case_paren_pattern() {
+    let body = "\
+        case $x in\n\
+        (a | b) echo ab ;;\n\
+        \n\
+        (c)\n\
+        \x20 echo c\n\
+        \x20 ;;\n\
+        *)\n\
+        esac";
+    assert_eq!(
+        dump_ast(&parse(body).unwrap()),
+        "Case $x\n\
+         \x20 \"a\" | \"b\") ;;\n\
+         \x20   SimpleCommand \"echo\" \"ab\"\n\
+         \x20 \"c\") ;;\n\
+         \x20   SimpleCommand \"echo\" \"c\"\n\
+         \x20 \"*\") ;;\n"
+    );
+
+    assert_eq!(
+        parse("case a in (a echo; esac")
+            .unwrap_err()
+            .downcast::<ParseErrorKind>()
+            .unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Word(vec![WordComponent {
+                kind: WordComponentKind::literal("echo"),
+                span: Span::new_to(0, 13, 16),
+                splittable: true,
+                remove_backslash: true,
+                quoting: Quoting::Unquoted
+            }]),
+            ParseErrorContext::ExpectingRightParen,
+            "test".to_owned()
+        )
+    );
+}
+
+#[test]
+fn case_fall_through() {
+    let command = parse("case a in a) echo a;& b) echo b;;& c) echo c;; esac").unwrap();
+    let case = match command.command {
+        CommandType::Case(case) => case,
+        command => panic!("expected a case command, got {:?}", command),
+    };
+    assert_eq!(
+        case.clauses
+            .iter()
+            .map(|clause| clause.terminator)
+            .collect::<Vec<_>>(),
+        vec![
+            CaseTerminator::FallThrough,
+            CaseTerminator::Continue,
+            CaseTerminator::Break
+        ]
+    );
+
+    assert!(parse("case a in a) echo a esac").is_err());
+    assert!(parse("case a in a) echo a ;;").is_err());
+}
+
+#[test]
+fn double_semicolon_only_in_case() {
+    // `;;` is a single operator that only terminates a case clause,
+    // rather than two empty commands
+    assert_eq!(
+        parse("a ;; b")
+            .unwrap_err()
+            .downcast::<ParseErrorKind>()
+            .unwrap(),
+        ParseErrorKind::UnexpectedToken(
+            Token::Operator(Operator::DoubleSemicolon, Span::new_to(0, 2, 3)),
+            ParseErrorContext::List,
+            "test".to_owned()
+        )
+    );
+    assert!(parse(";;").is_err());
+    assert!(parse("{ a ;; }").is_err());
+    assert!(parse("if a;; then b; fi").is_err());
+    assert!(parse("while a;; do b; done").is_err());
+
+    let command = parse("case x in\n a) echo a ;;\n b) echo b\n ;;\nesac; echo after").unwrap();
+    let commands = match command.command {
+        CommandType::Program(list) => list.commands,
+        command => panic!("expected a program, got {:?}", command),
+    };
+    assert_eq!(commands.len(), 2);
+    match &commands[0].command {
+        CommandType::Case(case) => assert_eq!(
+            case.clauses
+                .iter()
+                .map(|clause| clause.terminator)
+                .collect::<Vec<_>>(),
+            vec![CaseTerminator::Break, CaseTerminator::Break]
+        ),
+        command => panic!("expected a case command, got {:?}", command),
+    }
+
+    // `;;` ends the case item after an if clause within it
+    assert_eq!(
+        dump_ast(&parse("case x in x) if a; then b; fi;; esac").unwrap()),
+        "Case \"x\"\n\
+         \x20 \"x\") ;;\n\
+         \x20   If\n\
+         \x20     condition:\n\
+         \x20       SimpleCommand \"a\"\n\
+         \x20     then:\n\
+         \x20       SimpleCommand \"b\"\n"
+    );
+}
+
+#[test]
+fn array_assignment() {
+    assert_eq!(
+        dump_ast(&parse("arr=(a 'b c'\n d) arr[1]=x arr+=() echo").unwrap()),
+        "SimpleCommand arr=(\"a\" \"b c\" \"d\") arr[\"1\"]=\"x\" arr+=() \"echo\"\n"
+    );
+
+    assert!(parse("arr=(a").is_err());
+}
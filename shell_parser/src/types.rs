@@ -23,17 +23,27 @@ pub enum CommandType {
     BraceGroup(CompoundList),
     Subshell(CompoundList),
     ForEach(ForEach),
+    Select(Select),
     If(If),
     UntilLoop(UntilLoop),
     WhileLoop(WhileLoop),
-    FunctionDefinition { name: String, body: Box<Command> },
-    // TODO: Case
+    /// `((expression))`; the expression text is evaluated arithmetically
+    Arithmetic(String),
+    /// `[[ expression ]]`
+    Conditional(ConditionalExpr),
+    FunctionDefinition {
+        name: String,
+        body: Box<Command>,
+    },
+    Case(Case),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Pipeline {
     /// true if the pipeline starts with a bang
     pub inverted: bool,
+    /// true if the pipeline is preceded by the `time` reserved word
+    pub timed: bool,
     pub commands: Vec<Command>,
 }
 
@@ -63,14 +73,92 @@ pub struct WhileLoop {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ForEach {
+    /// The name of the variable assigned on each iteration
+    pub name: String,
     pub wordlist: Vec<Vec<WordComponent>>,
     pub body: CompoundList,
 }
 
+/// The bash `select NAME in WORDS; do BODY; done` construct
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Select {
+    /// The name of the variable assigned the selected word
+    pub name: String,
+    pub wordlist: Vec<Vec<WordComponent>>,
+    pub body: CompoundList,
+}
+
+/// `case WORD in PATTERN) LIST ;; ... esac`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    /// The word that is matched against the patterns
+    pub word: Vec<WordComponent>,
+    pub clauses: Vec<CaseClause>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseClause {
+    /// The `|` separated alternative patterns
+    pub patterns: Vec<Vec<WordComponent>>,
+    pub body: CompoundList,
+    pub terminator: CaseTerminator,
+}
+
+/// How a case clause ends, which determines what happens after
+/// its body has run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseTerminator {
+    /// `;;` ends the case command.  This is also the terminator
+    /// of a final clause that is followed directly by `esac`.
+    Break,
+    /// `;&` runs the body of the next clause without testing
+    /// its patterns
+    FallThrough,
+    /// `;;&` tests the patterns of the following clauses
+    Continue,
+}
+
+impl std::fmt::Display for CaseTerminator {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CaseTerminator::Break => write!(fmt, ";;"),
+            CaseTerminator::FallThrough => write!(fmt, ";&"),
+            CaseTerminator::Continue => write!(fmt, ";;&"),
+        }
+    }
+}
+
+/// An expression from the bash `[[ expression ]]` command.
+/// The words are expanded without field splitting or pathname
+/// expansion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionalExpr {
+    /// A lone word; true if its expansion is non-empty
+    Word(Vec<WordComponent>),
+    /// `-f word` and friends
+    Unary {
+        operator: String,
+        operand: Vec<WordComponent>,
+    },
+    /// `word == pattern`, `word =~ regex`, `word -eq word` and friends
+    Binary {
+        operator: String,
+        left: Vec<WordComponent>,
+        right: Vec<WordComponent>,
+    },
+    /// `! expression`
+    Not(Box<ConditionalExpr>),
+    /// `expression && expression`
+    And(Box<ConditionalExpr>, Box<ConditionalExpr>),
+    /// `expression || expression`
+    Or(Box<ConditionalExpr>, Box<ConditionalExpr>),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Redirection {
     File(FileRedirection),
     Fd(FdDuplication),
+    HereString(HereString),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -87,6 +175,14 @@ pub struct FileRedirection {
     pub append: bool,
 }
 
+/// `<<< word`: the expanded word, followed by a newline,
+/// is supplied as the input on `fd_number`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HereString {
+    pub fd_number: usize,
+    pub word: Vec<WordComponent>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FdDuplication {
     /// Dup `src_fd_number` ...
@@ -108,7 +204,7 @@ impl From<CommandType> for Command {
 impl From<Pipeline> for Command {
     fn from(pipeline: Pipeline) -> Command {
         // Simplify a pipeline to the command itself if possible
-        if !pipeline.inverted && pipeline.commands.len() == 1 {
+        if !pipeline.inverted && !pipeline.timed && pipeline.commands.len() == 1 {
             pipeline.commands.into_iter().next().unwrap()
         } else {
             CommandType::Pipeline(pipeline).into()
@@ -0,0 +1,462 @@
+//! Evaluates shell arithmetic expressions, as used by the `((expr))`
+//! arithmetic command.
+//! With reference to https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_04
+//! and the bash extensions for increment/decrement and exponentiation.
+use crate::Environment;
+use failure::{bail, Fallible};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ArithToken {
+    Number(isize),
+    Ident(String),
+    Op(&'static str),
+}
+
+/// Operators, ordered such that longer operators are matched first
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "^",
+    "|", "?", ":", "(", ")", ",",
+];
+
+const ASSIGNMENT_OPERATORS: &[&str] = &[
+    "=", "+=", "-=", "*=", "/=", "%=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+fn tokenize(expr: &str) -> Fallible<Vec<ArithToken>> {
+    let mut tokens = vec![];
+    let mut remain = expr;
+
+    'next: loop {
+        remain = remain.trim_start();
+        let c = match remain.chars().next() {
+            None => return Ok(tokens),
+            Some(c) => c,
+        };
+
+        if c.is_ascii_digit() {
+            let len = remain
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(remain.len());
+            tokens.push(ArithToken::Number(parse_number(&remain[..len])?));
+            remain = &remain[len..];
+            continue;
+        }
+
+        // We allow `$name` and `${name}` as well as the bare `name`
+        let (skip, terminator) = if remain.starts_with("${") {
+            (2, Some('}'))
+        } else if remain.starts_with('$') {
+            (1, None)
+        } else {
+            (0, None)
+        };
+        let ident = &remain[skip..];
+        if ident.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let len = ident
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(ident.len());
+            tokens.push(ArithToken::Ident(ident[..len].to_owned()));
+            remain = &ident[len..];
+            if let Some(terminator) = terminator {
+                if !remain.starts_with(terminator) {
+                    bail!(
+                        "missing `{}` in arithmetic expression {:?}",
+                        terminator,
+                        expr
+                    );
+                }
+                remain = &remain[1..];
+            }
+            continue;
+        }
+
+        for &op in OPERATORS {
+            if remain.starts_with(op) {
+                tokens.push(ArithToken::Op(op));
+                remain = &remain[op.len()..];
+                continue 'next;
+            }
+        }
+
+        bail!("syntax error in arithmetic expression at {:?}", remain);
+    }
+}
+
+/// Parse an integer constant, which may be decimal, octal (with
+/// a leading zero) or hexadecimal (with a leading 0x)
+fn parse_number(s: &str) -> Fallible<isize> {
+    let result = if s.starts_with("0x") || s.starts_with("0X") {
+        isize::from_str_radix(&s[2..], 16)
+    } else if s.len() > 1 && s.starts_with('0') {
+        isize::from_str_radix(&s[1..], 8)
+    } else {
+        s.parse()
+    };
+    match result {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("invalid number {:?} in arithmetic expression", s),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(isize),
+    Variable(String),
+    /// A unary operator: one of `- + ! ~`
+    Unary(&'static str, Box<Expr>),
+    /// `++name`, `--name`, `name++`, `name--`
+    IncDec {
+        name: String,
+        delta: isize,
+        prefix: bool,
+    },
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `name = value` or `name op= value`
+    Assign(String, &'static str, Box<Expr>),
+    Comma(Box<Expr>, Box<Expr>),
+}
+
+/// Returns the binding power for a binary operator; higher values
+/// bind more tightly.
+fn binary_precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        "**" => 11,
+        _ => return None,
+    })
+}
+
+struct ArithParser {
+    tokens: Vec<ArithToken>,
+    idx: usize,
+}
+
+impl ArithParser {
+    fn peek(&self, offset: usize) -> Option<&ArithToken> {
+        self.tokens.get(self.idx + offset)
+    }
+
+    fn next(&mut self) -> Option<ArithToken> {
+        let tok = self.tokens.get(self.idx).cloned();
+        if tok.is_some() {
+            self.idx += 1;
+        }
+        tok
+    }
+
+    fn next_is_op(&mut self, op: &str) -> bool {
+        match self.peek(0) {
+            Some(ArithToken::Op(candidate)) if *candidate == op => {
+                self.idx += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Fallible<()> {
+        if self.next_is_op(op) {
+            Ok(())
+        } else {
+            bail!("expected `{}` in arithmetic expression", op)
+        }
+    }
+
+    fn expression(&mut self) -> Fallible<Expr> {
+        let mut expr = self.assignment()?;
+        while self.next_is_op(",") {
+            expr = Expr::Comma(Box::new(expr), Box::new(self.assignment()?));
+        }
+        Ok(expr)
+    }
+
+    fn assignment(&mut self) -> Fallible<Expr> {
+        if let (Some(ArithToken::Ident(name)), Some(ArithToken::Op(op))) =
+            (self.peek(0), self.peek(1))
+        {
+            if ASSIGNMENT_OPERATORS.contains(op) {
+                let name = name.clone();
+                let op = *op;
+                self.idx += 2;
+                let value = self.assignment()?;
+                return Ok(Expr::Assign(name, op, Box::new(value)));
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Fallible<Expr> {
+        let condition = self.binary(1)?;
+        if self.next_is_op("?") {
+            let if_true = self.assignment()?;
+            self.expect_op(":")?;
+            let if_false = self.conditional()?;
+            Ok(Expr::Conditional(
+                Box::new(condition),
+                Box::new(if_true),
+                Box::new(if_false),
+            ))
+        } else {
+            Ok(condition)
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Fallible<Expr> {
+        let mut lhs = self.unary()?;
+        while let Some(&ArithToken::Op(op)) = self.peek(0) {
+            let precedence = match binary_precedence(op) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
+            };
+            self.idx += 1;
+            // `**` is right associative; everything else is left associative
+            let next_min = if op == "**" {
+                precedence
+            } else {
+                precedence + 1
+            };
+            let rhs = self.binary(next_min)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Fallible<Expr> {
+        for op in &["++", "--"] {
+            if self.next_is_op(op) {
+                return match self.next() {
+                    Some(ArithToken::Ident(name)) => Ok(Expr::IncDec {
+                        name,
+                        delta: if *op == "++" { 1 } else { -1 },
+                        prefix: true,
+                    }),
+                    _ => bail!("`{}` must be followed by a variable name", op),
+                };
+            }
+        }
+        for &op in &["-", "+", "!", "~"] {
+            if self.next_is_op(op) {
+                return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Fallible<Expr> {
+        let expr = self.primary()?;
+        if let Expr::Variable(name) = &expr {
+            for op in &["++", "--"] {
+                if self.next_is_op(op) {
+                    return Ok(Expr::IncDec {
+                        name: name.clone(),
+                        delta: if *op == "++" { 1 } else { -1 },
+                        prefix: false,
+                    });
+                }
+            }
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Fallible<Expr> {
+        match self.next() {
+            Some(ArithToken::Number(n)) => Ok(Expr::Number(n)),
+            Some(ArithToken::Ident(name)) => Ok(Expr::Variable(name)),
+            Some(ArithToken::Op("(")) => {
+                let expr = self.expression()?;
+                self.expect_op(")")?;
+                Ok(expr)
+            }
+            Some(ArithToken::Op(op)) => bail!("unexpected `{}` in arithmetic expression", op),
+            None => bail!("unexpected end of arithmetic expression"),
+        }
+    }
+}
+
+fn parse(expr: &str) -> Fallible<Expr> {
+    let mut parser = ArithParser {
+        tokens: tokenize(expr)?,
+        idx: 0,
+    };
+    if parser.peek(0).is_none() {
+        // An empty expression evaluates to zero
+        return Ok(Expr::Number(0));
+    }
+    let result = parser.expression()?;
+    if let Some(tok) = parser.peek(0) {
+        bail!("unexpected {:?} in arithmetic expression", tok);
+    }
+    Ok(result)
+}
+
+fn get_variable(name: &str, env: &Environment) -> Fallible<isize> {
+    match env.get_str(name)? {
+        None => Ok(0),
+        Some(value) => {
+            let value = value.trim();
+            if value.is_empty() {
+                Ok(0)
+            } else {
+                parse_number(value)
+            }
+        }
+    }
+}
+
+fn apply_binary(op: &str, a: isize, b: isize) -> Fallible<isize> {
+    Ok(match op {
+        "|" => a | b,
+        "^" => a ^ b,
+        "&" => a & b,
+        "==" => (a == b) as isize,
+        "!=" => (a != b) as isize,
+        "<" => (a < b) as isize,
+        ">" => (a > b) as isize,
+        "<=" => (a <= b) as isize,
+        ">=" => (a >= b) as isize,
+        "<<" => a.wrapping_shl(b as u32),
+        ">>" => a.wrapping_shr(b as u32),
+        "+" => a.wrapping_add(b),
+        "-" => a.wrapping_sub(b),
+        "*" => a.wrapping_mul(b),
+        "/" | "%" if b == 0 => bail!("division by 0"),
+        "/" => a.wrapping_div(b),
+        "%" => a.wrapping_rem(b),
+        "**" => {
+            if b < 0 {
+                bail!("exponent less than 0");
+            }
+            a.wrapping_pow(b as u32)
+        }
+        _ => bail!("unknown arithmetic operator {}", op),
+    })
+}
+
+fn eval(expr: &Expr, env: &mut Environment) -> Fallible<isize> {
+    match expr {
+        Expr::Number(n) => Ok(*n),
+        Expr::Variable(name) => get_variable(name, env),
+        Expr::Unary(op, expr) => {
+            let value = eval(expr, env)?;
+            Ok(match *op {
+                "-" => value.wrapping_neg(),
+                "!" => (value == 0) as isize,
+                "~" => !value,
+                _ => value,
+            })
+        }
+        Expr::IncDec {
+            name,
+            delta,
+            prefix,
+        } => {
+            let value = get_variable(name, env)?;
+            let updated = value.wrapping_add(*delta);
+            env.assign(name.as_str(), updated.to_string())?;
+            Ok(if *prefix { updated } else { value })
+        }
+        Expr::Binary("&&", a, b) => Ok((eval(a, env)? != 0 && eval(b, env)? != 0) as isize),
+        Expr::Binary("||", a, b) => Ok((eval(a, env)? != 0 || eval(b, env)? != 0) as isize),
+        Expr::Binary(op, a, b) => {
+            let a = eval(a, env)?;
+            let b = eval(b, env)?;
+            apply_binary(op, a, b)
+        }
+        Expr::Conditional(condition, if_true, if_false) => {
+            if eval(condition, env)? != 0 {
+                eval(if_true, env)
+            } else {
+                eval(if_false, env)
+            }
+        }
+        Expr::Assign(name, op, value) => {
+            let value = eval(value, env)?;
+            let value = if *op == "=" {
+                value
+            } else {
+                let current = get_variable(name, env)?;
+                apply_binary(&op[..op.len() - 1], current, value)?
+            };
+            env.assign(name.as_str(), value.to_string())?;
+            Ok(value)
+        }
+        Expr::Comma(a, b) => {
+            eval(a, env)?;
+            eval(b, env)
+        }
+    }
+}
+
+/// Evaluate an arithmetic expression.  Variables are resolved from
+/// and assigned to the provided environment.
+pub fn evaluate_arithmetic(expr: &str, env: &mut Environment) -> Fallible<isize> {
+    let expr = parse(expr)?;
+    eval(&expr, env)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval_str(expr: &str) -> Fallible<isize> {
+        evaluate_arithmetic(expr, &mut Environment::new_empty())
+    }
+
+    #[test]
+    fn precedence() -> Fallible<()> {
+        assert_eq!(eval_str("1 + 2 * 3")?, 7);
+        assert_eq!(eval_str("(1 + 2) * 3")?, 9);
+        assert_eq!(eval_str("2 ** 3 ** 2")?, 512);
+        assert_eq!(eval_str("10 - 4 - 3")?, 3);
+        assert_eq!(eval_str("-2 + 5")?, 3);
+        assert_eq!(eval_str("1 < 2 && 3 > 4")?, 0);
+        assert_eq!(eval_str("1 < 2 || 3 > 4")?, 1);
+        assert_eq!(eval_str("!0")?, 1);
+        assert_eq!(eval_str("1 ? 2 : 3")?, 2);
+        assert_eq!(eval_str("0x10 + 010")?, 24);
+        assert_eq!(eval_str("")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn variables() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.set("i", "5");
+        assert_eq!(evaluate_arithmetic("i * 2", &mut env)?, 10);
+        assert_eq!(evaluate_arithmetic("$i + ${i}", &mut env)?, 10);
+        assert_eq!(evaluate_arithmetic("unset + 1", &mut env)?, 1);
+
+        assert_eq!(evaluate_arithmetic("i++", &mut env)?, 5);
+        assert_eq!(env.get_str("i")?, Some("6"));
+        assert_eq!(evaluate_arithmetic("--i", &mut env)?, 5);
+        assert_eq!(env.get_str("i")?, Some("5"));
+
+        assert_eq!(evaluate_arithmetic("j = i += 2", &mut env)?, 7);
+        assert_eq!(env.get_str("i")?, Some("7"));
+        assert_eq!(env.get_str("j")?, Some("7"));
+
+        // Short circuit evaluation
+        assert_eq!(evaluate_arithmetic("0 && k++", &mut env)?, 0);
+        assert_eq!(env.get_str("k")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval_str("1 / 0").unwrap_err().to_string(), "division by 0");
+        assert!(eval_str("1 +").is_err());
+        assert!(eval_str("(1").is_err());
+        assert!(eval_str("1 2").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Evaluates the primitive tests from the `[[ expression ]]`
+//! conditional command.  The `!`, `&&` and `||` operators are
+//! handled by the compiler.
+use crate::{evaluate_arithmetic, extended_pattern_matches, pattern_matches, Environment};
+use failure::{bail, Fallible};
+use regex::Regex;
+use std::fs::Metadata;
+use std::path::Path;
+
+/// Evaluate `operator` against its already expanded `operands`.
+/// Relative paths are resolved against `cwd`.
+/// A successful `=~` match assigns the matched text to `BASH_REMATCH`;
+/// the text matched by the Nth capture group is assigned to
+/// `BASH_REMATCH_N`.
+pub fn evaluate_conditional(
+    operator: &str,
+    operands: &[String],
+    cwd: &Path,
+    env: &mut Environment,
+) -> Fallible<bool> {
+    match (operator, operands) {
+        ("-n", [s]) => Ok(!s.is_empty()),
+        ("-z", [s]) => Ok(s.is_empty()),
+        ("-v", [name]) => Ok(env.get(name).is_some()),
+        (_, [path]) => file_test(operator, &cwd.join(path)),
+
+        ("==", [s, pattern]) | ("=", [s, pattern]) => env_pattern_matches(pattern, s, env),
+        ("!=", [s, pattern]) => Ok(!env_pattern_matches(pattern, s, env)?),
+        ("=~", [s, regex]) => regex_match(s, regex, env),
+        ("<", [a, b]) => Ok(a < b),
+        (">", [a, b]) => Ok(a > b),
+
+        ("-eq", [a, b]) => Ok(evaluate_arithmetic(a, env)? == evaluate_arithmetic(b, env)?),
+        ("-ne", [a, b]) => Ok(evaluate_arithmetic(a, env)? != evaluate_arithmetic(b, env)?),
+        ("-lt", [a, b]) => Ok(evaluate_arithmetic(a, env)? < evaluate_arithmetic(b, env)?),
+        ("-le", [a, b]) => Ok(evaluate_arithmetic(a, env)? <= evaluate_arithmetic(b, env)?),
+        ("-gt", [a, b]) => Ok(evaluate_arithmetic(a, env)? > evaluate_arithmetic(b, env)?),
+        ("-ge", [a, b]) => Ok(evaluate_arithmetic(a, env)? >= evaluate_arithmetic(b, env)?),
+
+        ("-nt", [a, b]) => Ok(modified(&cwd.join(a)) > modified(&cwd.join(b))),
+        ("-ot", [a, b]) => Ok(modified(&cwd.join(a)) < modified(&cwd.join(b))),
+        ("-ef", [a, b]) => Ok(same_file(&cwd.join(a), &cwd.join(b))),
+
+        _ => bail!("{}: unsupported conditional operator", operator),
+    }
+}
+
+/// Match a pattern, recognizing the extended patterns if the
+/// `extglob` option is enabled
+pub(crate) fn env_pattern_matches(pattern: &str, s: &str, env: &Environment) -> Fallible<bool> {
+    if env.options().extglob {
+        extended_pattern_matches(pattern, s)
+    } else {
+        pattern_matches(pattern, s)
+    }
+}
+
+fn regex_match(s: &str, regex: &str, env: &mut Environment) -> Fallible<bool> {
+    let regex = Regex::new(regex)?;
+    let captures = match regex.captures(s) {
+        Some(captures) => captures,
+        None => return Ok(false),
+    };
+
+    for (idx, group) in captures.iter().enumerate() {
+        let name = if idx == 0 {
+            "BASH_REMATCH".to_owned()
+        } else {
+            format!("BASH_REMATCH_{}", idx)
+        };
+        env.assign(name, group.map(|m| m.as_str()).unwrap_or(""))?;
+    }
+    Ok(true)
+}
+
+fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    path.metadata().and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn file_test(operator: &str, path: &Path) -> Fallible<bool> {
+    if operator == "-h" || operator == "-L" {
+        return Ok(path
+            .symlink_metadata()
+            .map(|meta| meta.file_type().is_symlink())
+            .unwrap_or(false));
+    }
+
+    let meta = match path.metadata() {
+        Ok(meta) => meta,
+        Err(_) => {
+            return match operator {
+                "-a" | "-b" | "-c" | "-d" | "-e" | "-f" | "-g" | "-k" | "-p" | "-r" | "-s"
+                | "-S" | "-u" | "-w" | "-x" => Ok(false),
+                _ => bail!("{}: unsupported conditional operator", operator),
+            }
+        }
+    };
+
+    Ok(match operator {
+        "-a" | "-e" => true,
+        "-d" => meta.is_dir(),
+        "-f" => meta.is_file(),
+        "-s" => meta.len() > 0,
+        "-r" => true,
+        "-w" => !meta.permissions().readonly(),
+        _ => return file_type_test(operator, &meta),
+    })
+}
+
+#[cfg(unix)]
+fn file_type_test(operator: &str, meta: &Metadata) -> Fallible<bool> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    let mode = meta.permissions().mode();
+    let file_type = meta.file_type();
+    Ok(match operator {
+        "-b" => file_type.is_block_device(),
+        "-c" => file_type.is_char_device(),
+        "-p" => file_type.is_fifo(),
+        "-S" => file_type.is_socket(),
+        "-x" => mode & 0o111 != 0,
+        "-u" => mode & 0o4000 != 0,
+        "-g" => mode & 0o2000 != 0,
+        "-k" => mode & 0o1000 != 0,
+        _ => bail!("{}: unsupported conditional operator", operator),
+    })
+}
+
+#[cfg(windows)]
+fn file_type_test(operator: &str, meta: &Metadata) -> Fallible<bool> {
+    Ok(match operator {
+        "-b" | "-c" | "-p" | "-S" | "-u" | "-g" | "-k" => false,
+        "-x" => meta.is_file(),
+        _ => bail!("{}: unsupported conditional operator", operator),
+    })
+}
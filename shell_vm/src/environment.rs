@@ -1,3 +1,4 @@
+use crate::{evaluate_arithmetic, ShellOptions};
 use caseless::{canonical_caseless_match_str, Caseless};
 use failure::{bail, Fallible};
 use std::cmp::Ordering;
@@ -71,7 +72,7 @@ impl Ord for CaseInsensitiveOsString {
 
 impl PartialOrd for CaseInsensitiveOsString {
     fn partial_cmp(&self, other: &CaseInsensitiveOsString) -> Option<Ordering> {
-        Some(self.compare(other.0.as_os_str()))
+        Some(self.cmp(other))
     }
 }
 
@@ -99,13 +100,17 @@ impl Hash for CaseInsensitiveOsString {
     }
 }
 
+/// Maps variable names to values.  Names are case insensitive on
+/// Windows, as they are in its process environment.  The attributes
+/// of variables are keyed the same way; an `EnvMap<()>` is a set of
+/// variable names.
 #[derive(Debug, Clone, Eq, PartialEq)]
-enum EnvMap {
-    Posix(BTreeMap<OsString, OsString>),
-    Windows(BTreeMap<CaseInsensitiveOsString, OsString>),
+enum EnvMap<V = OsString> {
+    Posix(BTreeMap<OsString, V>),
+    Windows(BTreeMap<CaseInsensitiveOsString, V>),
 }
 
-impl Default for EnvMap {
+impl<V> Default for EnvMap<V> {
     fn default() -> Self {
         if cfg!(windows) {
             Self::windows()
@@ -115,7 +120,7 @@ impl Default for EnvMap {
     }
 }
 
-impl EnvMap {
+impl<V> EnvMap<V> {
     fn posix() -> Self {
         EnvMap::Posix(BTreeMap::new())
     }
@@ -124,19 +129,22 @@ impl EnvMap {
         EnvMap::Windows(BTreeMap::new())
     }
 
-    fn set(&mut self, key: OsString, value: OsString) {
+    fn set(&mut self, key: OsString, value: V) {
         match self {
             EnvMap::Posix(map) => map.insert(key, value),
             EnvMap::Windows(map) => map.insert(CaseInsensitiveOsString(key), value),
         };
     }
 
-    fn get(&self, key: &OsStr) -> Option<&OsStr> {
+    fn get(&self, key: &OsStr) -> Option<&V> {
         match self {
             EnvMap::Posix(map) => map.get(key),
             EnvMap::Windows(map) => map.get(&CaseInsensitiveOsString(key.to_os_string())),
         }
-        .map(OsString::as_os_str)
+    }
+
+    fn contains(&self, key: &OsStr) -> bool {
+        self.get(key).is_some()
     }
 
     fn unset(&mut self, key: &OsStr) {
@@ -146,7 +154,7 @@ impl EnvMap {
         };
     }
 
-    fn iter(&self) -> impl Iterator<Item = (&OsString, &OsString)> {
+    fn iter(&self) -> impl Iterator<Item = (&OsString, &V)> {
         // Using this technique to avoid incompatible match arms errors:
         // https://stackoverflow.com/a/54728634/149111
         let mut posix = None;
@@ -163,19 +171,100 @@ impl EnvMap {
     }
 }
 
+/// The elements of an indexed array variable, keyed by index.
+/// Arrays may be sparse, as elements can be assigned at any index.
+pub type IndexedArray = BTreeMap<usize, OsString>;
+
+/// The elements of an associative array variable, keyed by string
+pub type AssociativeArray = BTreeMap<OsString, OsString>;
+
+/// The case conversion applied to the values assigned to a
+/// variable, as set by `declare -l` and `declare -u`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseAttribute {
+    Lower,
+    Upper,
+}
+
+/// The value and attributes that a variable declared `local` held
+/// prior to entering a function scope
+#[derive(Clone, Debug)]
+struct SavedVariable {
+    name: OsString,
+    value: Option<OsString>,
+    array: Option<IndexedArray>,
+    associative: Option<AssociativeArray>,
+    readonly: bool,
+    exported: bool,
+    integer: bool,
+    case: Option<CaseAttribute>,
+}
+
+/// Add `key` to `set` if `present`, otherwise remove it
+fn restore_attribute(set: &mut EnvMap<()>, key: &OsStr, present: bool) {
+    if present {
+        set.set(key.to_os_string(), ());
+    } else {
+        set.unset(key);
+    }
+}
+
+/// Records the values that variables declared `local` held prior
+/// to entering a function scope, so that they can be restored
+/// when that scope is popped.
+type Scope = Vec<SavedVariable>;
+
 /// The environment represents the environmental variables
 /// associated with the shell and the processes that it spawns.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 pub struct Environment {
     map: EnvMap,
+    /// The array variables.  A name is either a scalar in `map` or
+    /// an array here, but not both.
+    arrays: BTreeMap<OsString, IndexedArray>,
+    /// The associative array variables, which must be declared
+    /// as such before they are assigned.
+    associative: BTreeMap<OsString, AssociativeArray>,
+    scopes: Vec<Scope>,
+    options: ShellOptions,
+    readonly: EnvMap<()>,
+    exported: EnvMap<()>,
+    /// Variables whose assigned values are evaluated as arithmetic
+    integer: EnvMap<()>,
+    case: EnvMap<CaseAttribute>,
+    background_pid: Option<u32>,
+}
+
+/// Environments are considered equal if they hold the same set
+/// of variables; the function scope bookkeeping, the shell
+/// options and the readonly and exported attributes are not
+/// significant.
+impl PartialEq for Environment {
+    fn eq(&self, other: &Environment) -> bool {
+        self.map == other.map
+            && self.arrays == other.arrays
+            && self.associative == other.associative
+    }
 }
 
+impl Eq for Environment {}
+
 impl Environment {
+    /// Construct an environment from the variables of the current
+    /// process; this is equivalent to `from_system`.  There is no
+    /// Default impl, as an empty environment is the more natural default.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        let mut environ = Self {
-            map: Default::default(),
-        };
+        Self::from_system()
+    }
+
+    /// Construct an environment from the variables of the current
+    /// process, all of which are marked as exported.  Values that are
+    /// not valid UTF-8 are preserved as-is.
+    pub fn from_system() -> Self {
+        let mut environ = Self::new_empty();
         for (key, value) in std::env::vars_os() {
+            environ.export(&key);
             environ.set(key, value);
         }
         environ
@@ -184,9 +273,114 @@ impl Environment {
     pub fn new_empty() -> Self {
         Self {
             map: Default::default(),
+            arrays: BTreeMap::new(),
+            associative: BTreeMap::new(),
+            scopes: vec![],
+            options: ShellOptions::default(),
+            readonly: Default::default(),
+            exported: Default::default(),
+            integer: Default::default(),
+            case: Default::default(),
+            background_pid: None,
+        }
+    }
+
+    /// The shell options.  They are carried along with the variables
+    /// so that subshells and functions inherit them.
+    pub fn options(&self) -> &ShellOptions {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut ShellOptions {
+        &mut self.options
+    }
+
+    /// The process id of the most recent command run in the
+    /// background, which is the value of `$!`
+    pub fn background_pid(&self) -> Option<u32> {
+        self.background_pid
+    }
+
+    pub fn set_background_pid(&mut self, pid: u32) {
+        self.background_pid = Some(pid);
+    }
+
+    /// Enter a new function scope.  Variables subsequently passed
+    /// to `declare_local` will be restored to their current values
+    /// by the matching call to `pop_scope`.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    /// Leave the current function scope, restoring any variables
+    /// that were declared local within it.
+    pub fn pop_scope(&mut self) {
+        if let Some(scope) = self.scopes.pop() {
+            for saved in scope.into_iter().rev() {
+                let key = saved.name;
+                match saved.value {
+                    Some(value) => self.map.set(key.clone(), value),
+                    None => self.map.unset(&key),
+                }
+                match saved.array {
+                    Some(array) => {
+                        self.arrays.insert(key.clone(), array);
+                    }
+                    None => {
+                        self.arrays.remove(&key);
+                    }
+                }
+                match saved.associative {
+                    Some(associative) => {
+                        self.associative.insert(key.clone(), associative);
+                    }
+                    None => {
+                        self.associative.remove(&key);
+                    }
+                }
+                restore_attribute(&mut self.readonly, &key, saved.readonly);
+                restore_attribute(&mut self.exported, &key, saved.exported);
+                restore_attribute(&mut self.integer, &key, saved.integer);
+                match saved.case {
+                    Some(case) => self.case.set(key, case),
+                    None => self.case.unset(&key),
+                }
+            }
         }
     }
 
+    /// Returns true if there is an active function scope
+    pub fn in_function_scope(&self) -> bool {
+        !self.scopes.is_empty()
+    }
+
+    /// Mark a variable as local to the current function scope.
+    /// Its present value and attributes are saved so that they can be
+    /// restored when the scope is popped.  Declaring the same variable more than
+    /// once in the same scope retains the originally saved value.
+    pub fn declare_local<K: AsRef<OsStr>>(&mut self, key: K) -> Fallible<()> {
+        let key = key.as_ref();
+        self.check_writable(key)?;
+        let saved = SavedVariable {
+            name: key.to_os_string(),
+            value: self.map.get(key).cloned(),
+            array: self.arrays.get(key).cloned(),
+            associative: self.associative.get(key).cloned(),
+            readonly: self.readonly.contains(key),
+            exported: self.exported.contains(key),
+            integer: self.integer.contains(key),
+            case: self.case.get(key).copied(),
+        };
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => bail!("local: can only be used in a function"),
+        };
+        if !scope.iter().any(|prior| prior.name == key) {
+            scope.push(saved);
+        }
+        Ok(())
+    }
+
     pub fn get_str<K: AsRef<OsStr> + std::fmt::Debug>(&self, key: K) -> Fallible<Option<&str>> {
         match self.get(key.as_ref()) {
             None => Ok(None),
@@ -201,20 +395,329 @@ impl Environment {
         }
     }
 
-    pub fn set<K: Into<OsString> + ?Sized, V: Into<OsString> + ?Sized>(
+    /// Set the value of a variable.  Setting an array as a scalar
+    /// sets its first element, or the element with key `0` in the
+    /// case of an associative array.
+    pub fn set<K: Into<OsString>, V: Into<OsString>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        if let Some(associative) = self.associative.get_mut(&key) {
+            associative.insert("0".into(), value.into());
+            return;
+        }
+        match self.arrays.get_mut(&key) {
+            Some(array) => {
+                array.insert(0, value.into());
+            }
+            None => self.map.set(key, value.into()),
+        }
+    }
+
+    /// Assign a value to a variable on behalf of the user, failing
+    /// if the variable is readonly.  The value is transformed according
+    /// to the integer and case attributes of the variable.
+    pub fn assign<K: AsRef<OsStr>, V: Into<OsString>>(&mut self, key: K, value: V) -> Fallible<()> {
+        let key = key.as_ref();
+        self.check_writable(key)?;
+        let value = self.apply_attributes(key, value.into())?;
+        self.set(key, value);
+        Ok(())
+    }
+
+    /// Transform a value that is being assigned to key according to
+    /// its attributes: an integer variable is assigned the result of
+    /// evaluating the value as an arithmetic expression, and then
+    /// any case conversion is applied.
+    fn apply_attributes(&mut self, key: &OsStr, value: OsString) -> Fallible<OsString> {
+        let mut value = value;
+        if self.integer.contains(key) {
+            let expression = value.to_string_lossy().into_owned();
+            let result = if expression.trim().is_empty() {
+                0
+            } else {
+                evaluate_arithmetic(&expression, self)?
+            };
+            value = result.to_string().into();
+        }
+        if let (Some(case), Some(s)) = (self.case.get(key), value.to_str()) {
+            value = match case {
+                CaseAttribute::Lower => s.to_lowercase(),
+                CaseAttribute::Upper => s.to_uppercase(),
+            }
+            .into();
+        }
+        Ok(value)
+    }
+
+    /// Returns the value of a variable.  The value of an array
+    /// is its first element, and that of an associative array is
+    /// its element with key `0`.
+    pub fn get<K: AsRef<OsStr>>(&self, key: K) -> Option<&OsStr> {
+        let key = key.as_ref();
+        if let Some(associative) = self.associative.get(key) {
+            return associative.get(OsStr::new("0")).map(OsString::as_os_str);
+        }
+        match self.arrays.get(key) {
+            Some(array) => array.get(&0).map(OsString::as_os_str),
+            None => self.map.get(key).map(OsString::as_os_str),
+        }
+    }
+
+    /// Replace a variable with an array of the given values, which
+    /// are assigned consecutive indices starting from 0.
+    /// Fails if the variable is readonly.
+    pub fn assign_array<K: AsRef<OsStr>, I: IntoIterator<Item = OsString>>(
         &mut self,
         key: K,
+        values: I,
+    ) -> Fallible<()> {
+        let key = key.as_ref();
+        self.check_writable(key)?;
+        self.check_not_associative(key)?;
+        let mut array = IndexedArray::new();
+        for (index, value) in values.into_iter().enumerate() {
+            array.insert(index, self.apply_attributes(key, value)?);
+        }
+        self.map.unset(key);
+        self.arrays.insert(key.to_os_string(), array);
+        Ok(())
+    }
+
+    /// Append values to an array, following its highest index.
+    /// A scalar variable is first converted to an array holding its
+    /// value as element 0.  Fails if the variable is readonly.
+    pub fn append_array<K: AsRef<OsStr>, I: IntoIterator<Item = OsString>>(
+        &mut self,
+        key: K,
+        values: I,
+    ) -> Fallible<()> {
+        let key = key.as_ref();
+        self.check_writable(key)?;
+        self.check_not_associative(key)?;
+        let values = values
+            .into_iter()
+            .map(|value| self.apply_attributes(key, value))
+            .collect::<Fallible<Vec<_>>>()?;
+        let array = self.array_mut(key);
+        let next = array.keys().next_back().map(|i| i + 1).unwrap_or(0);
+        for (index, value) in (next..).zip(values) {
+            array.insert(index, value);
+        }
+        Ok(())
+    }
+
+    /// Assign a single element of an array.  A scalar variable is
+    /// first converted to an array holding its value as element 0.
+    /// Fails if the variable is readonly.
+    pub fn assign_element<K: AsRef<OsStr>, V: Into<OsString>>(
+        &mut self,
+        key: K,
+        index: usize,
         value: V,
-    ) {
-        self.map.set(key.into(), value.into());
+    ) -> Fallible<()> {
+        let key = key.as_ref();
+        self.check_writable(key)?;
+        self.check_not_associative(key)?;
+        let value = self.apply_attributes(key, value.into())?;
+        self.array_mut(key).insert(index, value);
+        Ok(())
     }
 
-    pub fn get<K: AsRef<OsStr>>(&self, key: K) -> Option<&OsStr> {
-        self.map.get(key.as_ref())
+    fn array_mut(&mut self, key: &OsStr) -> &mut IndexedArray {
+        if !self.arrays.contains_key(key) {
+            let mut array = IndexedArray::new();
+            if let Some(value) = self.map.get(key) {
+                array.insert(0, value.to_os_string());
+            }
+            self.map.unset(key);
+            self.arrays.insert(key.to_os_string(), array);
+        }
+        self.arrays.get_mut(key).unwrap()
+    }
+
+    /// Returns the elements of an array variable, or None if the
+    /// variable is not an array
+    pub fn get_array<K: AsRef<OsStr>>(&self, key: K) -> Option<&IndexedArray> {
+        self.arrays.get(key.as_ref())
+    }
+
+    pub fn is_array<K: AsRef<OsStr>>(&self, key: K) -> bool {
+        self.arrays.contains_key(key.as_ref())
+    }
+
+    /// Declare a variable to be an indexed array.  A scalar value
+    /// is retained as element 0, and an unset variable becomes an
+    /// empty array.  Fails if the variable is readonly or is an
+    /// associative array.
+    pub fn declare_array<K: AsRef<OsStr>>(&mut self, key: K) -> Fallible<()> {
+        let key = key.as_ref();
+        if self.arrays.contains_key(key) {
+            return Ok(());
+        }
+        self.check_writable(key)?;
+        if self.associative.contains_key(key) {
+            bail!(
+                "{}: cannot convert associative to indexed array",
+                key.to_string_lossy()
+            );
+        }
+        self.array_mut(key);
+        Ok(())
+    }
+
+    /// Returns the indexed array variables
+    pub fn iter_arrays(&self) -> impl Iterator<Item = (&OsString, &IndexedArray)> {
+        self.arrays.iter()
+    }
+
+    /// Declare a variable to be an associative array.  A scalar value
+    /// is retained as the element with key `0`.  Declaring an existing
+    /// associative array has no effect.  Fails if the variable is
+    /// readonly or is an indexed array.
+    pub fn declare_associative<K: AsRef<OsStr>>(&mut self, key: K) -> Fallible<()> {
+        let key = key.as_ref();
+        if self.associative.contains_key(key) {
+            return Ok(());
+        }
+        self.check_writable(key)?;
+        if self.arrays.contains_key(key) {
+            bail!(
+                "{}: cannot convert indexed to associative array",
+                key.to_string_lossy()
+            );
+        }
+        let mut associative = AssociativeArray::new();
+        if let Some(value) = self.map.get(key) {
+            associative.insert("0".into(), value.to_os_string());
+        }
+        self.map.unset(key);
+        self.associative.insert(key.to_os_string(), associative);
+        Ok(())
+    }
+
+    /// Assign the element with the given key of an associative array.
+    /// Fails if the variable is readonly or has not been declared as
+    /// an associative array.
+    pub fn assign_key<K: AsRef<OsStr>, S: Into<OsString>, V: Into<OsString>>(
+        &mut self,
+        key: K,
+        subscript: S,
+        value: V,
+    ) -> Fallible<()> {
+        let key = key.as_ref();
+        self.check_writable(key)?;
+        if !self.associative.contains_key(key) {
+            bail!("{}: not an associative array", key.to_string_lossy());
+        }
+        let value = self.apply_attributes(key, value.into())?;
+        match self.associative.get_mut(key) {
+            Some(associative) => {
+                associative.insert(subscript.into(), value);
+                Ok(())
+            }
+            None => bail!("{}: not an associative array", key.to_string_lossy()),
+        }
+    }
+
+    /// Returns the elements of an associative array variable, or None
+    /// if the variable is not an associative array
+    pub fn get_associative<K: AsRef<OsStr>>(&self, key: K) -> Option<&AssociativeArray> {
+        self.associative.get(key.as_ref())
+    }
+
+    pub fn is_associative<K: AsRef<OsStr>>(&self, key: K) -> bool {
+        self.associative.contains_key(key.as_ref())
+    }
+
+    /// Returns the associative array variables
+    pub fn iter_associative(&self) -> impl Iterator<Item = (&OsString, &AssociativeArray)> {
+        self.associative.iter()
+    }
+
+    fn check_not_associative(&self, key: &OsStr) -> Fallible<()> {
+        if self.is_associative(key) {
+            bail!(
+                "{}: must use a subscript when assigning an associative array",
+                key.to_string_lossy()
+            );
+        }
+        Ok(())
+    }
+
+    /// Remove a variable, failing if it is readonly.
+    /// The variable loses its export attribute.
+    pub fn unset<K: AsRef<OsStr>>(&mut self, key: K) -> Fallible<()> {
+        let key = key.as_ref();
+        self.check_writable(key)?;
+        self.map.unset(key);
+        self.arrays.remove(key);
+        self.associative.remove(key);
+        self.exported.unset(key);
+        self.integer.unset(key);
+        self.case.unset(key);
+        Ok(())
     }
 
-    pub fn unset<K: AsRef<OsStr>>(&mut self, key: K) {
-        self.map.unset(key.as_ref());
+    /// Mark a variable as exported, so that it is passed in the
+    /// environment of spawned commands.  The attribute is retained
+    /// when the variable is subsequently assigned; variables that
+    /// are created by assignment are not exported by default.
+    pub fn export<K: AsRef<OsStr>>(&mut self, key: K) {
+        self.exported.set(key.as_ref().to_os_string(), ());
+    }
+
+    pub fn is_exported<K: AsRef<OsStr>>(&self, key: K) -> bool {
+        self.exported.contains(key.as_ref())
+    }
+
+    /// Returns the exported variables and their values
+    pub fn iter_exported(&self) -> impl Iterator<Item = (&OsString, &OsString)> {
+        self.map
+            .iter()
+            .filter(move |(key, _)| self.exported.contains(key.as_os_str()))
+    }
+
+    /// Mark a variable as readonly; it can no longer be assigned,
+    /// unset or declared local.
+    pub fn set_readonly<K: AsRef<OsStr>>(&mut self, key: K) {
+        self.readonly.set(key.as_ref().to_os_string(), ());
+    }
+
+    pub fn is_readonly<K: AsRef<OsStr>>(&self, key: K) -> bool {
+        self.readonly.contains(key.as_ref())
+    }
+
+    /// Returns the names of the readonly variables, in sorted order
+    pub fn readonly_names(&self) -> impl Iterator<Item = &OsString> {
+        self.readonly.iter().map(|(key, _)| key)
+    }
+
+    /// Give a variable the integer attribute, so that the values
+    /// subsequently assigned to it are evaluated as arithmetic
+    /// expressions
+    pub fn set_integer<K: AsRef<OsStr>>(&mut self, key: K) {
+        self.integer.set(key.as_ref().to_os_string(), ());
+    }
+
+    pub fn is_integer<K: AsRef<OsStr>>(&self, key: K) -> bool {
+        self.integer.contains(key.as_ref())
+    }
+
+    /// Set the case conversion applied to the values subsequently
+    /// assigned to a variable.  A variable has at most one case
+    /// attribute; setting one replaces the other.
+    pub fn set_case<K: AsRef<OsStr>>(&mut self, key: K, case: CaseAttribute) {
+        self.case.set(key.as_ref().to_os_string(), case);
+    }
+
+    pub fn case_attribute<K: AsRef<OsStr>>(&self, key: K) -> Option<CaseAttribute> {
+        self.case.get(key.as_ref()).copied()
+    }
+
+    fn check_writable(&self, key: &OsStr) -> Fallible<()> {
+        if self.is_readonly(key) {
+            bail!("{}: readonly variable", key.to_string_lossy());
+        }
+        Ok(())
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (&OsString, &OsString)> {
@@ -226,6 +729,251 @@ impl Environment {
 mod test {
     use super::*;
 
+    #[test]
+    fn local_scope() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.set("foo", "global");
+        assert!(env.declare_local("foo").is_err());
+
+        env.push_scope();
+        env.declare_local("foo")?;
+        env.set("foo", "inner");
+        env.declare_local("bar")?;
+        env.set("bar", "inner");
+        assert_eq!(env.get_str("foo")?, Some("inner"));
+
+        // A nested scope shadows the outer local
+        env.push_scope();
+        env.declare_local("foo")?;
+        env.set("foo", "nested");
+        env.declare_local("foo")?;
+        env.set("foo", "nested again");
+        assert_eq!(env.get_str("foo")?, Some("nested again"));
+        env.pop_scope();
+        assert_eq!(env.get_str("foo")?, Some("inner"));
+
+        env.pop_scope();
+        assert_eq!(env.get_str("foo")?, Some("global"));
+        assert_eq!(env.get_str("bar")?, None);
+        assert!(!env.in_function_scope());
+
+        // Attributes given to a local are discarded with the scope,
+        // and those of the variable that it shadowed are restored
+        env.set_integer("foo");
+        env.push_scope();
+        env.declare_local("foo")?;
+        env.export("foo");
+        env.set_readonly("foo");
+        env.set_case("foo", CaseAttribute::Upper);
+        env.declare_local("bar")?;
+        env.export("bar");
+        env.pop_scope();
+        assert!(!env.is_exported("foo"));
+        assert!(!env.is_readonly("foo"));
+        assert!(env.is_integer("foo"));
+        assert_eq!(env.case_attribute("foo"), None);
+        assert!(!env.is_exported("bar"));
+        Ok(())
+    }
+
+    #[test]
+    fn readonly() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.assign("foo", "1")?;
+        env.set_readonly("foo");
+        assert!(env.is_readonly("foo"));
+
+        let err = env.assign("foo", "2").unwrap_err();
+        assert_eq!(err.to_string(), "foo: readonly variable");
+        assert!(env.unset("foo").is_err());
+        assert_eq!(env.get_str("foo")?, Some("1"));
+
+        env.push_scope();
+        assert!(env.declare_local("foo").is_err());
+        env.pop_scope();
+
+        env.assign("bar", "1")?;
+        env.unset("bar")?;
+        assert_eq!(env.get_str("bar")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn from_system() -> Fallible<()> {
+        std::env::set_var("WZSH_TEST_FROM_SYSTEM", "hello");
+        let env = Environment::from_system();
+        assert_eq!(env.get_str("WZSH_TEST_FROM_SYSTEM")?, Some("hello"));
+        assert!(env.is_exported("WZSH_TEST_FROM_SYSTEM"));
+        let env = Environment::new();
+        assert_eq!(env.get_str("WZSH_TEST_FROM_SYSTEM")?, Some("hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn exported() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.assign("local", "1")?;
+        assert!(!env.is_exported("local"));
+
+        env.assign("foo", "1")?;
+        env.export("foo");
+        env.set_readonly("foo");
+        env.unset("local")?;
+        env.export("bar");
+
+        // Updating an exported variable keeps its attributes
+        env.set("foo", "2");
+        assert!(env.is_exported("foo"));
+        assert!(env.is_readonly("foo"));
+        env.assign("bar", "3")?;
+        assert!(env.is_exported("bar"));
+        assert_eq!(
+            env.iter_exported()
+                .map(|(k, v)| (k.to_str().unwrap(), v.to_str().unwrap()))
+                .collect::<Vec<_>>(),
+            vec![("bar", "3"), ("foo", "2")]
+        );
+
+        // Unsetting removes the attribute; a new assignment is local
+        env.unset("bar")?;
+        env.assign("bar", "4")?;
+        assert!(!env.is_exported("bar"));
+        Ok(())
+    }
+
+    #[test]
+    fn arrays() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.assign("arr", "scalar")?;
+        env.assign_array("arr", vec!["a".into(), "b".into()])?;
+        assert!(env.is_array("arr"));
+        assert_eq!(env.get_str("arr")?, Some("a"));
+        assert_eq!(env.get_array("arr").unwrap().get(&1), Some(&"b".into()));
+
+        // Assigning a scalar value sets the first element
+        env.assign("arr", "c")?;
+        assert_eq!(env.get_array("arr").unwrap().len(), 2);
+        assert_eq!(env.get_str("arr")?, Some("c"));
+
+        // Arrays are not passed to child processes
+        env.export("arr");
+        assert_eq!(env.iter_exported().count(), 0);
+
+        env.unset("arr")?;
+        assert!(!env.is_array("arr"));
+        assert_eq!(env.get("arr"), None);
+
+        // Element assignment and appending convert a scalar
+        env.assign("sparse", "zero")?;
+        env.assign_element("sparse", 3, "three")?;
+        env.append_array("sparse", vec!["four".into()])?;
+        assert_eq!(
+            env.get_array("sparse").unwrap().keys().collect::<Vec<_>>(),
+            vec![&0, &3, &4]
+        );
+
+        env.assign_array("ro", vec![])?;
+        env.set_readonly("ro");
+        assert!(env.assign_element("ro", 0, "x").is_err());
+        assert!(env.assign_array("ro", vec!["x".into()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn associative_arrays() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+
+        // Keys can only be assigned once declared
+        assert!(env.assign_key("map", "key", "value").is_err());
+        env.declare_associative("map")?;
+        env.assign_key("map", "some key", "one")?;
+        env.assign_key("map", "0", "zero")?;
+        assert!(env.is_associative("map"));
+        assert!(!env.is_array("map"));
+        assert_eq!(env.get_str("map")?, Some("zero"));
+        assert_eq!(
+            env.get_associative("map")
+                .unwrap()
+                .get(OsStr::new("some key")),
+            Some(&"one".into())
+        );
+        assert!(env.assign_array("map", vec![]).is_err());
+
+        // Redeclaring retains the elements; a scalar becomes key 0
+        env.declare_associative("map")?;
+        assert_eq!(env.get_associative("map").unwrap().len(), 2);
+        env.assign("scalar", "value")?;
+        env.declare_associative("scalar")?;
+        assert_eq!(env.get_str("scalar")?, Some("value"));
+
+        env.assign_array("arr", vec![])?;
+        assert!(env.declare_associative("arr").is_err());
+
+        // Locals are restored when the scope is popped
+        env.push_scope();
+        env.declare_local("map")?;
+        env.unset("map")?;
+        assert!(!env.is_associative("map"));
+        env.pop_scope();
+        assert_eq!(env.get_associative("map").unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn attributes() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        env.set_integer("n");
+        env.assign("n", "2 * 3")?;
+        assert_eq!(env.get_str("n")?, Some("6"));
+        env.assign("n", "n + 1")?;
+        assert_eq!(env.get_str("n")?, Some("7"));
+        env.assign("n", "")?;
+        assert_eq!(env.get_str("n")?, Some("0"));
+
+        // Setting is not subject to the attributes
+        env.set("n", "1 + 1");
+        assert_eq!(env.get_str("n")?, Some("1 + 1"));
+
+        env.set_case("word", CaseAttribute::Upper);
+        env.assign("word", "Hello")?;
+        assert_eq!(env.get_str("word")?, Some("HELLO"));
+        env.set_case("word", CaseAttribute::Lower);
+        env.assign_element("word", 1, "World")?;
+        assert_eq!(
+            env.get_array("word").unwrap().get(&1),
+            Some(&"world".into())
+        );
+
+        // Unsetting a variable removes its attributes
+        env.unset("n")?;
+        assert!(!env.is_integer("n"));
+        env.assign("n", "1 + 1")?;
+        assert_eq!(env.get_str("n")?, Some("1 + 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn windows_attributes() -> Fallible<()> {
+        // Attributes are keyed case insensitively, as are the
+        // variables themselves, on Windows
+        let mut env = Environment::new_empty();
+        env.map = EnvMap::windows();
+        env.readonly = EnvMap::windows();
+        env.exported = EnvMap::windows();
+        env.integer = EnvMap::windows();
+        env.assign("Path", "C:\\")?;
+        env.export("PATH");
+        env.set_readonly("path");
+        assert!(env.is_exported("path"));
+        assert_eq!(env.iter_exported().count(), 1);
+        assert!(env.assign("PATH", "D:\\").is_err());
+
+        env.set_integer("N");
+        env.assign("n", "1 + 1")?;
+        assert_eq!(env.get_str("N")?, Some("2"));
+        Ok(())
+    }
+
     fn case_insensitive() {
         let foo = CaseInsensitiveOsString("foo".into());
         let food = CaseInsensitiveOsString("food".into());
@@ -9,7 +9,7 @@ use std::sync::Arc;
 /// running in a another thread, or perhaps even be an inline
 /// or immediately ready thing, the trait gives some flexibility
 /// in waiting on whatever that implementation may be.
-pub trait WaitForStatus: std::fmt::Debug {
+pub trait WaitForStatus: std::fmt::Debug + Send + Sync {
     /// Non-blocking check for the status of the item
     fn poll(&self) -> Option<Status>;
     /// Block until the status of the item changes from Running
@@ -17,6 +17,10 @@ pub trait WaitForStatus: std::fmt::Debug {
     /// subject to a spurious wakeup and that the returned
     /// status still shows as Running.
     fn wait(&self) -> Option<Status>;
+    /// The id of the process whose status this is, if any
+    fn pid(&self) -> Option<u32> {
+        None
+    }
 }
 
 /// Status is always immediately ready with its own value.
@@ -41,7 +45,7 @@ impl From<Status> for WaitableStatus {
 /// so that the status is visible to the vm.
 #[derive(Clone, Debug)]
 pub struct WaitableStatus {
-    waiter: Arc<WaitForStatus>,
+    waiter: Arc<dyn WaitForStatus>,
 }
 
 /// PartialEq is required by the Value enum.  This is a simple test for
@@ -58,7 +62,7 @@ impl PartialEq for WaitableStatus {
 impl Eq for WaitableStatus {}
 
 impl WaitableStatus {
-    pub fn new(waiter: Arc<WaitForStatus>) -> Self {
+    pub fn new(waiter: Arc<dyn WaitForStatus>) -> Self {
         Self { waiter }
     }
 
@@ -74,6 +78,11 @@ impl WaitableStatus {
     pub fn wait(&self) -> Option<Status> {
         self.waiter.wait()
     }
+
+    /// The id of the process whose status this is, if any
+    pub fn pid(&self) -> Option<u32> {
+        self.waiter.pid()
+    }
 }
 
 pub trait ShellHost: std::fmt::Debug {
@@ -91,6 +100,7 @@ pub trait ShellHost: std::fmt::Debug {
     /// * Spawning a child process
     /// * Execution a function defined by the shell language script
     /// * Executing a builtin function.
+    ///
     /// This interface allows for any of these and for others to
     /// occur by allowing the host to initiate running the command,
     /// but not requiring that it be complete upon returning from
@@ -118,11 +128,27 @@ pub trait ShellHost: std::fmt::Debug {
     /// to the stdio streams, or other defined descriptor numbers.
     fn spawn_command(
         &self,
-        argv: &Vec<Value>,
+        argv: &[Value],
         environment: &mut Environment,
         current_directory: &mut PathBuf,
         io_env: &IoEnvironment,
     ) -> Fallible<WaitableStatus>;
 
     fn define_function(&self, name: &str, program: &Arc<Program>) -> Fallible<()>;
+
+    /// Returns true if the user has asked for the running program to
+    /// be interrupted, for example by pressing CTRL-C.  The machine
+    /// checks this before each command that it spawns, and stops
+    /// running the program if it is set, so that a loop such as
+    /// `while true; do :; done` can be broken out of.
+    fn interrupted(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the host places the commands that it spawns
+    /// into process groups under job control.  The `lastpipe` option
+    /// only takes effect when this is false.
+    fn job_control_enabled(&self) -> bool {
+        false
+    }
 }
@@ -1,5 +1,5 @@
 use failure::{format_err, Fallible};
-use filedescriptor::FileDescriptor;
+use filedescriptor::{FdTable, FileDescriptor};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -86,6 +86,10 @@ impl IoEnvironment {
         self.fds.insert(fd_number, Arc::new(Mutex::new(fd)));
     }
 
+    /// Make `dest_fd` refer to the descriptor that `src_fd` currently
+    /// refers to, as for `dest_fd>&src_fd`.  Redirections are applied
+    /// in source order, so a later redirection of `src_fd` doesn't
+    /// affect `dest_fd`: `2>&1 >file` leaves stderr where stdout was.
     pub fn duplicate_to(&mut self, src_fd: usize, dest_fd: usize) -> Fallible<()> {
         let fd = Arc::clone(
             self.fds
@@ -103,4 +107,14 @@ impl IoEnvironment {
             .ok_or_else(|| format_err!("fd_as_stdio: fd {} not present", fd_number))?;
         fd.lock().unwrap().as_stdio()
     }
+
+    /// Build the table of descriptors that a child process spawned
+    /// in this environment should see
+    pub fn fd_table(&self) -> Fallible<FdTable> {
+        let mut table = FdTable::new();
+        for (&fd_number, fd) in &self.fds {
+            table.set(fd_number, fd.lock().unwrap().try_clone()?);
+        }
+        Ok(table)
+    }
 }
@@ -7,16 +7,34 @@ use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+mod arith;
+mod conditional;
 mod environment;
 mod host;
 mod ioenv;
+mod options;
+mod pattern;
+mod timing;
+mod transform;
+mod xtrace;
 
 pub mod op;
+pub use arith::evaluate_arithmetic;
+pub use conditional::evaluate_conditional;
 pub use environment::*;
 pub use host::*;
 pub use ioenv::*;
 pub use op::Operation;
 use op::*;
+pub use options::ShellOptions;
+pub use pattern::{
+    escape_pattern, extended_pattern_matches, pattern_matches, pattern_to_regex, replace_pattern,
+    ReplaceMode,
+};
+pub use shell_lexer::expand_backslash_escapes;
+pub use timing::{format_duration, should_report_time, TimeSnapshot, Times};
+pub use transform::{transform_value, Transform};
+pub use xtrace::{shell_quote, xtrace_line};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
@@ -179,13 +197,18 @@ pub struct Machine {
     io_env: VecDeque<IoEnvironment>,
     positional: Vec<Value>,
     cwd: PathBuf,
-    host: Option<Arc<ShellHost>>,
+    host: Option<Arc<dyn ShellHost>>,
     pipes: VecDeque<FileDescriptor>,
+    timers: Vec<TimeSnapshot>,
 
     program: Arc<Program>,
     program_counter: usize,
 
     last_wait_status: Option<Value>,
+
+    /// The number of command substitutions within which this machine
+    /// is running; reflected in the prefix of `set -x` trace lines
+    nesting: usize,
 }
 
 /// This enum is essentially why this vm exists; it allows stepping
@@ -205,32 +228,56 @@ pub enum Status {
     Complete(Value),
 }
 
+/// Split value into fields, as directed by the characters in ifs.
+/// Runs of IFS whitespace (space, tab and newline) separate fields and
+/// are ignored at the start and end of value.  Each other IFS character
+/// delimits a field, along with any IFS whitespace around it, so that
+/// two adjacent delimiters such as `a::b` produce an empty field.
 fn split_by_ifs<'a>(value: &'a str, ifs: &str) -> Vec<&'a str> {
-    let ifs: std::collections::HashSet<char> = ifs.chars().collect();
+    let is_ifs = |c: char| ifs.contains(c);
+    let is_ifs_white = |c: char| is_ifs(c) && (c == ' ' || c == '\t' || c == '\n');
     let mut split = vec![];
-    let mut run_start = None;
-
-    for (idx, c) in value.char_indices() {
-        if ifs.contains(&c) {
-            if let Some(start) = run_start.take() {
-                if idx > start {
-                    split.push(&value[start..idx]);
-                }
+    let mut chars = value.char_indices().peekable();
+
+    let skip_white = |chars: &mut std::iter::Peekable<std::str::CharIndices>| {
+        while chars.next_if(|&(_, c)| is_ifs_white(c)).is_some() {}
+    };
+
+    skip_white(&mut chars);
+    while let Some(&(start, _)) = chars.peek() {
+        let mut end = value.len();
+        while let Some(&(idx, c)) = chars.peek() {
+            if is_ifs(c) {
+                end = idx;
+                break;
             }
-            continue;
+            chars.next();
         }
-        if run_start.is_none() {
-            run_start = Some(idx);
-        }
-    }
+        split.push(&value[start..end]);
 
-    if let Some(start) = run_start.take() {
-        split.push(&value[start..]);
+        skip_white(&mut chars);
+        if chars.next_if(|&(_, c)| is_ifs(c)).is_some() {
+            skip_white(&mut chars);
+        }
     }
 
     split
 }
 
+/// Render the numbered menu displayed by the `select` command
+pub fn render_select_menu(items: &[Value]) -> String {
+    let width = items.len().to_string().len();
+    let mut menu = String::new();
+    for (idx, item) in items.iter().enumerate() {
+        let item = match item.as_os_str() {
+            Some(s) => s.to_string_lossy().into_owned(),
+            None => format!("{:?}", item),
+        };
+        menu.push_str(&format!("{:>width$}) {}\n", idx + 1, item, width = width));
+    }
+    menu
+}
+
 impl Machine {
     pub fn new(program: &Arc<Program>, env: Option<Environment>, cwd: &Path) -> Fallible<Self> {
         let mut environment = VecDeque::new();
@@ -256,7 +303,7 @@ impl Machine {
         (self.cwd.clone(), self.environment.front().unwrap().clone())
     }
 
-    pub fn set_host(&mut self, host: Arc<ShellHost>) {
+    pub fn set_host(&mut self, host: Arc<dyn ShellHost>) {
         self.host = Some(host)
     }
 
@@ -394,30 +441,48 @@ impl Machine {
         remove_backslash: bool,
         v: Value,
     ) -> Fallible<()> {
-        if glob && contains_glob_specials(&v) {
+        let options = self.environment()?.options();
+        if glob && contains_glob_specials(&v, options.extglob) {
             let pattern = v
                 .as_str()
                 .ok_or_else(|| err_msg("contains_glob_specials returned true for non String?"))?;
-            let glob = filenamegen::Glob::new(pattern)?;
-            for item in glob.walk(&self.cwd) {
-                list.push(item.into_os_string().into())
+            let match_options = filenamegen::MatchOptions {
+                dot_files: options.dotglob,
+                case_insensitive: options.nocaseglob,
+                extended: options.extglob,
+            };
+            let glob = filenamegen::Glob::with_options(pattern, match_options)?;
+            let matches = glob.walk(&self.cwd);
+            // As in bash, a pattern that matches nothing is left as-is
+            // unless nullglob is enabled
+            if !matches.is_empty() || options.nullglob {
+                for item in matches {
+                    list.push(item.into_os_string().into())
+                }
+                return Ok(());
             }
-        } else {
-            match (remove_backslash, v.as_str()) {
-                (true, Some(s)) => {
-                    let mut string = s.to_owned();
-                    string.retain(|c| c != '\\');
-                    list.push(string.into());
+        }
+        match (remove_backslash, v.as_str()) {
+            (true, Some(s)) => {
+                let mut string = String::with_capacity(s.len());
+                let mut chars = s.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => string.extend(chars.next()),
+                        c => string.push(c),
+                    }
                 }
-                _ => list.push(v),
+                list.push(string.into());
             }
+            _ => list.push(v),
         }
         Ok(())
     }
 }
 
-fn contains_glob_specials(v: &Value) -> bool {
+fn contains_glob_specials(v: &Value, extglob: bool) -> bool {
     match v.as_str() {
+        Some(s) if extglob && filenamegen::ExtendedPattern::is_extended(s) => true,
         Some(s) => {
             for c in s.chars() {
                 if c == '*' || c == '[' || c == '{' {
@@ -515,6 +580,97 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_render_select_menu() {
+        assert_eq!(
+            render_select_menu(&["foo".into(), "bar".into()]),
+            "1) foo\n2) bar\n"
+        );
+
+        let items: Vec<Value> = (0..10).map(|n| format!("item{}", n).into()).collect();
+        let menu = render_select_menu(&items);
+        assert!(menu.starts_with(" 1) item0\n 2) item1\n"));
+        assert!(menu.ends_with(" 9) item8\n10) item9\n"));
+    }
+
+    #[test]
+    fn test_glob_options() -> Fallible<()> {
+        let root = tempdir::TempDir::new("shell_vm")?;
+        for name in &["foo.rs", ".hidden.rs", "README.md"] {
+            std::fs::File::create(root.path().join(name))?;
+        }
+        let mut m = Machine::new(&prog(&[]), Some(Environment::new_empty()), root.path())?;
+        let expand = |m: &mut Machine, option: Option<&str>, pattern: &str| -> Vec<String> {
+            if let Some(option) = option {
+                m.environment_mut()
+                    .unwrap()
+                    .options_mut()
+                    .set(option, true)
+                    .unwrap();
+            }
+            let mut list = vec![];
+            m.push_with_glob(&mut list, true, true, pattern.into())
+                .unwrap();
+            list.iter()
+                .map(|v| v.as_str().unwrap().to_owned())
+                .collect()
+        };
+
+        // By default, no match leaves the pattern as-is, dotfiles
+        // are excluded and matching is case sensitive
+        assert_eq!(expand(&mut m, None, "*.txt"), vec!["*.txt"]);
+        assert_eq!(expand(&mut m, None, "*.rs"), vec!["foo.rs"]);
+        assert_eq!(expand(&mut m, None, "*.MD"), vec!["*.MD"]);
+        assert_eq!(
+            expand(&mut m, None, "@(foo|README).*"),
+            vec!["@(foo|README).*"]
+        );
+
+        assert_eq!(
+            expand(&mut m, Some("nullglob"), "*.txt"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            expand(&mut m, Some("dotglob"), "*.rs"),
+            vec![".hidden.rs", "foo.rs"]
+        );
+        assert_eq!(
+            expand(&mut m, Some("nocaseglob"), "*.MD"),
+            vec!["README.md"]
+        );
+        assert_eq!(
+            expand(&mut m, Some("extglob"), "@(foo|README).*"),
+            vec!["README.md", "foo.rs"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_noclobber_redirect() -> Fallible<()> {
+        let root = tempdir::TempDir::new("shell_vm")?;
+        let path = root.path().join("out");
+        let append = |path: &Path, text: &str| -> std::io::Result<()> {
+            let mut file = op::open_redirect(path, false, true, true, false, true)?;
+            std::io::Write::write_all(&mut file, text.as_bytes())
+        };
+
+        // `>>` creates a new file and appends to an existing one
+        append(&path, "one\n")?;
+        append(&path, "two\n")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "one\ntwo\n");
+
+        // `>` refuses to overwrite it, but `>|` may
+        let err = op::open_redirect(&path, false, true, false, false, true).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path)?, "one\ntwo\n");
+        op::open_redirect(&path, false, true, false, true, true)?;
+        assert_eq!(std::fs::read_to_string(&path)?, "");
+
+        // `>` to a new file is permitted
+        op::open_redirect(&root.path().join("new"), false, true, false, false, true)?;
+        Ok(())
+    }
+
     #[test]
     fn test_split_by_ifs() {
         let ifs = " \t\n";
@@ -523,5 +679,15 @@ mod test {
         assert_eq!(split_by_ifs("foo bar", ifs), vec!["foo", "bar"]);
         assert_eq!(split_by_ifs("foo  bar ", ifs), vec!["foo", "bar"]);
         assert_eq!(split_by_ifs("\t foo  bar ", ifs), vec!["foo", "bar"]);
+        assert_eq!(split_by_ifs("", ifs), Vec::<&str>::new());
+
+        let ifs = ":";
+        assert_eq!(split_by_ifs("a:b c", ifs), vec!["a", "b c"]);
+        assert_eq!(split_by_ifs("a::b", ifs), vec!["a", "", "b"]);
+        assert_eq!(split_by_ifs(":a:", ifs), vec!["", "a"]);
+
+        let ifs = " :";
+        assert_eq!(split_by_ifs(" a : b  c ", ifs), vec!["a", "b", "c"]);
+        assert_eq!(split_by_ifs("a : : b", ifs), vec!["a", "", "b"]);
     }
 }
@@ -1,8 +1,8 @@
 use super::*;
-use failure::{bail, ensure, format_err, ResultExt};
-use filedescriptor::{FileDescriptor, Pipe};
+use failure::{bail, ensure, err_msg, format_err, ResultExt};
+use filedescriptor::FileDescriptor;
 use std::convert::TryInto;
-use std::io::Write;
+use std::io::{Read, Write};
 
 /// The Dispatch trait is implemented by the individual operation
 /// types, and via the Operation enum that encompasses all possible
@@ -39,9 +39,9 @@ pub struct $name {
     )*
 }
 
-impl Into<Operation> for $name {
-    fn into(self) -> Operation {
-        Operation::$name(self)
+impl From<$name> for Operation {
+    fn from(op: $name) -> Operation {
+        Operation::$name(op)
     }
 }
 
@@ -76,15 +76,47 @@ op!(
     /// Append the string value from the source to
     /// the string value at the destination.
     /// Appending Value::None is allowed and is a NOP.
+    /// A destination of Value::None is treated as an empty string.
     StringAppend {
         source: Operand,
         destination: Operand
     },
-    /// Evaluates to the length of the specified string operand
+    /// Replace matches of pattern within string by replacement,
+    /// as for `${NAME/pattern/string}`.  An unset string is treated
+    /// as empty.
+    ReplacePattern {
+        string: Operand,
+        pattern: Operand,
+        replacement: Operand,
+        mode: ReplaceMode,
+        destination: Operand,
+    },
+    /// Evaluates to the length of the specified string operand,
+    /// or to the number of elements if the operand is a list
     StringLength {
         string: Operand,
         length: Operand,
     },
+    /// Convert the first (or, if all is true, every) character of
+    /// string that is matched by pattern to upper or lower case.
+    /// An empty pattern matches any character.  The conversion uses
+    /// the full Unicode case mapping, so a single character may
+    /// become several; `ß` is upper cased to `SS`.
+    ChangeCase {
+        string: Operand,
+        pattern: Operand,
+        upper: bool,
+        all: bool,
+        destination: Operand,
+    },
+    /// Apply a `${NAME@op}` transformation to string, the value of
+    /// the parameter name.  An unset parameter expands to nothing.
+    TransformValue {
+        string: Operand,
+        name: Operand,
+        transform: Transform,
+        destination: Operand,
+    },
     /// Terminate the program and return the specified value.
     /// If the value is a string that can be represented as an integer,
     /// the string is converted to an integer and that value is
@@ -109,6 +141,12 @@ op!(
         src_list: Operand,
         dest_list: Operand,
     },
+    /// Remove the first element of the list and store it into
+    /// destination.  If the list is empty, Value::None is stored.
+    ListPopFront {
+        list: Operand,
+        destination: Operand,
+    },
     /// destination = a + b
     Add {
         a: Operand,
@@ -182,24 +220,100 @@ op!(
         clobber: bool,
         append: bool,
     },
+    /// Create a pipe containing the string value followed by a
+    /// newline, and assign its readable end as fd_number in the
+    /// current IO environment.  This implements `<<< word`.
+    HereString {
+        value: Operand,
+        fd_number: usize,
+    },
     /// Clone the current output and environment variables and
     /// push them on the environment stack.  Subsequent command
     /// invocations will use the top of the environment stack.
     PushEnvironment {},
     /// Pop the top of the environment stack
     PopEnvironment {},
+    /// Set up the environment for the final stage of a pipeline.
+    /// If the lastpipe option is enabled and the host doesn't have
+    /// job control enabled, the stage runs in the current shell
+    /// environment.  Otherwise it runs in a copy of the environment,
+    /// as though in a subshell, and `isolated` is set to true.
+    PushPipelineEnvironment {
+        isolated: Operand,
+    },
+    /// Undo PushPipelineEnvironment, popping the copy of the
+    /// environment if `isolated` is true
+    PopPipelineEnvironment {
+        isolated: Operand,
+    },
     /// Set a variable in the current environment
     SetEnv {
         name: Operand,
         value: Operand,
     },
+    /// Mark a variable in the current environment as exported
+    ExportEnv {
+        name: Operand,
+    },
     /// Get a variable from the current environment and store it
     /// into the destination.  If the variable isn't present,
-    /// Value::None is stored instead.
+    /// Value::None is stored instead.  The name may itself be
+    /// Value::None, as happens when the reference of `${!NAME}` is
+    /// unset, in which case Value::None is stored.
     GetEnv {
         name: Operand,
         target: Operand,
     },
+    /// Get an element of the named array variable and store it into
+    /// target.  The subscript is an arithmetic expression; negative
+    /// indices count back from the end of the array.  A subscript of
+    /// `@` or `*` stores a list of all of the elements instead.
+    /// A scalar variable behaves as an array holding only element 0.
+    /// For an associative array the subscript is used as the key.
+    /// Value::None is stored if the element isn't present.
+    GetArrayElement {
+        name: Operand,
+        subscript: Operand,
+        target: Operand,
+    },
+    /// Store a list of the indices, or in the case of an associative
+    /// array the keys, of the elements of the named array variable
+    /// into target.  This implements `${!NAME[@]}`.
+    GetArrayKeys {
+        name: Operand,
+        target: Operand,
+    },
+    /// Assign value to the element of the named array variable that
+    /// is selected by subscript, converting a scalar variable to an
+    /// array if necessary.  The subscript is an arithmetic expression
+    /// unless the variable is an associative array, in which case it
+    /// is used as the key.
+    SetArrayElement {
+        name: Operand,
+        subscript: Operand,
+        value: Operand,
+    },
+    /// Replace the named variable with an array holding the elements
+    /// of the value list, or append them to its existing elements
+    /// if append is true.
+    SetArray {
+        name: Operand,
+        value: Operand,
+        append: bool,
+    },
+    /// If the nounset option is enabled and value is Value::None,
+    /// report that the named parameter is unbound and halt the
+    /// program.  Otherwise, do nothing.
+    CheckBound {
+        name: Operand,
+        value: Operand,
+    },
+    /// Print the value produced by the named stage of word expansion
+    /// to stderr.  The compiler only emits this when tracing expansion.
+    TraceExpansion {
+        stage: String,
+        value: Operand,
+    },
     /// Perform tilde expansion on the input and store in the output.
     TildeExpand {
         name: Operand,
@@ -235,13 +349,85 @@ op!(
     /// Wait for the status of a WaitableStatus to change.
     /// This calls WaitableStatus::wait and may be subject to spurious wakeups.
     Wait { status: Operand },
+    /// Record the process id of the WaitableStatus in status, which
+    /// was spawned in the background, as the value of `$!`
+    RecordBackgroundPid { status: Operand },
     /// Invert the truthiness of the last wait status
     InvertLastWait {},
+    /// Display a numbered menu of the elements of list on stderr,
+    /// prompting with PS3 and reading a line from stdin into the
+    /// REPLY variable.  The element selected by the reply is stored
+    /// into destination; if the reply is not a valid selection an
+    /// empty string is stored instead.  Empty lines cause the menu
+    /// to be displayed again.  At EOF, Value::None is stored.
+    SelectMenu {
+        list: Operand,
+        destination: Operand,
+    },
+    /// Evaluate a primitive test from a `[[ ]]` conditional command.
+    /// operator is a string such as `-f` or `==` and operands is a
+    /// list holding its expanded operands.  Sets the last wait
+    /// status to 0 if the test is true, 1 if it is false, or 2
+    /// if it could not be evaluated.
+    ConditionalTest {
+        operator: Operand,
+        operands: Operand,
+    },
+    /// Escape the string value so that it matches literally when
+    /// used as a shell pattern, or as a regex if regex is true,
+    /// and store the result into destination.
+    EscapePattern {
+        value: Operand,
+        destination: Operand,
+        regex: bool,
+    },
+    /// Evaluate the arithmetic expression string and store the
+    /// resultant Integer into destination.  Variables referenced
+    /// by the expression are resolved from and assigned to the
+    /// current environment.
+    Arithmetic {
+        expression: Operand,
+        destination: Operand,
+    },
+    /// Push a snapshot of the current time and resource usage
+    /// onto the timer stack, for use by a subsequent ReportTime.
+    StartTimer {},
+    /// Pop the most recently started timer and print the real, user
+    /// and system time consumed since it was started to stderr.
+    ReportTime {},
+    /// Set the last wait status.  The value may be either a
+    /// WaitableStatus or an Integer.
+    SetLastWaitStatus { value: Operand },
     /// Define a function
     DefineFunction {
         name: String,
         program: Arc<Program>,
     },
+    /// Run program in a copy of the current environment, with its
+    /// stdout connected to a pipe, and store what it printed, less
+    /// any trailing newlines, into destination.  Changes that the
+    /// program makes to the environment are discarded.
+    CommandSubstitution {
+        program: Arc<Program>,
+        destination: Operand,
+    },
+    /// Return from the currently executing function.
+    /// argv is the expanded argument list of the `return` command;
+    /// the status is taken from its first argument, or from the last
+    /// wait status if no argument was given.  If no function is active,
+    /// an error is reported and execution continues with a status of 1.
+    Return { argv: Operand },
+    /// Jump out of enclosing loops for `break` or `continue`.
+    /// argv is the expanded argument list of the command; its first
+    /// argument is the number of loops, and `targets[n - 1]` is the
+    /// address that leaves `n` loops.  Counts beyond the number of
+    /// targets use the last one.  If the count isn't a positive
+    /// integer, an error is reported and execution continues with
+    /// the next instruction and a status of 1.
+    LoopControl {
+        argv: Operand,
+        targets: Vec<InstructionAddress>,
+    },
 );
 
 impl Dispatch for Copy {
@@ -295,11 +481,39 @@ impl Dispatch for PopEnvironment {
     }
 }
 
+impl Dispatch for PushPipelineEnvironment {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let job_control = machine
+            .host
+            .as_ref()
+            .is_some_and(|host| host.job_control_enabled());
+        let isolated = job_control || !machine.environment()?.options().lastpipe;
+        if isolated {
+            let cloned = machine.environment()?.clone();
+            machine.environment.push_back(cloned);
+        }
+        *machine.operand_mut(&self.isolated)? = Value::Integer(isolated as _);
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for PopPipelineEnvironment {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        if machine.operand(&self.isolated)?.truthy() {
+            machine
+                .environment
+                .pop_back()
+                .ok_or_else(|| err_msg("environment underflow"))?;
+        }
+        Ok(Status::Running)
+    }
+}
+
 impl Dispatch for PushPipe {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
-        let pipe = Pipe::new()?;
-        machine.io_env_mut()?.assign_fd(1, pipe.write);
-        machine.pipes.push_back(pipe.read);
+        let (read, write) = FileDescriptor::pipe()?;
+        machine.io_env_mut()?.assign_fd(1, write);
+        machine.pipes.push_back(read);
         Ok(Status::Running)
     }
 }
@@ -335,6 +549,10 @@ impl Dispatch for PopIo {
 
 impl Dispatch for GetEnv {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        if let Value::None = machine.operand(&self.name)? {
+            *machine.operand_mut(&self.target)? = Value::None;
+            return Ok(Status::Running);
+        }
         let name = machine.operand_as_str(&self.name)?;
         if name == "@" || name == "*" {
             /*
@@ -346,6 +564,11 @@ impl Dispatch for GetEnv {
             */
             *machine.operand_mut(&self.target)? =
                 Value::List(machine.positional.iter().skip(1).cloned().collect());
+        } else if name == "!" {
+            *machine.operand_mut(&self.target)? = match machine.environment()?.background_pid() {
+                Some(pid) => Value::String(pid.to_string()),
+                None => Value::None,
+            };
         } else if name == "#" {
             *machine.operand_mut(&self.target)? =
                 Value::String(machine.positional.len().saturating_sub(1).to_string());
@@ -376,11 +599,166 @@ impl Dispatch for GetEnv {
     }
 }
 
+/// Convert an element of an array to a Value, preferring a String
+/// where it is valid UTF-8
+fn string_value(value: &OsStr) -> Value {
+    match value.to_str() {
+        Some(s) => Value::String(s.to_owned()),
+        None => Value::OsString(value.to_os_string()),
+    }
+}
+
+/// Evaluate an array subscript to an index into the named array.
+/// Negative indices are taken relative to the end of the array;
+/// None is returned if that falls before the start of the array.
+fn array_index(env: &mut Environment, name: &OsStr, subscript: &str) -> Fallible<Option<usize>> {
+    let index = evaluate_arithmetic(subscript, env)
+        .context(format!("evaluating subscript [{}]", subscript))?;
+    if index >= 0 {
+        return Ok(Some(index as usize));
+    }
+    let len = match env.get_array(name) {
+        Some(array) => array.keys().next_back().map(|i| i + 1).unwrap_or(0),
+        None if env.get(name).is_some() => 1,
+        None => 0,
+    };
+    Ok((len as isize + index).try_into().ok())
+}
+
+impl Dispatch for GetArrayElement {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let name = machine.operand_as_os_str(&self.name)?.to_os_string();
+        let subscript = machine.operand_as_str(&self.subscript)?.to_owned();
+        let env = machine.environment_mut()?;
+        let value = if let Some(associative) = env.get_associative(&name) {
+            if subscript == "@" || subscript == "*" {
+                Value::List(
+                    associative
+                        .values()
+                        .map(|value| string_value(value))
+                        .collect(),
+                )
+            } else {
+                associative
+                    .get(OsStr::new(&subscript))
+                    .map(|value| string_value(value))
+                    .unwrap_or(Value::None)
+            }
+        } else if subscript == "@" || subscript == "*" {
+            Value::List(match env.get_array(&name) {
+                Some(array) => array.values().map(|value| string_value(value)).collect(),
+                None => env.get(&name).map(string_value).into_iter().collect(),
+            })
+        } else {
+            let index = array_index(env, &name, &subscript)?;
+            let value = match (env.get_array(&name), index) {
+                (Some(array), Some(index)) => array.get(&index).map(OsString::as_os_str),
+                (None, Some(0)) => env.get(&name),
+                _ => None,
+            };
+            value.map(string_value).unwrap_or(Value::None)
+        };
+        *machine.operand_mut(&self.target)? = value;
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for GetArrayKeys {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let name = machine.operand_as_os_str(&self.name)?.to_os_string();
+        let env = machine.environment()?;
+        let keys = if let Some(associative) = env.get_associative(&name) {
+            associative.keys().map(|key| string_value(key)).collect()
+        } else if let Some(array) = env.get_array(&name) {
+            array.keys().map(|index| index.to_string().into()).collect()
+        } else if env.get(&name).is_some() {
+            vec!["0".into()]
+        } else {
+            vec![]
+        };
+        *machine.operand_mut(&self.target)? = Value::List(keys);
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for SetArrayElement {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let name = machine.operand_as_os_str(&self.name)?.to_os_string();
+        let subscript = machine.operand_as_str(&self.subscript)?.to_owned();
+        let value = machine.operand_as_os_str(&self.value)?.to_os_string();
+        let env = machine.environment_mut()?;
+        if env.is_associative(&name) {
+            env.assign_key(&name, subscript, value)?;
+            return Ok(Status::Running);
+        }
+        match array_index(env, &name, &subscript)? {
+            Some(index) => env.assign_element(&name, index, value)?,
+            None => bail!(
+                "{}[{}]: bad array subscript",
+                name.to_string_lossy(),
+                subscript
+            ),
+        }
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for SetArray {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let name = machine.operand_as_os_str(&self.name)?.to_os_string();
+        let values = match machine.operand(&self.value)? {
+            Value::List(list) => {
+                list.iter()
+                    .map(|value| {
+                        value.as_os_str().map(OsStr::to_os_string).ok_or_else(|| {
+                            format_err!("SetArray: element {:?} is not a string", value)
+                        })
+                    })
+                    .collect::<Fallible<Vec<_>>>()?
+            }
+            value => bail!("SetArray: value {:?} is not a list", value),
+        };
+        let env = machine.environment_mut()?;
+        if self.append {
+            env.append_array(&name, values)?;
+        } else {
+            env.assign_array(&name, values)?;
+        }
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for CheckBound {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        if !machine.environment()?.options().nounset {
+            return Ok(Status::Running);
+        }
+        if let Value::None = machine.operand(&self.value)? {
+            let name = machine.operand_as_str(&self.name)?.to_owned();
+            writeln!(
+                machine.io_env_mut()?.stderr(),
+                "wzsh: {}: unbound variable",
+                name
+            )?;
+            return Ok(Status::Complete(1.into()));
+        }
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for ExportEnv {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let name = machine.operand_as_os_str(&self.name)?.to_os_string();
+        machine.environment_mut()?.export(name);
+        Ok(Status::Running)
+    }
+}
+
 impl Dispatch for SetEnv {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
         let name = machine.operand_as_os_str(&self.name)?.to_os_string();
         let value = machine.operand_as_os_str(&self.value)?.to_os_string();
-        machine.environment_mut()?.set(name, value);
+        machine.environment_mut()?.assign(name, value)?;
         Ok(Status::Running)
     }
 }
@@ -389,7 +767,7 @@ impl Dispatch for Exit {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
         let value = match machine.operand(&self.value)? {
             Value::String(s) => {
-                if let Ok(n) = isize::from_str_radix(s, 10) {
+                if let Ok(n) = s.parse::<isize>() {
                     n.into()
                 } else {
                     s.into()
@@ -397,7 +775,7 @@ impl Dispatch for Exit {
             }
             Value::OsString(s) => {
                 if let Some(s) = s.to_str() {
-                    if let Ok(n) = isize::from_str_radix(s, 10) {
+                    if let Ok(n) = s.parse::<isize>() {
                         n.into()
                     } else {
                         s.into()
@@ -428,12 +806,77 @@ impl Dispatch for Error {
     }
 }
 
+impl Dispatch for TransformValue {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let result = match machine.operand(&self.string)? {
+            Value::None => String::new(),
+            _ => {
+                let string = machine.operand_as_str(&self.string)?;
+                let name = machine.operand_as_str(&self.name)?;
+                transform_value(self.transform, name, string)
+            }
+        };
+        *machine.operand_mut(&self.destination)? = Value::String(result);
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for ChangeCase {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let string = match machine.operand(&self.string)? {
+            Value::None => String::new(),
+            _ => machine.operand_as_str(&self.string)?.to_owned(),
+        };
+        let pattern = machine.operand_as_str(&self.pattern)?;
+        let env = machine.environment()?;
+
+        let mut result = String::with_capacity(string.len());
+        for (idx, c) in string.chars().enumerate() {
+            let selected = (idx == 0 || self.all)
+                && (pattern.is_empty()
+                    || crate::conditional::env_pattern_matches(
+                        pattern,
+                        c.encode_utf8(&mut [0; 4]),
+                        env,
+                    )?);
+            if !selected {
+                result.push(c);
+            } else if self.upper {
+                result.extend(c.to_uppercase());
+            } else {
+                result.extend(c.to_lowercase());
+            }
+        }
+        *machine.operand_mut(&self.destination)? = Value::String(result);
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for ReplacePattern {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let string = match machine.operand(&self.string)? {
+            Value::None => "",
+            _ => machine.operand_as_str(&self.string)?,
+        };
+        let result = replace_pattern(
+            string,
+            machine.operand_as_str(&self.pattern)?,
+            machine.operand_as_str(&self.replacement)?,
+            self.mode,
+            machine.environment()?.options().extglob,
+        )?;
+        *machine.operand_mut(&self.destination)? = Value::String(result);
+        Ok(Status::Running)
+    }
+}
+
 impl Dispatch for StringLength {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
         let len = match machine.operand(&self.string)? {
             Value::String(s) => s.len(),
             Value::None => 0,
             Value::OsString(s) => s.len(),
+            Value::List(list) => list.len(),
             value => bail!(
                 "cannot StringLength non-string operand {:?} value {:?}",
                 self.string,
@@ -453,15 +896,18 @@ impl Dispatch for StringAppend {
             .ok_or_else(|| err_msg("StringAppend: operand is not representable as a BStr"))?
             .to_bstring();
 
-        if src.is_empty() {
-            // Append would be a NOP
-            return Ok(Status::Running);
-        }
-
         let dest = std::mem::replace(machine.operand_mut(&self.destination)?, Value::None);
-        let mut dest = dest.into_bstring().ok_or_else(|| {
-            err_msg("StringAppend: destinatination is not representable as a BString")
-        })?;
+        let mut dest = match dest {
+            Value::None => BString::new(),
+            dest if src.is_empty() => {
+                // Append would be a NOP
+                *machine.operand_mut(&self.destination)? = dest;
+                return Ok(Status::Running);
+            }
+            dest => dest.into_bstring().ok_or_else(|| {
+                err_msg("StringAppend: destinatination is not representable as a BString")
+            })?,
+        };
 
         dest.push(src);
         *machine.operand_mut(&self.destination)? = dest.try_into()?;
@@ -479,9 +925,9 @@ fn join_list_ifs(machine: &mut Machine, list: Value) -> Fallible<Value> {
         _ => bail!("JoinList called on non-list value {:?}", list),
     };
 
-    for element in list {
-        if !dest.is_empty() && join_char.is_some() {
-            dest.push_char(*join_char.as_ref().unwrap());
+    for (idx, element) in list.into_iter().enumerate() {
+        if let Some(join_char) = join_char.filter(|_| idx > 0) {
+            dest.push_char(join_char);
         }
 
         let element = element
@@ -489,7 +935,7 @@ fn join_list_ifs(machine: &mut Machine, list: Value) -> Fallible<Value> {
             .ok_or_else(|| err_msg("JoinList: element is not representable as BString"))?;
         dest.push(element);
     }
-    Ok(dest.try_into()?)
+    dest.try_into()
 }
 
 impl JoinList {
@@ -506,7 +952,7 @@ impl Dispatch for ListAppend {
         let ifs = machine.ifs()?.to_owned();
         let src = machine.operand(&self.value)?.clone();
         let mut list = match machine.operand_mut(&self.list)? {
-            Value::List(dest) => std::mem::replace(dest, Vec::new()),
+            Value::List(dest) => std::mem::take(dest),
             _ => bail!("cannot ListAppend to non-list"),
         };
 
@@ -539,7 +985,7 @@ impl Dispatch for ListAppendList {
             _ => bail!("cannot ListAppendList from non-list"),
         };
         let mut dest_list = match machine.operand_mut(&self.dest_list)? {
-            Value::List(dest) => std::mem::replace(dest, Vec::new()),
+            Value::List(dest) => std::mem::take(dest),
             _ => bail!("cannot ListAppendList to non-list"),
         };
 
@@ -550,6 +996,18 @@ impl Dispatch for ListAppendList {
     }
 }
 
+impl Dispatch for ListPopFront {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let value = match machine.operand_mut(&self.list)? {
+            Value::List(list) if list.is_empty() => Value::None,
+            Value::List(list) => list.remove(0),
+            _ => bail!("cannot ListPopFront from non-list"),
+        };
+        *machine.operand_mut(&self.destination)? = value;
+        Ok(Status::Running)
+    }
+}
+
 impl Dispatch for DupFd {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
         machine
@@ -581,26 +1039,85 @@ impl Dispatch for OpenFile {
         } else {
             machine.cwd.join(file_name)
         };
-        let mut options = std::fs::OpenOptions::new();
-        options
-            .read(self.input)
-            .write(self.output || self.append || self.clobber)
-            .append(self.append)
-            .truncate(self.output && !self.append)
-            // TODO: if a noclobber option is set, and !self.clobber,
-            // then we should look at .create_new() instead
-            .create(self.output || self.append || self.clobber);
-        let file = options.open(&file_name).context(format!(
-            "opening '{}' using {:#?}",
-            file_name.display(),
-            options
-        ))?;
+        let noclobber = machine.environment()?.options().noclobber;
+        let file = open_redirect(
+            &file_name,
+            self.input,
+            self.output,
+            self.append,
+            self.clobber,
+            noclobber,
+        )
+        .context(format!("opening '{}'", file_name.display()))?;
         let fd = FileDescriptor::new(file);
         machine.io_env_mut()?.assign_fd(self.fd_number, fd);
         Ok(Status::Running)
     }
 }
 
+/// Open `file_name` as the target of a redirection.
+/// `>>` appends to the file, creating it if it doesn't exist.
+/// `>` creates or truncates the file, unless `noclobber` is set, in
+/// which case an existing regular file is an error; `>|` truncates
+/// regardless of `noclobber`.  `noclobber` doesn't restrict `>>`.
+pub(crate) fn open_redirect(
+    file_name: &Path,
+    input: bool,
+    output: bool,
+    append: bool,
+    clobber: bool,
+    noclobber: bool,
+) -> std::io::Result<std::fs::File> {
+    let write = output || append || clobber;
+    let mut options = std::fs::OpenOptions::new();
+    options
+        .read(input)
+        .write(write)
+        .append(append)
+        .truncate(output && !append)
+        .create(write);
+
+    if noclobber && output && !input && !append && !clobber {
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(file_name)
+        {
+            Ok(file) => return Ok(file),
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+        // Devices such as /dev/null may still be written to
+        if std::fs::metadata(file_name)?.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "cannot overwrite existing file",
+            ));
+        }
+        options.truncate(false);
+    }
+
+    options.open(file_name)
+}
+
+impl Dispatch for HereString {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let mut text = machine
+            .operand(&self.value)?
+            .as_bstr()
+            .ok_or_else(|| err_msg("here-string is not representable as bytes"))?
+            .as_bytes()
+            .to_vec();
+        text.push(b'\n');
+        let (read, mut write) = FileDescriptor::pipe()?;
+        // Write from a separate thread so that text larger than the
+        // pipe buffer cannot block the shell before the reader starts
+        std::thread::spawn(move || write.write_all(&text));
+        machine.io_env_mut()?.assign_fd(self.fd_number, read);
+        Ok(Status::Running)
+    }
+}
+
 /// Calculate the new program counter value after applying target.
 fn compute_jump_target(machine: &mut Machine, target: InstructionAddress) -> Fallible<usize> {
     // we need to account for the fact that the
@@ -694,6 +1211,33 @@ impl Dispatch for IsNoneOrEmptyString {
     }
 }
 
+/// Render a value as shown by TraceExpansion; each field is quoted
+/// so that empty fields and embedded whitespace are visible
+fn trace_fields(value: &Value) -> String {
+    let quote = |value: &Value| match value.as_bstr() {
+        Some(s) => format!("'{}'", s),
+        None => format!("{:?}", value),
+    };
+    match value {
+        Value::List(list) => list.iter().map(quote).collect::<Vec<_>>().join(" "),
+        Value::None => "(unset)".to_owned(),
+        value => quote(value),
+    }
+}
+
+impl Dispatch for TraceExpansion {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let fields = trace_fields(machine.operand(&self.value)?);
+        writeln!(
+            machine.io_env()?.stderr(),
+            "+ expand {}: {}",
+            self.stage,
+            fields
+        )?;
+        Ok(Status::Running)
+    }
+}
+
 impl Dispatch for TildeExpand {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
         let name = match machine.operand(&self.name)? {
@@ -717,7 +1261,7 @@ impl Dispatch for TildeExpand {
         })?;
 
         let home = host
-            .lookup_homedir(name.as_ref().map(String::as_str))
+            .lookup_homedir(name.as_deref())
             .context(format!("TildeExpand {:?} failed", name))?;
 
         *machine.operand_mut(&self.destination)? = home.into();
@@ -772,6 +1316,192 @@ impl Dispatch for InvertLastWait {
     }
 }
 
+impl Dispatch for RecordBackgroundPid {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let pid = match machine.operand(&self.status)? {
+            Value::WaitableStatus(status) => status.pid(),
+            status => bail!("RecordBackgroundPid: invalid status {:?}", status),
+        };
+        // Builtins and functions complete before returning, so only
+        // external commands have a process id to record.  It is recorded
+        // in every environment so that it outlives any assignments that
+        // were scoped to the command, as in `FOO=bar cmd &`.
+        if let Some(pid) = pid {
+            for env in machine.environment.iter_mut() {
+                env.set_background_pid(pid);
+            }
+        }
+        Ok(Status::Running)
+    }
+}
+
+/// Read a single line from stdin.  This reads a byte at a time
+/// so that we don't consume any data beyond the end of the line
+/// that may be intended for a subsequent command.
+fn read_line<R: std::io::Read>(reader: &mut R) -> Fallible<Option<String>> {
+    let mut line = vec![];
+    let mut buf = [0u8; 1];
+    loop {
+        if reader.read(&mut buf)? == 0 {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            break;
+        }
+        if buf[0] == b'\n' {
+            break;
+        }
+        line.push(buf[0]);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+impl Dispatch for SelectMenu {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let items = match machine.operand(&self.list)? {
+            Value::List(list) => list.clone(),
+            _ => bail!("cannot SelectMenu from non-list"),
+        };
+        if items.is_empty() {
+            *machine.operand_mut(&self.destination)? = Value::None;
+            return Ok(Status::Running);
+        }
+
+        let menu = render_select_menu(&items);
+        let prompt = machine
+            .environment()?
+            .get_str("PS3")?
+            .unwrap_or("#? ")
+            .to_owned();
+
+        let reply = {
+            let io_env = machine.io_env()?;
+            let mut stdin = io_env.stdin();
+            let mut stderr = io_env.stderr();
+            loop {
+                write!(stderr, "{}{}", menu, prompt)?;
+                match read_line(&mut stdin)? {
+                    None => {
+                        writeln!(stderr)?;
+                        break None;
+                    }
+                    Some(line) => {
+                        let line = line.trim();
+                        if !line.is_empty() {
+                            break Some(line.to_owned());
+                        }
+                    }
+                }
+            }
+        };
+
+        let value = match reply {
+            None => Value::None,
+            Some(reply) => {
+                let selected = reply
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|idx| items.get(idx))
+                    .cloned()
+                    .unwrap_or_else(|| "".into());
+                machine.environment_mut()?.assign("REPLY", reply)?;
+                selected
+            }
+        };
+        *machine.operand_mut(&self.destination)? = value;
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for ConditionalTest {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let operator = machine.operand_as_str(&self.operator)?.to_owned();
+        let operands = match machine.operand(&self.operands)? {
+            Value::List(list) => list
+                .iter()
+                .map(|v| {
+                    v.as_os_str()
+                        .map(|s| s.to_string_lossy().into_owned())
+                        .ok_or_else(|| {
+                            format_err!("ConditionalTest operand {:?} is not a string", v)
+                        })
+                })
+                .collect::<Fallible<Vec<String>>>()?,
+            operands => bail!(
+                "ConditionalTest operands must be a list, got {:?}",
+                operands
+            ),
+        };
+
+        let cwd = machine.cwd.clone();
+        let status =
+            match evaluate_conditional(&operator, &operands, &cwd, machine.environment_mut()?) {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(err) => {
+                    writeln!(machine.io_env()?.stderr(), "wzsh: [[: {}", err)?;
+                    2
+                }
+            };
+        machine.last_wait_status = Some(Value::WaitableStatus(
+            Status::Complete(Value::Integer(status)).into(),
+        ));
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for EscapePattern {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let escaped = match machine.operand(&self.value)? {
+            Value::None => String::new(),
+            _ => escape_pattern(machine.operand_as_str(&self.value)?, self.regex),
+        };
+        *machine.operand_mut(&self.destination)? = escaped.into();
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for Arithmetic {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let expression = machine.operand_as_str(&self.expression)?.to_owned();
+        let value = evaluate_arithmetic(&expression, machine.environment_mut()?)
+            .context(format!("evaluating (({}))", expression))?;
+        *machine.operand_mut(&self.destination)? = value.into();
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for StartTimer {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        machine.timers.push(TimeSnapshot::now());
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for ReportTime {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let start = machine
+            .timers
+            .pop()
+            .ok_or_else(|| err_msg("ReportTime: no timer was started"))?;
+        write!(machine.io_env()?.stderr(), "{}", start.elapsed())?;
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for SetLastWaitStatus {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let status = match machine.operand(&self.value)? {
+            Value::WaitableStatus(status) => status.clone(),
+            Value::Integer(n) => Status::Complete(Value::Integer(*n)).into(),
+            value => bail!("SetLastWaitStatus used with invalid value {:?}", value),
+        };
+        machine.last_wait_status = Some(Value::WaitableStatus(status));
+        Ok(Status::Running)
+    }
+}
+
 impl Dispatch for SpawnCommand {
     fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
         let argv = match machine.operand(&self.argv)? {
@@ -779,6 +1509,16 @@ impl Dispatch for SpawnCommand {
             argv => bail!("SpawnCommand argv must be a list, got {:?}", argv),
         };
 
+        if machine.host.as_ref().is_some_and(|host| host.interrupted()) {
+            // The status of a command that was terminated by SIGINT
+            return Ok(Status::Complete(Value::Integer(130)));
+        }
+
+        if !argv.is_empty() && machine.environment()?.options().xtrace {
+            let trace = xtrace_line(machine.environment()?, machine.nesting, &argv);
+            write!(machine.io_env()?.stderr(), "{}", trace)?;
+        }
+
         let host = machine.host.as_mut().ok_or_else(|| {
             err_msg("unable to SpawnCommand because no shell host has been configured")
         })?;
@@ -813,6 +1553,134 @@ impl Dispatch for DefineFunction {
     }
 }
 
+impl Dispatch for CommandSubstitution {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let mut sub = Machine::new(
+            &self.program,
+            Some(machine.environment()?.clone()),
+            &machine.cwd,
+        )?;
+        sub.host = machine.host.clone();
+        sub.positional = machine.positional.clone();
+        sub.nesting = machine.nesting + 1;
+        *sub.io_env_mut()? = machine.io_env()?.clone();
+
+        let (mut read, write) = FileDescriptor::pipe()?;
+        sub.io_env_mut()?.assign_fd(1, write);
+
+        // Read concurrently so that the program cannot block
+        // on writing more than fits in the pipe buffer
+        let reader = std::thread::spawn(move || -> Fallible<Vec<u8>> {
+            let mut output = vec![];
+            read.read_to_end(&mut output)?;
+            Ok(output)
+        });
+
+        let status = sub.run();
+        // Close our copy of the write end of the pipe so that
+        // the reader can see EOF
+        drop(sub);
+        let output = reader
+            .join()
+            .map_err(|_| err_msg("command substitution reader panicked"))??;
+
+        let status = match status? {
+            Status::Complete(value) => value,
+            status => bail!("command substitution did not complete: {:?}", status),
+        };
+        machine
+            .last_wait_status
+            .replace(Value::WaitableStatus(Status::Complete(status).into()));
+
+        let mut output = String::from_utf8_lossy(&output).into_owned();
+        let trimmed = output.trim_end_matches('\n').len();
+        output.truncate(trimmed);
+        *machine.operand_mut(&self.destination)? = Value::String(output);
+        Ok(Status::Running)
+    }
+}
+
+impl Dispatch for Return {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let argv = match machine.operand(&self.argv)? {
+            Value::List(argv) => argv.clone(),
+            argv => bail!("Return argv must be a list, got {:?}", argv),
+        };
+
+        if !machine.environment()?.in_function_scope() {
+            writeln!(
+                machine.io_env()?.stderr(),
+                "wzsh: return: can only `return' from a function"
+            )?;
+            machine.last_wait_status = Some(Value::WaitableStatus(
+                Status::Complete(Value::Integer(1)).into(),
+            ));
+            return Ok(Status::Running);
+        }
+
+        let value = match argv.get(1) {
+            Some(arg) => match arg.as_str().and_then(|s| s.parse::<isize>().ok()) {
+                Some(n) => n.into(),
+                None => {
+                    writeln!(
+                        machine.io_env()?.stderr(),
+                        "wzsh: return: {:?}: numeric argument required",
+                        arg
+                    )?;
+                    2.into()
+                }
+            },
+            None => match machine.last_wait_status.take() {
+                Some(Value::WaitableStatus(status)) => match status.wait() {
+                    Some(Status::Complete(n)) => n,
+                    _ => bail!("last wait status is not complete!?"),
+                },
+                Some(value) => value,
+                None => 0.into(),
+            },
+        };
+        Ok(Status::Complete(value))
+    }
+}
+
+impl Dispatch for LoopControl {
+    fn dispatch(&self, machine: &mut Machine) -> Fallible<Status> {
+        let argv = match machine.operand(&self.argv)? {
+            Value::List(argv) => argv.clone(),
+            argv => bail!("LoopControl argv must be a list, got {:?}", argv),
+        };
+        let name = argv.first().and_then(Value::as_str).unwrap_or_default();
+
+        match argv.get(1).map(|arg| arg.as_str().unwrap_or_default()) {
+            None => {
+                machine.program_counter = compute_jump_target(machine, self.targets[0])?;
+            }
+            Some(count) => {
+                let message = match count.parse::<isize>() {
+                    Ok(n) if n > 0 => {
+                        let target = self.targets[(n as usize).min(self.targets.len()) - 1];
+                        machine.program_counter = compute_jump_target(machine, target)?;
+                        return Ok(Status::Running);
+                    }
+                    Ok(_) => "loop count out of range",
+                    Err(_) => "numeric argument required",
+                };
+                writeln!(
+                    machine.io_env()?.stderr(),
+                    "wzsh: {}: {}: {}",
+                    name,
+                    count,
+                    message
+                )?;
+                machine.last_wait_status = Some(Value::WaitableStatus(
+                    Status::Complete(Value::Integer(1)).into(),
+                ));
+            }
+        }
+        Ok(Status::Running)
+    }
+}
+
 macro_rules! notyet {
     ($($name:ty),* $(,)?) => {
         $(
@@ -0,0 +1,146 @@
+//! Shell options, as manipulated by `set -o NAME` and `set +o NAME`.
+use failure::{bail, Fallible};
+
+macro_rules! shell_options {
+    ($(
+        $(#[$doc:meta])*
+        $field:ident: $name:expr, default: $default:expr,
+    )*) => {
+        /// The set of shell options.  Each option is a boolean flag
+        /// that can be looked up by its `set -o` name.
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct ShellOptions {
+            $(
+                $(#[$doc])*
+                pub $field: bool,
+            )*
+        }
+
+        impl Default for ShellOptions {
+            fn default() -> Self {
+                Self {
+                    $($field: $default,)*
+                }
+            }
+        }
+
+        impl ShellOptions {
+            /// The names of all of the options, in the order in which
+            /// they are listed by `set -o`
+            pub const NAMES: &'static [&'static str] = &[$($name,)*];
+
+            /// Returns the state of the named option, or None if there
+            /// is no such option
+            pub fn get(&self, name: &str) -> Option<bool> {
+                match name {
+                    $($name => Some(self.$field),)*
+                    _ => None,
+                }
+            }
+
+            fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+                match name {
+                    $($name => Some(&mut self.$field),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+shell_options!(
+    /// Use emacs style key bindings in the line editor
+    emacs: "emacs", default: true,
+    /// Use vi style key bindings in the line editor
+    vi: "vi", default: false,
+    /// Patterns that match no files expand to nothing rather
+    /// than to the pattern itself
+    nullglob: "nullglob", default: false,
+    /// Wildcards may match a leading `.` in a filename
+    dotglob: "dotglob", default: false,
+    /// Patterns match filenames without regard to case
+    nocaseglob: "nocaseglob", default: false,
+    /// Recognize the extended pattern operators `?(...)`, `*(...)`,
+    /// `+(...)`, `@(...)` and `!(...)`
+    extglob: "extglob", default: false,
+    /// Print the result of each stage of word expansion to stderr.
+    /// This applies to commands that are compiled while it is enabled
+    expandtrace: "expandtrace", default: false,
+    /// Report the completion of background jobs as soon as the shell
+    /// notices it, rather than waiting until the next prompt
+    notify: "notify", default: false,
+    /// Treat the expansion of an unset parameter as an error,
+    /// other than in expansions such as `${NAME:-word}` that
+    /// test whether it is set
+    nounset: "nounset", default: false,
+    /// Print each simple command to stderr, after it has been
+    /// expanded and before it is run, prefixed by the expansion of `PS4`
+    xtrace: "xtrace", default: false,
+    /// Share the history file with other shells: each command is
+    /// appended to it as it is run, and the commands appended by
+    /// other shells are merged in before each prompt
+    histappend: "histappend", default: false,
+    /// Prevent `>` from overwriting an existing file; `>|` and `>>`
+    /// may still be used to write to it
+    noclobber: "noclobber", default: false,
+    /// Run the last command of a pipeline in the current shell
+    /// environment when job control is not active, so that variables
+    /// that it assigns persist after the pipeline completes
+    lastpipe: "lastpipe", default: false,
+);
+
+impl ShellOptions {
+    /// Enable or disable the named option.
+    /// `emacs` and `vi` select the key bindings of the line editor,
+    /// so enabling one of them disables the other.
+    pub fn set(&mut self, name: &str, value: bool) -> Fallible<()> {
+        match self.flag_mut(name) {
+            Some(flag) => *flag = value,
+            None => bail!("{}: invalid option name", name),
+        }
+        match (name, value) {
+            ("emacs", true) => self.vi = false,
+            ("vi", true) => self.emacs = false,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the name and state of each option
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        Self::NAMES
+            .iter()
+            .map(move |&name| (name, self.get(name).unwrap_or(false)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_by_name() -> Fallible<()> {
+        let mut options = ShellOptions::default();
+        assert_eq!(options.get("emacs"), Some(true));
+        assert_eq!(options.get("nullglob"), Some(false));
+        assert_eq!(options.get("bogus"), None);
+
+        options.set("nullglob", true)?;
+        assert_eq!(options.get("nullglob"), Some(true));
+
+        assert!(options.set("bogus", true).is_err());
+        assert_eq!(
+            options.iter().take(3).collect::<Vec<_>>(),
+            vec![("emacs", true), ("vi", false), ("nullglob", true)]
+        );
+
+        // Only one set of key bindings is in effect at a time
+        options.set("vi", true)?;
+        assert_eq!(options.get("emacs"), Some(false));
+        assert_eq!(options.get("vi"), Some(true));
+        options.set("emacs", true)?;
+        assert_eq!(options.get("emacs"), Some(true));
+        assert_eq!(options.get("vi"), Some(false));
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Shell pattern matching, as used by the `[[ word == pattern ]]`
+//! conditional command and by `${NAME/pattern/string}`.  Unlike
+//! pathname expansion, `*` and `?` match any character, including
+//! `/` and a leading `.`.
+use failure::Fallible;
+use filenamegen::ExtendedPattern;
+use regex::Regex;
+
+/// Translate a shell pattern into an anchored regex string.
+/// A `\` quotes the character that follows it.  A `[` that has
+/// no matching `]` is treated literally.
+pub fn pattern_to_regex(pattern: &str) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut regex = String::from("^(?s:");
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        match chars[idx] {
+            '\\' if idx + 1 < chars.len() => {
+                idx += 1;
+                push_literal(&mut regex, chars[idx]);
+            }
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => match bracket_expression(&chars[idx + 1..]) {
+                Some((class, len)) => {
+                    regex.push_str(&class);
+                    idx += len;
+                }
+                None => push_literal(&mut regex, '['),
+            },
+            c => push_literal(&mut regex, c),
+        }
+        idx += 1;
+    }
+
+    regex.push_str(")$");
+    regex
+}
+
+fn push_literal(regex: &mut String, c: char) {
+    regex.push_str(&regex::escape(&c.to_string()));
+}
+
+/// Parse the body of a bracket expression; `chars` starts just
+/// after the opening `[`.  Returns the regex character class and
+/// the number of chars consumed, including the closing `]`.
+fn bracket_expression(chars: &[char]) -> Option<(String, usize)> {
+    let mut class = String::from("[");
+    let mut idx = 0;
+
+    if let Some('!') | Some('^') = chars.get(idx) {
+        class.push('^');
+        idx += 1;
+    }
+
+    // A `]` in the first position is literal
+    if let Some(']') = chars.get(idx) {
+        class.push_str("\\]");
+        idx += 1;
+    }
+
+    while idx < chars.len() {
+        match chars[idx] {
+            ']' => {
+                class.push(']');
+                return Some((class, idx + 1));
+            }
+            '[' if chars.get(idx + 1) == Some(&':') => {
+                // A character class name such as `[:alpha:]`
+                let rest: String = chars[idx..].iter().collect();
+                let end = rest[2..].find(":]")? + 2;
+                class.push_str(&rest[..end + 2]);
+                idx += rest[..end + 2].chars().count();
+                continue;
+            }
+            '\\' if idx + 1 < chars.len() => {
+                idx += 1;
+                class.push_str(&regex::escape(&chars[idx].to_string()));
+            }
+            c @ '[' | c @ '&' | c @ '~' | c @ '^' => {
+                class.push('\\');
+                class.push(c);
+            }
+            c => class.push(c),
+        }
+        idx += 1;
+    }
+
+    None
+}
+
+/// Returns true if the entirety of `text` is matched by `pattern`
+pub fn pattern_matches(pattern: &str, text: &str) -> Fallible<bool> {
+    let regex = Regex::new(&pattern_to_regex(pattern))?;
+    Ok(regex.is_match(text))
+}
+
+/// As `pattern_matches`, but also recognizing the extended patterns,
+/// such as `@(foo|bar)`, that are enabled by the `extglob` option
+pub fn extended_pattern_matches(pattern: &str, text: &str) -> Fallible<bool> {
+    if ExtendedPattern::is_extended(pattern) {
+        Ok(ExtendedPattern::new(pattern)?.is_match(text))
+    } else {
+        pattern_matches(pattern, text)
+    }
+}
+
+/// Selects which matches of the pattern are replaced by
+/// `replace_pattern`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceMode {
+    /// `${NAME/pattern/string}` replaces the first match
+    First,
+    /// `${NAME//pattern/string}` replaces every match
+    All,
+    /// `${NAME/#pattern/string}` replaces a match at the start
+    Prefix,
+    /// `${NAME/%pattern/string}` replaces a match at the end
+    Suffix,
+}
+
+/// Replace the longest match(es) of `pattern` within `text` by
+/// `replacement`, as for `${NAME/pattern/string}`.  Only the anchored
+/// modes can match an empty string, so that `${NAME/#/x}` prefixes
+/// $NAME with x.  If `extended` is true, the extended patterns that
+/// are enabled by the `extglob` option are recognized.
+pub fn replace_pattern(
+    text: &str,
+    pattern: &str,
+    replacement: &str,
+    mode: ReplaceMode,
+    extended: bool,
+) -> Fallible<String> {
+    let matches: Box<dyn Fn(&str) -> bool> = if extended && ExtendedPattern::is_extended(pattern) {
+        let pattern = ExtendedPattern::new(pattern)?;
+        Box::new(move |s| pattern.is_match(s))
+    } else {
+        let regex = Regex::new(&pattern_to_regex(pattern))?;
+        Box::new(move |s| regex.is_match(s))
+    };
+
+    // The byte offsets of each character boundary, including the end
+    let bounds: Vec<usize> = text
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .chain(std::iter::once(text.len()))
+        .collect();
+
+    match mode {
+        ReplaceMode::Prefix => {
+            for &end in bounds.iter().rev() {
+                if matches(&text[..end]) {
+                    return Ok(format!("{}{}", replacement, &text[end..]));
+                }
+            }
+        }
+        ReplaceMode::Suffix => {
+            for &start in &bounds {
+                if matches(&text[start..]) {
+                    return Ok(format!("{}{}", &text[..start], replacement));
+                }
+            }
+        }
+        ReplaceMode::First | ReplaceMode::All => {
+            let mut result = String::with_capacity(text.len());
+            let mut idx = 0;
+            while idx + 1 < bounds.len() {
+                let start = bounds[idx];
+                let end = (idx + 1..bounds.len())
+                    .rev()
+                    .find(|&end| matches(&text[start..bounds[end]]));
+                match end {
+                    Some(end) => {
+                        result.push_str(replacement);
+                        idx = end;
+                        if mode == ReplaceMode::First {
+                            break;
+                        }
+                    }
+                    None => {
+                        result.push_str(&text[start..bounds[idx + 1]]);
+                        idx += 1;
+                    }
+                }
+            }
+            result.push_str(&text[bounds[idx]..]);
+            return Ok(result);
+        }
+    }
+    Ok(text.to_owned())
+}
+
+/// Escape `text` so that it matches literally when used as a shell
+/// pattern, or as a regex if `regex` is true.  This is used to
+/// handle the quoted portions of the right hand side of `==` and
+/// `=~` in a conditional expression, and of words that are subject
+/// to pathname expansion.
+pub fn escape_pattern(text: &str, regex: bool) -> String {
+    if regex {
+        return regex::escape(text);
+    }
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if let '\\' | '*' | '?' | '[' | ']' | '(' | ')' | '|' | '{' | '}' = c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        assert!(pattern_matches("*.rs", "src/lib.rs").unwrap());
+        assert!(pattern_matches("*", ".hidden").unwrap());
+        assert!(pattern_matches("a?c", "abc").unwrap());
+        assert!(!pattern_matches("a?c", "abbc").unwrap());
+        assert!(pattern_matches("[a-c]x", "bx").unwrap());
+        assert!(!pattern_matches("[!a-c]x", "bx").unwrap());
+        assert!(pattern_matches("[]]", "]").unwrap());
+        assert!(pattern_matches("[[:digit:]]*", "1abc").unwrap());
+        assert!(pattern_matches("[abc", "[abc").unwrap());
+        assert!(pattern_matches("a\\*", "a*").unwrap());
+        assert!(!pattern_matches("a\\*", "ab").unwrap());
+    }
+
+    #[test]
+    fn extended() {
+        assert!(extended_pattern_matches("@(foo|bar)", "bar").unwrap());
+        assert!(!extended_pattern_matches("!(foo|bar)", "bar").unwrap());
+        assert!(extended_pattern_matches("*.rs", "lib.rs").unwrap());
+    }
+
+    #[test]
+    fn replacing() {
+        let replace =
+            |text, pattern, mode| replace_pattern(text, pattern, "X", mode, false).unwrap();
+        assert_eq!(replace("banana", "an", ReplaceMode::First), "bXana");
+        assert_eq!(replace("banana", "an", ReplaceMode::All), "bXXa");
+        assert_eq!(replace("banana", "a*a", ReplaceMode::First), "bX");
+        assert_eq!(replace("banana", "b?", ReplaceMode::Prefix), "Xnana");
+        assert_eq!(replace("banana", "an", ReplaceMode::Prefix), "banana");
+        assert_eq!(replace("banana", "n?", ReplaceMode::Suffix), "banaX");
+        assert_eq!(replace("banana", "", ReplaceMode::Prefix), "Xbanana");
+        assert_eq!(replace("banana", "", ReplaceMode::All), "banana");
+        assert_eq!(
+            replace_pattern("naïve", "ï", "", ReplaceMode::All, false).unwrap(),
+            "nave"
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let text = "a*b?[c]\\";
+        assert!(pattern_matches(&escape_pattern(text, false), text).unwrap());
+        let text = "@(a|b)";
+        assert!(extended_pattern_matches(&escape_pattern(text, false), text).unwrap());
+        assert!(!pattern_matches(&escape_pattern("a*", false), "abc").unwrap());
+        assert_eq!(escape_pattern("a.b", true), "a\\.b");
+    }
+}
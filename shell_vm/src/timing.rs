@@ -0,0 +1,137 @@
+//! Timing of commands, as reported by the `time` reserved word
+//! and by the `REPORTTIME` variable.
+use std::time::{Duration, Instant};
+
+/// A snapshot of the wall clock and of the CPU time consumed by
+/// the shell and by the children that it has waited for.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSnapshot {
+    instant: Instant,
+    user: Duration,
+    sys: Duration,
+}
+
+/// The time consumed between two snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Times {
+    pub real: Duration,
+    pub user: Duration,
+    pub sys: Duration,
+}
+
+impl TimeSnapshot {
+    pub fn now() -> Self {
+        let (user, sys) = cpu_times();
+        Self {
+            instant: Instant::now(),
+            user,
+            sys,
+        }
+    }
+
+    /// Returns the time consumed since this snapshot was taken
+    pub fn elapsed(&self) -> Times {
+        let now = Self::now();
+        Times {
+            real: now.instant.duration_since(self.instant),
+            user: now.user.checked_sub(self.user).unwrap_or_default(),
+            sys: now.sys.checked_sub(self.sys).unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn cpu_times() -> (Duration, Duration) {
+    fn to_duration(tv: libc::timeval) -> Duration {
+        Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+    }
+    let mut user = Duration::default();
+    let mut sys = Duration::default();
+    for &who in &[libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+        unsafe {
+            let mut usage: libc::rusage = std::mem::zeroed();
+            if libc::getrusage(who, &mut usage) == 0 {
+                user += to_duration(usage.ru_utime);
+                sys += to_duration(usage.ru_stime);
+            }
+        }
+    }
+    (user, sys)
+}
+
+#[cfg(windows)]
+fn cpu_times() -> (Duration, Duration) {
+    (Duration::default(), Duration::default())
+}
+
+/// Format a duration in the style of the bash `time` command,
+/// eg: `0m1.250s`.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    format!(
+        "{}m{}.{:03}s",
+        millis / 60_000,
+        (millis / 1000) % 60,
+        millis % 1000
+    )
+}
+
+impl std::fmt::Display for Times {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        writeln!(fmt)?;
+        writeln!(fmt, "real\t{}", format_duration(self.real))?;
+        writeln!(fmt, "user\t{}", format_duration(self.user))?;
+        writeln!(fmt, "sys\t{}", format_duration(self.sys))
+    }
+}
+
+/// Decide whether `times` should be reported given the value of
+/// the `REPORTTIME` variable.  As in zsh, the threshold is a number
+/// of seconds of combined user and system time; a missing, negative
+/// or unparseable threshold disables reporting.
+pub fn should_report_time(times: &Times, threshold: Option<&str>) -> bool {
+    let threshold: f64 = match threshold.and_then(|t| t.trim().parse().ok()) {
+        Some(threshold) if threshold >= 0.0 => threshold,
+        _ => return false,
+    };
+    (times.user + times.sys).as_secs_f64() > threshold
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format_duration(Duration::from_millis(0)), "0m0.000s");
+        assert_eq!(format_duration(Duration::from_millis(1250)), "0m1.250s");
+        assert_eq!(format_duration(Duration::from_millis(61_005)), "1m1.005s");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "60m0.000s");
+
+        let times = Times {
+            real: Duration::from_millis(2500),
+            user: Duration::from_millis(10),
+            sys: Duration::from_millis(1),
+        };
+        assert_eq!(
+            times.to_string(),
+            "\nreal\t0m2.500s\nuser\t0m0.010s\nsys\t0m0.001s\n"
+        );
+    }
+
+    #[test]
+    fn report_threshold() {
+        let times = Times {
+            real: Duration::from_secs(10),
+            user: Duration::from_millis(1500),
+            sys: Duration::from_millis(600),
+        };
+        assert!(!should_report_time(&times, None));
+        assert!(!should_report_time(&times, Some("")));
+        assert!(!should_report_time(&times, Some("-1")));
+        assert!(!should_report_time(&times, Some("3")));
+        assert!(should_report_time(&times, Some("2")));
+        assert!(should_report_time(&times, Some("0")));
+        assert!(should_report_time(&times, Some("1.5")));
+    }
+}
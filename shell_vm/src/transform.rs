@@ -0,0 +1,47 @@
+//! The `${NAME@op}` parameter transformations
+use shell_lexer::expand_backslash_escapes;
+
+/// Selects the transformation applied by `transform_value`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// `${NAME@Q}` quotes the value for reuse as shell input
+    Quote,
+    /// `${NAME@E}` expands the backslash escapes in the value
+    Escape,
+    /// `${NAME@A}` produces an assignment that recreates NAME
+    Assignment,
+}
+
+/// Apply `transform` to `value`, the value of the parameter `name`
+pub fn transform_value(transform: Transform, name: &str, value: &str) -> String {
+    match transform {
+        Transform::Quote => single_quote(value),
+        Transform::Escape => expand_backslash_escapes(value),
+        Transform::Assignment => format!("{}={}", name, single_quote(value)),
+    }
+}
+
+/// Single quote `value`.  Unlike `shell_quote`, the quotes are
+/// always present, which matches the output of bash for `@Q`.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transforms() {
+        assert_eq!(
+            transform_value(Transform::Quote, "V", "a b"),
+            "'a b'".to_owned()
+        );
+        assert_eq!(transform_value(Transform::Quote, "V", "it's"), "'it'\\''s'");
+        assert_eq!(
+            transform_value(Transform::Assignment, "V", "hello world"),
+            "V='hello world'"
+        );
+        assert_eq!(transform_value(Transform::Escape, "V", "a\\tb"), "a\tb");
+    }
+}
@@ -0,0 +1,128 @@
+//! Tracing of commands, as enabled by `set -x`
+use crate::{Environment, Value};
+
+/// Format the line that the `xtrace` option prints to stderr before
+/// running the command `argv`.
+/// The line is prefixed by the expansion of `PS4`, which defaults to
+/// `+ `.  The first character of that expansion is repeated once for
+/// each level of command substitution in which the command is nested,
+/// so that `echo $(date)` traces `++ date` ahead of `+ echo ...`.
+/// Only parameter references are expanded in `PS4`; command
+/// substitution and arithmetic are not performed there.
+pub fn xtrace_line(env: &Environment, nesting: usize, argv: &[Value]) -> String {
+    let ps4 = match env.get("PS4") {
+        Some(ps4) => expand_parameters(&ps4.to_string_lossy(), env),
+        None => "+ ".to_owned(),
+    };
+
+    let mut line = String::new();
+    if let Some(first) = ps4.chars().next() {
+        for _ in 0..nesting {
+            line.push(first);
+        }
+    }
+    line.push_str(&ps4);
+
+    let words: Vec<String> = argv
+        .iter()
+        .map(|arg| match arg.as_os_str() {
+            Some(s) => shell_quote(&s.to_string_lossy()),
+            None => format!("{:?}", arg),
+        })
+        .collect();
+    line.push_str(&words.join(" "));
+    line.push('\n');
+    line
+}
+
+/// Quote `word` so that the shell would read it back as a single word
+/// with the same value.  Words that contain no characters special to
+/// the shell are returned unchanged; everything else is single quoted.
+pub fn shell_quote(word: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(is_plain) {
+        return word.to_owned();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Substitute the `$NAME` and `${NAME}` references in `text` with
+/// their values in `env`.  Unset names expand to nothing, and a `$`
+/// that does not introduce a name is left alone.
+fn expand_parameters(text: &str, env: &Environment) -> String {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let lookup = |name: &str| {
+        env.get(name)
+            .map(|value| value.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(dollar) = rest.find('$') {
+        result.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+
+        if after.starts_with('{') {
+            if let Some(close) = after.find('}') {
+                let name = &after[1..close];
+                if !name.is_empty() && name.chars().all(is_name_char) {
+                    result.push_str(&lookup(name));
+                    rest = &after[close + 1..];
+                    continue;
+                }
+            }
+        } else {
+            let len = after
+                .find(|c: char| !is_name_char(c))
+                .unwrap_or(after.len());
+            if len > 0 && !after.starts_with(|c: char| c.is_ascii_digit()) {
+                result.push_str(&lookup(&after[..len]));
+                rest = &after[len..];
+                continue;
+            }
+        }
+
+        result.push('$');
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn argv(words: &[&str]) -> Vec<Value> {
+        words.iter().map(|w| Value::String(w.to_string())).collect()
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("plain-word_1.txt"), "plain-word_1.txt");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+    }
+
+    #[test]
+    fn trace_line() {
+        let mut env = Environment::new_empty();
+        assert_eq!(
+            xtrace_line(&env, 0, &argv(&["echo", "a b", "c"])),
+            "+ echo 'a b' c\n"
+        );
+        assert_eq!(xtrace_line(&env, 2, &argv(&["date"])), "+++ date\n");
+
+        env.set("PS4", "[$LEVEL:${NAME}$] ");
+        env.set("LEVEL", "1");
+        env.set("NAME", "x");
+        assert_eq!(xtrace_line(&env, 0, &argv(&["true"])), "[1:x$] true\n");
+        assert_eq!(xtrace_line(&env, 1, &argv(&["true"])), "[[1:x$] true\n");
+
+        env.set("PS4", "");
+        assert_eq!(xtrace_line(&env, 1, &argv(&["true"])), "true\n");
+    }
+}
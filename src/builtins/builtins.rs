@@ -24,7 +24,7 @@ impl Builtin for BuiltinsCommand {
         _cancel: Arc<Token>,
         _functions: &Arc<FunctionRegistry>,
     ) -> Fallible<WaitableStatus> {
-        let mut builtins: Vec<&'static str> = super::BUILTINS.iter().map(|(k, _)| *k).collect();
+        let mut builtins: Vec<&'static str> = super::BUILTINS.keys().copied().collect();
         builtins.sort_unstable();
         for k in builtins {
             writeln!(io_env.stdout(), "{}", k)?;
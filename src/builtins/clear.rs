@@ -0,0 +1,55 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Clear the terminal screen.
+/// Nothing is output if TERM is unset or is `dumb`.
+/// The line editor performs the same action when Ctrl-L is pressed.
+pub struct ClearCommand {}
+
+/// Returns the escape sequence that homes the cursor and clears
+/// the screen for the terminal named by `term`
+fn clear_sequence(term: Option<&str>) -> &'static str {
+    match term {
+        None | Some("") | Some("dumb") => "",
+        Some(_) => "\x1b[H\x1b[2J",
+    }
+}
+
+impl Builtin for ClearCommand {
+    fn name() -> &'static str {
+        "clear"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let term = environment.get("TERM").and_then(|term| term.to_str());
+        write!(io_env.stdout(), "{}", clear_sequence(term))?;
+        Ok(Status::Complete(0.into()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sequence() {
+        assert_eq!(clear_sequence(Some("xterm-256color")), "\x1b[H\x1b[2J");
+        assert_eq!(clear_sequence(Some("dumb")), "");
+        assert_eq!(clear_sequence(None), "");
+    }
+}
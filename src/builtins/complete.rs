@@ -0,0 +1,167 @@
+use crate::builtins::Builtin;
+use crate::completion::{filter_words, CompletionSpec, COMPLETIONS};
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Specify how the arguments of commands are completed.
+/// `complete -F FUNC NAME...` completes the arguments of each NAME by
+/// running the shell function FUNC with the command name, the word being
+/// completed and the word before it as its arguments; each line that it
+/// prints is a candidate.  `complete -W WORDS NAME...` offers those
+/// words that start with the word being completed.
+/// With no options, or with `-p`, the completions are printed
+/// as the commands that would recreate them.
+pub struct CompleteCommand {
+    /// Complete using the output of the named function
+    #[structopt(short = "F")]
+    function: Option<String>,
+
+    /// Complete from the whitespace separated list of words
+    #[structopt(short = "W")]
+    wordlist: Option<String>,
+
+    /// Print the completions for the named commands, or
+    /// for all commands if none are given
+    #[structopt(short = "p")]
+    print: bool,
+
+    /// Remove the completions for the named commands, or
+    /// for all commands if none are given
+    #[structopt(short = "r")]
+    remove: bool,
+
+    names: Vec<String>,
+}
+
+impl Builtin for CompleteCommand {
+    fn name() -> &'static str {
+        "complete"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let mut completions = COMPLETIONS.lock().unwrap();
+
+        if self.remove {
+            if self.names.is_empty() {
+                completions.clear();
+            }
+            let mut status = 0;
+            for name in &self.names {
+                if !completions.remove(name) {
+                    writeln!(
+                        io_env.stderr(),
+                        "wzsh: complete: {}: no completion specification",
+                        name
+                    )?;
+                    status = 1;
+                }
+            }
+            return Ok(Status::Complete(status.into()).into());
+        }
+
+        let spec = match (self.function.take(), self.wordlist.take()) {
+            (Some(_), Some(_)) => {
+                writeln!(
+                    io_env.stderr(),
+                    "wzsh: complete: -F and -W cannot be used together"
+                )?;
+                return Ok(Status::Complete(2.into()).into());
+            }
+            (Some(func), None) => Some(CompletionSpec::Function(func)),
+            (None, Some(words)) => Some(CompletionSpec::WordList(words)),
+            (None, None) => None,
+        };
+
+        match spec {
+            Some(spec) if !self.print => {
+                if self.names.is_empty() {
+                    writeln!(
+                        io_env.stderr(),
+                        "wzsh: complete: a command name is required"
+                    )?;
+                    return Ok(Status::Complete(2.into()).into());
+                }
+                for name in &self.names {
+                    completions.register(name, spec.clone());
+                }
+                Ok(Status::Complete(0.into()).into())
+            }
+            _ => {
+                if self.names.is_empty() {
+                    for (name, spec) in completions.iter() {
+                        write!(io_env.stdout(), "{}", spec.as_command(name))?;
+                    }
+                    return Ok(Status::Complete(0.into()).into());
+                }
+                let mut status = 0;
+                for name in &self.names {
+                    match completions.get(name) {
+                        Some(spec) => write!(io_env.stdout(), "{}", spec.as_command(name))?,
+                        None => {
+                            writeln!(
+                                io_env.stderr(),
+                                "wzsh: complete: {}: no completion specification",
+                                name
+                            )?;
+                            status = 1;
+                        }
+                    }
+                }
+                Ok(Status::Complete(status.into()).into())
+            }
+        }
+    }
+}
+
+#[derive(StructOpt)]
+/// Print the possible completions of a word.
+/// `compgen -W WORDS [WORD]` prints, one per line, each of the
+/// whitespace separated WORDS that start with WORD.  The exit status
+/// is 1 if there are no matches.
+pub struct CompgenCommand {
+    /// Complete from the whitespace separated list of words
+    #[structopt(short = "W")]
+    wordlist: Option<String>,
+
+    /// The word to complete; all of the candidates are printed
+    /// if it is omitted
+    word: Option<String>,
+}
+
+impl Builtin for CompgenCommand {
+    fn name() -> &'static str {
+        "compgen"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let words = self.wordlist.as_deref().unwrap_or("");
+        let prefix = self.word.as_deref().unwrap_or("");
+        let matches = filter_words(words, prefix);
+        for word in &matches {
+            writeln!(io_env.stdout(), "{}", word)?;
+        }
+        let status = if matches.is_empty() { 1 } else { 0 };
+        Ok(Status::Complete(status.into()).into())
+    }
+}
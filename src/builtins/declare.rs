@@ -0,0 +1,322 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::{bail, Fallible};
+use shell_vm::{CaseAttribute, Environment, IoEnvironment, Status, WaitableStatus};
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Declare variables and give them attributes.
+/// `declare [-aAilrux] NAME[=VALUE]...` sets the attributes of each
+/// NAME, assigning VALUE if given.  The integer and case attributes
+/// apply to all subsequent assignments to the variable.
+/// An associative array must be declared with `declare -A NAME`
+/// before its elements can be assigned as `NAME[key]=value`.
+/// `declare -p [NAME...]` prints the variables and their attributes
+/// as `declare` commands.
+pub struct DeclareCommand {
+    /// Variables to declare, optionally in `name=value` form
+    names: Vec<String>,
+    /// Make each name an indexed array
+    #[structopt(short = "a", conflicts_with = "associative")]
+    array: bool,
+    /// Make each name an associative array
+    #[structopt(short = "A")]
+    associative: bool,
+    /// Evaluate the values assigned to each name as arithmetic
+    #[structopt(short = "i")]
+    integer: bool,
+    /// Convert the values assigned to each name to lower case
+    #[structopt(short = "l", conflicts_with = "upper")]
+    lower: bool,
+    /// Convert the values assigned to each name to upper case
+    #[structopt(short = "u")]
+    upper: bool,
+    /// Mark each name as readonly
+    #[structopt(short = "r")]
+    readonly: bool,
+    /// Mark each name as exported
+    #[structopt(short = "x")]
+    export: bool,
+    /// Print the definitions of the named variables, or of all
+    /// variables if no names are given
+    #[structopt(short = "p")]
+    print: bool,
+}
+
+#[derive(StructOpt)]
+/// Declare variables and give them attributes.
+/// This is a synonym for `declare`.
+pub struct TypesetCommand {
+    #[structopt(flatten)]
+    declare: DeclareCommand,
+}
+
+/// Quote a value in the double quoted form used by `declare -p`
+fn double_quote(value: &OsStr) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.to_string_lossy().chars() {
+        if c == '"' || c == '\\' || c == '$' || c == '`' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the attribute flags of a variable, in the order that
+/// `declare -p` shows them
+fn attribute_flags(environment: &Environment, name: &str) -> String {
+    let mut flags = String::new();
+    if environment.is_array(name) {
+        flags.push('a');
+    }
+    if environment.is_associative(name) {
+        flags.push('A');
+    }
+    if environment.is_integer(name) {
+        flags.push('i');
+    }
+    if environment.is_readonly(name) {
+        flags.push('r');
+    }
+    if environment.is_exported(name) {
+        flags.push('x');
+    }
+    match environment.case_attribute(name) {
+        Some(CaseAttribute::Lower) => flags.push('l'),
+        Some(CaseAttribute::Upper) => flags.push('u'),
+        None => {}
+    }
+    flags
+}
+
+/// Returns the `declare` command that recreates the named variable,
+/// or None if it has neither a value nor any attributes
+fn describe(environment: &Environment, name: &str) -> Option<String> {
+    let flags = attribute_flags(environment, name);
+    let prefix = format!(
+        "declare -{} {}",
+        if flags.is_empty() { "-" } else { &flags },
+        name
+    );
+
+    if let Some(array) = environment.get_array(name) {
+        let elements: Vec<String> = array
+            .iter()
+            .map(|(index, value)| format!("[{}]={}", index, double_quote(value)))
+            .collect();
+        Some(format!("{}=({})", prefix, elements.join(" ")))
+    } else if let Some(associative) = environment.get_associative(name) {
+        let elements: Vec<String> = associative
+            .iter()
+            .map(|(key, value)| format!("[{}]={}", double_quote(key), double_quote(value)))
+            .collect();
+        Some(format!("{}=({})", prefix, elements.join(" ")))
+    } else if let Some(value) = environment.get(name) {
+        Some(format!("{}={}", prefix, double_quote(value)))
+    } else if !flags.is_empty() {
+        Some(prefix)
+    } else {
+        None
+    }
+}
+
+impl DeclareCommand {
+    fn declare(&self, environment: &mut Environment, name: &str) -> Fallible<()> {
+        let split: Vec<&str> = name.splitn(2, '=').collect();
+        let name = split[0];
+        if (self.integer || self.lower || self.upper) && environment.is_readonly(name) {
+            bail!("{}: readonly variable", name);
+        }
+        if self.array {
+            environment.declare_array(name)?;
+        }
+        if self.associative {
+            environment.declare_associative(name)?;
+        }
+        if self.integer {
+            environment.set_integer(name);
+        }
+        if self.lower {
+            environment.set_case(name, CaseAttribute::Lower);
+        }
+        if self.upper {
+            environment.set_case(name, CaseAttribute::Upper);
+        }
+        if split.len() == 2 {
+            environment.assign(name, split[1])?;
+        }
+        if self.export {
+            environment.export(name);
+        }
+        if self.readonly {
+            environment.set_readonly(name);
+        }
+        Ok(())
+    }
+
+    /// Returns the definitions of the variables to be printed, along
+    /// with the names that have no definition
+    fn print(&self, environment: &Environment) -> (String, Vec<String>) {
+        let names: BTreeSet<OsString> = if self.names.is_empty() {
+            environment
+                .iter()
+                .map(|(name, _)| name)
+                .chain(environment.iter_arrays().map(|(name, _)| name))
+                .chain(environment.iter_associative().map(|(name, _)| name))
+                .cloned()
+                .collect()
+        } else {
+            self.names.iter().map(OsString::from).collect()
+        };
+
+        let mut output = String::new();
+        let mut missing = vec![];
+        for name in &names {
+            let name = name.to_string_lossy();
+            match describe(environment, &name) {
+                Some(line) => {
+                    output.push_str(&line);
+                    output.push('\n');
+                }
+                None => missing.push(name.into_owned()),
+            }
+        }
+        (output, missing)
+    }
+
+    fn run_declare(
+        &self,
+        environment: &mut Environment,
+        io_env: &IoEnvironment,
+    ) -> Fallible<WaitableStatus> {
+        if self.print || self.names.is_empty() {
+            let (output, missing) = self.print(environment);
+            write!(io_env.stdout(), "{}", output)?;
+            for name in &missing {
+                writeln!(io_env.stderr(), "wzsh: declare: {}: not found", name)?;
+            }
+            let status = if missing.is_empty() { 0 } else { 1 };
+            return Ok(Status::Complete(status.into()).into());
+        }
+
+        let mut status = 0;
+        for name in &self.names {
+            if let Err(err) = self.declare(environment, name) {
+                writeln!(io_env.stderr(), "wzsh: declare: {}", err)?;
+                status = 1;
+            }
+        }
+        Ok(Status::Complete(status.into()).into())
+    }
+}
+
+impl Builtin for DeclareCommand {
+    fn name() -> &'static str {
+        "declare"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        self.run_declare(environment, io_env)
+    }
+}
+
+impl Builtin for TypesetCommand {
+    fn name() -> &'static str {
+        "typeset"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        self.declare.run_declare(environment, io_env)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn declare(environment: &mut Environment, args: &[&str]) -> Fallible<()> {
+        let command =
+            DeclareCommand::from_iter_safe(std::iter::once("declare").chain(args.iter().cloned()))?;
+        for name in &command.names {
+            command.declare(environment, name)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn integer() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        declare(&mut env, &["-i", "n=2+3", "m"])?;
+        assert_eq!(env.get_str("n")?, Some("5"));
+        assert_eq!(env.get_str("m")?, None);
+
+        // The attribute applies to later assignments
+        env.assign("n", "n * 2")?;
+        assert_eq!(env.get_str("n")?, Some("10"));
+        env.assign("m", "n - 1")?;
+        assert_eq!(env.get_str("m")?, Some("9"));
+
+        declare(&mut env, &["-r", "n"])?;
+        assert!(declare(&mut env, &["-i", "n"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn print_format() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        declare(&mut env, &["plain=say \"$hi\""])?;
+        declare(&mut env, &["-ix", "n=1"])?;
+        declare(&mut env, &["-u", "upper=shout"])?;
+        declare(&mut env, &["-a", "arr"])?;
+        env.append_array("arr", vec!["a".into(), "b c".into()])?;
+        declare(&mut env, &["-A", "map"])?;
+        env.assign_key("map", "k", "v")?;
+        declare(&mut env, &["-i", "counter"])?;
+
+        let command = DeclareCommand::from_iter(&["declare", "-p"]);
+        assert_eq!(
+            command.print(&env),
+            (
+                "declare -a arr=([0]=\"a\" [1]=\"b c\")\n\
+                 declare -A map=([\"k\"]=\"v\")\n\
+                 declare -ix n=\"1\"\n\
+                 declare -- plain=\"say \\\"\\$hi\\\"\"\n\
+                 declare -u upper=\"SHOUT\"\n"
+                    .to_owned(),
+                vec![]
+            )
+        );
+
+        let command = DeclareCommand::from_iter(&["declare", "-p", "counter", "missing"]);
+        assert_eq!(
+            command.print(&env),
+            (
+                "declare -i counter\n".to_owned(),
+                vec!["missing".to_owned()]
+            )
+        );
+        Ok(())
+    }
+}
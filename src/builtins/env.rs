@@ -9,8 +9,9 @@ use std::sync::Arc;
 use structopt::*;
 
 #[derive(StructOpt)]
-/// Set the export attribute for variables (note that this is always
-/// set in the current version of wzsh)
+/// Set the export attribute for variables.
+/// Exported variables are passed in the environment of the commands
+/// run by the shell, and remain exported when they are reassigned.
 pub struct ExportCommand {
     names: Vec<String>,
     /// Print exported variables in a syntax compatible with the shell
@@ -31,8 +32,8 @@ impl Builtin for ExportCommand {
         cancel: Arc<Token>,
         _functions: &Arc<FunctionRegistry>,
     ) -> Fallible<WaitableStatus> {
-        if self.print {
-            for (k, v) in environment.iter() {
+        if self.print || self.names.is_empty() {
+            for (k, v) in environment.iter_exported() {
                 cancel.check_cancel()?;
                 match (k.to_str(), v.to_str()) {
                     (Some(k), Some(v)) => writeln!(io_env.stdout(), "export {}={}", k, v)?,
@@ -49,8 +50,12 @@ impl Builtin for ExportCommand {
                 // parse `name=value` and assign
                 let split: Vec<&str> = name.splitn(2, '=').collect();
                 if split.len() == 2 {
-                    environment.set(split[0], split[1]);
+                    if let Err(err) = environment.assign(split[0], split[1]) {
+                        writeln!(io_env.stderr(), "wzsh: export: {}", err)?;
+                        return Ok(Status::Complete(1.into()).into());
+                    }
                 }
+                environment.export(split[0]);
             }
         }
         Ok(Status::Complete(0.into()).into())
@@ -72,12 +77,116 @@ impl Builtin for UnsetCommand {
         &mut self,
         environment: &mut Environment,
         _current_directory: &mut PathBuf,
-        _io_env: &IoEnvironment,
+        io_env: &IoEnvironment,
         _cancel: Arc<Token>,
         _functions: &Arc<FunctionRegistry>,
     ) -> Fallible<WaitableStatus> {
+        let mut status = 0;
         for name in &self.names {
-            environment.unset(name);
+            if let Err(err) = environment.unset(name) {
+                writeln!(io_env.stderr(), "wzsh: unset: {}", err)?;
+                status = 1;
+            }
+        }
+        Ok(Status::Complete(status.into()).into())
+    }
+}
+
+#[derive(StructOpt)]
+/// Declare variables that are local to the current function invocation.
+/// The prior values are restored when the function returns.
+pub struct LocalCommand {
+    /// Variables to declare, optionally in `name=value` form
+    names: Vec<String>,
+}
+
+impl Builtin for LocalCommand {
+    fn name() -> &'static str {
+        "local"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        if !environment.in_function_scope() {
+            writeln!(
+                io_env.stderr(),
+                "wzsh: local: can only be used in a function"
+            )?;
+            return Ok(Status::Complete(1.into()).into());
+        }
+        for name in &self.names {
+            let split: Vec<&str> = name.splitn(2, '=').collect();
+            let result = environment.declare_local(split[0]).and_then(|_| {
+                if split.len() == 2 {
+                    environment.assign(split[0], split[1])
+                } else {
+                    environment.unset(split[0])
+                }
+            });
+            if let Err(err) = result {
+                writeln!(io_env.stderr(), "wzsh: local: {}", err)?;
+                return Ok(Status::Complete(1.into()).into());
+            }
+        }
+        Ok(Status::Complete(0.into()).into())
+    }
+}
+
+#[derive(StructOpt)]
+/// Mark variables as readonly, optionally assigning them a value
+/// first.  Readonly variables cannot be assigned to or unset.
+pub struct ReadonlyCommand {
+    /// Variables to mark, optionally in `name=value` form
+    names: Vec<String>,
+    /// Print the readonly variables in a syntax compatible with the shell
+    #[structopt(short = "p", conflicts_with = "names")]
+    print: bool,
+}
+
+impl Builtin for ReadonlyCommand {
+    fn name() -> &'static str {
+        "readonly"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        if self.print || self.names.is_empty() {
+            for name in environment.readonly_names() {
+                cancel.check_cancel()?;
+                match environment.get(name) {
+                    Some(value) => writeln!(
+                        io_env.stdout(),
+                        "readonly {}={}",
+                        name.to_string_lossy(),
+                        value.to_string_lossy()
+                    )?,
+                    None => writeln!(io_env.stdout(), "readonly {}", name.to_string_lossy())?,
+                }
+            }
+            return Ok(Status::Complete(0.into()).into());
+        }
+
+        for name in &self.names {
+            let split: Vec<&str> = name.splitn(2, '=').collect();
+            if split.len() == 2 {
+                if let Err(err) = environment.assign(split[0], split[1]) {
+                    writeln!(io_env.stderr(), "wzsh: readonly: {}", err)?;
+                    return Ok(Status::Complete(1.into()).into());
+                }
+            }
+            environment.set_readonly(split[0]);
         }
         Ok(Status::Complete(0.into()).into())
     }
@@ -0,0 +1,106 @@
+use crate::builtins::Builtin;
+use crate::errorprint::{format_error, ErrorKind};
+use crate::script::compile_and_run_string_with_io;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen"))]
+/// Run arguments as shell commands.
+/// The arguments are joined with spaces and the result is parsed
+/// and run in the current shell environment.  The exit status is
+/// that of the commands, or 0 if there are none.
+pub struct EvalCommand {
+    args: Vec<String>,
+}
+
+impl Builtin for EvalCommand {
+    fn name() -> &'static str {
+        "eval"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let command = self.args.join(" ");
+        if command.trim().is_empty() {
+            return Ok(Status::Complete(0.into()).into());
+        }
+
+        match compile_and_run_string_with_io(
+            &command,
+            current_directory,
+            environment,
+            functions,
+            io_env,
+        ) {
+            Ok(status) => Ok(status.into()),
+            Err(err) if ErrorKind::of(&err) == ErrorKind::Syntax => {
+                write!(io_env.stderr(), "{}", format_error(&err, &command))?;
+                Ok(Status::Complete(2.into()).into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shell_vm::Value;
+
+    fn eval(args: &[&str], env: &mut Environment) -> Fallible<Option<Status>> {
+        let mut argv: Vec<Value> = vec!["eval".into()];
+        argv.extend(args.iter().map(|arg| Value::from(*arg)));
+        let mut cwd = std::env::current_dir()?;
+        let status = EvalCommand::eval(
+            &argv,
+            env,
+            &mut cwd,
+            &IoEnvironment::new()?,
+            Arc::new(Token::new()),
+            &Arc::new(FunctionRegistry::new()),
+        )?;
+        Ok(status.poll())
+    }
+
+    #[test]
+    fn assigns_in_current_environment() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        assert_eq!(eval(&["X=5"], &mut env)?, Some(Status::Complete(0.into())));
+        assert_eq!(env.get_str("X")?, Some("5"));
+
+        // The arguments are joined with spaces before being parsed
+        eval(&["Y=a;", "Z=b"], &mut env)?;
+        assert_eq!(env.get_str("Y")?, Some("a"));
+        assert_eq!(env.get_str("Z")?, Some("b"));
+
+        eval(&["eval 'NESTED=1'"], &mut env)?;
+        assert_eq!(env.get_str("NESTED")?, Some("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn status() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        assert_eq!(eval(&[], &mut env)?, Some(Status::Complete(0.into())));
+        assert_eq!(eval(&[""], &mut env)?, Some(Status::Complete(0.into())));
+        assert_eq!(
+            eval(&["false"], &mut env)?,
+            Some(Status::Complete(1.into()))
+        );
+        assert_eq!(eval(&["&&"], &mut env)?, Some(Status::Complete(2.into())));
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use crate::builtins::Builtin;
+use crate::history::{format_history_time, HISTORY};
+use crate::script::compile_and_run_string;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::{bail, Fallible, ResultExt};
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::collections::hash_map::RandomState;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use structopt::*;
+
+#[derive(StructOpt)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::AllowNegativeNumbers"))]
+/// List, or edit and re-execute, commands from the history.
+/// FIRST and LAST may be a history number, a negative offset
+/// from the current command, or a string matching the start of
+/// the most recent command that begins with it.
+/// When `HISTTIMEFORMAT` is set, listed commands are preceded by the
+/// time at which they were entered, formatted as by strftime(3).
+pub struct FcCommand {
+    /// List the commands rather than editing them
+    #[structopt(short = "l")]
+    list: bool,
+    /// Omit the command numbers when listing
+    #[structopt(short = "n")]
+    no_numbers: bool,
+    /// Reverse the order of the commands
+    #[structopt(short = "r")]
+    reverse: bool,
+    /// Re-execute a command without invoking an editor, optionally
+    /// replacing each occurrence of OLD with NEW: `fc -s [OLD=NEW] [FIRST]`
+    #[structopt(short = "s")]
+    substitute: bool,
+    /// The editor to use; defaults to $FCEDIT, then $EDITOR, then vi
+    #[structopt(short = "e")]
+    editor: Option<String>,
+    args: Vec<String>,
+}
+
+/// Resolve a FIRST or LAST operand to an index into `entries`
+fn resolve_entry(entries: &[String], spec: &str) -> Option<usize> {
+    if entries.is_empty() {
+        return None;
+    }
+    match spec.parse::<isize>() {
+        Ok(n) if n < 0 => {
+            let idx = entries.len() as isize + n;
+            if idx < 0 {
+                Some(0)
+            } else {
+                Some(idx as usize)
+            }
+        }
+        Ok(0) => Some(entries.len() - 1),
+        Ok(n) => Some((n as usize - 1).min(entries.len() - 1)),
+        Err(_) => entries.iter().rposition(|entry| entry.starts_with(spec)),
+    }
+}
+
+/// Compute the indices of the entries selected by the FIRST and
+/// LAST operands, in the order that they should be processed.
+/// When listing, the default selection is the 16 most recent
+/// commands; otherwise it is the most recent command.
+fn select_entries(
+    entries: &[String],
+    first: Option<&str>,
+    last: Option<&str>,
+    listing: bool,
+) -> Fallible<Vec<usize>> {
+    let resolve = |spec: &str| match resolve_entry(entries, spec) {
+        Some(idx) => Ok(idx),
+        None => bail!("{}: history specification out of range", spec),
+    };
+
+    let latest = match entries.len() {
+        0 => bail!("history is empty"),
+        n => n - 1,
+    };
+
+    let first_idx = match first {
+        Some(first) => resolve(first)?,
+        None if listing => latest.saturating_sub(15),
+        None => latest,
+    };
+    let last_idx = match last {
+        Some(last) => resolve(last)?,
+        None if listing => latest,
+        None => first_idx,
+    };
+
+    Ok(if first_idx <= last_idx {
+        (first_idx..=last_idx).collect()
+    } else {
+        (last_idx..=first_idx).rev().collect()
+    })
+}
+
+/// Apply an optional `old=new` substitution to `command`
+fn substitute(command: &str, old_new: Option<&str>) -> String {
+    match old_new.and_then(|spec| {
+        let mut split = spec.splitn(2, '=');
+        Some((split.next()?, split.next()?))
+    }) {
+        Some((old, new)) if !old.is_empty() => command.replace(old, new),
+        _ => command.to_owned(),
+    }
+}
+
+/// Create a new file in the temporary directory for the commands to
+/// be edited.  The temporary directory may be shared with other users,
+/// so the file is given a name that they can't predict, is readable
+/// only by us, and must not already exist; a file or symlink that
+/// someone else created is never opened.
+fn create_temp_file() -> std::io::Result<(PathBuf, File)> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut attempts = 0;
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        if let Ok(elapsed) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            hasher.write_u128(elapsed.as_nanos());
+        }
+        let path = std::env::temp_dir().join(format!("wzsh-fc-{:016x}.sh", hasher.finish()));
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists && attempts < 100 => {
+                attempts += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+impl FcCommand {
+    fn choose_editor(&self, environment: &Environment) -> String {
+        if let Some(editor) = &self.editor {
+            return editor.clone();
+        }
+        for name in &["FCEDIT", "EDITOR"] {
+            if let Some(editor) = environment.get(name).and_then(|e| e.to_str()) {
+                if !editor.is_empty() {
+                    return editor.to_owned();
+                }
+            }
+        }
+        "vi".to_owned()
+    }
+
+    /// Write `text` to a temporary file, run the editor on it and
+    /// return the edited text.
+    fn edit(
+        &self,
+        text: &str,
+        environment: &Environment,
+        current_directory: &PathBuf,
+    ) -> Fallible<String> {
+        let (path, mut file) = create_temp_file().context("creating fc temporary file")?;
+        let written = file.write_all(text.as_bytes());
+        drop(file);
+        if written.is_err() {
+            std::fs::remove_file(&path).ok();
+        }
+        written.context("writing fc temporary file")?;
+
+        let editor = self.choose_editor(environment);
+        let mut words = editor.split_whitespace();
+        let program = match words.next() {
+            Some(program) => program,
+            None => bail!("no editor"),
+        };
+        let status = std::process::Command::new(program)
+            .args(words)
+            .arg(&path)
+            .env_clear()
+            .envs(environment.iter_exported())
+            .current_dir(current_directory)
+            .status()
+            .with_context(|_| format!("running {}", editor))?;
+
+        let edited = std::fs::read_to_string(&path);
+        std::fs::remove_file(&path).ok();
+        if !status.success() {
+            bail!("{} exited with {}", editor, status);
+        }
+        Ok(edited?)
+    }
+}
+
+impl Builtin for FcCommand {
+    fn name() -> &'static str {
+        "fc"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        // The most recent entry is the command line that invoked us,
+        // so it is excluded from consideration.
+        let (entries, times) = {
+            let history = HISTORY.lock().unwrap();
+            let entries = history.entries();
+            let len = entries.len().saturating_sub(1);
+            (entries[..len].to_vec(), history.times()[..len].to_vec())
+        };
+
+        let mut args = self.args.iter().map(String::as_str);
+        let old_new = if self.substitute {
+            match self.args.first() {
+                Some(arg) if arg.contains('=') => args.next(),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        let first = args.next();
+        let last = if self.substitute { None } else { args.next() };
+
+        let mut selected = match select_entries(&entries, first, last, self.list) {
+            Ok(selected) => selected,
+            Err(err) => {
+                writeln!(io_env.stderr(), "wzsh: fc: {}", err)?;
+                return Ok(Status::Complete(1.into()).into());
+            }
+        };
+        if self.reverse {
+            selected.reverse();
+        }
+
+        if self.list {
+            // With $HISTTIMEFORMAT, each command is preceded by the
+            // time at which it was entered
+            let time_format = environment.get("HISTTIMEFORMAT").and_then(|f| f.to_str());
+            for idx in selected {
+                let time = match (time_format, times[idx]) {
+                    (Some(format), Some(time)) => format_history_time(time, format),
+                    _ => String::new(),
+                };
+                if self.no_numbers {
+                    writeln!(io_env.stdout(), "\t{}{}", time, entries[idx])?;
+                } else {
+                    writeln!(io_env.stdout(), "{}\t{}{}", idx + 1, time, entries[idx])?;
+                }
+            }
+            return Ok(Status::Complete(0.into()).into());
+        }
+
+        let command = if self.substitute {
+            substitute(&entries[selected[0]], old_new)
+        } else {
+            let text: Vec<&str> = selected.iter().map(|&idx| entries[idx].as_str()).collect();
+            match self.edit(&text.join("\n"), environment, current_directory) {
+                Ok(edited) => edited.trim_end().to_owned(),
+                Err(err) => {
+                    writeln!(io_env.stderr(), "wzsh: fc: {}", err)?;
+                    return Ok(Status::Complete(1.into()).into());
+                }
+            }
+        };
+
+        if command.is_empty() {
+            return Ok(Status::Complete(0.into()).into());
+        }
+
+        writeln!(io_env.stderr(), "{}", command)?;
+        HISTORY.lock().unwrap().replace_last(&command);
+        let status = compile_and_run_string(&command, current_directory, environment, functions)?;
+        Ok(status.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn history(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("echo {}", i)).collect()
+    }
+
+    #[test]
+    fn listing_range() {
+        let entries = history(20);
+        // The default listing is the 16 most recent entries
+        assert_eq!(
+            select_entries(&entries, None, None, true).unwrap(),
+            (4..20).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            select_entries(&entries, Some("10"), Some("12"), true).unwrap(),
+            vec![9, 10, 11]
+        );
+        // A reversed range is listed in reverse
+        assert_eq!(
+            select_entries(&entries, Some("12"), Some("10"), true).unwrap(),
+            vec![11, 10, 9]
+        );
+        // FIRST alone lists through the most recent entry
+        assert_eq!(
+            select_entries(&entries, Some("-3"), None, true).unwrap(),
+            vec![17, 18, 19]
+        );
+        // A string selects the most recent entry that starts with it
+        assert_eq!(
+            select_entries(&entries, Some("echo 1"), None, true).unwrap(),
+            vec![18, 19]
+        );
+        assert!(select_entries(&entries, Some("ls"), None, true).is_err());
+        assert!(select_entries(&[], None, None, true).is_err());
+
+        // When editing, the default is the most recent entry
+        assert_eq!(
+            select_entries(&entries, None, None, false).unwrap(),
+            vec![19]
+        );
+        assert_eq!(
+            select_entries(&entries, Some("3"), None, false).unwrap(),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(substitute("echo foo", None), "echo foo");
+        assert_eq!(substitute("echo foo", Some("foo=bar")), "echo bar");
+        assert_eq!(substitute("cp a a.bak", Some("a=b")), "cp b b.bbk");
+        assert_eq!(substitute("echo foo", Some("foo=")), "echo ");
+        assert_eq!(substitute("echo foo", Some("=x")), "echo foo");
+    }
+
+    #[test]
+    fn temp_files_are_private() -> std::io::Result<()> {
+        let (first, _) = create_temp_file()?;
+        let (second, _) = create_temp_file()?;
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(&first)?.permissions().mode() & 0o777
+        };
+        std::fs::remove_file(&first)?;
+        std::fs::remove_file(&second)?;
+
+        assert_ne!(first, second);
+        #[cfg(unix)]
+        assert_eq!(mode, 0o600);
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(Debug, StructOpt)]
+/// Display information about builtin commands.
+/// With no NAME, each builtin is listed with a one line summary.
+pub struct HelpCommand {
+    /// The builtin to describe
+    name: Option<String>,
+}
+
+/// Produce the help text for the builtin `name`, or the summary
+/// of every builtin if no name is given.  Returns None if there
+/// is no such builtin.
+fn help_text(name: Option<&str>) -> Option<String> {
+    match name {
+        Some(name) => super::BUILTINS.get(name).map(|entry| (entry.usage)()),
+        None => {
+            let mut builtins: Vec<_> = super::BUILTINS.iter().collect();
+            builtins.sort_unstable_by_key(|(name, _)| *name);
+            let width = builtins.iter().map(|(name, _)| name.len()).max();
+            Some(
+                builtins
+                    .into_iter()
+                    .map(|(name, entry)| {
+                        format!(
+                            "{:<width$}  {}\n",
+                            name,
+                            entry.summary,
+                            width = width.unwrap()
+                        )
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
+
+impl Builtin for HelpCommand {
+    fn name() -> &'static str {
+        "help"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        match help_text(self.name.as_deref()) {
+            Some(text) => {
+                write!(io_env.stdout(), "{}", text)?;
+                Ok(Status::Complete(0.into()).into())
+            }
+            None => {
+                writeln!(
+                    io_env.stderr(),
+                    "wzsh: help: no help topics match `{}`",
+                    self.name.as_ref().unwrap()
+                )?;
+                Ok(Status::Complete(1.into()).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn help() {
+        assert_eq!(
+            super::super::BUILTINS["cd"].summary,
+            "The cd utility changes the working directory of the current shell environment"
+        );
+        let cd = help_text(Some("cd")).unwrap();
+        assert!(cd.contains("The cd utility changes the working directory"));
+        assert!(cd.contains("USAGE:"));
+
+        let listing = help_text(None).unwrap();
+        assert!(listing.lines().any(|line| line.starts_with("cd ")
+            && line.ends_with(
+                " The cd utility changes the working directory of the current shell environment"
+            )));
+        assert!(listing
+            .lines()
+            .any(|line| line.starts_with("set ") && line.ends_with("  Set shell options")));
+
+        assert_eq!(help_text(Some("bogus")), None);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::builtins::Builtin;
-use crate::job::JOB_LIST;
+use crate::job::{JobState, JOB_LIST};
 use crate::shellhost::FunctionRegistry;
 use cancel::Token;
 use failure::{err_msg, Fallible};
@@ -27,22 +27,19 @@ impl Builtin for FgCommand {
     ) -> Fallible<WaitableStatus> {
         let mut jobs = JOB_LIST.jobs();
         if let Some(mut job) = jobs.pop() {
-            writeln!(
-                io_env.stderr(),
-                "wzsh: putting [{}] {} into fg",
-                job.process_group_id(),
-                job
-            )?;
+            writeln!(io_env.stderr(), "{}", job)?;
             job.put_in_foreground()?;
-            let status = job
-                .wait()
+            let state = job
+                .wait_state()
                 .ok_or_else(|| err_msg("job.wait returned None?"))?;
-            writeln!(
-                io_env.stderr(),
-                "wzsh: after fg, wait returned {:?}",
-                status
-            )?;
-            Ok(Status::Complete(0.into()).into())
+            if state == JobState::Stopped {
+                writeln!(
+                    io_env.stderr(),
+                    "{}",
+                    state.format_job(job.process_group_id(), &job.to_string())
+                )?;
+            }
+            Ok(Status::from(state).into())
         } else {
             writeln!(io_env.stderr(), "wzsh: fg: no jobs to put in foreground")?;
             Ok(Status::Complete(1.into()).into())
@@ -68,20 +65,12 @@ impl Builtin for JobsCommand {
     ) -> Fallible<WaitableStatus> {
         let mut jobs = JOB_LIST.jobs();
         for job in &mut jobs {
-            match job.poll() {
-                Some(status) => writeln!(
-                    io_env.stdout(),
-                    "[{}] - {:?} {}",
-                    job.process_group_id(),
-                    status,
-                    job
-                )?,
-                None => writeln!(
+            if let Some(state) = job.poll_state() {
+                writeln!(
                     io_env.stdout(),
-                    "[{}] - <nochange> {}", // TODO: be smarter about stopped status
-                    job.process_group_id(),
-                    job
-                )?,
+                    "{}",
+                    state.format_job(job.process_group_id(), &job.to_string())
+                )?;
             }
         }
         Ok(Status::Complete(0.into()).into())
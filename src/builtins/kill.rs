@@ -0,0 +1,297 @@
+use crate::builtins::Builtin;
+use crate::job::JOB_LIST;
+use crate::shellhost::FunctionRegistry;
+use crate::signal::{list_signals, signal_from_name, signal_name};
+use cancel::Token;
+use failure::{bail, Fallible};
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen"))]
+/// Send a signal to processes or jobs.
+/// `kill [-SIGNAL | -s SIGNAL] PID|%JOB...` sends SIGNAL, given by
+/// name or number, to each process or job; the default is `TERM`.
+/// A job is named by `%` followed by its number or by a prefix of its
+/// command.  `kill -l` lists the signals, while `kill -l SIGNAL...`
+/// converts between signal names and numbers.
+pub struct KillCommand {
+    args: Vec<String>,
+}
+
+/// The process or job that a signal is sent to
+#[derive(Debug, PartialEq, Eq)]
+enum KillTarget {
+    Pid(i32),
+    /// A job spec, less its leading `%`
+    Job(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum KillArgs {
+    /// `kill -l`, with the signals to convert, if any
+    List(Vec<String>),
+    Send {
+        signal: i32,
+        targets: Vec<KillTarget>,
+    },
+}
+
+/// Parse a signal given by name or by number, as in `-TERM` or `-15`.
+/// 0 is accepted; it tests whether the target exists without
+/// affecting it.
+fn parse_signal(spec: &str) -> Option<i32> {
+    match spec.parse::<i32>() {
+        Ok(0) => Some(0),
+        Ok(signo) => signal_name(signo).map(|_| signo),
+        Err(_) => signal_from_name(spec),
+    }
+}
+
+fn parse_args(args: &[String]) -> Fallible<KillArgs> {
+    let mut signal = None;
+    let mut args = args.iter().peekable();
+
+    if let Some(first) = args.peek().cloned() {
+        if first == "-l" || first == "-L" {
+            args.next();
+            return Ok(KillArgs::List(args.cloned().collect()));
+        } else if first == "-s" || first == "-n" {
+            args.next();
+            let spec = match args.next() {
+                Some(spec) => spec,
+                None => bail!("{}: option requires an argument", first),
+            };
+            match parse_signal(spec) {
+                Some(signo) => signal = Some(signo),
+                None => bail!("{}: invalid signal specification", spec),
+            }
+        } else if first == "--" {
+            args.next();
+        } else if let Some(spec) = first.strip_prefix('-') {
+            match parse_signal(spec) {
+                Some(signo) => signal = Some(signo),
+                None => bail!("{}: invalid signal specification", first),
+            }
+            args.next();
+        }
+    }
+
+    let mut targets = vec![];
+    for arg in args {
+        if let Some(spec) = arg.strip_prefix('%') {
+            targets.push(KillTarget::Job(spec.to_owned()));
+        } else {
+            match arg.parse::<i32>() {
+                Ok(pid) => targets.push(KillTarget::Pid(pid)),
+                Err(_) => bail!("{}: arguments must be process or job IDs", arg),
+            }
+        }
+    }
+    if targets.is_empty() {
+        bail!("usage: kill [-s SIGNAL | -SIGNAL] PID|%JOB... or kill -l [SIGNAL]");
+    }
+
+    Ok(KillArgs::Send {
+        signal: signal.unwrap_or(SIGTERM),
+        targets,
+    })
+}
+
+#[cfg(unix)]
+const SIGTERM: i32 = libc::SIGTERM;
+#[cfg(windows)]
+const SIGTERM: i32 = 15;
+
+/// Find the process group of the job named by spec, given the
+/// process group id and label of each job.  The spec is either the
+/// number of the job, as shown by `jobs`, or a prefix of its command.
+fn resolve_job(spec: &str, jobs: &[(i32, String)]) -> Option<i32> {
+    match spec.parse::<i32>() {
+        Ok(id) => jobs.iter().find(|(pgid, _)| *pgid == id),
+        Err(_) => jobs.iter().find(|(_, label)| label.starts_with(spec)),
+    }
+    .map(|(pgid, _)| *pgid)
+}
+
+/// Convert each signal between its name and number, as for `kill -l 15`.
+/// An exit status greater than 128 is taken to be that of a process that
+/// was terminated by a signal.
+fn convert_signals(signals: &[String]) -> Fallible<String> {
+    let mut output = String::new();
+    for spec in signals {
+        match spec.parse::<i32>() {
+            Ok(num) => {
+                let signo = if num > 128 { num - 128 } else { num };
+                match signal_name(signo) {
+                    Some(name) => output.push_str(&format!("{}\n", name)),
+                    None => bail!("{}: invalid signal specification", spec),
+                }
+            }
+            Err(_) => match signal_from_name(spec) {
+                Some(signo) => output.push_str(&format!("{}\n", signo)),
+                None => bail!("{}: invalid signal specification", spec),
+            },
+        }
+    }
+    Ok(output)
+}
+
+/// Send signal to pid; a negative pid names a process group
+#[cfg(unix)]
+fn send_signal(pid: i32, signal: i32) -> std::io::Result<()> {
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn send_signal(_pid: i32, _signal: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "signals are not supported on this platform",
+    ))
+}
+
+impl Builtin for KillCommand {
+    fn name() -> &'static str {
+        "kill"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let (signal, targets) = match parse_args(&self.args) {
+            Ok(KillArgs::List(signals)) if signals.is_empty() => {
+                write!(io_env.stdout(), "{}", list_signals())?;
+                return Ok(Status::Complete(0.into()).into());
+            }
+            Ok(KillArgs::List(signals)) => {
+                return match convert_signals(&signals) {
+                    Ok(output) => {
+                        write!(io_env.stdout(), "{}", output)?;
+                        Ok(Status::Complete(0.into()).into())
+                    }
+                    Err(err) => {
+                        writeln!(io_env.stderr(), "wzsh: kill: {}", err)?;
+                        Ok(Status::Complete(1.into()).into())
+                    }
+                };
+            }
+            Ok(KillArgs::Send { signal, targets }) => (signal, targets),
+            Err(err) => {
+                writeln!(io_env.stderr(), "wzsh: kill: {}", err)?;
+                return Ok(Status::Complete(2.into()).into());
+            }
+        };
+
+        let jobs: Vec<(i32, String)> = JOB_LIST
+            .jobs()
+            .iter()
+            .map(|job| (job.process_group_id(), job.to_string()))
+            .collect();
+
+        let mut status = 0;
+        for target in targets {
+            let pid = match &target {
+                KillTarget::Pid(pid) => *pid,
+                KillTarget::Job(spec) => match resolve_job(spec, &jobs) {
+                    Some(pgid) => -pgid,
+                    None => {
+                        writeln!(io_env.stderr(), "wzsh: kill: %{}: no such job", spec)?;
+                        status = 1;
+                        continue;
+                    }
+                },
+            };
+            if let Err(err) = send_signal(pid, signal) {
+                let name = match &target {
+                    KillTarget::Pid(pid) => pid.to_string(),
+                    KillTarget::Job(spec) => format!("%{}", spec),
+                };
+                writeln!(io_env.stderr(), "wzsh: kill: ({}) - {}", name, err)?;
+                status = 1;
+            }
+        }
+        Ok(Status::Complete(status.into()).into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Fallible<KillArgs> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        parse_args(&args)
+    }
+
+    #[test]
+    fn signal_forms() -> Fallible<()> {
+        let send = |signal, targets| KillArgs::Send { signal, targets };
+        assert_eq!(
+            parse(&["123"])?,
+            send(libc::SIGTERM, vec![KillTarget::Pid(123)])
+        );
+        assert_eq!(
+            parse(&["-KILL", "1", "2"])?,
+            send(libc::SIGKILL, vec![KillTarget::Pid(1), KillTarget::Pid(2)])
+        );
+        assert_eq!(
+            parse(&["-9", "%1"])?,
+            send(libc::SIGKILL, vec![KillTarget::Job("1".to_owned())])
+        );
+        assert_eq!(
+            parse(&["-s", "sighup", "%vim"])?,
+            send(libc::SIGHUP, vec![KillTarget::Job("vim".to_owned())])
+        );
+        assert_eq!(parse(&["-0", "1"])?, send(0, vec![KillTarget::Pid(1)]));
+        assert_eq!(
+            parse(&["--", "-1"])?,
+            send(libc::SIGTERM, vec![KillTarget::Pid(-1)])
+        );
+
+        assert!(parse(&["-BOGUS", "1"]).is_err());
+        assert!(parse(&["-s"]).is_err());
+        assert!(parse(&["-TERM"]).is_err());
+        assert!(parse(&["foo"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn job_resolution() {
+        let jobs = vec![(100, "sleep 10".to_owned()), (200, "vim foo".to_owned())];
+        assert_eq!(resolve_job("200", &jobs), Some(200));
+        assert_eq!(resolve_job("vim", &jobs), Some(200));
+        assert_eq!(resolve_job("sl", &jobs), Some(100));
+        assert_eq!(resolve_job("300", &jobs), None);
+        assert_eq!(resolve_job("emacs", &jobs), None);
+    }
+
+    #[test]
+    fn listing() -> Fallible<()> {
+        assert_eq!(parse(&["-l"])?, KillArgs::List(vec![]));
+        assert!(list_signals().contains(" 9) SIGKILL\n"));
+
+        assert_eq!(
+            parse(&["-l", "15", "INT"])?,
+            KillArgs::List(vec!["15".to_owned(), "INT".to_owned()])
+        );
+        assert_eq!(
+            convert_signals(&["15".to_owned(), "INT".to_owned(), "137".to_owned()])?,
+            "TERM\n2\nKILL\n"
+        );
+        assert!(convert_signals(&["BOGUS".to_owned()]).is_err());
+        Ok(())
+    }
+}
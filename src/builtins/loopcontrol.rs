@@ -0,0 +1,82 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+// `break` and `continue` are compiled into jumps when they appear
+// inside a loop; these builtins are only reached when they are used
+// outside of a loop.  In that situation we follow bash and print a
+// warning, but otherwise succeed, unless the loop count is invalid.
+
+/// Report the use of `name` outside of a loop
+fn outside_loop(name: &str, n: Option<usize>, io_env: &IoEnvironment) -> Fallible<WaitableStatus> {
+    if n == Some(0) {
+        writeln!(
+            io_env.stderr(),
+            "wzsh: {}: 0: loop count out of range",
+            name
+        )?;
+        return Ok(Status::Complete(1.into()).into());
+    }
+    writeln!(
+        io_env.stderr(),
+        "wzsh: {}: only meaningful in a `for', `while', or `until' loop",
+        name
+    )?;
+    Ok(Status::Complete(0.into()).into())
+}
+
+#[derive(StructOpt)]
+/// Exit from the enclosing for, while or until loop(s).
+/// When used outside of a loop this prints a warning and has no effect.
+pub struct BreakCommand {
+    /// The number of enclosing loops to break out of
+    n: Option<usize>,
+}
+
+impl Builtin for BreakCommand {
+    fn name() -> &'static str {
+        "break"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        outside_loop(Self::name(), self.n, io_env)
+    }
+}
+
+#[derive(StructOpt)]
+/// Resume the next iteration of the enclosing for, while or until loop(s).
+/// When used outside of a loop this prints a warning and has no effect.
+pub struct ContinueCommand {
+    /// The number of enclosing loops to resume
+    n: Option<usize>,
+}
+
+impl Builtin for ContinueCommand {
+    fn name() -> &'static str {
+        "continue"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        outside_loop(Self::name(), self.n, io_env)
+    }
+}
@@ -0,0 +1,175 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Read lines from the standard input into an array.
+/// `mapfile [-t] [-n COUNT] [-s COUNT] [ARRAY]` assigns each line,
+/// including its trailing newline unless `-t` is given, to successive
+/// elements of ARRAY, which is `MAPFILE` if omitted.
+pub struct MapfileCommand {
+    /// Remove the trailing newline from each line
+    #[structopt(short = "t")]
+    trim: bool,
+
+    /// Read at most COUNT lines; all of the lines are read
+    /// if COUNT is 0
+    #[structopt(short = "n", default_value = "0")]
+    count: usize,
+
+    /// Discard the first COUNT lines
+    #[structopt(short = "s", default_value = "0")]
+    skip: usize,
+
+    array: Option<String>,
+}
+
+#[derive(StructOpt)]
+/// Read lines from the standard input into an array.
+/// This is a synonym for `mapfile`.
+pub struct ReadarrayCommand {
+    #[structopt(flatten)]
+    mapfile: MapfileCommand,
+}
+
+/// Read lines from reader, skipping the first `skip` and then
+/// returning up to `count` of them, or all of them if `count` is 0.
+/// The input is read a byte at a time so that nothing beyond the
+/// last line that is returned is consumed, leaving it for the
+/// next command to read.
+fn read_lines<R: Read>(
+    mut reader: R,
+    trim: bool,
+    count: usize,
+    skip: usize,
+) -> Fallible<Vec<String>> {
+    let mut lines = vec![];
+    let mut skipped = 0;
+    let mut line = vec![];
+    let mut buf = [0u8; 1];
+
+    while count == 0 || lines.len() < count {
+        let eof = reader.read(&mut buf)? == 0;
+        if !eof {
+            line.push(buf[0]);
+            if buf[0] != b'\n' {
+                continue;
+            }
+        } else if line.is_empty() {
+            break;
+        }
+
+        if skipped < skip {
+            skipped += 1;
+        } else {
+            if trim && line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+        line.clear();
+
+        if eof {
+            break;
+        }
+    }
+
+    Ok(lines)
+}
+
+impl MapfileCommand {
+    fn mapfile(
+        &mut self,
+        name: &str,
+        environment: &mut Environment,
+        io_env: &IoEnvironment,
+    ) -> Fallible<WaitableStatus> {
+        let array = self.array.as_deref().unwrap_or("MAPFILE");
+        let lines = read_lines(io_env.stdin(), self.trim, self.count, self.skip)?;
+        if let Err(err) = environment.assign_array(array, lines.into_iter().map(OsString::from)) {
+            writeln!(io_env.stderr(), "wzsh: {}: {}", name, err)?;
+            return Ok(Status::Complete(1.into()).into());
+        }
+        Ok(Status::Complete(0.into()).into())
+    }
+}
+
+impl Builtin for MapfileCommand {
+    fn name() -> &'static str {
+        "mapfile"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        self.mapfile(Self::name(), environment, io_env)
+    }
+}
+
+impl Builtin for ReadarrayCommand {
+    fn name() -> &'static str {
+        "readarray"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        self.mapfile.mapfile(Self::name(), environment, io_env)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INPUT: &[u8] = b"one\ntwo\n\nfour\nfive";
+
+    #[test]
+    fn trim() -> Fallible<()> {
+        assert_eq!(
+            read_lines(INPUT, false, 0, 0)?,
+            vec!["one\n", "two\n", "\n", "four\n", "five"]
+        );
+        assert_eq!(
+            read_lines(INPUT, true, 0, 0)?,
+            vec!["one", "two", "", "four", "five"]
+        );
+        assert!(read_lines(&b""[..], true, 0, 0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn count() -> Fallible<()> {
+        let mut input = INPUT;
+        assert_eq!(read_lines(&mut input, true, 2, 0)?, vec!["one", "two"]);
+        // The remaining lines are left unread
+        assert_eq!(input, b"\nfour\nfive");
+        assert_eq!(read_lines(INPUT, true, 10, 0)?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn skip() -> Fallible<()> {
+        assert_eq!(read_lines(INPUT, true, 0, 3)?, vec!["four", "five"]);
+        assert_eq!(read_lines(INPUT, true, 1, 1)?, vec!["two"]);
+        assert!(read_lines(INPUT, true, 0, 5)?.is_empty());
+        Ok(())
+    }
+}
@@ -8,17 +8,71 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use structopt::*;
 
+#[allow(clippy::module_inception)]
 mod builtins;
+mod clear;
 mod colon;
+mod complete;
+mod declare;
 mod env;
+mod eval;
+mod fc;
+mod help;
 mod jobcontrol;
+mod kill;
+mod loopcontrol;
+mod mapfile;
+mod printf;
+mod read;
+mod reload;
+mod set;
+mod title;
+mod trap;
 mod truefalse;
+mod version;
 mod which;
 mod workingdir;
 
 /// The `Builtin` trait extends `StructOpt` by adding `name` and `run`
 /// methods that allow registering a command with the shell.
 pub trait Builtin: StructOpt {
+    /// Returns the clap app that parses the arguments to this builtin
+    fn app<'a, 'b>() -> structopt::clap::App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        Self::clap()
+            .global_setting(structopt::clap::AppSettings::DisableVersion)
+            .name(Self::name())
+    }
+
+    /// Returns the full usage information, as shown by `help NAME`
+    fn usage() -> String
+    where
+        Self: Sized,
+    {
+        let mut usage = vec![];
+        Self::app().write_help(&mut usage).ok();
+        String::from_utf8_lossy(&usage).into_owned()
+    }
+
+    /// Returns a one line description of the builtin; this is the
+    /// first sentence of its doc comment.
+    fn summary() -> String
+    where
+        Self: Sized,
+    {
+        let mut about = vec![];
+        Self::app().template("{about}").write_help(&mut about).ok();
+        let about = String::from_utf8_lossy(&about);
+        let about = about.trim();
+        let sentence = match about.find(". ") {
+            Some(end) => &about[..end],
+            None => about,
+        };
+        sentence.trim_end_matches('.').to_owned()
+    }
+
     fn eval(
         argv: &[Value],
         environment: &mut Environment,
@@ -30,10 +84,7 @@ pub trait Builtin: StructOpt {
     where
         Self: Sized,
     {
-        let app = Self::clap()
-            .global_setting(structopt::clap::AppSettings::ColoredHelp)
-            .global_setting(structopt::clap::AppSettings::DisableVersion)
-            .name(Self::name());
+        let app = Self::app().global_setting(structopt::clap::AppSettings::ColoredHelp);
         let mut os_args = vec![];
         for arg in argv {
             os_args.push(
@@ -66,16 +117,40 @@ pub type BuiltinFunc = fn(
     functions: &Arc<FunctionRegistry>,
 ) -> Fallible<WaitableStatus>;
 
+/// An entry in the builtin registry
+pub struct BuiltinEntry {
+    pub func: BuiltinFunc,
+    /// A one line description of the builtin
+    pub summary: String,
+    /// Produces the full usage information for the builtin
+    pub usage: fn() -> String,
+}
+
+/// The POSIX special builtins.  These are found before shell
+/// functions when resolving a command, so a function cannot replace
+/// them, whereas a function of the same name as a regular builtin
+/// takes precedence over it.  Only those that are registered in
+/// `BUILTINS` take effect.
+const SPECIAL_BUILTINS: &[&str] = &[
+    ".", ":", "break", "continue", "eval", "exec", "exit", "export", "readonly", "return", "set",
+    "shift", "times", "trap", "unset",
+];
+
+/// Returns true if `name` is a special builtin
+pub fn is_special_builtin(name: &str) -> bool {
+    SPECIAL_BUILTINS.contains(&name)
+}
+
 pub fn lookup_builtin(name: &Value) -> Option<BuiltinFunc> {
     if let Some(s) = name.as_str() {
-        BUILTINS.get(s).map(|f| *f)
+        BUILTINS.get(s).map(|entry| entry.func)
     } else {
         None
     }
 }
 
 lazy_static! {
-    static ref BUILTINS: HashMap<&'static str, BuiltinFunc> = {
+    static ref BUILTINS: HashMap<&'static str, BuiltinEntry> = {
         let mut builtins = HashMap::new();
         // This identity helper effectively casts away the per-function
         // type information that would otherwise cause a type mismatch
@@ -86,20 +161,46 @@ lazy_static! {
         macro_rules! builtins {
             ($($CmdType:ty),* $(,)? ) => {
                 $(
-                builtins.insert(<$CmdType>::name(), identity(<$CmdType>::eval));
+                builtins.insert(<$CmdType>::name(), BuiltinEntry {
+                    func: identity(<$CmdType>::eval),
+                    summary: <$CmdType>::summary(),
+                    usage: <$CmdType>::usage,
+                });
                 )*
             }
         }
 
         builtins!(
             builtins::BuiltinsCommand,
+            clear::ClearCommand,
             colon::ColonCommand,
+            complete::CompgenCommand,
+            complete::CompleteCommand,
+            declare::DeclareCommand,
+            declare::TypesetCommand,
             env::ExportCommand,
+            env::LocalCommand,
+            env::ReadonlyCommand,
             env::UnsetCommand,
+            eval::EvalCommand,
+            fc::FcCommand,
+            help::HelpCommand,
             jobcontrol::FgCommand,
             jobcontrol::JobsCommand,
+            kill::KillCommand,
+            loopcontrol::BreakCommand,
+            loopcontrol::ContinueCommand,
+            mapfile::MapfileCommand,
+            mapfile::ReadarrayCommand,
+            printf::PrintfCommand,
+            read::ReadCommand,
+            reload::ReloadCommand,
+            set::SetCommand,
+            title::TitleCommand,
+            trap::TrapCommand,
             truefalse::FalseCommand,
             truefalse::TrueCommand,
+            version::VersionCommand,
             which::WhichCommand,
             workingdir::CdCommand,
             workingdir::PwdCommand,
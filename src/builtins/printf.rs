@@ -0,0 +1,520 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{shell_quote, Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen"))]
+/// Write the ARGS to stdout as directed by FORMAT.
+/// FORMAT is output as-is, other than backslash escapes and the
+/// conversions `%s`, `%d`, `%i`, `%u`, `%x`, `%X`, `%o`, `%c`, `%b`
+/// (expand backslash escapes in the argument), `%q` (quote the
+/// argument for reuse as shell input) and `%%`.  Conversions accept
+/// the flags `-+ #0`, a width and a precision.  FORMAT is reused as
+/// many times as is needed to consume all of the ARGS.
+pub struct PrintfCommand {
+    format: String,
+    args: Vec<String>,
+}
+
+/// The result of formatting: the output, along with complaints
+/// about arguments that could not be converted.  The output is
+/// bytes because escapes such as `\xff` need not produce UTF-8.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Formatted {
+    output: Vec<u8>,
+    errors: Vec<String>,
+}
+
+/// A parsed `%` conversion specification
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Pad `text` to the field width
+    fn pad(&self, text: &str, zero_ok: bool) -> String {
+        let len = text.chars().count();
+        let width = match self.width {
+            Some(width) if width > len => width,
+            _ => return text.to_owned(),
+        };
+        let padding = width - len;
+        if self.left {
+            format!("{}{}", text, " ".repeat(padding))
+        } else if self.zero && zero_ok {
+            // Zeros go after any sign or radix prefix
+            let mut prefix_len = 0;
+            if text.starts_with(['-', '+', ' ']) {
+                prefix_len += 1;
+            }
+            if text[prefix_len..].starts_with("0x") || text[prefix_len..].starts_with("0X") {
+                prefix_len += 2;
+            }
+            format!(
+                "{}{}{}",
+                &text[..prefix_len],
+                "0".repeat(padding),
+                &text[prefix_len..]
+            )
+        } else {
+            format!("{}{}", " ".repeat(padding), text)
+        }
+    }
+}
+
+/// Supplies the arguments to the conversions in turn
+struct Args<'a> {
+    args: &'a [String],
+    next: usize,
+}
+
+impl<'a> Args<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let arg = self.args.get(self.next)?;
+        self.next += 1;
+        Some(arg)
+    }
+
+    /// Take the next argument as a number; missing arguments are 0
+    fn number(&mut self, errors: &mut Vec<String>) -> i64 {
+        match self.next() {
+            Some(arg) => parse_number(arg).unwrap_or_else(|partial| {
+                errors.push(format!("{}: invalid number", arg));
+                partial
+            }),
+            None => 0,
+        }
+    }
+}
+
+/// Parse a numeric argument as accepted by printf: decimal, octal
+/// with a leading `0`, hexadecimal with a leading `0x`, or the
+/// character code of the character following a leading quote.
+/// On failure, the value of the valid prefix is returned as the error.
+fn parse_number(arg: &str) -> Result<i64, i64> {
+    let trimmed = arg.trim_start();
+    if let Some(quoted) = trimmed
+        .strip_prefix('\'')
+        .or_else(|| trimmed.strip_prefix('"'))
+    {
+        return Ok(quoted.chars().next().map(|c| c as i64).unwrap_or(0));
+    }
+
+    let (negative, digits) = match trimmed.as_bytes().first() {
+        Some(b'-') => (true, &trimmed[1..]),
+        Some(b'+') => (false, &trimmed[1..]),
+        _ => (false, trimmed),
+    };
+    let (radix, digits) = if digits.starts_with("0x") || digits.starts_with("0X") {
+        (16, &digits[2..])
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (8, &digits[1..])
+    } else {
+        (10, digits)
+    };
+
+    let mut value: i64 = 0;
+    let mut valid = !digits.is_empty();
+    for c in digits.chars() {
+        match c.to_digit(radix) {
+            Some(digit) => value = value.wrapping_mul(radix as i64).wrapping_add(digit as i64),
+            None => {
+                valid = false;
+                break;
+            }
+        }
+    }
+    let value = if negative { -value } else { value };
+    if valid {
+        Ok(value)
+    } else {
+        Err(value)
+    }
+}
+
+/// Append the UTF-8 encoding of `c` to `output`
+fn push_char(output: &mut Vec<u8>, c: char) {
+    output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+/// Expand the backslash escape at the start of `chars`, which follows
+/// a backslash, appending the result to `output`.  In `%b` arguments,
+/// octal escapes are written `\0NNN` rather than `\NNN`.  Octal and
+/// hex escapes produce a single byte.
+/// Returns false if the escape was `\c`, which ends all output.
+fn escape(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    output: &mut Vec<u8>,
+    in_arg: bool,
+) -> bool {
+    let take_digits = |chars: &mut std::iter::Peekable<std::str::Chars>, radix, max| {
+        let mut value = 0u32;
+        let mut count = 0;
+        while count < max {
+            match chars.peek().and_then(|c| c.to_digit(radix)) {
+                Some(digit) => {
+                    value = value * radix + digit;
+                    chars.next();
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        (value, count)
+    };
+
+    let c = match chars.next() {
+        Some(c) => c,
+        None => {
+            output.push(b'\\');
+            return true;
+        }
+    };
+    match c {
+        'a' => output.push(b'\x07'),
+        'b' => output.push(b'\x08'),
+        'e' | 'E' => output.push(b'\x1b'),
+        'f' => output.push(b'\x0c'),
+        'n' => output.push(b'\n'),
+        'r' => output.push(b'\r'),
+        't' => output.push(b'\t'),
+        'v' => output.push(b'\x0b'),
+        '\\' => output.push(b'\\'),
+        'c' if in_arg => return false,
+        '0'..='7' => {
+            let (value, _) = if in_arg && c == '0' {
+                take_digits(chars, 8, 3)
+            } else {
+                let (rest, count) = take_digits(chars, 8, 2);
+                let digit = c.to_digit(8).unwrap();
+                (digit * 8u32.pow(count as u32) + rest, count + 1)
+            };
+            output.push((value & 0xff) as u8);
+        }
+        'x' => {
+            let (value, count) = take_digits(chars, 16, 2);
+            if count == 0 {
+                output.extend_from_slice(b"\\x");
+            } else {
+                output.push(value as u8);
+            }
+        }
+        '"' | '\'' if !in_arg => output.push(c as u8),
+        c => {
+            output.push(b'\\');
+            push_char(output, c);
+        }
+    }
+    true
+}
+
+/// Expand the backslash escapes in `text`.  Returns the expansion
+/// and false if the text contained `\c`, which ends all output.
+fn expand_escapes(text: &str) -> (Vec<u8>, bool) {
+    let mut output = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if !escape(&mut chars, &mut output, true) {
+                return (output, false);
+            }
+        } else {
+            push_char(&mut output, c);
+        }
+    }
+    (output, true)
+}
+
+/// Format the integer `value` according to `spec` and the conversion
+fn format_integer(value: i64, spec: &Spec, conversion: char) -> String {
+    let negative = value < 0 && (conversion == 'd' || conversion == 'i');
+    let mut digits = match conversion {
+        'x' => format!("{:x}", value),
+        'X' => format!("{:X}", value),
+        'o' => format!("{:o}", value),
+        'u' => format!("{}", value as u64),
+        _ => format!("{}", value.unsigned_abs()),
+    };
+    if let Some(precision) = spec.precision {
+        if digits.len() < precision {
+            digits = format!("{}{}", "0".repeat(precision - digits.len()), digits);
+        }
+    }
+    if spec.alternate && value != 0 {
+        match conversion {
+            'x' => digits.insert_str(0, "0x"),
+            'X' => digits.insert_str(0, "0X"),
+            'o' if !digits.starts_with('0') => digits.insert(0, '0'),
+            _ => {}
+        }
+    }
+    if conversion == 'd' || conversion == 'i' {
+        if negative {
+            digits.insert(0, '-');
+        } else if spec.plus {
+            digits.insert(0, '+');
+        } else if spec.space {
+            digits.insert(0, ' ');
+        }
+    }
+    spec.pad(&digits, spec.precision.is_none())
+}
+
+/// Apply `format` to `args`, reusing the format until all of the
+/// arguments have been consumed
+fn printf(format: &str, args: &[String]) -> Formatted {
+    let mut result = Formatted::default();
+    let mut args = Args { args, next: 0 };
+
+    loop {
+        let consumed_before = args.next;
+        if !format_once(format, &mut args, &mut result) {
+            break;
+        }
+        // Stop when the arguments are exhausted, or when the format
+        // has no conversions that would consume them
+        if args.next >= args.args.len() || args.next == consumed_before {
+            break;
+        }
+    }
+    result
+}
+
+/// A single pass over the format.  Returns false if output was
+/// ended by `\c` or by an invalid conversion.
+fn format_once(format: &str, args: &mut Args, result: &mut Formatted) -> bool {
+    let output = &mut result.output;
+    let errors = &mut result.errors;
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            escape(&mut chars, output, false);
+            continue;
+        }
+        if c != '%' {
+            push_char(output, c);
+            continue;
+        }
+
+        let mut spec = Spec::default();
+        while let Some(&flag) = chars.peek() {
+            match flag {
+                '-' => spec.left = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alternate = true,
+                '0' => spec.zero = true,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        let mut number = |chars: &mut std::iter::Peekable<std::str::Chars>, args: &mut Args| {
+            if chars.peek() == Some(&'*') {
+                chars.next();
+                let value = args.number(errors);
+                return Some(value);
+            }
+            let mut value = None;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                value = Some(value.unwrap_or(0) * 10 + digit as i64);
+                chars.next();
+            }
+            value
+        };
+
+        if let Some(width) = number(&mut chars, args) {
+            if width < 0 {
+                spec.left = true;
+            }
+            spec.width = Some(width.unsigned_abs() as usize);
+        }
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            spec.precision = Some(number(&mut chars, args).unwrap_or(0).max(0) as usize);
+        }
+
+        let conversion = match chars.next() {
+            Some(conversion) => conversion,
+            None => {
+                errors.push("missing format character".to_owned());
+                return false;
+            }
+        };
+        let text = match conversion {
+            '%' => "%".to_owned(),
+            's' | 'b' | 'q' => {
+                let arg = args.next().unwrap_or("");
+                let (text, more) = match conversion {
+                    's' => (arg.as_bytes().to_vec(), true),
+                    'b' => expand_escapes(arg),
+                    _ => (shell_quote(arg).into_bytes(), true),
+                };
+                match String::from_utf8(text) {
+                    Ok(text) => {
+                        let text = match spec.precision {
+                            Some(precision) => text.chars().take(precision).collect(),
+                            None => text,
+                        };
+                        output.extend_from_slice(spec.pad(&text, false).as_bytes());
+                    }
+                    Err(err) => {
+                        // Raw bytes from `%b` are measured in bytes
+                        let mut text = err.into_bytes();
+                        if let Some(precision) = spec.precision {
+                            text.truncate(precision);
+                        }
+                        let padding = spec.width.unwrap_or(0).saturating_sub(text.len());
+                        if spec.left {
+                            output.extend_from_slice(&text);
+                            output.resize(output.len() + padding, b' ');
+                        } else {
+                            output.resize(output.len() + padding, b' ');
+                            output.extend_from_slice(&text);
+                        }
+                    }
+                }
+                if !more {
+                    return false;
+                }
+                continue;
+            }
+            'c' => {
+                let c = args.next().and_then(|arg| arg.chars().next());
+                spec.pad(&c.map(String::from).unwrap_or_default(), false)
+            }
+            'd' | 'i' | 'u' | 'x' | 'X' | 'o' => {
+                let value = args.number(errors);
+                format_integer(value, &spec, conversion)
+            }
+            other => {
+                errors.push(format!("%{}: invalid format character", other));
+                return false;
+            }
+        };
+        output.extend_from_slice(text.as_bytes());
+    }
+    true
+}
+
+impl Builtin for PrintfCommand {
+    fn name() -> &'static str {
+        "printf"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let formatted = printf(&self.format, &self.args);
+        io_env.stdout().write_all(&formatted.output)?;
+        for error in &formatted.errors {
+            writeln!(io_env.stderr(), "wzsh: printf: {}", error)?;
+        }
+        let status = if formatted.errors.is_empty() { 0 } else { 1 };
+        Ok(Status::Complete(status.into()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn output(format: &str, args: &[&str]) -> String {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let formatted = printf(format, &args);
+        assert_eq!(formatted.errors, Vec::<String>::new());
+        String::from_utf8(formatted.output).unwrap()
+    }
+
+    #[test]
+    fn strings_and_numbers() {
+        assert_eq!(output("%s=%d\\n", &["x", "42"]), "x=42\n");
+        assert_eq!(
+            output("[%5s|%-5s|%.2s]", &["ab", "cd", "efg"]),
+            "[   ab|cd   |ef]"
+        );
+        assert_eq!(
+            output(
+                "%05d %+d %x %X %o %#x %#o",
+                &["-42", "7", "255", "255", "8", "255", "8"]
+            ),
+            "-0042 +7 ff FF 10 0xff 010"
+        );
+        assert_eq!(
+            output("%.3d|%*d|%c|%%", &["5", "4", "7", "xyz"]),
+            "005|   7|x|%"
+        );
+        assert_eq!(output("%d %d %d", &["0x10", "010", "'A"]), "16 8 65");
+        // Missing arguments are empty or zero
+        assert_eq!(output("[%s][%d]", &[]), "[][0]");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(output("a\\tb\\\\\\101", &[]), "a\tb\\A");
+        assert_eq!(output("%b|", &["1\\n2\\0101"]), "1\n2A|");
+        // `\c` in a %b argument ends all output
+        assert_eq!(output("%b%s", &["stop\\chere", "more"]), "stop");
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let args = vec!["\\0377\\xfe".to_owned(), "\\xff\\xfe".to_owned()];
+        assert_eq!(
+            printf("\\xff\\200%b|%3.1b|", &args).output,
+            b"\xff\x80\xff\xfe|  \xff|".to_vec()
+        );
+        // Characters outside of escapes are written as UTF-8
+        assert_eq!(printf("é\\351", &[]).output, b"\xc3\xa9\xe9".to_vec());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            output("%q %q", &["plain", "it's a test"]),
+            "plain 'it'\\''s a test'"
+        );
+        assert_eq!(output("%q", &[""]), "''");
+    }
+
+    #[test]
+    fn cycling() {
+        assert_eq!(output("%s-%s\\n", &["a", "b", "c"]), "a-b\nc-\n");
+        assert_eq!(output("<%d>", &["1", "2", "3"]), "<1><2><3>");
+        // A format without conversions is printed once
+        assert_eq!(output("hi\\n", &["unused"]), "hi\n");
+    }
+
+    #[test]
+    fn errors() {
+        let formatted = printf("%d %d|", &["12abc".to_owned(), "3".to_owned()]);
+        assert_eq!(formatted.output, b"12 3|");
+        assert_eq!(formatted.errors, vec!["12abc: invalid number".to_owned()]);
+
+        let formatted = printf("a%zb", &[]);
+        assert_eq!(formatted.output, b"a");
+        assert_eq!(
+            formatted.errors,
+            vec!["%z: invalid format character".to_owned()]
+        );
+    }
+}
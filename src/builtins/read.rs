@@ -0,0 +1,199 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Read a line from the standard input and split it into fields.
+/// `read [-r] [NAME...]` splits the line as directed by `IFS` and
+/// assigns each field to the next NAME; the last NAME is assigned
+/// the remainder of the line.  The line is assigned to `REPLY` if
+/// no NAME is given.  The exit status is 1 if the end of the input
+/// is reached before a newline.
+pub struct ReadCommand {
+    /// Don't treat a backslash as an escape character
+    #[structopt(short = "r")]
+    raw: bool,
+
+    names: Vec<String>,
+}
+
+/// Read a line from reader, without its trailing newline.
+/// Unless `raw` is set, a backslash before the newline continues
+/// the line onto the next one.  The input is read a byte at a time
+/// so that nothing beyond the line is consumed.
+/// Returns the line and whether the newline was found.
+fn read_line<R: Read>(mut reader: R, raw: bool) -> Fallible<(String, bool)> {
+    let mut line = vec![];
+    let mut buf = [0u8; 1];
+    loop {
+        if reader.read(&mut buf)? == 0 {
+            return Ok((String::from_utf8_lossy(&line).into_owned(), false));
+        }
+        if buf[0] != b'\n' {
+            line.push(buf[0]);
+            continue;
+        }
+        let backslashes = line.iter().rev().take_while(|&&b| b == b'\\').count();
+        if raw || backslashes % 2 == 0 {
+            return Ok((String::from_utf8_lossy(&line).into_owned(), true));
+        }
+        line.pop();
+    }
+}
+
+/// Split line into at most `count` fields, as directed by the
+/// characters in ifs.  The last field holds the remainder of the
+/// line, less any leading and trailing IFS whitespace.  Unless `raw`
+/// is set, a backslash causes the following character to be taken
+/// literally, rather than as a separator, and is removed.
+fn split_fields(line: &str, ifs: &str, count: usize, raw: bool) -> Vec<String> {
+    let is_ifs = |c: char| ifs.contains(c);
+    let is_ifs_white = |c: char| is_ifs(c) && (c == ' ' || c == '\t' || c == '\n');
+
+    let mut fields = vec![];
+    let mut chars = line.chars().peekable();
+    while chars.next_if(|&c| is_ifs_white(c)).is_some() {}
+
+    let mut field = String::new();
+    // The length of field up to the last escaped character, which
+    // is kept even if it is IFS whitespace
+    let mut literal_len = 0;
+    while let Some(c) = chars.next() {
+        if c == '\\' && !raw {
+            if let Some(c) = chars.next() {
+                field.push(c);
+                literal_len = field.len();
+            }
+            continue;
+        }
+        if !is_ifs(c) || fields.len() + 1 >= count {
+            field.push(c);
+            continue;
+        }
+        fields.push(std::mem::take(&mut field));
+        literal_len = 0;
+        while chars.next_if(|&c| is_ifs_white(c)).is_some() {}
+        if is_ifs_white(c) && chars.next_if(|&c| is_ifs(c)).is_some() {
+            while chars.next_if(|&c| is_ifs_white(c)).is_some() {}
+        }
+    }
+
+    let trimmed = field[literal_len..].trim_end_matches(is_ifs_white).len();
+    field.truncate(literal_len + trimmed);
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+    }
+    fields
+}
+
+impl Builtin for ReadCommand {
+    fn name() -> &'static str {
+        "read"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let (line, complete) = read_line(io_env.stdin(), self.raw)?;
+
+        let names = match self.names.is_empty() {
+            true => vec!["REPLY".to_owned()],
+            false => self.names.clone(),
+        };
+        let ifs = environment.get_str("IFS")?.unwrap_or(" \t\n").to_owned();
+        let mut fields = split_fields(&line, &ifs, names.len(), self.raw).into_iter();
+        for name in &names {
+            let value = fields.next().unwrap_or_default();
+            if let Err(err) = environment.assign(name, value) {
+                writeln!(io_env.stderr(), "wzsh: {}: {}", Self::name(), err)?;
+                return Ok(Status::Complete(1.into()).into());
+            }
+        }
+
+        let status = if complete { 0 } else { 1 };
+        Ok(Status::Complete(status.into()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::compile_and_run_string;
+
+    const IFS: &str = " \t\n";
+
+    #[test]
+    fn lines() -> Fallible<()> {
+        let mut input = &b"one\ntwo"[..];
+        assert_eq!(read_line(&mut input, false)?, ("one".to_owned(), true));
+        // The rest of the input is left unread
+        assert_eq!(input, b"two");
+        assert_eq!(read_line(&mut input, false)?, ("two".to_owned(), false));
+
+        let input = &b"one \\\ntwo\n"[..];
+        assert_eq!(read_line(input, false)?, ("one two".to_owned(), true));
+        assert_eq!(read_line(input, true)?, ("one \\".to_owned(), true));
+        Ok(())
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(split_fields("  a  b c  ", IFS, 2, false), vec!["a", "b c"]);
+        assert_eq!(split_fields("  a  b c  ", IFS, 1, false), vec!["a  b c"]);
+        assert_eq!(split_fields("a b c", IFS, 5, false), vec!["a", "b", "c"]);
+        assert!(split_fields("   ", IFS, 2, false).is_empty());
+
+        assert_eq!(
+            split_fields("a:b::c", ":", 4, false),
+            vec!["a", "b", "", "c"]
+        );
+        assert_eq!(split_fields("a : b", " :", 2, false), vec!["a", "b"]);
+
+        // An escaped separator is part of the field
+        assert_eq!(split_fields("a\\ b c", IFS, 2, false), vec!["a b", "c"]);
+        assert_eq!(split_fields("a\\ b c", IFS, 2, true), vec!["a\\", "b c"]);
+        assert_eq!(split_fields("a b\\ ", IFS, 2, false), vec!["a", "b "]);
+    }
+
+    #[test]
+    fn lastpipe() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        let mut cwd = std::env::current_dir()?;
+        let funcs = Arc::new(FunctionRegistry::new());
+
+        // By default, the last stage runs as though in a subshell
+        compile_and_run_string(
+            "v=old; printf 'x y z\\n' | read v",
+            &mut cwd,
+            &mut env,
+            &funcs,
+        )?;
+        assert_eq!(env.get_str("v")?, Some("old"));
+
+        compile_and_run_string(
+            "set -o lastpipe; printf 'x y z\\n' | read v w",
+            &mut cwd,
+            &mut env,
+            &funcs,
+        )?;
+        assert_eq!(env.get_str("v")?, Some("x"));
+        assert_eq!(env.get_str("w")?, Some("y z"));
+
+        // The exit status reports whether a whole line was read
+        let status = compile_and_run_string("printf x | read", &mut cwd, &mut env, &funcs)?;
+        assert_eq!(status, Status::Complete(1.into()));
+        assert_eq!(env.get_str("REPLY")?, Some("x"));
+        Ok(())
+    }
+}
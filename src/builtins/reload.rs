@@ -0,0 +1,113 @@
+use crate::builtins::Builtin;
+use crate::errorprint::format_error;
+use crate::repl::rc_file;
+use crate::script::compile_and_run_script_file;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Re-read the rc file into the current shell.
+/// `reload [FILE]` runs FILE, or by default the rc file that is read
+/// at startup, in the current shell, so that the functions and
+/// variables that it defines replace those from an earlier version.
+/// Commands are looked up in PATH each time that they are run, so
+/// there is no cache of their locations to be cleared.
+pub struct ReloadCommand {
+    /// The file to read in place of the rc file
+    #[structopt(parse(from_os_str))]
+    file: Option<PathBuf>,
+}
+
+impl Builtin for ReloadCommand {
+    fn name() -> &'static str {
+        "reload"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let path = match &self.file {
+            Some(file) => current_directory.join(file),
+            None => rc_file(environment),
+        };
+        if !path.is_file() {
+            writeln!(
+                io_env.stderr(),
+                "wzsh: reload: {}: no such file",
+                path.display()
+            )?;
+            return Ok(Status::Complete(1.into()).into());
+        }
+
+        match compile_and_run_script_file(&path, current_directory, environment, functions) {
+            Ok(status) => Ok(status.into()),
+            Err(err) => {
+                let input = std::fs::read_to_string(&path).unwrap_or_default();
+                write!(io_env.stderr(), "{}", format_error(&err, &input))?;
+                Ok(Status::Complete(1.into()).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shell_vm::Value;
+
+    fn reload(
+        env: &mut Environment,
+        funcs: &Arc<FunctionRegistry>,
+        path: &std::path::Path,
+    ) -> Fallible<Option<Status>> {
+        let argv: Vec<Value> = vec!["reload".into(), path.as_os_str().to_owned().into()];
+        let mut cwd = std::env::current_dir()?;
+        let status = ReloadCommand::eval(
+            &argv,
+            env,
+            &mut cwd,
+            &IoEnvironment::new()?,
+            Arc::new(Token::new()),
+            funcs,
+        )?;
+        Ok(status.poll())
+    }
+
+    #[test]
+    fn replaces_definitions() -> Fallible<()> {
+        let root = tempdir::TempDir::new("wzsh")?;
+        let rc = root.path().join("wzshrc");
+        let mut env = Environment::new_empty();
+        let funcs = Arc::new(FunctionRegistry::new());
+
+        std::fs::write(&rc, "GREETING=hello\ngreet() { echo hello; }\n")?;
+        reload(&mut env, &funcs, &rc)?;
+        let first = funcs.lookup_function("greet").unwrap();
+
+        std::fs::write(&rc, "GREETING=goodbye\ngreet() { echo goodbye; }\n")?;
+        reload(&mut env, &funcs, &rc)?;
+        // Reloading again is harmless
+        reload(&mut env, &funcs, &rc)?;
+
+        assert_eq!(env.get_str("GREETING")?, Some("goodbye"));
+        let second = funcs.lookup_function("greet").unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        assert_eq!(
+            reload(&mut env, &funcs, &root.path().join("missing"))?,
+            Some(Status::Complete(1.into()))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, ShellOptions, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::AllowLeadingHyphen"))]
+/// Set shell options.
+/// `set -o NAME` enables an option and `set +o NAME` disables it.
+/// `set -o` lists the state of each option, while `set +o` prints
+/// the commands that would recreate the current settings.
+/// `set -u` and `set +u` are shorthand for `set -o nounset` and
+/// `set +o nounset`, as are `set -x` and `set +x` for `xtrace`
+/// and `set -C` and `set +C` for `noclobber`.
+pub struct SetCommand {
+    args: Vec<String>,
+}
+
+/// The single letter flags that are shorthand for `set -o NAME`
+const SHORT_OPTIONS: &[(&str, &str)] = &[("u", "nounset"), ("x", "xtrace"), ("C", "noclobber")];
+
+/// Parse a `-o`, `+o` or single letter flag, returning the new state
+/// of the option along with its name.  The name is None for `-o` and
+/// `+o`, as it is taken from the following argument.
+fn parse_flag(arg: &str) -> Option<(bool, Option<&'static str>)> {
+    let value = match arg.get(..1)? {
+        "-" => true,
+        "+" => false,
+        _ => return None,
+    };
+    match &arg[1..] {
+        "o" => Some((value, None)),
+        flag => SHORT_OPTIONS
+            .iter()
+            .find(|(short, _)| *short == flag)
+            .map(|(_, name)| (value, Some(*name))),
+    }
+}
+
+/// Format the options as listed by `set -o`
+fn list_options(options: &ShellOptions) -> String {
+    options
+        .iter()
+        .map(|(name, value)| format!("{:<15}\t{}\n", name, if value { "on" } else { "off" }))
+        .collect()
+}
+
+/// Format the options as the `set` commands that reproduce them
+fn options_as_commands(options: &ShellOptions) -> String {
+    options
+        .iter()
+        .map(|(name, value)| format!("set {}o {}\n", if value { '-' } else { '+' }, name))
+        .collect()
+}
+
+impl Builtin for SetCommand {
+    fn name() -> &'static str {
+        "set"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            let (value, name) = match parse_flag(arg) {
+                Some((value, Some(name))) => (value, Some(name)),
+                Some((value, None)) => (value, args.next().map(String::as_str)),
+                None => {
+                    writeln!(io_env.stderr(), "wzsh: set: {}: invalid option", arg)?;
+                    return Ok(Status::Complete(2.into()).into());
+                }
+            };
+            match name {
+                Some(name) => {
+                    if let Err(err) = environment.options_mut().set(name, value) {
+                        writeln!(io_env.stderr(), "wzsh: set: {}", err)?;
+                        return Ok(Status::Complete(1.into()).into());
+                    }
+                }
+                None if value => {
+                    write!(io_env.stdout(), "{}", list_options(environment.options()))?
+                }
+                None => write!(
+                    io_env.stdout(),
+                    "{}",
+                    options_as_commands(environment.options())
+                )?,
+            }
+        }
+        Ok(Status::Complete(0.into()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flags() {
+        assert_eq!(parse_flag("-o"), Some((true, None)));
+        assert_eq!(parse_flag("+o"), Some((false, None)));
+        assert_eq!(parse_flag("-u"), Some((true, Some("nounset"))));
+        assert_eq!(parse_flag("+u"), Some((false, Some("nounset"))));
+        assert_eq!(parse_flag("-x"), Some((true, Some("xtrace"))));
+        assert_eq!(parse_flag("+C"), Some((false, Some("noclobber"))));
+        assert_eq!(parse_flag("-q"), None);
+        assert_eq!(parse_flag("u"), None);
+    }
+
+    #[test]
+    fn listing() -> Fallible<()> {
+        let mut options = ShellOptions::default();
+        options.set("dotglob", true)?;
+        assert_eq!(
+            list_options(&options),
+            "emacs          \ton\n\
+             vi             \toff\n\
+             nullglob       \toff\n\
+             dotglob        \ton\n\
+             nocaseglob     \toff\n\
+             extglob        \toff\n\
+             expandtrace    \toff\n\
+             notify         \toff\n\
+             nounset        \toff\n\
+             xtrace         \toff\n\
+             histappend     \toff\n\
+             noclobber      \toff\n\
+             lastpipe       \toff\n"
+        );
+        assert_eq!(
+            options_as_commands(&options),
+            "set -o emacs\nset +o vi\nset +o nullglob\nset -o dotglob\nset +o nocaseglob\nset +o extglob\nset +o expandtrace\nset +o notify\nset +o nounset\nset +o xtrace\nset +o histappend\nset +o noclobber\nset +o lastpipe\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::builtins::Builtin;
+use crate::prompt::title_sequence;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Set the title of the terminal window.
+/// The words are joined with spaces to form the title.  Nothing is
+/// output if TERM is unset or is `dumb`, or if the standard output
+/// is not a terminal.
+pub struct TitleCommand {
+    words: Vec<String>,
+}
+
+/// Returns true if the standard output is a terminal
+#[cfg(unix)]
+fn is_terminal(io_env: &IoEnvironment) -> bool {
+    use std::os::unix::io::AsRawFd;
+    match io_env.stdout().dup() {
+        Ok(fd) => unsafe { libc::isatty(fd.as_raw_fd()) == 1 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(windows)]
+fn is_terminal(_io_env: &IoEnvironment) -> bool {
+    true
+}
+
+impl Builtin for TitleCommand {
+    fn name() -> &'static str {
+        "title"
+    }
+
+    fn run(
+        &mut self,
+        environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        if is_terminal(io_env) {
+            let term = environment.get("TERM").and_then(|term| term.to_str());
+            write!(
+                io_env.stdout(),
+                "{}",
+                title_sequence(term, &self.words.join(" "))
+            )?;
+        }
+        Ok(Status::Complete(0.into()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sequence() {
+        assert_eq!(
+            title_sequence(Some("xterm-256color"), "foo"),
+            "\x1b]0;foo\x07"
+        );
+        assert_eq!(
+            title_sequence(Some("xterm"), "evil\x07\x1b]0;title"),
+            "\x1b]0;evil]0;title\x07"
+        );
+        assert_eq!(title_sequence(Some("dumb"), "foo"), "");
+        assert_eq!(title_sequence(None, "foo"), "");
+    }
+}
@@ -0,0 +1,117 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use crate::signal::list_signals;
+use crate::trap::{trap_as_command, traps_as_commands, TrapCondition, TRAPS};
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Run commands when the shell receives a signal.
+/// `trap 'commands' SIGNAL...` runs the commands when any of the signals
+/// arrive.  In addition to the signal names, `EXIT` runs the commands
+/// when the shell exits, `ERR` runs them when a command fails and
+/// `DEBUG` runs them before each simple command, which is available
+/// to them in `BASH_COMMAND`.
+/// An empty command ignores the signal, while `-` restores its default
+/// behavior.
+pub struct TrapCommand {
+    /// List the signal names and numbers
+    #[structopt(short = "l")]
+    list: bool,
+
+    /// Print the commands registered for each of the conditions,
+    /// or for all conditions if none are given
+    #[structopt(short = "p")]
+    print: bool,
+
+    args: Vec<String>,
+}
+
+impl Builtin for TrapCommand {
+    fn name() -> &'static str {
+        "trap"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        if self.list {
+            write!(io_env.stdout(), "{}", list_signals())?;
+            return Ok(Status::Complete(0.into()).into());
+        }
+
+        let mut traps = TRAPS.lock().unwrap();
+
+        if self.print || self.args.is_empty() {
+            if self.args.is_empty() {
+                write!(io_env.stdout(), "{}", traps_as_commands(&traps))?;
+                return Ok(Status::Complete(0.into()).into());
+            }
+            let mut status = 0;
+            for name in &self.args {
+                match TrapCondition::parse(name) {
+                    Some(condition) => {
+                        if let Some(action) = traps.get(condition) {
+                            write!(io_env.stdout(), "{}", trap_as_command(condition, action))?;
+                        }
+                    }
+                    None => {
+                        writeln!(io_env.stderr(), "wzsh: trap: {}: invalid signal", name)?;
+                        status = 1;
+                    }
+                }
+            }
+            return Ok(Status::Complete(status.into()).into());
+        }
+
+        // A lone condition, or an action of `-`, resets the conditions
+        let (action, conditions) = if self.args.len() == 1 {
+            (None, &self.args[..])
+        } else if self.args[0] == "-" {
+            (None, &self.args[1..])
+        } else {
+            (Some(self.args[0].as_str()), &self.args[1..])
+        };
+
+        let mut status = 0;
+        for name in conditions {
+            let condition = match TrapCondition::parse(name) {
+                Some(condition) => condition,
+                None => {
+                    writeln!(io_env.stderr(), "wzsh: trap: {}: invalid signal", name)?;
+                    status = 1;
+                    continue;
+                }
+            };
+            if let Err(err) = traps.set(condition, action) {
+                writeln!(io_env.stderr(), "wzsh: trap: {}", err)?;
+                status = 1;
+            }
+        }
+        Ok(Status::Complete(status.into()).into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let listing = list_signals();
+        let mut lines = listing.lines();
+        assert_eq!(lines.next(), Some(" 1) SIGHUP"));
+        assert_eq!(lines.next(), Some(" 2) SIGINT"));
+        assert!(listing.contains("15) SIGTERM\n"));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::builtins::Builtin;
+use crate::shellhost::FunctionRegistry;
+use cancel::Token;
+use failure::Fallible;
+use shell_vm::{Environment, IoEnvironment, Status, WaitableStatus};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::*;
+
+#[derive(StructOpt)]
+/// Print the version of the shell.
+/// The version is followed by the platform for which the shell was
+/// built and the optional features that it includes, which is
+/// useful to include in bug reports.
+pub struct VersionCommand {}
+
+/// The family of platforms for which the shell was built
+const PLATFORM: &str = if cfg!(windows) { "windows" } else { "unix" };
+
+/// Returns the names of the optional features included in this build
+fn features() -> Vec<&'static str> {
+    let mut features = vec!["extglob"];
+    if cfg!(unix) {
+        features.push("job-control");
+        features.push("signals");
+    }
+    if cfg!(debug_assertions) {
+        features.push("debug");
+    }
+    features
+}
+
+/// Returns the version report printed by `version`
+fn version_info() -> String {
+    format!(
+        "wzsh {}\nplatform: {} ({} {})\nfeatures: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        PLATFORM,
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        features().join(" ")
+    )
+}
+
+impl Builtin for VersionCommand {
+    fn name() -> &'static str {
+        "version"
+    }
+
+    fn run(
+        &mut self,
+        _environment: &mut Environment,
+        _current_directory: &mut PathBuf,
+        io_env: &IoEnvironment,
+        _cancel: Arc<Token>,
+        _functions: &Arc<FunctionRegistry>,
+    ) -> Fallible<WaitableStatus> {
+        write!(io_env.stdout(), "{}", version_info())?;
+        Ok(Status::Complete(0.into()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let info = version_info();
+        assert!(info.starts_with(&format!("wzsh {}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(!env!("CARGO_PKG_VERSION").is_empty());
+        assert!(info.contains(&format!("platform: {} (", PLATFORM)));
+        #[cfg(unix)]
+        assert!(info.contains("platform: unix"));
+        assert!(info.contains("features: extglob"));
+    }
+}
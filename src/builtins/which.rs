@@ -44,14 +44,14 @@ impl Builtin for WhichCommand {
         let mut found = false;
 
         if let Some(name) = self.command.to_str() {
-            if let Some(_) = functions.lookup_function(name) {
+            if functions.lookup_function(name).is_some() {
                 found = true;
                 if !self.quiet {
                     writeln!(io_env.stdout(), "{}: shell function", name)?;
                 }
             }
         }
-        if let Some(_) = lookup_builtin(&Value::OsString(self.command.as_os_str().to_os_string())) {
+        if lookup_builtin(&Value::OsString(self.command.as_os_str().to_os_string())).is_some() {
             found = true;
             if !self.quiet {
                 writeln!(
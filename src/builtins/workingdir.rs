@@ -14,6 +14,7 @@ pub struct PwdCommand {
     /// Display the logical current working directory.
     /// This is the default behavior.
     #[structopt(short = "L", overrides_with = "physical")]
+    #[allow(dead_code)]
     logical: bool,
 
     /// Display the physical current working directory (all symbolic links resolved).
@@ -51,6 +52,7 @@ impl Builtin for PwdCommand {
 pub struct CdCommand {
     /// Handle the operand dot-dot logically; symbolic link components shall not be resolved before dot-dot components are processed
     #[structopt(short = "L", overrides_with = "physical")]
+    #[allow(dead_code)]
     logical: bool,
 
     /// Handle the operand dot-dot physically; symbolic link components shall be resolved before dot-dot components are processed
@@ -151,6 +153,6 @@ impl Builtin for CdCommand {
         if print {
             writeln!(io_env.stdout(), "{}", cwd.display())?;
         }
-        return Ok(Status::Complete(0.into()).into());
+        Ok(Status::Complete(0.into()).into())
     }
 }
@@ -0,0 +1,843 @@
+//! Tab completion for the interactive line editor.
+//! Completion is resolved relative to the shell's notion of the
+//! current directory rather than that of the process, as the two
+//! are not necessarily the same.
+use crate::prompt::{prompt_elements, PromptSegment};
+use crate::script::run_capture_in;
+use crate::shellhost::FunctionRegistry;
+use failure::{bail, Fallible};
+use lazy_static::lazy_static;
+use shell_lexer::{Lexer, Operator, Token};
+use shell_vm::{shell_quote, Environment};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use termwiz::cell::CellAttributes;
+use termwiz::lineedit::{CompletionCandidate, OutputElement};
+
+/// Returns the byte offset of the start of the word that ends at
+/// `cursor_position`.
+fn word_start(line: &str, cursor_position: usize) -> usize {
+    line[..cursor_position]
+        .rfind(char::is_whitespace)
+        .map(|idx| idx + 1)
+        .unwrap_or(0)
+}
+
+/// Converts a character column within `line` to a byte offset
+fn byte_offset(line: &str, col: usize) -> usize {
+    line.char_indices()
+        .nth(col)
+        .map(|(idx, _)| idx)
+        .unwrap_or_else(|| line.len())
+}
+
+/// If the cursor is positioned on the target of a `<`, `>`, `>>`,
+/// `>|` or `<>` redirection, returns the byte offset of the start
+/// of that target.  The target may be empty, as in `echo hi > `,
+/// and need not be separated from the operator by a space.
+pub fn redirect_target_start(line: &str, cursor_position: usize) -> Option<usize> {
+    let text = &line[..cursor_position];
+    if text.contains('\n') {
+        return None;
+    }
+    let mut lexer = Lexer::new("completion", text.as_bytes());
+    let mut tokens = vec![];
+    loop {
+        match lexer.next_token().ok()? {
+            Token::Eof(_) => break,
+            token => tokens.push(token),
+        }
+    }
+
+    let is_redirect = |token: &Token| match token {
+        Token::Operator(oper, _) => matches!(
+            oper,
+            Operator::Less
+                | Operator::Great
+                | Operator::DoubleGreat
+                | Operator::Clobber
+                | Operator::LessGreat
+        ),
+        _ => false,
+    };
+
+    match tokens.as_slice() {
+        [.., last] if is_redirect(last) => Some(cursor_position),
+        [.., oper, word @ Token::Word(_)]
+            if is_redirect(oper) && !text.ends_with(char::is_whitespace) =>
+        {
+            Some(byte_offset(text, word.span().start.col))
+        }
+        _ => None,
+    }
+}
+
+/// What is being completed within the value of an assignment word
+#[derive(Debug, PartialEq, Eq)]
+pub enum AssignmentValue {
+    /// A filename that starts at the given byte offset.  Values such
+    /// as `PATH` are lists separated by `:`, so the filename starts
+    /// after the last `:` in the value.
+    Filename(usize),
+    /// A variable reference whose `$` is at the given byte offset
+    Variable(usize),
+}
+
+/// If the cursor is positioned within the value of an assignment
+/// word at the start of a command, as in `FOO=ba` or `FOO=$oth`,
+/// returns what is being completed there.  Only assignments that
+/// precede the command name count, so in `echo FOO=ba` or
+/// `ls; echo FOO=ba` the word is an ordinary argument.
+pub fn assignment_value_context(line: &str, cursor_position: usize) -> Option<AssignmentValue> {
+    let text = &line[..cursor_position];
+    if text.contains('\n') || text.ends_with(char::is_whitespace) {
+        return None;
+    }
+    let mut lexer = Lexer::new("completion", text.as_bytes());
+    let mut last = None;
+    // Whether the command that contains the cursor has a word other
+    // than an assignment or the target of a redirection before it
+    let mut seen_command_word = false;
+    let mut after_redirect = false;
+    loop {
+        let token = match lexer.next_token().ok()? {
+            Token::Eof(_) => break,
+            token => token,
+        };
+        if let Some(previous) = last.take() {
+            match previous {
+                Token::Operator(oper, _) => match oper {
+                    Operator::AndIf
+                    | Operator::OrIf
+                    | Operator::DoubleSemicolon
+                    | Operator::DoubleSemicolonAnd
+                    | Operator::SemicolonAnd
+                    | Operator::Ampersand
+                    | Operator::Pipe
+                    | Operator::Semicolon
+                    | Operator::LeftParen
+                    | Operator::RightParen => seen_command_word = false,
+                    _ => after_redirect = true,
+                },
+                Token::Word(_) if after_redirect => after_redirect = false,
+                // Reserved words such as `do` or `{` start a command
+                ref word @ Token::Word(_) if !seen_command_word && word.is_any_reserved_word() => {}
+                Token::Word(_) | Token::ArithmeticCommand(..) => seen_command_word = true,
+                _ => {}
+            }
+        }
+        last = Some(token);
+    }
+    if seen_command_word {
+        return None;
+    }
+
+    let value_start = match last? {
+        Token::Assignment(assignment) if assignment.array.is_none() => {
+            byte_offset(text, assignment.span.end.col)
+        }
+        _ => return None,
+    };
+    let segment_start = text[value_start..]
+        .rfind(':')
+        .map(|idx| value_start + idx + 1)
+        .unwrap_or(value_start);
+
+    if let Some(idx) = text[segment_start..].rfind('$') {
+        let dollar = segment_start + idx;
+        if text[dollar + 1..]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Some(AssignmentValue::Variable(dollar));
+        }
+    }
+    Some(AssignmentValue::Filename(segment_start))
+}
+
+/// Complete the variable reference between `start`, the offset of
+/// its `$`, and `cursor_position` with the names of the variables
+/// that are set in `env`
+pub fn complete_variable(
+    line: &str,
+    start: usize,
+    cursor_position: usize,
+    env: &Environment,
+) -> Vec<CompletionCandidate> {
+    let prefix = &line[start + 1..cursor_position];
+    let mut names: Vec<String> = env
+        .iter()
+        .filter_map(|(name, _)| name.to_str())
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| format!("${}", name))
+        .collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .map(|text| CompletionCandidate {
+            range: start..cursor_position,
+            text,
+        })
+        .collect()
+}
+
+/// Complete the word to the left of the cursor as a filename.
+/// Relative names are resolved against `cwd`, and a leading `~/`
+/// against `home`.  When the word contains a slash, the directory
+/// that precedes it is listed and kept as the prefix of each candidate.
+/// Directories are completed with a trailing slash.
+/// Dot files are only offered if the word being completed starts
+/// with a dot.
+pub fn complete_filename(
+    line: &str,
+    cursor_position: usize,
+    cwd: &Path,
+    home: Option<&Path>,
+) -> Vec<CompletionCandidate> {
+    let start = word_start(line, cursor_position);
+    complete_filename_from(line, start, cursor_position, cwd, home)
+}
+
+/// Complete the text between `start` and `cursor_position` as a
+/// filename, in the same manner as `complete_filename`
+pub fn complete_filename_from(
+    line: &str,
+    start: usize,
+    cursor_position: usize,
+    cwd: &Path,
+    home: Option<&Path>,
+) -> Vec<CompletionCandidate> {
+    let word = &line[start..cursor_position];
+
+    let (dir_part, prefix) = match word.rfind('/') {
+        Some(idx) => word.split_at(idx + 1),
+        None => ("", word),
+    };
+
+    // An absolute dir_part replaces cwd when joined
+    let dir = match (dir_part.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => home.join(rest),
+        (Some(_), None) => return vec![],
+        (None, _) if dir_part.is_empty() => cwd.to_path_buf(),
+        (None, _) => cwd.join(dir_part),
+    };
+
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_owned();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            Some(format!(
+                "{}{}{}",
+                dir_part,
+                name,
+                if is_dir { "/" } else { "" }
+            ))
+        })
+        .collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .map(|text| CompletionCandidate {
+            range: start..cursor_position,
+            text,
+        })
+        .collect()
+}
+
+lazy_static! {
+    /// The completions registered by the `complete` builtin
+    pub static ref COMPLETIONS: Mutex<CompletionRegistry> =
+        Mutex::new(CompletionRegistry::default());
+}
+
+/// How the arguments of a command are completed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompletionSpec {
+    /// `complete -F FUNC`: the function is run with the name of the
+    /// command, the word being completed and the word before it as
+    /// its arguments.  Each line that it prints is a candidate.
+    Function(String),
+    /// `complete -W WORDS`: the words that start with the word
+    /// being completed
+    WordList(String),
+}
+
+impl CompletionSpec {
+    /// The `complete` command that registers this spec for `name`,
+    /// as printed by `complete -p`
+    pub fn as_command(&self, name: &str) -> String {
+        match self {
+            CompletionSpec::Function(func) => {
+                format!("complete -F {} {}\n", shell_quote(func), shell_quote(name))
+            }
+            CompletionSpec::WordList(words) => {
+                format!("complete -W {} {}\n", shell_quote(words), shell_quote(name))
+            }
+        }
+    }
+}
+
+/// The completion specs, keyed by the name of the command
+#[derive(Debug, Default)]
+pub struct CompletionRegistry {
+    specs: BTreeMap<String, CompletionSpec>,
+}
+
+impl CompletionRegistry {
+    pub fn register(&mut self, name: &str, spec: CompletionSpec) {
+        self.specs.insert(name.to_owned(), spec);
+    }
+
+    /// Remove the spec for name, returning true if there was one
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.specs.remove(name).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.specs.clear();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CompletionSpec> {
+        self.specs.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &CompletionSpec)> {
+        self.specs.iter().map(|(name, spec)| (name.as_str(), spec))
+    }
+}
+
+/// Returns the whitespace separated words of `words` that start
+/// with `prefix`, in the order in which they appear, as produced
+/// by `compgen -W`
+pub fn filter_words(words: &str, prefix: &str) -> Vec<String> {
+    words
+        .split_whitespace()
+        .filter(|word| word.starts_with(prefix))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Run `func` to produce the completions for `word`, the argument of
+/// `command` that follows `prev`.  The function runs in a copy of
+/// the environment, so it cannot affect the shell.
+/// Anything that the function writes to stderr is returned as an error.
+fn run_completion_function(
+    func: &str,
+    command: &str,
+    word: &str,
+    prev: &str,
+    cwd: &Path,
+    env: &Environment,
+    funcs: &Arc<FunctionRegistry>,
+) -> Fallible<Vec<String>> {
+    let source = format!(
+        "{} {} {} {}",
+        shell_quote(func),
+        shell_quote(command),
+        shell_quote(word),
+        shell_quote(prev)
+    );
+    let (_status, stdout, stderr) =
+        run_capture_in(&source, &mut cwd.to_path_buf(), &mut env.clone(), funcs)?;
+    if !stderr.is_empty() {
+        bail!("{}: {}", func, stderr.trim_end());
+    }
+    Ok(stdout
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Complete the word to the left of the cursor using the spec that
+/// `complete` registered for the command at the start of the line.
+/// Returns None if the cursor is on the command name itself, or
+/// if there is no spec for the command, in which case the caller
+/// falls back to some other form of completion.
+/// Returns an error if the registered completion function fails.
+pub fn complete_registered(
+    line: &str,
+    cursor_position: usize,
+    cwd: &Path,
+    env: &Environment,
+    funcs: &Arc<FunctionRegistry>,
+) -> Option<Fallible<Vec<CompletionCandidate>>> {
+    let start = word_start(line, cursor_position);
+    let mut preceding = line[..start].split_whitespace();
+    let command = preceding.next()?;
+    let prev = preceding.last().unwrap_or(command);
+    let word = &line[start..cursor_position];
+
+    let spec = COMPLETIONS.lock().unwrap().get(command).cloned()?;
+    let texts = match spec {
+        CompletionSpec::WordList(words) => Ok(filter_words(&words, word)),
+        CompletionSpec::Function(func) => {
+            run_completion_function(&func, command, word, prev, cwd, env, funcs)
+        }
+    };
+
+    Some(texts.map(|texts| {
+        texts
+            .into_iter()
+            .map(|text| CompletionCandidate {
+                range: start..cursor_position,
+                text,
+            })
+            .collect()
+    }))
+}
+
+/// The colors used to display filenames, as configured by `LS_COLORS`.
+/// The variable holds a colon separated list of `KEY=CODES` entries,
+/// where CODES are the parameters of an SGR escape sequence, such as
+/// `01;34`.  KEY is either a two letter file type code (`di` for
+/// directories, `ln` for symlinks, `ex` for executables, `fi` for
+/// regular files, `pi`, `so`, `bd` and `cd` for fifos, sockets and
+/// devices) or a `*suffix` pattern that applies to regular files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LsColors {
+    types: HashMap<String, String>,
+    suffixes: Vec<(String, String)>,
+}
+
+impl LsColors {
+    pub fn parse(value: &str) -> LsColors {
+        let mut colors = LsColors::default();
+        for entry in value.split(':') {
+            let mut split = entry.splitn(2, '=');
+            let (key, codes) = match (split.next(), split.next()) {
+                (Some(key), Some(codes)) if !key.is_empty() => (key, codes),
+                _ => continue,
+            };
+            match key.strip_prefix('*') {
+                Some(suffix) => colors.suffixes.push((suffix.to_owned(), codes.to_owned())),
+                None => {
+                    colors.types.insert(key.to_owned(), codes.to_owned());
+                }
+            }
+        }
+        colors
+    }
+
+    /// Returns the colors from `LS_COLORS`, or None if it is unset or
+    /// empty, in which case filenames are displayed as plain text
+    pub fn from_env(env: &Environment) -> Option<LsColors> {
+        match env.get_str("LS_COLORS") {
+            Ok(Some(value)) if !value.is_empty() => Some(LsColors::parse(value)),
+            _ => None,
+        }
+    }
+
+    /// Returns the codes for the given two letter file type
+    pub fn type_codes(&self, file_type: &str) -> Option<&str> {
+        self.types.get(file_type).map(String::as_str)
+    }
+
+    /// Returns the codes with which the file at `path` is displayed
+    pub fn codes_for(&self, path: &Path) -> Option<&str> {
+        let meta = path.symlink_metadata().ok()?;
+        let file_type = meta.file_type();
+        let code = if file_type.is_symlink() {
+            "ln"
+        } else if file_type.is_dir() {
+            "di"
+        } else if !file_type.is_file() {
+            special_file_type(&file_type)
+        } else if is_executable(&meta) && self.types.contains_key("ex") {
+            "ex"
+        } else {
+            let name = path.file_name()?.to_string_lossy();
+            if let Some((_, codes)) = self
+                .suffixes
+                .iter()
+                .find(|(suffix, _)| name.ends_with(suffix.as_str()))
+            {
+                return Some(codes);
+            }
+            "fi"
+        };
+        self.type_codes(code)
+    }
+}
+
+#[cfg(unix)]
+fn special_file_type(file_type: &std::fs::FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_fifo() {
+        "pi"
+    } else if file_type.is_socket() {
+        "so"
+    } else if file_type.is_block_device() {
+        "bd"
+    } else if file_type.is_char_device() {
+        "cd"
+    } else {
+        "fi"
+    }
+}
+
+#[cfg(windows)]
+fn special_file_type(_file_type: &std::fs::FileType) -> &'static str {
+    "fi"
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(windows)]
+fn is_executable(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Render `line` for the line editor, coloring the filename to the
+/// left of the cursor according to its type.  This highlights each
+/// candidate in turn as completion cycles through them.  The line
+/// is rendered as plain text when there are no colors or the word
+/// doesn't name an existing file.
+pub fn highlight_filename(
+    line: &str,
+    cursor_position: usize,
+    cwd: &Path,
+    colors: Option<&LsColors>,
+) -> Vec<OutputElement> {
+    let plain = || vec![OutputElement::Text(line.to_owned())];
+    let colors = match colors {
+        Some(colors) if cursor_position <= line.len() => colors,
+        _ => return plain(),
+    };
+    let start = redirect_target_start(line, cursor_position)
+        .unwrap_or_else(|| word_start(line, cursor_position));
+    let word = &line[start..cursor_position];
+    if word.is_empty() {
+        return plain();
+    }
+    let codes = match colors.codes_for(&cwd.join(word.trim_end_matches('/'))) {
+        Some(codes) => codes,
+        None => return plain(),
+    };
+
+    let mut elements = vec![OutputElement::Text(line[..start].to_owned())];
+    elements.extend(prompt_elements(&[PromptSegment::NonPrinting(format!(
+        "\u{1b}[{}m",
+        codes
+    ))]));
+    elements.push(OutputElement::Text(word.to_owned()));
+    elements.push(OutputElement::AllAttributes(CellAttributes::default()));
+    elements.push(OutputElement::Text(line[cursor_position..].to_owned()));
+    elements
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn scratch_dir(name: &str) -> TempDir {
+        let root = TempDir::new(&format!("wzsh-{}", name)).unwrap();
+        let dir = root.path();
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("hello.txt"), b"").unwrap();
+        std::fs::write(dir.join("help.txt"), b"").unwrap();
+        std::fs::write(dir.join(".hidden"), b"").unwrap();
+        std::fs::write(dir.join("subdir").join("inner"), b"").unwrap();
+        root
+    }
+
+    fn texts(candidates: Vec<CompletionCandidate>) -> Vec<String> {
+        candidates.into_iter().map(|c| c.text).collect()
+    }
+
+    #[test]
+    fn relative_to_supplied_dir() {
+        let root = scratch_dir("complete");
+        let dir = root.path().to_path_buf();
+        assert_ne!(std::env::current_dir().unwrap(), dir);
+
+        let line = "cat hel";
+        let candidates = complete_filename(line, line.len(), &dir, None);
+        assert_eq!(candidates[0].range, 4..7);
+        assert_eq!(texts(candidates), vec!["hello.txt", "help.txt"]);
+
+        let line = "ls sub";
+        assert_eq!(
+            texts(complete_filename(line, line.len(), &dir, None)),
+            vec!["subdir/"]
+        );
+
+        let line = "cat subdir/";
+        assert_eq!(
+            texts(complete_filename(line, line.len(), &dir, None)),
+            vec!["subdir/inner"]
+        );
+
+        let line = "cat .h";
+        assert_eq!(
+            texts(complete_filename(line, line.len(), &dir, None)),
+            vec![".hidden"]
+        );
+
+        let line = "cat ";
+        assert_eq!(
+            texts(complete_filename(line, line.len(), &dir, None)),
+            vec!["hello.txt", "help.txt", "subdir/"]
+        );
+    }
+
+    #[test]
+    fn within_directories() {
+        let root = scratch_dir("complete-nested");
+        let dir = root.path().to_path_buf();
+        let nested = dir.join("subdir").join("nested");
+        std::fs::create_dir_all(nested.join("deeper")).unwrap();
+        std::fs::write(nested.join("file.rs"), b"").unwrap();
+        std::fs::write(nested.join("fixture"), b"").unwrap();
+
+        // The directory prefix is preserved in each candidate
+        let line = "vim subdir/nested/f";
+        let candidates = complete_filename(line, line.len(), &dir, None);
+        assert_eq!(candidates[0].range, 4..19);
+        assert_eq!(
+            texts(candidates),
+            vec!["subdir/nested/file.rs", "subdir/nested/fixture"]
+        );
+
+        let line = "cd subdir/n";
+        assert_eq!(
+            texts(complete_filename(line, line.len(), &dir, None)),
+            vec!["subdir/nested/"]
+        );
+        let line = "cd subdir/nested/d";
+        assert_eq!(
+            texts(complete_filename(line, line.len(), &dir, None)),
+            vec!["subdir/nested/deeper/"]
+        );
+        let line = "cat subdir/missing/f";
+        assert!(complete_filename(line, line.len(), &dir, None).is_empty());
+
+        // Absolute paths are independent of the current directory
+        let absolute = format!("cat {}/subdir/nested/fi", dir.display());
+        assert_eq!(
+            texts(complete_filename(
+                &absolute,
+                absolute.len(),
+                Path::new("/"),
+                None
+            )),
+            vec![
+                format!("{}/subdir/nested/file.rs", dir.display()),
+                format!("{}/subdir/nested/fixture", dir.display())
+            ]
+        );
+
+        // `~/` is resolved against the home directory
+        let line = "cat ~/subdir/nested/fi";
+        assert_eq!(
+            texts(complete_filename(
+                line,
+                line.len(),
+                Path::new("/"),
+                Some(&dir)
+            )),
+            vec!["~/subdir/nested/file.rs", "~/subdir/nested/fixture"]
+        );
+        let line = "cat ~/h";
+        assert_eq!(
+            texts(complete_filename(
+                line,
+                line.len(),
+                Path::new("/"),
+                Some(&dir)
+            )),
+            vec!["~/hello.txt", "~/help.txt"]
+        );
+        assert!(complete_filename(line, line.len(), Path::new("/"), None).is_empty());
+    }
+
+    fn element_texts(elements: &[OutputElement]) -> Vec<&str> {
+        elements
+            .iter()
+            .filter_map(|element| match element {
+                OutputElement::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ls_colors() {
+        let colors = LsColors::parse("rs=0:di=01;34:ln=01;36:*.txt=00;33:bogus:ex=01;32:");
+        assert_eq!(colors.type_codes("di"), Some("01;34"));
+        assert_eq!(colors.type_codes("ln"), Some("01;36"));
+        assert_eq!(colors.type_codes("ex"), Some("01;32"));
+        assert_eq!(colors.type_codes("fi"), None);
+
+        let root = scratch_dir("ls-colors");
+        let dir = root.path().to_path_buf();
+        assert_eq!(colors.codes_for(&dir.join("subdir")), Some("01;34"));
+        assert_eq!(colors.codes_for(&dir.join("hello.txt")), Some("00;33"));
+        assert_eq!(colors.codes_for(&dir.join(".hidden")), None);
+        assert_eq!(colors.codes_for(&dir.join("missing")), None);
+
+        let line = "cat hello.txt";
+        let plain = highlight_filename(line, line.len(), &dir, None);
+        assert_eq!(element_texts(&plain), vec![line]);
+        let colored = highlight_filename(line, line.len(), &dir, Some(&colors));
+        assert_eq!(element_texts(&colored), vec!["cat ", "hello.txt", ""]);
+        assert!(colored.len() > 3);
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = CompletionRegistry::default();
+        registry.register("git", CompletionSpec::Function("_git".to_owned()));
+        registry.register("make", CompletionSpec::WordList("all clean".to_owned()));
+        registry.register("git", CompletionSpec::Function("_git2".to_owned()));
+        assert_eq!(
+            registry.get("git"),
+            Some(&CompletionSpec::Function("_git2".to_owned()))
+        );
+        assert_eq!(
+            registry
+                .iter()
+                .map(|(name, spec)| spec.as_command(name))
+                .collect::<String>(),
+            "complete -F _git2 git\ncomplete -W 'all clean' make\n"
+        );
+        assert!(registry.remove("git"));
+        assert!(!registry.remove("git"));
+        assert_eq!(registry.get("git"), None);
+    }
+
+    #[test]
+    fn word_list() {
+        assert_eq!(
+            filter_words("start stop  status\nrestart", "st"),
+            vec!["start", "stop", "status"]
+        );
+        assert_eq!(filter_words("a b c", ""), vec!["a", "b", "c"]);
+        assert!(filter_words("a b c", "d").is_empty());
+    }
+
+    #[test]
+    fn registered_function() -> Fallible<()> {
+        let mut cwd = std::env::current_dir()?;
+        let mut env = Environment::new_empty();
+        let funcs = Arc::new(FunctionRegistry::new());
+        crate::script::compile_and_run_string(
+            "_wzsh_test_complete() { printf '%s\\n' \"$1:$2:$3\" other; }",
+            &mut cwd,
+            &mut env,
+            &funcs,
+        )?;
+        COMPLETIONS.lock().unwrap().register(
+            "wzsh-test-command",
+            CompletionSpec::Function("_wzsh_test_complete".to_owned()),
+        );
+
+        let line = "wzsh-test-command -v ab";
+        let candidates = complete_registered(line, line.len(), &cwd, &env, &funcs).unwrap()?;
+        assert_eq!(candidates[0].range, 21..23);
+        assert_eq!(texts(candidates), vec!["wzsh-test-command:ab:-v", "other"]);
+
+        // The command name itself is not completed by the spec
+        let line = "wzsh-test";
+        assert!(complete_registered(line, line.len(), &cwd, &env, &funcs).is_none());
+        let line = "unregistered ab";
+        assert!(complete_registered(line, line.len(), &cwd, &env, &funcs).is_none());
+
+        // A failing completion function is reported rather than
+        // producing no candidates
+        crate::script::compile_and_run_string(
+            "_wzsh_test_broken() { nosuchcommand-wzsh-test; }",
+            &mut cwd,
+            &mut env,
+            &funcs,
+        )?;
+        COMPLETIONS.lock().unwrap().register(
+            "wzsh-test-command",
+            CompletionSpec::Function("_wzsh_test_broken".to_owned()),
+        );
+        let line = "wzsh-test-command ab";
+        match complete_registered(line, line.len(), &cwd, &env, &funcs).unwrap() {
+            Ok(_) => panic!("expected the completion function to fail"),
+            Err(err) => assert!(err.to_string().starts_with("_wzsh_test_broken: ")),
+        }
+
+        COMPLETIONS.lock().unwrap().remove("wzsh-test-command");
+        Ok(())
+    }
+
+    #[test]
+    fn redirect_targets() {
+        let target = |line: &str| redirect_target_start(line, line.len());
+        assert_eq!(target("echo hi > f"), Some(10));
+        assert_eq!(target("echo hi > "), Some(10));
+        assert_eq!(target("echo hi >"), Some(9));
+        assert_eq!(target("echo hi >f"), Some(9));
+        assert_eq!(target("echo hi 2>>log"), Some(11));
+        assert_eq!(target("cat <in"), Some(5));
+        assert_eq!(target("echo hi"), None);
+        assert_eq!(target("echo hi > f "), None);
+        assert_eq!(target("echo hi | f"), None);
+
+        let root = scratch_dir("redirect");
+        let dir = root.path().to_path_buf();
+        let line = "echo hi >hel";
+        let start = target(line).unwrap();
+        assert_eq!(
+            texts(complete_filename_from(line, start, line.len(), &dir, None)),
+            vec!["hello.txt", "help.txt"]
+        );
+    }
+
+    #[test]
+    fn assignment_values() {
+        let context = |line: &str| assignment_value_context(line, line.len());
+        assert_eq!(context("FOO=ba"), Some(AssignmentValue::Filename(4)));
+        assert_eq!(context("FOO="), Some(AssignmentValue::Filename(4)));
+        assert_eq!(context("FOO+=ba"), Some(AssignmentValue::Filename(5)));
+        assert_eq!(
+            context("PATH=/bin:/us"),
+            Some(AssignmentValue::Filename(10))
+        );
+        assert_eq!(context("FOO=$oth"), Some(AssignmentValue::Variable(4)));
+        assert_eq!(context("A=1 FOO=x$oth"), Some(AssignmentValue::Variable(9)));
+        assert_eq!(context("echo FOO=ba"), None);
+        assert_eq!(context("A=1 echo FOO=ba"), None);
+        assert_eq!(context("ls >out FOO=ba"), None);
+        assert_eq!(context(">out FOO=ba"), Some(AssignmentValue::Filename(9)));
+        assert_eq!(context("ls; FOO=ba"), Some(AssignmentValue::Filename(8)));
+        assert_eq!(context("ls | FOO=ba"), Some(AssignmentValue::Filename(9)));
+        assert_eq!(
+            context("while true; do FOO=ba"),
+            Some(AssignmentValue::Filename(19))
+        );
+        assert_eq!(context("FOO=ba ls"), None);
+        assert_eq!(context("FOO=ba "), None);
+
+        let mut env = Environment::new_empty();
+        env.set("OTHER", "1");
+        env.set("OTHELLO", "2");
+        env.set("HOME", "/");
+        let line = "FOO=$OTH";
+        assert_eq!(
+            texts(complete_variable(line, 4, line.len(), &env)),
+            vec!["$OTHELLO", "$OTHER"]
+        );
+    }
+}
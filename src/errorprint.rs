@@ -4,12 +4,20 @@ use shell_parser::ParseErrorKind;
 use std::io::Read;
 use std::path::Path;
 
-fn extract_error_range(e: &Error) -> Option<Span> {
+/// Returns the name of the source and the range within it
+/// to which a syntax error refers.  For input that ended part way
+/// through a quoted string or similar construct, this is the
+/// delimiter that opened it, which is more useful than the end
+/// of the input for finding the problem.
+fn extract_error_location(e: &Error) -> Option<(&str, Span)> {
     if let Some(lex_err) = e.downcast_ref::<LexError>() {
-        Some(lex_err.span)
+        match lex_err.opened_at {
+            Some(pos) => Some((&lex_err.source, Span::new(pos, pos))),
+            None => Some((&lex_err.source, lex_err.span)),
+        }
     } else if let Some(parse_err) = e.downcast_ref::<ParseErrorKind>() {
         match parse_err {
-            ParseErrorKind::UnexpectedToken(token, ..) => Some(token.span()),
+            ParseErrorKind::UnexpectedToken(token, _, source) => Some((source, token.span())),
         }
     } else {
         None
@@ -34,15 +42,66 @@ pub fn print_error_path(e: &Error, path: &Path) {
     print_error(e, &input);
 }
 
+/// Distinguishes problems with the syntax of the input from
+/// failures that arose while running it, such as a redirection
+/// that could not be opened or a command that could not be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Syntax,
+    Runtime,
+}
+
+impl ErrorKind {
+    pub fn of(e: &Error) -> ErrorKind {
+        if e.downcast_ref::<LexError>().is_some() || e.downcast_ref::<ParseErrorKind>().is_some() {
+            ErrorKind::Syntax
+        } else {
+            ErrorKind::Runtime
+        }
+    }
+}
+
 pub fn print_error(e: &Error, input: &str) {
-    for item in e.iter_chain() {
-        eprintln!("wzsh: {}", item);
+    eprint!("{}", format_error(e, input));
+}
+
+/// Format an error for presentation to the user.  Syntax errors are
+/// labelled as such, along with the `source:line:col` at which they
+/// occurred, and followed by the offending portion of `input`,
+/// while runtime errors are reported with just their messages.
+pub fn format_error(e: &Error, input: &str) -> String {
+    let mut output = String::new();
+    let kind = ErrorKind::of(e);
+    let location = extract_error_location(e);
+    for (idx, item) in e.iter_chain().enumerate() {
+        if idx == 0 && kind == ErrorKind::Syntax {
+            match location {
+                Some((source, span)) if !source.is_empty() => output.push_str(&format!(
+                    "wzsh: {}:{}:{}: syntax error: {}\n",
+                    source,
+                    span.start.line + 1,
+                    span.start.col + 1,
+                    item
+                )),
+                _ => output.push_str(&format!("wzsh: syntax error: {}\n", item)),
+            }
+        } else {
+            output.push_str(&format!("wzsh: {}\n", item));
+        }
+    }
+    if kind == ErrorKind::Runtime {
+        return output;
     }
-    if let Some(span) = extract_error_range(e) {
+    if let Some((_, span)) = location {
         let lines: Vec<&str> = input.split('\n').collect();
 
-        let start_line = &lines[span.start.line];
-        let end_line = &lines[span.end.line];
+        // The error may have come from some other source, such as
+        // a file sourced by the input, in which case there is
+        // nothing meaningful to show
+        let (start_line, end_line) = match (lines.get(span.start.line), lines.get(span.end.line)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return output,
+        };
 
         let mut indicator = String::new();
         let end_col = if span.start.line == span.end.line {
@@ -61,8 +120,7 @@ pub fn print_error(e: &Error, input: &str) {
         }
         indicator.push_str("\x1b[0m");
 
-        eprintln!("{}", start_line);
-        eprintln!("{}", indicator);
+        output.push_str(&format!("{}\n{}\n", start_line, indicator));
 
         if span.end.line != span.start.line {
             indicator.clear();
@@ -71,8 +129,73 @@ pub fn print_error(e: &Error, input: &str) {
                 indicator.push('^');
             }
             indicator.push_str("\x1b[0m");
-            eprintln!("{}", end_line);
-            eprintln!("{}", indicator);
+            output.push_str(&format!("{}\n{}\n", end_line, indicator));
         }
     }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure::err_msg;
+    use shell_lexer::{LexErrorKind, Span};
+
+    #[test]
+    fn syntax_error() {
+        let err: Error = LexErrorKind::UnmatchedParenInArithmeticCommand
+            .at(Span::new_to(0, 8, 9))
+            .into();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Syntax);
+        assert_eq!(
+            format_error(&err, "echo $((1)))"),
+            "wzsh: syntax error: unmatched `)` in arithmetic command at line 0 column 8 thru 9\n\
+             echo $((1)))\n        \x1b[1m^^\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn named_source() {
+        let parse = |text: &str| {
+            shell_parser::Parser::new("script.sh", text.as_bytes())
+                .parse()
+                .unwrap_err()
+        };
+
+        let err = parse("true\n{ echo");
+        assert!(format_error(&err, "true\n{ echo")
+            .starts_with("wzsh: script.sh:2:7: syntax error: Unexpected token"));
+
+        let err = parse("echo 'foo");
+        assert_eq!(err.downcast_ref::<LexError>().unwrap().source, "script.sh");
+        assert!(format_error(&err, "echo 'foo").starts_with(
+            "wzsh: script.sh:1:6: syntax error: EOF while lexing single quoted string"
+        ));
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        let text = "echo 'one' 'two\nthree";
+        let err = shell_parser::Parser::new("", text.as_bytes())
+            .parse()
+            .unwrap_err();
+        assert_eq!(
+            format_error(&err, text),
+            "wzsh: syntax error: EOF while lexing single quoted string at line 1 column 5\n\
+             echo 'one' 'two\n           \x1b[1m^\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn runtime_error() {
+        let err: Error = err_msg("PC=3: No such file or directory (os error 2)")
+            .context("opening nosuchdir/file for output")
+            .into();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Runtime);
+        assert_eq!(
+            format_error(&err, "echo > nosuchdir/file"),
+            "wzsh: opening nosuchdir/file for output\n\
+             wzsh: PC=3: No such file or directory (os error 2)\n"
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::signal::signal_name;
 #[cfg(windows)]
 use filedescriptor::OwnedHandle;
 use shell_vm::{Status, WaitForStatus};
@@ -26,10 +27,53 @@ impl ExitStatus {
             ExitStatus::Stopped | ExitStatus::Running => false,
         }
     }
+
+    /// Decode a status as reported by waitpid(2)
+    #[cfg(unix)]
+    pub fn from_wait_status(status: libc::c_int) -> ExitStatus {
+        if libc::WIFSTOPPED(status) {
+            ExitStatus::Stopped
+        } else if libc::WIFSIGNALED(status) {
+            ExitStatus::Signalled(libc::WTERMSIG(status))
+        } else if libc::WIFEXITED(status) {
+            ExitStatus::ExitCode(libc::WEXITSTATUS(status))
+        } else {
+            ExitStatus::Running
+        }
+    }
+
+    /// Decode the exit code of a windows process.  Windows has no
+    /// signals, but a process that was interrupted by CTRL-C or that
+    /// crashed exits with an NTSTATUS error code; these are mapped to
+    /// the signal that would have had the same effect on unix, so
+    /// that `$?` is 128 plus that signal number rather than a large
+    /// negative number.
+    #[cfg(any(windows, test))]
+    pub fn from_windows_exit_code(code: u32) -> ExitStatus {
+        const STATUS_ACCESS_VIOLATION: u32 = 0xC000_0005;
+        const STATUS_CONTROL_C_EXIT: u32 = 0xC000_013A;
+        const SEVERITY_ERROR: u32 = 0xC000_0000;
+        const SIGINT: i32 = 2;
+        const SIGSEGV: i32 = 11;
+        const SIGABRT: i32 = 6;
+        match code {
+            STATUS_CONTROL_C_EXIT => ExitStatus::Signalled(SIGINT),
+            STATUS_ACCESS_VIOLATION => ExitStatus::Signalled(SIGSEGV),
+            code if code & SEVERITY_ERROR == SEVERITY_ERROR => ExitStatus::Signalled(SIGABRT),
+            code => ExitStatus::ExitCode(code as i32),
+        }
+    }
 }
 
 impl From<std::process::ExitStatus> for ExitStatus {
     fn from(status: std::process::ExitStatus) -> ExitStatus {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signo) = status.signal() {
+                return ExitStatus::Signalled(signo);
+            }
+        }
         if let Some(code) = status.code() {
             ExitStatus::ExitCode(code)
         } else if status.success() {
@@ -51,37 +95,25 @@ impl From<ExitStatus> for Status {
     }
 }
 
-// libc doesn't provide an NSIG value, so we guess; this
-// value is likely larger than reality but it is safe because
-// we only use signal numbers produced from a process exit
-// status to index into the sys_signame and sys_siglist
-// globals, and those are therefore valid signal offsets.
-#[cfg(unix)]
-const NSIG: usize = 1024;
-#[cfg(unix)]
-extern "C" {
-    static sys_signame: [*const i8; NSIG];
-    static sys_siglist: [*const i8; NSIG];
-}
-
-fn signame(n: i32) -> Cow<'static, str> {
-    #[cfg(unix)]
-    unsafe {
-        let c_str = sys_signame[n as usize];
-        std::ffi::CStr::from_ptr(c_str).to_string_lossy()
+fn signame(n: i32) -> String {
+    match signal_name(n) {
+        Some(name) => format!("SIG{}", name),
+        None => format!("SIG{}", n),
     }
-    #[cfg(windows)]
-    unreachable!(n);
 }
 
 fn sigdesc(n: i32) -> Cow<'static, str> {
     #[cfg(unix)]
     unsafe {
-        let c_str = sys_siglist[n as usize];
-        std::ffi::CStr::from_ptr(c_str).to_string_lossy()
+        let c_str = libc::strsignal(n);
+        if !c_str.is_null() {
+            return std::ffi::CStr::from_ptr(c_str)
+                .to_string_lossy()
+                .into_owned()
+                .into();
+        }
     }
-    #[cfg(windows)]
-    unreachable!(n);
+    "Unknown signal".into()
 }
 
 impl std::fmt::Display for ExitStatus {
@@ -93,7 +125,7 @@ impl std::fmt::Display for ExitStatus {
             ExitStatus::Signalled(n) => {
                 let name = signame(*n);
                 let desc = sigdesc(*n);
-                write!(fmt, "signal {} sig{}: {}", n, name, desc)
+                write!(fmt, "signal {} {}: {}", n, name, desc)
             }
         }
     }
@@ -132,15 +164,7 @@ impl ChildProcessInner {
                 return Some(status);
             }
 
-            let status = if libc::WIFSTOPPED(status) {
-                ExitStatus::Stopped
-            } else if libc::WIFSIGNALED(status) {
-                ExitStatus::Signalled(libc::WTERMSIG(status))
-            } else if libc::WIFEXITED(status) {
-                ExitStatus::ExitCode(libc::WEXITSTATUS(status))
-            } else {
-                ExitStatus::Running
-            };
+            let status = ExitStatus::from_wait_status(status);
 
             self.last_status = status;
             Some(status)
@@ -170,7 +194,7 @@ impl ChildProcessInner {
             let status =
                 if unsafe { GetExitCodeProcess(self.process.as_raw_handle(), &mut exit_code) } != 0
                 {
-                    ExitStatus::ExitCode(exit_code as i32)
+                    ExitStatus::from_windows_exit_code(exit_code)
                 } else {
                     let err = std::io::Error::last_os_error();
                     eprintln!("error getting exit code for child pid {} {}", self.pid, err);
@@ -207,6 +231,12 @@ impl ChildProcess {
     fn wait(&self, blocking: bool) -> Option<ExitStatus> {
         self.inner.lock().unwrap().wait(blocking)
     }
+
+    /// Returns the exit status of the process, waiting for it to
+    /// stop or terminate if `blocking` is true.
+    pub fn exit_status(&self, blocking: bool) -> Option<ExitStatus> {
+        self.wait(blocking)
+    }
 }
 
 impl WaitForStatus for ChildProcess {
@@ -216,4 +246,55 @@ impl WaitForStatus for ChildProcess {
     fn poll(&self) -> Option<Status> {
         ChildProcess::wait(self, false).map(Into::into)
     }
+    fn pid(&self) -> Option<u32> {
+        Some(ChildProcess::pid(self) as u32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn signalled_wait_status() {
+        // The layout of the status word used by linux and the BSDs:
+        // the terminating signal is held in the low 7 bits, the exit
+        // code in the next byte and 0x7f marks a stopped process.
+        let status = ExitStatus::from_wait_status(libc::SIGKILL);
+        assert_eq!(status, ExitStatus::Signalled(libc::SIGKILL));
+        assert_eq!(Status::from(status), Status::Complete(137.into()));
+
+        let status = ExitStatus::from_wait_status(libc::SIGTERM);
+        assert_eq!(Status::from(status), Status::Complete(143.into()));
+
+        assert_eq!(
+            ExitStatus::from_wait_status(3 << 8),
+            ExitStatus::ExitCode(3)
+        );
+        assert_eq!(
+            ExitStatus::from_wait_status((libc::SIGTSTP << 8) | 0x7f),
+            ExitStatus::Stopped
+        );
+    }
+
+    #[test]
+    fn windows_exit_codes() {
+        assert_eq!(
+            ExitStatus::from_windows_exit_code(0xC000_013A),
+            ExitStatus::Signalled(2)
+        );
+        assert_eq!(
+            Status::from(ExitStatus::from_windows_exit_code(0xC000_0005)),
+            Status::Complete(139.into())
+        );
+        assert_eq!(
+            ExitStatus::from_windows_exit_code(0xC000_0409),
+            ExitStatus::Signalled(6)
+        );
+        assert_eq!(
+            ExitStatus::from_windows_exit_code(3),
+            ExitStatus::ExitCode(3)
+        );
+    }
 }
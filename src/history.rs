@@ -0,0 +1,611 @@
+//! The command history, shared between the line editor and the
+//! builtins that inspect it, such as `fc`.
+use failure::{Fail, Fallible};
+use lazy_static::lazy_static;
+use shell_vm::Environment;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use termwiz::lineedit::{History, HistoryIndex};
+
+lazy_static! {
+    pub static ref HISTORY: Mutex<ShellHistory> = Mutex::new(ShellHistory::default());
+}
+
+/// Holds the history entries in memory.
+/// Entries are numbered from 1 for presentation to the user;
+/// the `History` trait implementation uses 0-based indices.
+#[derive(Default, Debug, Clone)]
+pub struct ShellHistory {
+    entries: Vec<String>,
+    /// The time at which each entry was added, in seconds since the
+    /// epoch.  This parallels `entries`; entries loaded from a history
+    /// file that predates timestamps have no time.
+    times: Vec<Option<u64>>,
+    /// The number of entries, at the end of `entries`, that have yet
+    /// to be appended to the history file by `sync`
+    pending: usize,
+}
+
+impl ShellHistory {
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// The time at which each of the entries was added
+    pub fn times(&self) -> &[Option<u64>] {
+        &self.times
+    }
+
+    /// Returns the autosuggestion for `line`: the remainder of the
+    /// most recent entry that starts with it.  Nothing is suggested
+    /// for an empty line, or when the line is already complete.
+    pub fn suggest(&self, line: &str) -> Option<&str> {
+        if line.is_empty() {
+            return None;
+        }
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.len() > line.len() && entry.starts_with(line))
+            .map(|entry| &entry[line.len()..])
+    }
+
+    /// Replace the most recently added entry.  This is used by `fc`
+    /// to record the command that it ran in place of the `fc`
+    /// command itself.
+    pub fn replace_last(&mut self, line: &str) {
+        if self.entries.pop().is_some() {
+            self.times.pop();
+            self.pending = self.pending.saturating_sub(1);
+        }
+        self.add(line);
+    }
+
+    /// Add `line` to the history, subject to the filtering requested
+    /// by `control`
+    pub fn add_history_entry(&mut self, line: &str, control: HistoryControl) {
+        if control.ignorespace && line.starts_with([' ', '\t']) {
+            return;
+        }
+        if control.ignoredups && self.entries.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        if control.erasedups {
+            let unsynced = self.entries.len() - self.pending;
+            let mut idx = self.entries.len();
+            while idx > 0 {
+                idx -= 1;
+                if self.entries[idx] == line {
+                    self.entries.remove(idx);
+                    self.times.remove(idx);
+                    if idx >= unsynced {
+                        self.pending -= 1;
+                    }
+                }
+            }
+        }
+        self.add_at(line, now());
+        self.pending += 1;
+    }
+
+    fn add_at(&mut self, line: &str, time: Option<u64>) {
+        self.entries.push(line.to_owned());
+        self.times.push(time);
+    }
+
+    /// Parse the contents of a history file.  Each entry is a line,
+    /// optionally preceded by a `#` line holding the time at which it
+    /// was added, in the same format as bash.
+    fn parse(text: &str) -> Self {
+        let mut history = Self::default();
+        let mut time = None;
+        for line in text.lines() {
+            if line.len() > 1 && line.starts_with('#') {
+                if let Ok(secs) = line[1..].parse::<u64>() {
+                    time = Some(secs);
+                    continue;
+                }
+            }
+            history.add_at(line, time.take());
+        }
+        history
+    }
+
+    fn format_entries(entries: &[String], times: &[Option<u64>]) -> String {
+        let mut text = String::new();
+        for (line, time) in entries.iter().zip(times) {
+            if let Some(time) = time {
+                text.push_str(&format!("#{}\n", time));
+            }
+            text.push_str(line);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Append the entries from the history file at `path`.
+    /// A missing file is treated as an empty history.
+    pub fn load(&mut self, path: &Path) -> Fallible<()> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err
+                    .context(format!("reading history from {}", path.display()))
+                    .into())
+            }
+        };
+        let loaded = Self::parse(&text);
+        self.entries.extend(loaded.entries);
+        self.times.extend(loaded.times);
+        Ok(())
+    }
+
+    /// Write the entries to the history file at `path`, one per line
+    pub fn save(&self, path: &Path) -> Fallible<()> {
+        let text = Self::format_entries(&self.entries, &self.times);
+        std::fs::write(path, text)
+            .map_err(|err| err.context(format!("saving history to {}", path.display())))?;
+        Ok(())
+    }
+
+    /// Merge the entries of `other` into this history, ordered by the
+    /// time at which they were added.  Entries present in both, such as
+    /// those that this shell has already appended to the history file,
+    /// appear only once.  Both histories are expected to be in the
+    /// order in which their entries were added.
+    pub fn merge(&mut self, other: &ShellHistory) {
+        let ours = self.entries.iter().zip(&self.times);
+        let theirs = other.entries.iter().zip(&other.times);
+        let mut ours = ours.peekable();
+        let mut theirs = theirs.peekable();
+        let mut merged = ShellHistory {
+            pending: self.pending,
+            ..Default::default()
+        };
+
+        loop {
+            let (line, time) = match (ours.peek(), theirs.peek()) {
+                (Some(a), Some(b)) if a == b => {
+                    theirs.next();
+                    ours.next().unwrap()
+                }
+                // Entries added in the same second keep the order in
+                // which they appear in `other`, which is the history file
+                (Some((_, a_time)), Some((_, b_time))) => {
+                    if a_time.unwrap_or(0) < b_time.unwrap_or(0) {
+                        ours.next().unwrap()
+                    } else {
+                        theirs.next().unwrap()
+                    }
+                }
+                (Some(_), None) => ours.next().unwrap(),
+                (None, Some(_)) => theirs.next().unwrap(),
+                (None, None) => break,
+            };
+            merged.add_at(line, *time);
+        }
+
+        *self = merged;
+    }
+
+    /// Share the history with other shells using the file at `path`:
+    /// the entries added since the last call are appended to the file,
+    /// and then those appended by other shells are merged in.
+    /// The file is locked while it is updated.
+    pub fn sync(&mut self, path: &Path) -> Fallible<()> {
+        let context =
+            |err: std::io::Error| err.context(format!("sharing history with {}", path.display()));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(context)?;
+        lock_file(&file).map_err(context)?;
+
+        let start = self.entries.len() - self.pending;
+        let text = Self::format_entries(&self.entries[start..], &self.times[start..]);
+        file.write_all(text.as_bytes()).map_err(context)?;
+        self.pending = 0;
+
+        let mut text = String::new();
+        file.seek(SeekFrom::Start(0)).map_err(context)?;
+        file.read_to_string(&mut text).map_err(context)?;
+        self.merge(&Self::parse(&text));
+        Ok(())
+    }
+}
+
+/// Take an exclusive lock on `file`; it is released when the file
+/// is closed
+#[cfg(unix)]
+fn lock_file(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn lock_file(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+/// The filtering applied to the commands added to the history,
+/// as set by the colon separated options in `$HISTCONTROL`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryControl {
+    /// Don't record commands that begin with a space
+    pub ignorespace: bool,
+    /// Don't record a command that repeats the previous command
+    pub ignoredups: bool,
+    /// Remove earlier copies of a command as it is recorded
+    pub erasedups: bool,
+}
+
+impl Default for HistoryControl {
+    /// Repeated commands are ignored when `$HISTCONTROL` is unset
+    fn default() -> Self {
+        Self {
+            ignorespace: false,
+            ignoredups: true,
+            erasedups: false,
+        }
+    }
+}
+
+impl HistoryControl {
+    /// Parse a `$HISTCONTROL` value.  `ignoreboth` is shorthand for
+    /// `ignorespace:ignoredups`, and unknown options are ignored.
+    pub fn parse(value: &str) -> Self {
+        let mut control = Self {
+            ignorespace: false,
+            ignoredups: false,
+            erasedups: false,
+        };
+        for option in value.split(':') {
+            match option {
+                "ignorespace" => control.ignorespace = true,
+                "ignoredups" => control.ignoredups = true,
+                "ignoreboth" => {
+                    control.ignorespace = true;
+                    control.ignoredups = true;
+                }
+                "erasedups" => control.erasedups = true,
+                _ => {}
+            }
+        }
+        control
+    }
+
+    pub fn from_env(env: &Environment) -> Self {
+        match env.get("HISTCONTROL").and_then(|v| v.to_str()) {
+            Some(value) => Self::parse(value),
+            None => Self::default(),
+        }
+    }
+}
+
+const DAY_NAMES: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTH_NAMES: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Format the time at which a history entry was added for display,
+/// as directed by `$HISTTIMEFORMAT`, in the local time zone
+pub fn format_history_time(time: u64, format: &str) -> String {
+    format_time(time, utc_offset(time), format)
+}
+
+/// Format `time`, in seconds since the epoch, as `strftime` would in
+/// a time zone `utc_offset` seconds east of UTC.  The commonly used
+/// conversions are supported; any others are copied unchanged.
+fn format_time(time: u64, utc_offset: i64, format: &str) -> String {
+    let secs = time as i64 + utc_offset;
+    let days = secs.div_euclid(86400);
+    let secs_of_day = secs.rem_euclid(86400);
+    let (hour, minute, second) = (secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60);
+    let (year, month, day) = civil_from_days(days);
+    let weekday = (days + 4).rem_euclid(7) as usize;
+    let yday = days - days_from_civil(year, 1, 1) + 1;
+    let hour12 = if hour % 12 == 0 { 12 } else { hour % 12 };
+
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        let conversion = match chars.next() {
+            Some(conversion) => conversion,
+            None => {
+                result.push('%');
+                break;
+            }
+        };
+        let text = match conversion {
+            'a' => DAY_NAMES[weekday][..3].to_string(),
+            'A' => DAY_NAMES[weekday].to_string(),
+            'b' | 'h' => MONTH_NAMES[month as usize - 1][..3].to_string(),
+            'B' => MONTH_NAMES[month as usize - 1].to_string(),
+            'd' => format!("{:02}", day),
+            'e' => format!("{:2}", day),
+            'H' => format!("{:02}", hour),
+            'I' => format!("{:02}", hour12),
+            'j' => format!("{:03}", yday),
+            'm' => format!("{:02}", month),
+            'M' => format!("{:02}", minute),
+            'p' => if hour < 12 { "AM" } else { "PM" }.to_string(),
+            'S' => format!("{:02}", second),
+            's' => time.to_string(),
+            'y' => format!("{:02}", year.rem_euclid(100)),
+            'Y' => year.to_string(),
+            'F' => format!("{}-{:02}-{:02}", year, month, day),
+            'T' => format!("{:02}:{:02}:{:02}", hour, minute, second),
+            'R' => format!("{:02}:{:02}", hour, minute),
+            'D' => format!("{:02}/{:02}/{:02}", month, day, year.rem_euclid(100)),
+            'n' => "\n".to_string(),
+            't' => "\t".to_string(),
+            '%' => "%".to_string(),
+            other => format!("%{}", other),
+        };
+        result.push_str(&text);
+    }
+    result
+}
+
+/// Convert a count of days since 1970-01-01 to a (year, month, day)
+/// date in the proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The inverse of `civil_from_days`
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The offset of the local time zone from UTC at `time`, in seconds
+#[cfg(unix)]
+fn utc_offset(time: u64) -> i64 {
+    let time = time as libc::time_t;
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+}
+
+#[cfg(windows)]
+fn utc_offset(_time: u64) -> i64 {
+    0
+}
+
+/// Returns the path of the history file; this is `$HISTFILE` if set,
+/// otherwise `history` in the wzsh configuration directory.
+pub fn history_file(env: &Environment) -> PathBuf {
+    match env.get("HISTFILE") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => crate::config_dir().join("history"),
+    }
+}
+
+impl History for ShellHistory {
+    fn get(&self, idx: HistoryIndex) -> Option<&str> {
+        self.entries.get(idx).map(String::as_str)
+    }
+
+    fn last(&self) -> Option<HistoryIndex> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some(self.entries.len() - 1)
+        }
+    }
+
+    fn add(&mut self, line: &str) {
+        self.add_history_entry(line, HistoryControl::default());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn history(entries: &[(u64, &str)]) -> ShellHistory {
+        let mut history = ShellHistory::default();
+        for (time, line) in entries {
+            history.add_at(line, Some(*time));
+        }
+        history
+    }
+
+    #[test]
+    fn suggestion() {
+        let history = history(&[
+            (1, "git status"),
+            (2, "git commit -m wip"),
+            (3, "ls"),
+            (4, "git"),
+        ]);
+        // The most recent matching entry is preferred
+        assert_eq!(history.suggest("git"), Some(" commit -m wip"));
+        assert_eq!(history.suggest("git s"), Some("tatus"));
+        assert_eq!(history.suggest("l"), Some("s"));
+        assert_eq!(history.suggest("ls"), None);
+        assert_eq!(history.suggest("cargo"), None);
+        assert_eq!(history.suggest(""), None);
+    }
+
+    #[test]
+    fn save_and_load() -> Fallible<()> {
+        let root = tempdir::TempDir::new("wzsh")?;
+        let path = root.path().join("history");
+        let mut history = ShellHistory::default();
+        history.add("echo one");
+        history.add("echo two");
+        history.save(&path)?;
+
+        let mut loaded = ShellHistory::default();
+        loaded.load(&path)?;
+        assert_eq!(loaded.entries(), history.entries());
+        assert_eq!(loaded.times(), history.times());
+
+        // A missing file is an empty history
+        let mut missing = ShellHistory::default();
+        missing.load(&root.path().join("missing"))?;
+        assert!(missing.entries().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_without_times() {
+        let history = ShellHistory::parse("ls\n#100\necho hi\n#comment\n");
+        assert_eq!(history.entries(), &["ls", "echo hi", "#comment"]);
+        assert_eq!(history.times(), &[None, Some(100), None]);
+    }
+
+    #[test]
+    fn merge() {
+        // Two shells that both loaded the same history and then
+        // each ran commands of their own
+        let mut first = history(&[(1, "ls"), (1, "ls"), (10, "echo a"), (30, "echo c")]);
+        let second = history(&[(1, "ls"), (1, "ls"), (20, "echo b"), (40, "echo d")]);
+
+        first.merge(&second);
+        assert_eq!(
+            first.entries(),
+            &["ls", "ls", "echo a", "echo b", "echo c", "echo d"]
+        );
+        assert_eq!(
+            first.times(),
+            &[Some(1), Some(1), Some(10), Some(20), Some(30), Some(40)]
+        );
+
+        // Merging again adds nothing new
+        let merged = first.clone();
+        first.merge(&merged);
+        assert_eq!(first.entries(), merged.entries());
+    }
+
+    #[test]
+    fn sync() -> Fallible<()> {
+        let root = tempdir::TempDir::new("wzsh")?;
+        let path = root.path().join("history");
+        let mut first = ShellHistory::default();
+        let mut second = ShellHistory::default();
+
+        first.add("echo one");
+        first.sync(&path)?;
+        second.add("echo two");
+        second.sync(&path)?;
+        first.sync(&path)?;
+
+        assert_eq!(first.entries(), &["echo one", "echo two"]);
+        assert_eq!(second.entries(), &["echo one", "echo two"]);
+        Ok(())
+    }
+
+    #[test]
+    fn time_format() {
+        // 2019-07-04 15:06:07 UTC, a Thursday
+        let time = 1_562_252_767;
+        assert_eq!(format_time(time, 0, "%F %T "), "2019-07-04 15:06:07 ");
+        assert_eq!(
+            format_time(time, 0, "%a %b %e %I:%M %p %j %% %q"),
+            "Thu Jul  4 03:06 PM 185 % %q"
+        );
+        // Two hours west of UTC, shortly after midnight on new year
+        assert_eq!(
+            format_time(1_546_304_400, -7200, "%A %d/%m/%Y %H:%M"),
+            "Monday 31/12/2018 23:00"
+        );
+        assert_eq!(
+            format_time(time, 3600, "%D %R %s"),
+            "07/04/19 16:06 1562252767"
+        );
+    }
+
+    #[test]
+    fn history_control() {
+        assert_eq!(
+            HistoryControl::parse("ignoreboth:bogus"),
+            HistoryControl {
+                ignorespace: true,
+                ignoredups: true,
+                erasedups: false,
+            }
+        );
+
+        let mut history = ShellHistory::default();
+        let none = HistoryControl::parse("");
+        history.add_history_entry("ls", none);
+        history.add_history_entry("ls", none);
+        assert_eq!(history.entries(), &["ls", "ls"]);
+
+        let ignoredups = HistoryControl::parse("ignoredups");
+        history.add_history_entry("ls", ignoredups);
+        history.add_history_entry(" secret", ignoredups);
+        assert_eq!(history.entries(), &["ls", "ls", " secret"]);
+
+        let ignorespace = HistoryControl::parse("ignorespace");
+        history.add_history_entry(" another", ignorespace);
+        assert_eq!(history.entries(), &["ls", "ls", " secret"]);
+
+        let erasedups = HistoryControl::parse("erasedups");
+        history.add_history_entry("pwd", erasedups);
+        history.add_history_entry("ls", erasedups);
+        assert_eq!(history.entries(), &[" secret", "pwd", "ls"]);
+        assert_eq!(history.times().len(), 3);
+        assert_eq!(history.pending, 3);
+    }
+}
@@ -1,14 +1,133 @@
-use crate::exitstatus::{ChildProcess, Pid};
+use crate::exitstatus::{ChildProcess, ExitStatus, Pid};
 use failure::Fallible;
 use lazy_static::lazy_static;
-use shell_vm::{Status, WaitForStatus};
+use shell_vm::Status;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 lazy_static! {
     pub static ref JOB_LIST: JobList = JobList::default();
 }
 
+/// Set when the state of a child process may have changed.
+/// On unix this is driven by SIGCHLD; this is a plain static rather
+/// than a member of JOB_LIST so that it is safe to touch from
+/// the signal handler.
+static JOBS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Flag that the job list needs to be re-examined
+pub fn notify_jobs_changed() {
+    JOBS_CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if a child process may have changed state since
+/// the last call, clearing the flag.
+pub fn take_jobs_changed() -> bool {
+    JOBS_CHANGED.swap(false, Ordering::SeqCst)
+}
+
+/// Returns true if changes to the status of jobs should be reported
+/// now.  They are always reported before the prompt is displayed; with
+/// `set -o notify` they are also reported as soon as the shell regains
+/// control after SIGCHLD has flagged a change.
+pub fn should_report_jobs(notify: bool, at_prompt: bool, jobs_changed: bool) -> bool {
+    at_prompt || (notify && jobs_changed)
+}
+
+/// Set when the user interrupts the shell with SIGINT while it is
+/// running a command itself, rather than waiting on a child process
+/// that owns the terminal.  The VM polls this between commands so
+/// that loops made up of builtins can be stopped.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Flag that the running command should be interrupted
+pub fn notify_interrupted() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if an interrupt has arrived since the last call
+/// to `clear_interrupted`
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Forget any interrupt; called before running each command line
+pub fn clear_interrupted() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn sigint_handler(_signo: libc::c_int) {
+    notify_interrupted();
+}
+
+/// Arrange for SIGINT to flag that the running command should be
+/// interrupted, rather than terminating the shell.
+#[cfg(unix)]
+pub fn install_sigint_handler() -> Fallible<()> {
+    use failure::Fail;
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = sigint_handler as *const () as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error()
+                .context("installing SIGINT handler")
+                .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn sigchld_handler(_signo: libc::c_int) {
+    notify_jobs_changed();
+}
+
+/// Arrange for SIGCHLD to mark the job list as changed.
+/// SA_RESTART is used so that blocking waitpid and read calls
+/// are not interrupted by the arrival of the signal.
+#[cfg(unix)]
+pub fn install_sigchld_handler() -> Fallible<()> {
+    use failure::Fail;
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = sigchld_handler as *const () as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error()
+                .context("installing SIGCHLD handler")
+                .into());
+        }
+    }
+    Ok(())
+}
+
+/// Block or unblock delivery of SIGCHLD to the calling thread.
+/// poll(2) is never restarted after a signal, so this is used to
+/// defer the signal while the line editor is waiting for input.
+/// A signal that arrives while blocked is delivered upon unblocking.
+#[cfg(unix)]
+pub fn block_sigchld(block: bool) {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGCHLD);
+        libc::pthread_sigmask(
+            if block {
+                libc::SIG_BLOCK
+            } else {
+                libc::SIG_UNBLOCK
+            },
+            &set,
+            std::ptr::null_mut(),
+        );
+    }
+}
+
 pub fn put_shell_in_foreground() {
     #[cfg(unix)]
     unsafe {
@@ -71,6 +190,107 @@ pub struct Job {
 #[derive(Default, Debug)]
 pub struct JobList {
     pub jobs: Mutex<HashMap<i32, Job>>,
+    statuses: Mutex<StatusTracker>,
+}
+
+/// The state of a job, as observed by polling its process group.
+///
+/// A job starts out `Running`.  It moves to `Stopped` when it is
+/// suspended (eg: by SIGTSTP) and back to `Running` when it is
+/// continued by `fg`.  `Done` and `Signaled` are terminal states:
+/// once a job has reached either of them it is removed from the
+/// job list after being reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    /// Exited with the specified exit code
+    Done(i32),
+    /// Killed by the specified signal number
+    Signaled(i32),
+}
+
+impl JobState {
+    /// Returns true if the job will not change state again
+    pub fn is_terminal(self) -> bool {
+        match self {
+            JobState::Done(_) | JobState::Signaled(_) => true,
+            JobState::Running | JobState::Stopped => false,
+        }
+    }
+
+    /// Format the state as reported by the `jobs` builtin and by
+    /// the notifications printed at the prompt.
+    pub fn format_job(self, id: i32, label: &str) -> String {
+        format!("[{}]+ {}\t{}", id, self, label)
+    }
+}
+
+impl From<ExitStatus> for JobState {
+    fn from(status: ExitStatus) -> JobState {
+        match status {
+            ExitStatus::Running => JobState::Running,
+            ExitStatus::Stopped => JobState::Stopped,
+            ExitStatus::ExitCode(n) => JobState::Done(n),
+            ExitStatus::Signalled(n) => JobState::Signaled(n),
+        }
+    }
+}
+
+impl From<JobState> for Status {
+    fn from(state: JobState) -> Status {
+        ExitStatus::from(state).into()
+    }
+}
+
+impl From<JobState> for ExitStatus {
+    fn from(state: JobState) -> ExitStatus {
+        match state {
+            JobState::Running => ExitStatus::Running,
+            JobState::Stopped => ExitStatus::Stopped,
+            JobState::Done(n) => ExitStatus::ExitCode(n),
+            JobState::Signaled(n) => ExitStatus::Signalled(n),
+        }
+    }
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            JobState::Running => write!(fmt, "Running"),
+            JobState::Stopped => write!(fmt, "Stopped"),
+            JobState::Done(0) => write!(fmt, "Done"),
+            JobState::Done(n) => write!(fmt, "Exit {}", n),
+            #[cfg(unix)]
+            JobState::Signaled(libc::SIGKILL) => write!(fmt, "Killed"),
+            JobState::Signaled(_) => write!(fmt, "Terminated"),
+        }
+    }
+}
+
+/// Remembers the most recently observed state of each job so
+/// that a change in state is reported exactly once.
+#[derive(Default, Debug)]
+struct StatusTracker {
+    last: HashMap<i32, JobState>,
+}
+
+impl StatusTracker {
+    /// Record the state of the job with the specified id.
+    /// Returns the state if it differs from the state that was
+    /// previously observed for that job.
+    fn observe(&mut self, id: i32, state: JobState) -> Option<JobState> {
+        if self.last.get(&id) == Some(&state) {
+            None
+        } else {
+            self.last.insert(id, state);
+            Some(state)
+        }
+    }
+
+    fn forget(&mut self, id: i32) {
+        self.last.remove(&id);
+    }
 }
 
 impl std::fmt::Display for Job {
@@ -103,6 +323,7 @@ impl Job {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn is_background(&self) -> bool {
         #[cfg(unix)]
         {
@@ -124,6 +345,7 @@ impl Job {
         0
     }
 
+    #[allow(dead_code)]
     pub fn put_in_background(&mut self) -> Fallible<()> {
         #[cfg(unix)]
         {
@@ -150,13 +372,16 @@ impl Job {
         Ok(())
     }
 
-    pub fn wait(&mut self) -> Option<Status> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.processes.last_mut().unwrap().wait()
+    /// Wait for the job to stop or terminate
+    pub fn wait_state(&mut self) -> Option<JobState> {
+        let inner = self.inner.lock().unwrap();
+        inner.processes.last()?.exit_status(true).map(Into::into)
     }
-    pub fn poll(&mut self) -> Option<Status> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.processes.last_mut().unwrap().poll()
+
+    /// Returns the current state of the job without blocking
+    pub fn poll_state(&mut self) -> Option<JobState> {
+        let inner = self.inner.lock().unwrap();
+        inner.processes.last()?.exit_status(false).map(Into::into)
     }
 }
 
@@ -170,25 +395,163 @@ impl JobList {
 
     pub fn jobs(&self) -> Vec<Job> {
         let jobs = self.jobs.lock().unwrap();
-        jobs.iter().map(|(_, v)| v.clone()).collect()
+        jobs.values().cloned().collect()
     }
 
+    /// Poll each job, reporting those whose status has changed
+    /// and removing those that have completed.
     pub fn check_and_print_status(&self) {
+        self.update_status(None);
+    }
+
+    /// Remove the foreground job if it has completed, without reporting
+    /// it, as its completion is self-evident; it is still reported if it
+    /// stopped.  Changes to the other jobs are left to be reported by
+    /// check_and_print_status.
+    pub fn remove_completed(&self, foreground: i32) {
+        self.update_status(Some(foreground));
+    }
+
+    /// Send SIGHUP to each job, followed by SIGCONT so that stopped
+    /// jobs are able to act upon it.  This is used when the shell exits.
+    pub fn hangup_all(&self) {
+        #[cfg(unix)]
+        {
+            let jobs = self.jobs.lock().unwrap();
+            for job in jobs.values() {
+                let pgid = job.process_group_id();
+                if pgid == 0 {
+                    continue;
+                }
+                unsafe {
+                    libc::kill(-pgid, libc::SIGHUP);
+                }
+                send_cont(-pgid).ok();
+            }
+        }
+    }
+
+    fn update_status(&self, foreground: Option<i32>) {
         let mut jobs = self.jobs.lock().unwrap();
+        let mut statuses = self.statuses.lock().unwrap();
         let mut terminated = vec![];
         for (id, job) in jobs.iter_mut() {
-            if let Some(Status::Complete(_status)) = job.poll() {
-                /* FIXME: only print if it wasn't the most recent fg command
-                if job.is_background() {
-                    eprintln!("[{}] - {} {}", id, status, job);
-                }
-                */
+            if foreground.map(|fg| fg != *id).unwrap_or(false) {
+                continue;
+            }
+            let state = match job.poll_state() {
+                Some(state) => state,
+                None => continue,
+            };
+            let changed = match statuses.observe(*id, state) {
+                Some(changed) => changed,
+                None => continue,
+            };
+            if changed.is_terminal() {
                 terminated.push(*id);
             }
+            let report = match changed {
+                JobState::Running => false,
+                JobState::Stopped | JobState::Signaled(_) => true,
+                JobState::Done(_) => foreground.is_none(),
+            };
+            if report {
+                eprintln!("{}", changed.format_job(*id, &job.to_string()));
+            }
         }
 
         for id in terminated {
             jobs.remove(&id);
+            statuses.forget(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status_transitions() {
+        use JobState::*;
+        let mut tracker = StatusTracker::default();
+        assert_eq!(tracker.observe(1, Running), Some(Running));
+        assert_eq!(tracker.observe(1, Running), None);
+        assert_eq!(tracker.observe(1, Stopped), Some(Stopped));
+        assert_eq!(tracker.observe(1, Stopped), None);
+        assert_eq!(tracker.observe(2, Stopped), Some(Stopped));
+        assert_eq!(tracker.observe(1, Running), Some(Running));
+        assert_eq!(tracker.observe(1, Done(0)), Some(Done(0)));
+        assert_eq!(tracker.observe(1, Done(0)), None);
+        assert_eq!(tracker.observe(2, Signaled(15)), Some(Signaled(15)));
+
+        // A recycled id is treated as a new job
+        tracker.forget(1);
+        assert_eq!(tracker.observe(1, Running), Some(Running));
+    }
+
+    #[test]
+    fn terminal_states() {
+        assert!(!JobState::Running.is_terminal());
+        assert!(!JobState::Stopped.is_terminal());
+        assert!(JobState::Done(1).is_terminal());
+        assert!(JobState::Signaled(9).is_terminal());
+    }
+
+    #[test]
+    fn exit_status_conversion() {
+        assert_eq!(JobState::from(ExitStatus::Stopped), JobState::Stopped);
+        assert_eq!(JobState::from(ExitStatus::ExitCode(3)), JobState::Done(3));
+        assert_eq!(
+            JobState::from(ExitStatus::Signalled(15)),
+            JobState::Signaled(15)
+        );
+        assert_eq!(
+            Status::from(JobState::Signaled(15)),
+            Status::Complete(143.into())
+        );
+        assert_eq!(Status::from(JobState::Stopped), Status::Stopped);
+    }
+
+    #[test]
+    fn printed_form() {
+        assert_eq!(
+            JobState::Running.format_job(1, "sleep 10"),
+            "[1]+ Running\tsleep 10"
+        );
+        assert_eq!(JobState::Stopped.format_job(1, "vim"), "[1]+ Stopped\tvim");
+        assert_eq!(JobState::Done(0).format_job(2, "ls"), "[2]+ Done\tls");
+        assert_eq!(JobState::Done(2).format_job(2, "ls"), "[2]+ Exit 2\tls");
+        #[cfg(unix)]
+        {
+            assert_eq!(
+                JobState::Signaled(libc::SIGTERM).format_job(3, "yes"),
+                "[3]+ Terminated\tyes"
+            );
+            assert_eq!(
+                JobState::Signaled(libc::SIGKILL).format_job(3, "yes"),
+                "[3]+ Killed\tyes"
+            );
         }
     }
+
+    #[test]
+    fn changed_flag() {
+        take_jobs_changed();
+        assert!(!take_jobs_changed());
+        notify_jobs_changed();
+        assert!(take_jobs_changed());
+        assert!(!take_jobs_changed());
+    }
+
+    #[test]
+    fn notify_decision() {
+        // Before the prompt, changes are reported regardless
+        assert!(should_report_jobs(false, true, false));
+        assert!(should_report_jobs(true, true, false));
+        // Otherwise they are deferred unless notify is set
+        assert!(!should_report_jobs(false, false, true));
+        assert!(should_report_jobs(true, false, true));
+        assert!(!should_report_jobs(true, false, false));
+    }
 }
@@ -0,0 +1,338 @@
+//! Key bindings for the line editor.
+//! The line editor only understands emacs style keys, so vi style
+//! key bindings are implemented by translating the keys that are
+//! typed into their emacs equivalents before the editor sees them.
+use failure::{err_msg, Error, Fallible};
+use shell_vm::ShellOptions;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+use termwiz::caps::{Capabilities, ProbeHintsBuilder};
+use termwiz::input::{InputEvent, KeyCode, KeyEvent, Modifiers};
+use termwiz::lineedit::LineEditor;
+use termwiz::surface::Change;
+use termwiz::terminal::{new_terminal, ScreenSize, Terminal, TerminalWaker};
+
+/// The key bindings used by the line editor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditMode {
+    Emacs,
+    Vi,
+}
+
+impl EditMode {
+    /// Returns the mode selected by the `vi` and `emacs` shell
+    /// options.  Emacs bindings are used unless `vi` is enabled.
+    pub fn from_options(options: &ShellOptions) -> EditMode {
+        if options.vi {
+            EditMode::Vi
+        } else {
+            EditMode::Emacs
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ViState {
+    Insert,
+    Command,
+    /// The `d` or `c` operator is waiting for the motion that
+    /// selects the text that it applies to
+    Operator(char),
+}
+
+fn key(key: KeyCode, modifiers: Modifiers) -> InputEvent {
+    InputEvent::Key(KeyEvent { key, modifiers })
+}
+
+fn plain(code: KeyCode) -> InputEvent {
+    key(code, Modifiers::NONE)
+}
+
+/// Kill to the end of the line
+fn kill_line() -> InputEvent {
+    key(KeyCode::Char('K'), Modifiers::CTRL)
+}
+
+/// Translates the keys typed with vi key bindings into the emacs
+/// style keys that have the same effect.  Each line starts out in
+/// insert mode; ESC switches to command mode.
+/// Only the commands that have an emacs equivalent are supported.
+#[derive(Debug)]
+pub struct ViKeys {
+    state: ViState,
+}
+
+impl ViKeys {
+    pub fn new() -> Self {
+        Self {
+            state: ViState::Insert,
+        }
+    }
+
+    /// Start editing a new line, in insert mode
+    pub fn reset(&mut self) {
+        self.state = ViState::Insert;
+    }
+
+    /// Returns the events that the line editor should see in place
+    /// of `event`
+    pub fn translate(&mut self, event: InputEvent) -> Vec<InputEvent> {
+        let (c, modifiers) = match &event {
+            InputEvent::Key(KeyEvent {
+                key: KeyCode::Escape,
+                ..
+            }) => {
+                if self.state == ViState::Insert {
+                    self.state = ViState::Command;
+                    // The cursor moves back onto the last character
+                    return vec![plain(KeyCode::LeftArrow)];
+                }
+                self.state = ViState::Command;
+                return vec![];
+            }
+            InputEvent::Key(KeyEvent {
+                key: KeyCode::Char(c),
+                modifiers,
+            }) => (*c, *modifiers),
+            _ => return vec![event],
+        };
+
+        if self.state == ViState::Insert {
+            // An ESC that is quickly followed by another key is
+            // reported as that key with the ALT modifier
+            if modifiers.contains(Modifiers::ALT) {
+                self.state = ViState::Command;
+                let mut events = vec![plain(KeyCode::LeftArrow)];
+                events.extend(self.command(c));
+                return events;
+            }
+            return vec![event];
+        }
+        if modifiers != Modifiers::NONE {
+            // Control keys such as CTRL-C and CTRL-D work in either mode
+            return vec![event];
+        }
+        self.command(c)
+    }
+
+    fn command(&mut self, c: char) -> Vec<InputEvent> {
+        if let ViState::Operator(operator) = self.state {
+            self.state = ViState::Command;
+            let events = match c {
+                // `dd` and `cc` apply to the whole line
+                _ if c == operator => vec![plain(KeyCode::Home), kill_line()],
+                '$' => vec![kill_line()],
+                'b' | 'B' => vec![key(KeyCode::Char('W'), Modifiers::CTRL)],
+                _ => return vec![],
+            };
+            if operator == 'c' {
+                self.state = ViState::Insert;
+            }
+            return events;
+        }
+
+        match c {
+            'h' => vec![plain(KeyCode::LeftArrow)],
+            'l' | ' ' => vec![plain(KeyCode::RightArrow)],
+            'k' | '-' => vec![plain(KeyCode::UpArrow)],
+            'j' | '+' => vec![plain(KeyCode::DownArrow)],
+            'w' | 'W' => vec![key(KeyCode::RightArrow, Modifiers::ALT)],
+            'b' | 'B' => vec![key(KeyCode::LeftArrow, Modifiers::ALT)],
+            '0' | '^' => vec![plain(KeyCode::Home)],
+            '$' => vec![plain(KeyCode::End)],
+            'x' => vec![plain(KeyCode::RightArrow), plain(KeyCode::Backspace)],
+            'X' => vec![plain(KeyCode::Backspace)],
+            'D' => vec![kill_line()],
+            'd' | 'c' => {
+                self.state = ViState::Operator(c);
+                vec![]
+            }
+            'i' | 'a' | 'I' | 'A' | 'C' | 'S' => {
+                self.state = ViState::Insert;
+                match c {
+                    'a' => vec![plain(KeyCode::RightArrow)],
+                    'I' => vec![plain(KeyCode::Home)],
+                    'A' => vec![plain(KeyCode::End)],
+                    'C' => vec![kill_line()],
+                    'S' => vec![plain(KeyCode::Home), kill_line()],
+                    _ => vec![],
+                }
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// A terminal whose input is translated according to the edit mode.
+/// The mode is shared with the repl so that `set -o vi` takes effect
+/// at the next prompt.
+pub struct KeyBindingTerminal<T> {
+    terminal: T,
+    mode: Rc<Cell<EditMode>>,
+    vi: ViKeys,
+    pending: VecDeque<InputEvent>,
+}
+
+impl<T: Terminal> KeyBindingTerminal<T> {
+    pub fn new(terminal: T, mode: Rc<Cell<EditMode>>) -> Self {
+        Self {
+            terminal,
+            mode,
+            vi: ViKeys::new(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Terminal> Terminal for KeyBindingTerminal<T> {
+    fn set_raw_mode(&mut self) -> Result<(), Error> {
+        // The line editor enters raw mode as it starts reading a line
+        self.vi.reset();
+        self.pending.clear();
+        self.terminal.set_raw_mode()
+    }
+
+    fn set_cooked_mode(&mut self) -> Fallible<()> {
+        self.terminal.set_cooked_mode()
+    }
+
+    fn enter_alternate_screen(&mut self) -> Result<(), Error> {
+        self.terminal.enter_alternate_screen()
+    }
+
+    fn exit_alternate_screen(&mut self) -> Result<(), Error> {
+        self.terminal.exit_alternate_screen()
+    }
+
+    fn get_screen_size(&mut self) -> Result<ScreenSize, Error> {
+        self.terminal.get_screen_size()
+    }
+
+    fn set_screen_size(&mut self, size: ScreenSize) -> Result<(), Error> {
+        self.terminal.set_screen_size(size)
+    }
+
+    fn render(&mut self, changes: &[Change]) -> Result<(), Error> {
+        self.terminal.render(changes)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.terminal.flush()
+    }
+
+    fn poll_input(&mut self, wait: Option<Duration>) -> Result<Option<InputEvent>, Error> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            let event = match self.terminal.poll_input(wait)? {
+                Some(event) => event,
+                None => return Ok(None),
+            };
+            match self.mode.get() {
+                EditMode::Emacs => return Ok(Some(event)),
+                EditMode::Vi => self.pending.extend(self.vi.translate(event)),
+            }
+        }
+    }
+
+    fn waker(&self) -> TerminalWaker {
+        self.terminal.waker()
+    }
+}
+
+/// Create a line editor whose key bindings follow `mode`.  The
+/// terminal is set up in the same way as by termwiz's `line_editor`.
+pub fn line_editor(
+    mode: Rc<Cell<EditMode>>,
+) -> Fallible<LineEditor<KeyBindingTerminal<impl Terminal>>> {
+    let hints = ProbeHintsBuilder::new_from_env()
+        .mouse_reporting(Some(false))
+        .build()
+        .map_err(err_msg)?;
+    let caps = Capabilities::new_with_hints(hints)?;
+    let terminal = new_terminal(caps)?;
+    Ok(LineEditor::new(KeyBindingTerminal::new(terminal, mode)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn typed(vi: &mut ViKeys, keys: &[InputEvent]) -> Vec<InputEvent> {
+        keys.iter()
+            .flat_map(|event| vi.translate(event.clone()))
+            .collect()
+    }
+
+    fn chars(text: &str) -> Vec<InputEvent> {
+        text.chars().map(|c| plain(KeyCode::Char(c))).collect()
+    }
+
+    #[test]
+    fn edit_mode_from_options() -> Fallible<()> {
+        let mut options = ShellOptions::default();
+        assert_eq!(EditMode::from_options(&options), EditMode::Emacs);
+        options.set("vi", true)?;
+        assert_eq!(EditMode::from_options(&options), EditMode::Vi);
+        options.set("emacs", true)?;
+        assert_eq!(EditMode::from_options(&options), EditMode::Emacs);
+        Ok(())
+    }
+
+    #[test]
+    fn vi_keys() {
+        let mut vi = ViKeys::new();
+        // Keys are inserted until ESC switches to command mode
+        assert_eq!(typed(&mut vi, &chars("hi")), chars("hi"));
+        assert_eq!(
+            typed(&mut vi, &[plain(KeyCode::Escape)]),
+            vec![plain(KeyCode::LeftArrow)]
+        );
+        assert_eq!(
+            typed(&mut vi, &chars("0lx")),
+            vec![
+                plain(KeyCode::Home),
+                plain(KeyCode::RightArrow),
+                plain(KeyCode::RightArrow),
+                plain(KeyCode::Backspace),
+            ]
+        );
+        assert_eq!(
+            typed(&mut vi, &chars("dd")),
+            vec![plain(KeyCode::Home), kill_line()]
+        );
+        // Unsupported commands are ignored
+        assert_eq!(typed(&mut vi, &chars("zdz")), vec![]);
+
+        // `A` appends at the end of the line, returning to insert mode
+        let mut expect = vec![plain(KeyCode::End)];
+        expect.extend(chars("k"));
+        assert_eq!(typed(&mut vi, &chars("Ak")), expect);
+
+        // `cb` kills the previous word and returns to insert mode
+        let keys = [
+            key(KeyCode::Char('c'), Modifiers::ALT),
+            plain(KeyCode::Char('b')),
+            plain(KeyCode::Char('x')),
+        ];
+        assert_eq!(
+            typed(&mut vi, &keys),
+            vec![
+                plain(KeyCode::LeftArrow),
+                key(KeyCode::Char('W'), Modifiers::CTRL),
+                plain(KeyCode::Char('x')),
+            ]
+        );
+
+        // Control keys pass through in command mode, and a new line
+        // starts in insert mode
+        let cancel = key(KeyCode::Char('C'), Modifiers::CTRL);
+        typed(&mut vi, &[plain(KeyCode::Escape)]);
+        assert_eq!(vi.translate(cancel.clone()), vec![cancel]);
+        vi.reset();
+        assert_eq!(typed(&mut vi, &chars("x")), chars("x"));
+    }
+}
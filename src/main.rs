@@ -4,14 +4,43 @@ use failure::Fallible;
 use shell_vm::Environment;
 use std::path::PathBuf;
 use std::sync::Arc;
+use structopt::StructOpt;
 
 mod builtins;
+mod completion;
 mod errorprint;
 mod exitstatus;
+mod history;
 mod job;
+mod keybindings;
+mod prompt;
 mod repl;
 mod script;
 mod shellhost;
+mod signal;
+mod trap;
+
+#[derive(StructOpt)]
+/// Wez's Shell
+struct Opt {
+    /// Skip sourcing the rc file at interactive startup
+    #[structopt(long = "norc")]
+    norc: bool,
+
+    /// Act as a login shell, sourcing the profile files at startup
+    #[structopt(short = "l", long = "login")]
+    login: bool,
+
+    /// Lex the standard input and print each token, rather than
+    /// running it
+    #[structopt(long = "dump-tokens")]
+    dump_tokens: bool,
+
+    /// Parse the standard input and print the resulting syntax
+    /// tree, rather than running it
+    #[structopt(long = "dump-ast")]
+    dump_ast: bool,
+}
 
 fn config_dir() -> PathBuf {
     dirs::home_dir()
@@ -21,8 +50,27 @@ fn config_dir() -> PathBuf {
 }
 
 fn main() -> Fallible<()> {
+    let opt = Opt::from_args();
+
+    if opt.dump_tokens {
+        let stdin = std::io::stdin();
+        let mut lexer = shell_lexer::Lexer::new("stdin", stdin.lock());
+        for token in lexer.dump_tokens()? {
+            println!("{}", token);
+        }
+        return Ok(());
+    }
+
+    if opt.dump_ast {
+        let stdin = std::io::stdin();
+        let mut parser = shell_parser::Parser::new("stdin", stdin.lock());
+        print!("{}", shell_parser::dump_ast(&parser.parse()?));
+        return Ok(());
+    }
+
     let mut cwd = std::env::current_dir()?;
-    let mut env = Environment::new();
+    let mut env = Environment::from_system();
+    repl::increment_shlvl(&mut env);
     let funcs = Arc::new(FunctionRegistry::new());
 
     let startup_script = config_dir().join("startup.wzsh");
@@ -35,5 +83,9 @@ fn main() -> Fallible<()> {
         }
     }
 
-    repl::repl(cwd, env, &funcs)
+    let argv0 = std::env::args().next().unwrap_or_default();
+    let login = repl::is_login_shell(&argv0, opt.login);
+    let startup_files = repl::startup_files(&env, login, opt.norc);
+
+    repl::repl(cwd, env, &funcs, startup_files)
 }
@@ -0,0 +1,413 @@
+//! Rendering of the PS1 and PS2 prompt strings.
+//! As in bash, `\[` and `\]` bracket a sequence of non-printing
+//! characters, such as a color escape, so that they are excluded
+//! when computing the width of the prompt.
+use std::time::Duration;
+use termwiz::cell::{AttributeChange, CellAttributes};
+use termwiz::color::ColorAttribute;
+use termwiz::escape::csi::{Sgr, CSI};
+use termwiz::escape::parser::Parser;
+use termwiz::escape::Action;
+use termwiz::lineedit::OutputElement;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Values substituted for the prompt escapes that depend upon
+/// the state of the shell.
+#[derive(Debug, Default, Clone)]
+pub struct PromptContext {
+    /// The wall clock time taken by the last foreground command,
+    /// which is substituted for `\T`
+    pub last_duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptSegment {
+    /// Text that occupies space on the screen
+    Text(String),
+    /// The content of a `\[ ... \]` region
+    NonPrinting(String),
+}
+
+/// Decode the backslash escapes in `prompt` and split it into
+/// printing and non-printing segments.
+/// `\e` is the escape character and `\NNN` is an octal character
+/// code; `\n`, `\a` and `\\` have their usual meanings.
+/// `\T` is replaced by the duration of the last command.
+pub fn parse_prompt(prompt: &str, context: &PromptContext) -> Vec<PromptSegment> {
+    let mut segments = vec![];
+    let mut current = String::new();
+    let mut non_printing = false;
+    let mut chars = prompt.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            current.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') | Some(']') => {
+                if !current.is_empty() {
+                    segments.push(make_segment(std::mem::take(&mut current), non_printing));
+                }
+                non_printing = !non_printing;
+            }
+            Some('e') => current.push('\x1b'),
+            Some('a') => current.push('\x07'),
+            Some('n') => current.push('\n'),
+            Some('\\') => current.push('\\'),
+            Some('T') => {
+                if let Some(duration) = context.last_duration {
+                    current.push_str(&format_elapsed(duration));
+                }
+            }
+            Some(d) if d.is_digit(8) => {
+                let mut code = d.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            code = code * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                current.extend(std::char::from_u32(code));
+            }
+            Some(other) => {
+                current.push('\\');
+                current.push(other);
+            }
+            None => current.push('\\'),
+        }
+    }
+
+    if !current.is_empty() {
+        segments.push(make_segment(current, non_printing));
+    }
+    segments
+}
+
+/// Format a duration compactly for display in a prompt,
+/// eg: `1.2s`, `3m5s` or `2h10m`.
+pub fn format_elapsed(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 3600 {
+        format!("{}m{}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
+
+fn make_segment(text: String, non_printing: bool) -> PromptSegment {
+    if non_printing {
+        PromptSegment::NonPrinting(text)
+    } else {
+        PromptSegment::Text(text)
+    }
+}
+
+/// Split the segments of a multi-line prompt into the text that
+/// precedes its final line, with escapes intact so that it can be
+/// written directly to the terminal, and the segments of the final
+/// line itself.
+pub fn split_last_line(segments: Vec<PromptSegment>) -> (String, Vec<PromptSegment>) {
+    let split_at = segments.iter().rposition(|segment| match segment {
+        PromptSegment::Text(text) => text.contains('\n'),
+        PromptSegment::NonPrinting(_) => false,
+    });
+    let split_at = match split_at {
+        Some(idx) => idx,
+        None => return (String::new(), segments),
+    };
+
+    let mut leading = String::new();
+    let mut last_line = vec![];
+    for (idx, segment) in segments.into_iter().enumerate() {
+        match segment {
+            PromptSegment::Text(text) if idx == split_at => {
+                let newline = text.rfind('\n').unwrap();
+                leading.push_str(&text[..=newline]);
+                if newline + 1 < text.len() {
+                    last_line.push(PromptSegment::Text(text[newline + 1..].to_owned()));
+                }
+            }
+            PromptSegment::Text(text) | PromptSegment::NonPrinting(text) if idx < split_at => {
+                leading.push_str(&text)
+            }
+            segment => last_line.push(segment),
+        }
+    }
+    (leading, last_line)
+}
+
+/// Returns the number of columns occupied by the final line of
+/// the prompt; non-printing segments are not counted.
+pub fn visible_width(segments: &[PromptSegment]) -> usize {
+    let mut width = 0;
+    for segment in segments {
+        if let PromptSegment::Text(text) = segment {
+            match text.rfind('\n') {
+                Some(newline) => width = UnicodeWidthStr::width(&text[newline + 1..]),
+                None => width += UnicodeWidthStr::width(text.as_str()),
+            }
+        }
+    }
+    width
+}
+
+/// The default distance between tab stops, which may be changed by
+/// setting `TABSIZE`
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// Returns the number of columns that text occupies when it is written
+/// starting at `start_column` of a terminal that is `terminal_width`
+/// columns wide.  Tabs advance to the next tab stop, wide characters
+/// take two columns and any character that doesn't fit on the rest of
+/// a row wraps onto the next, leaving the remainder of the row unused.
+pub fn display_width(
+    text: &str,
+    start_column: usize,
+    tab_width: usize,
+    terminal_width: usize,
+) -> usize {
+    let row_width = if terminal_width == 0 {
+        usize::MAX
+    } else {
+        terminal_width
+    };
+    let tab_width = tab_width.max(1);
+    let mut column = start_column;
+    for c in text.chars() {
+        let in_row = column % row_width;
+        let width = if c == '\t' {
+            (tab_width - in_row % tab_width).min(row_width - in_row)
+        } else {
+            let width = UnicodeWidthChar::width(c).unwrap_or(0);
+            if in_row + width > row_width {
+                column += row_width - in_row;
+            }
+            width
+        };
+        column += width;
+    }
+    column - start_column
+}
+
+/// Compute the number of columns of padding to place between the
+/// end of the edited line and a right-side prompt, so that the right
+/// prompt ends one column short of the right edge of the terminal.
+/// Returns None if the right prompt would overlap the prompt or the
+/// line, or touch them without an intervening space.
+pub fn right_prompt_padding(
+    terminal_width: usize,
+    prompt_width: usize,
+    line_width: usize,
+    right_prompt_width: usize,
+) -> Option<usize> {
+    let start = terminal_width.checked_sub(right_prompt_width + 1)?;
+    let used = prompt_width + line_width;
+    if start > used {
+        Some(start - used)
+    } else {
+        None
+    }
+}
+
+/// Returns the width of the terminal attached to stdout, falling
+/// back to $COLUMNS and then to 80 columns.
+pub fn terminal_width() -> usize {
+    #[cfg(unix)]
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            return size.ws_col as usize;
+        }
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|cols| cols.parse().ok())
+        .unwrap_or(80)
+}
+
+/// Returns the OSC 0 sequence that sets the terminal window and icon
+/// title to `title`, or nothing if TERM is unset or is `dumb`.
+/// Control characters are removed from the title so that it cannot
+/// terminate the sequence early.
+pub fn title_sequence(term: Option<&str>, title: &str) -> String {
+    match term {
+        None | Some("") | Some("dumb") => String::new(),
+        Some(_) => {
+            let title: String = title.chars().filter(|c| !c.is_control()).collect();
+            format!("\x1b]0;{}\x07", title)
+        }
+    }
+}
+
+/// Convert the segments into elements for the line editor.
+/// The line editor renders control characters inertly, so the
+/// graphic rendition escapes found in non-printing segments are
+/// translated into attribute changes; other escapes are dropped.
+pub fn prompt_elements(segments: &[PromptSegment]) -> Vec<OutputElement> {
+    let mut elements = vec![];
+    for segment in segments {
+        match segment {
+            PromptSegment::Text(text) => elements.push(OutputElement::Text(text.clone())),
+            PromptSegment::NonPrinting(text) => {
+                let mut parser = Parser::new();
+                parser.parse(text.as_bytes(), |action| {
+                    if let Action::CSI(CSI::Sgr(sgr)) = action {
+                        elements.extend(sgr_element(sgr));
+                    }
+                });
+            }
+        }
+    }
+    elements
+}
+
+fn sgr_element(sgr: Sgr) -> Option<OutputElement> {
+    let change = match sgr {
+        Sgr::Reset => return Some(OutputElement::AllAttributes(CellAttributes::default())),
+        Sgr::Intensity(intensity) => AttributeChange::Intensity(intensity),
+        Sgr::Underline(underline) => AttributeChange::Underline(underline),
+        Sgr::Blink(blink) => AttributeChange::Blink(blink),
+        Sgr::Italic(on) => AttributeChange::Italic(on),
+        Sgr::Inverse(on) => AttributeChange::Reverse(on),
+        Sgr::Invisible(on) => AttributeChange::Invisible(on),
+        Sgr::StrikeThrough(on) => AttributeChange::StrikeThrough(on),
+        Sgr::Foreground(color) => AttributeChange::Foreground(ColorAttribute::from(color)),
+        Sgr::Background(color) => AttributeChange::Background(ColorAttribute::from(color)),
+        Sgr::Font(_) => return None,
+    };
+    Some(OutputElement::Attribute(change))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn width_excludes_non_printing() {
+        let segments = parse_prompt("\\[\\e[32m\\]user\\[\\e[0m\\]$ ", &PromptContext::default());
+        assert_eq!(
+            segments,
+            vec![
+                PromptSegment::NonPrinting("\x1b[32m".to_owned()),
+                PromptSegment::Text("user".to_owned()),
+                PromptSegment::NonPrinting("\x1b[0m".to_owned()),
+                PromptSegment::Text("$ ".to_owned()),
+            ]
+        );
+        assert_eq!(visible_width(&segments), 6);
+
+        assert_eq!(
+            visible_width(&parse_prompt("\\[\\033[1m\\]λ ", &PromptContext::default())),
+            2
+        );
+        assert_eq!(
+            visible_width(&parse_prompt("你好> ", &PromptContext::default())),
+            6
+        );
+    }
+
+    #[test]
+    fn multi_line() {
+        let segments = parse_prompt(
+            "\\[\\e[1m\\]~/src\\n\\[\\e[0m\\]$ ",
+            &PromptContext::default(),
+        );
+        assert_eq!(visible_width(&segments), 2);
+
+        let (leading, last_line) = split_last_line(segments);
+        assert_eq!(leading, "\x1b[1m~/src\n");
+        assert_eq!(
+            last_line,
+            vec![
+                PromptSegment::NonPrinting("\x1b[0m".to_owned()),
+                PromptSegment::Text("$ ".to_owned()),
+            ]
+        );
+
+        let (leading, last_line) = split_last_line(parse_prompt("$ ", &PromptContext::default()));
+        assert_eq!(leading, "");
+        assert_eq!(last_line, vec![PromptSegment::Text("$ ".to_owned())]);
+    }
+
+    #[test]
+    fn elapsed_escape() {
+        let context = PromptContext {
+            last_duration: Some(Duration::from_millis(1234)),
+        };
+        assert_eq!(
+            parse_prompt("took \\T$ ", &context),
+            vec![PromptSegment::Text("took 1.2s$ ".to_owned())]
+        );
+        assert_eq!(
+            parse_prompt("\\T$ ", &PromptContext::default()),
+            vec![PromptSegment::Text("$ ".to_owned())]
+        );
+        assert_eq!(format_elapsed(Duration::from_secs(185)), "3m5s");
+        assert_eq!(format_elapsed(Duration::from_secs(7800)), "2h10m");
+    }
+
+    #[test]
+    fn right_prompt_layout() {
+        // "$ " followed by "ls" with a 5 column right prompt
+        assert_eq!(right_prompt_padding(80, 2, 2, 5), Some(70));
+        assert_eq!(right_prompt_padding(20, 2, 2, 5), Some(10));
+        // Exactly one space separates the line from the right prompt
+        assert_eq!(right_prompt_padding(11, 2, 2, 5), Some(1));
+        // The line has grown into the right prompt
+        assert_eq!(right_prompt_padding(10, 2, 2, 5), None);
+        assert_eq!(right_prompt_padding(80, 2, 80, 5), None);
+        // Narrower than the right prompt itself
+        assert_eq!(right_prompt_padding(4, 0, 0, 5), None);
+    }
+
+    #[test]
+    fn tab_expansion() {
+        assert_eq!(display_width("\t", 0, 8, 80), 8);
+        assert_eq!(display_width("ls\tx", 0, 8, 80), 9);
+        // Tab stops are relative to the start of the row, so the
+        // width of a tab depends upon where the text starts
+        assert_eq!(display_width("\t", 2, 8, 80), 6);
+        assert_eq!(display_width("a\tb", 2, 4, 80), 3);
+        // A tab doesn't extend past the end of the row
+        assert_eq!(display_width("\t", 18, 8, 20), 2);
+    }
+
+    #[test]
+    fn wide_character_width() {
+        assert_eq!(display_width("日本語", 0, 8, 80), 6);
+        assert_eq!(display_width("a😀b", 0, 8, 80), 4);
+        // Combining characters take no space of their own
+        assert_eq!(display_width("e\u{301}", 0, 8, 80), 1);
+        // A wide character that doesn't fit at the end of a row
+        // wraps, leaving the last column of the row empty
+        assert_eq!(display_width("日", 9, 8, 10), 3);
+        assert_eq!(display_width("日", 8, 8, 10), 2);
+    }
+
+    #[test]
+    fn color_escapes_become_attributes() {
+        let elements = prompt_elements(&parse_prompt(
+            "\\[\\e[32m\\]$ \\[\\e[0m\\]",
+            &PromptContext::default(),
+        ));
+        assert_eq!(elements.len(), 3);
+        match &elements[0] {
+            OutputElement::Attribute(AttributeChange::Foreground(_)) => {}
+            _ => panic!("expected a foreground change"),
+        }
+        match &elements[1] {
+            OutputElement::Text(text) => assert_eq!(text, "$ "),
+            _ => panic!("expected text"),
+        }
+        match &elements[2] {
+            OutputElement::AllAttributes(_) => {}
+            _ => panic!("expected an attribute reset"),
+        }
+    }
+}
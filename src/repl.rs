@@ -1,40 +1,38 @@
-use crate::errorprint::print_error;
-use crate::job::{put_shell_in_foreground, Job, JOB_LIST};
+use crate::completion::{
+    assignment_value_context, complete_filename, complete_filename_from, complete_registered,
+    complete_variable, highlight_filename, redirect_target_start, AssignmentValue, LsColors,
+};
+use crate::errorprint::{print_error, print_error_path};
+use crate::history::{history_file, HistoryControl, ShellHistory, HISTORY};
+#[cfg(unix)]
+use crate::job::{
+    block_sigchld, clear_interrupted, install_sigchld_handler, install_sigint_handler,
+};
+use crate::job::{put_shell_in_foreground, should_report_jobs, take_jobs_changed, Job, JOB_LIST};
+use crate::keybindings::{self, EditMode};
+use crate::prompt::{
+    display_width, parse_prompt, prompt_elements, right_prompt_padding, split_last_line,
+    terminal_width, visible_width, PromptContext, PromptSegment, DEFAULT_TAB_WIDTH,
+};
+use crate::script;
 use crate::shellhost::{FunctionRegistry, Host};
-use failure::{Error, Fail, Fallible};
+#[cfg(unix)]
+use crate::trap::block_trapped_signals;
+use crate::trap::{run_err_trap, run_exit_trap, run_pending_traps, TRAPS};
+use failure::{bail, Fail, Fallible};
 use shell_compiler::Compiler;
-use shell_lexer::{LexError, LexErrorKind};
-use shell_parser::{ParseErrorKind, Parser};
-use shell_vm::{Environment, Machine, Program, Status};
-use std::path::PathBuf;
-use std::sync::Arc;
-use termwiz::cell::AttributeChange;
+use shell_parser::{is_incomplete_input, Parser};
+use shell_vm::{should_report_time, Environment, Machine, Program, Status, TimeSnapshot};
+use std::cell::Cell;
+use std::io::{BufRead, BufReader, Stdin, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+use termwiz::cell::{AttributeChange, CellAttributes, Intensity};
 use termwiz::color::{AnsiColor, ColorAttribute, RgbColor};
 use termwiz::lineedit::*;
-
-/// Returns true if a given error might be resolved by allowing
-/// the user to continue typing more text on a subsequent line.
-/// Most lex errors fall into that category.
-fn is_recoverable_parse_error(e: &Error) -> bool {
-    if let Some(lex_err) = e.downcast_ref::<LexError>() {
-        match lex_err.kind {
-            LexErrorKind::EofDuringBackslash
-            | LexErrorKind::EofDuringComment
-            | LexErrorKind::EofDuringSingleQuotedString
-            | LexErrorKind::EofDuringDoubleQuotedString
-            | LexErrorKind::EofDuringAssignmentWord
-            | LexErrorKind::EofDuringCommandSubstitution
-            | LexErrorKind::EofDuringParameterExpansion => true,
-            LexErrorKind::IoError => false,
-        }
-    } else if let Some(parse_err) = e.downcast_ref::<ParseErrorKind>() {
-        match parse_err {
-            ParseErrorKind::UnexpectedToken(..) => false,
-        }
-    } else {
-        false
-    }
-}
+use termwiz::terminal::Terminal;
 
 #[cfg(unix)]
 fn init_job_control() -> Fallible<()> {
@@ -50,9 +48,9 @@ fn init_job_control() -> Fallible<()> {
             libc::kill(-shell_pgid, libc::SIGTTIN);
         }
 
-        // Ignore interactive and job control signals
+        // Ignore interactive and job control signals.  SIGINT is
+        // handled separately by install_sigint_handler.
         for s in &[
-            libc::SIGINT,
             libc::SIGQUIT,
             libc::SIGTSTP,
             libc::SIGTTIN,
@@ -79,15 +77,101 @@ fn init_job_control() -> Fallible<()> {
     Ok(())
 }
 
+/// The environment variable that selects the key bindings of the
+/// line editor at startup, either `emacs` or `vi`.  They can be
+/// changed later with `set -o emacs` and `set -o vi`.
+const EDIT_MODE_VARIABLE: &str = "WZSH_EDITMODE";
+
+/// Enable the option for the mode requested by $WZSH_EDITMODE, if any
+fn apply_edit_mode(env: &mut Environment) -> Fallible<()> {
+    let mode = match env.get(EDIT_MODE_VARIABLE) {
+        Some(mode) => mode.to_string_lossy().into_owned(),
+        None => return Ok(()),
+    };
+    match mode.as_str() {
+        "emacs" | "vi" => env.options_mut().set(&mode, true),
+        _ => bail!("{}: {}: unknown edit mode", EDIT_MODE_VARIABLE, mode),
+    }
+}
+
+/// Reads lines from a stream without any editing, writing the prompt
+/// to output first.  This is used when the line editor can't be
+/// initialized, as may happen with an unusual terminal.
+struct PlainLineReader<R, W> {
+    reader: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> PlainLineReader<R, W> {
+    /// Read a line, less its line terminator.  As with the line
+    /// editor, reaching the end of the input is reported as an error.
+    fn read_line(&mut self, prompt: &str) -> Fallible<Option<String>> {
+        write!(self.output, "{}", prompt)?;
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "End Of File").into(),
+            );
+        }
+        let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+        line.truncate(len);
+        Ok(Some(line))
+    }
+}
+
+/// Returns the text of a prompt line, including any non-printing
+/// escapes, for writing directly to the terminal
+fn prompt_text(segments: &[PromptSegment]) -> String {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            PromptSegment::Text(text) | PromptSegment::NonPrinting(text) => text.as_str(),
+        })
+        .collect()
+}
+
+/// The source of the lines read by the repl
+enum LineReader<T: Terminal> {
+    Editor(LineEditor<T>),
+    Plain(PlainLineReader<BufReader<Stdin>, Stdout>),
+}
+
+/// Build the line editor, falling back to reading plain lines from
+/// stdin if it can't be initialized.  The editor's key bindings
+/// follow `mode`.
+fn line_reader(mode: Rc<Cell<EditMode>>) -> LineReader<impl Terminal> {
+    match keybindings::line_editor(mode) {
+        Ok(editor) => LineReader::Editor(editor),
+        Err(err) => {
+            eprintln!(
+                "wzsh: the line editor is unavailable ({}); reading lines without editing",
+                err
+            );
+            LineReader::Plain(PlainLineReader {
+                reader: BufReader::new(std::io::stdin()),
+                output: std::io::stdout(),
+            })
+        }
+    }
+}
+
 struct EnvBits {
     cwd: PathBuf,
     env: Environment,
     funcs: Arc<FunctionRegistry>,
+    /// The wall clock time taken by the last foreground command
+    last_duration: Option<Duration>,
+    /// The process group of the job run by the last command
+    last_job: i32,
+    /// Whether commands are run with job control, which is only
+    /// the case when the shell is interactive
+    job_control: bool,
 }
 
 fn compile_and_run(prog: &str, env_bits: &mut EnvBits) -> Fallible<Status> {
     let job = Job::new_empty(prog.to_owned());
-    let mut parser = Parser::new(prog.as_bytes());
+    let mut parser = Parser::new("stdin", prog.as_bytes());
     let command = parser.parse()?;
     let mut compiler = Compiler::new();
     compiler.compile_command(&command)?;
@@ -97,86 +181,536 @@ fn compile_and_run(prog: &str, env_bits: &mut EnvBits) -> Fallible<Status> {
         Some(env_bits.env.clone()),
         &env_bits.cwd,
     )?;
-    machine.set_host(Arc::new(Host::with_job_control(job, &env_bits.funcs)));
+    machine.set_host(Arc::new(if env_bits.job_control {
+        Host::with_job_control(job.clone(), &env_bits.funcs)
+    } else {
+        Host::new(job.clone(), &env_bits.funcs)
+    }));
+    clear_interrupted();
+    let start = TimeSnapshot::now();
     let status = machine.run();
+    let times = start.elapsed();
 
     let (cwd, env) = machine.top_environment();
     env_bits.cwd = cwd;
     env_bits.env = env;
 
+    if !command.asynchronous {
+        env_bits.last_duration = Some(times.real);
+    }
+    env_bits.last_job = job.process_group_id();
+
+    let threshold = env_bits.env.get("REPORTTIME").and_then(|t| t.to_str());
+    if should_report_time(&times, threshold) {
+        eprint!("{}", times);
+    }
+
     status
 }
 
-#[derive(Default)]
-struct EditHost {
-    history: BasicHistory,
+/// The line editor host is constructed for the duration of each
+/// call to `read_line` so that the shared history is only locked
+/// while the user is editing.
+struct EditHost<'a> {
+    history: MutexGuard<'a, ShellHistory>,
+    /// The shell's current directory, used to resolve completions
+    cwd: &'a Path,
+    /// The environment and functions in which the completion
+    /// functions registered by `complete` are run
+    env: &'a Environment,
+    funcs: &'a Arc<FunctionRegistry>,
+    /// The final line of the prompt
+    prompt: Vec<PromptSegment>,
+    /// The right-side prompt from $RPROMPT
+    right_prompt: Vec<PromptSegment>,
+    terminal_width: usize,
+    /// The colors for filenames from $LS_COLORS
+    ls_colors: Option<LsColors>,
 }
 
-impl LineEditorHost for EditHost {
-    fn render_prompt(&self, prompt: &str) -> Vec<OutputElement> {
-        vec![
-            OutputElement::Attribute(AttributeChange::Foreground(
-                ColorAttribute::TrueColorWithPaletteFallback(
-                    RgbColor::from_named("plum").unwrap(),
-                    AnsiColor::Navy.into(),
-                ),
-            )),
-            OutputElement::Text(prompt.to_owned()),
-        ]
+impl<'a> LineEditorHost for EditHost<'a> {
+    /// This is also called to redraw the prompt after Ctrl-L has
+    /// cleared the screen.  Only the final line of a multi-line
+    /// prompt is redrawn, as the line editor has no knowledge of
+    /// the lines that were written ahead of it.
+    fn render_prompt(&self, _prompt: &str) -> Vec<OutputElement> {
+        let mut elements = vec![OutputElement::Attribute(AttributeChange::Foreground(
+            ColorAttribute::TrueColorWithPaletteFallback(
+                RgbColor::from_named("plum").unwrap(),
+                AnsiColor::Navy.into(),
+            ),
+        ))];
+        elements.extend(prompt_elements(&self.prompt));
+        elements
+    }
+
+    /// The autosuggestion from the history is shown dimmed after the
+    /// line while the cursor is at its end.  Like the right prompt, it
+    /// follows the line, so the cursor position is unaffected by it.
+    fn highlight_line(&self, line: &str, cursor_position: usize) -> Vec<OutputElement> {
+        let mut elements =
+            highlight_filename(line, cursor_position, self.cwd, self.ls_colors.as_ref());
+        let suggestion = self.suggestion(line, cursor_position).unwrap_or("");
+        if !suggestion.is_empty() {
+            elements.push(OutputElement::Attribute(AttributeChange::Intensity(
+                Intensity::Half,
+            )));
+            elements.push(OutputElement::Text(suggestion.to_owned()));
+            elements.push(OutputElement::AllAttributes(CellAttributes::default()));
+        }
+        if self.right_prompt.is_empty() {
+            return elements;
+        }
+        // The right prompt is emitted after the line; the cursor
+        // position is computed independently of this text
+        let prompt_width = visible_width(&self.prompt);
+        let line_width = display_width(
+            &format!("{}{}", line, suggestion),
+            prompt_width,
+            self.tab_width(),
+            self.terminal_width,
+        );
+        if let Some(padding) = right_prompt_padding(
+            self.terminal_width,
+            prompt_width,
+            line_width,
+            visible_width(&self.right_prompt),
+        ) {
+            elements.push(OutputElement::Text(" ".repeat(padding)));
+            elements.extend(prompt_elements(&self.right_prompt));
+        }
+        elements
     }
 
-    fn history(&mut self) -> &mut History {
-        &mut self.history
+    fn history(&mut self) -> &mut dyn History {
+        &mut *self.history
+    }
+
+    /// The targets of redirections are always completed as filenames,
+    /// even when they are written without a space after the operator.
+    /// The value of an assignment such as `FOO=ba` is completed as a
+    /// filename, or as a variable name after a `$`.
+    /// Other arguments are completed as registered by `complete`,
+    /// falling back to filenames.  When there is nothing to complete,
+    /// the autosuggestion is accepted instead; the line editor's key
+    /// bindings are fixed, so it can't be bound to right-arrow or End.
+    fn complete(&self, line: &str, cursor_position: usize) -> Vec<CompletionCandidate> {
+        let candidates = if let Some(start) = redirect_target_start(line, cursor_position) {
+            complete_filename_from(line, start, cursor_position, self.cwd, self.home())
+        } else if let Some(value) = assignment_value_context(line, cursor_position) {
+            match value {
+                AssignmentValue::Filename(start) => {
+                    complete_filename_from(line, start, cursor_position, self.cwd, self.home())
+                }
+                AssignmentValue::Variable(start) => {
+                    complete_variable(line, start, cursor_position, self.env)
+                }
+            }
+        } else if let Some(candidates) =
+            complete_registered(line, cursor_position, self.cwd, self.env, self.funcs)
+        {
+            candidates.unwrap_or_else(|err| {
+                eprintln!("\r\nwzsh: completion: {}", err);
+                vec![]
+            })
+        } else {
+            complete_filename(line, cursor_position, self.cwd, self.home())
+        };
+        if !candidates.is_empty() {
+            return candidates;
+        }
+        match self.suggestion(line, cursor_position) {
+            Some(suggestion) => vec![CompletionCandidate {
+                range: cursor_position..cursor_position,
+                text: suggestion.to_owned(),
+            }],
+            None => vec![],
+        }
     }
 }
 
-pub fn repl(cwd: PathBuf, env: Environment, funcs: &Arc<FunctionRegistry>) -> Fallible<()> {
+impl<'a> EditHost<'a> {
+    /// The home directory against which `~/` is completed
+    fn home(&self) -> Option<&Path> {
+        self.env.get("HOME").map(Path::new)
+    }
+
+    /// The distance between tab stops, from $TABSIZE
+    fn tab_width(&self) -> usize {
+        self.env
+            .get_str("TABSIZE")
+            .ok()
+            .and_then(|size| size?.parse().ok())
+            .filter(|&size| size > 0)
+            .unwrap_or(DEFAULT_TAB_WIDTH)
+    }
+
+    /// Returns the autosuggestion for line, which is only offered
+    /// while the cursor is at the end of the line
+    fn suggestion(&self, line: &str, cursor_position: usize) -> Option<&str> {
+        if cursor_position != line.len() {
+            return None;
+        }
+        self.history.suggest(line)
+    }
+}
+
+/// The steps taken when the shell exits.  These are expressed as
+/// a trait so that tests can observe the order in which they run.
+trait Teardown {
+    fn run_exit_trap(&mut self, env: &mut EnvBits);
+    fn hangup_jobs(&mut self);
+    fn save_history(&mut self, env: &EnvBits) -> Fallible<()>;
+}
+
+/// Tears down the interactive shell
+struct ShellTeardown;
+
+impl Teardown for ShellTeardown {
+    fn run_exit_trap(&mut self, env: &mut EnvBits) {
+        run_exit_trap(&TRAPS, &mut env.cwd, &mut env.env, &env.funcs);
+    }
+
+    fn hangup_jobs(&mut self) {
+        JOB_LIST.hangup_all();
+    }
+
+    fn save_history(&mut self, env: &EnvBits) -> Fallible<()> {
+        let mut history = HISTORY.lock().unwrap();
+        // Add our remaining commands to the shared history rather than
+        // replacing the commands that other shells have added to it
+        if env.env.options().histappend {
+            return history.sync(&history_file(&env.env));
+        }
+        // Avoid truncating the file if we failed before loading it
+        if history.entries().is_empty() {
+            return Ok(());
+        }
+        history.save(&history_file(&env.env))
+    }
+}
+
+/// Run each of the exit steps, in order.  This is the single place
+/// where the shell is torn down, regardless of why the repl ended,
+/// so that the EXIT trap runs before jobs are hung up and the history
+/// is saved last.
+fn teardown(env: &mut EnvBits, steps: &mut impl Teardown) {
+    steps.run_exit_trap(env);
+    steps.hangup_jobs();
+    if let Err(err) = steps.save_history(env) {
+        print_error(&err, "");
+    }
+}
+
+/// Returns the path of the rc file that is sourced by interactive
+/// shells; this is `$WZSH_RCFILE` if set, otherwise `~/.wzshrc`.
+pub fn rc_file(env: &Environment) -> PathBuf {
+    match env.get("WZSH_RCFILE") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => dirs::home_dir()
+            .expect("can't find HOME dir")
+            .join(".wzshrc"),
+    }
+}
+
+/// Returns true if the shell should act as a login shell; this is
+/// the case when `--login` was specified, or when the shell was
+/// invoked with a name starting with `-`, as done by login(1).
+pub fn is_login_shell(argv0: &str, login_flag: bool) -> bool {
+    login_flag || argv0.starts_with('-')
+}
+
+/// Increment `$SHLVL`, which counts the shells nested within one
+/// another, and export it so that child shells see our level.
+/// Values that are missing, not numeric or negative are treated as 0,
+/// while implausibly deep nesting is reset to 1.
+pub fn increment_shlvl(env: &mut Environment) {
+    let level = env
+        .get("SHLVL")
+        .and_then(|level| level.to_str())
+        .and_then(|level| level.trim().parse::<i64>().ok())
+        .unwrap_or(0)
+        .max(0)
+        + 1;
+    let level = if level >= 1000 { 1 } else { level };
+    env.set("SHLVL", level.to_string());
+    env.export("SHLVL");
+}
+
+/// Returns the files to source at interactive startup, in order.
+/// Login shells source the system and user profiles before the
+/// rc file, which is omitted when `norc` is true.
+pub fn startup_files(env: &Environment, login: bool, norc: bool) -> Vec<PathBuf> {
+    let mut files = vec![];
+    if login {
+        files.push(PathBuf::from("/etc/profile"));
+        files.push(
+            dirs::home_dir()
+                .expect("can't find HOME dir")
+                .join(".wzsh_profile"),
+        );
+    }
+    if !norc {
+        files.push(rc_file(env));
+    }
+    files
+}
+
+/// Run the script at `path`, if it exists, in the context of the
+/// shell.  Errors are reported but do not prevent the shell from
+/// starting.
+fn source_startup_file(path: &Path, env: &mut EnvBits) {
+    if !path.exists() {
+        return;
+    }
+    if let Err(err) =
+        script::compile_and_run_script_file(path, &mut env.cwd, &mut env.env, &env.funcs)
+    {
+        print_error_path(&err, path);
+        eprintln!("wzsh: ignoring error while sourcing {}.", path.display());
+    }
+}
+
+/// Whether the shell reads its commands from a user at a terminal,
+/// or from a pipe or file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputMode {
+    /// Commands are read with the line editor, with prompts,
+    /// history and job control
+    Interactive,
+    /// Commands are read a line at a time without prompting
+    NonInteractive,
+}
+
+impl InputMode {
+    /// Select the mode according to whether stdin is a terminal,
+    /// as reported by the supplied predicate
+    fn detect<F: FnOnce() -> bool>(stdin_is_terminal: F) -> Self {
+        if stdin_is_terminal() {
+            InputMode::Interactive
+        } else {
+            InputMode::NonInteractive
+        }
+    }
+}
+
+#[cfg(unix)]
+fn stdin_is_terminal() -> bool {
+    unsafe { libc::isatty(0) == 1 }
+}
+
+#[cfg(windows)]
+fn stdin_is_terminal() -> bool {
+    true
+}
+
+pub fn repl(
+    cwd: PathBuf,
+    env: Environment,
+    funcs: &Arc<FunctionRegistry>,
+    startup_files: Vec<PathBuf>,
+) -> Fallible<()> {
+    let mode = InputMode::detect(stdin_is_terminal);
     let mut env = EnvBits {
         cwd,
         env,
         funcs: Arc::clone(funcs),
+        last_duration: None,
+        last_job: 0,
+        job_control: mode == InputMode::Interactive,
     };
 
+    match mode {
+        InputMode::Interactive => {
+            let result = run_repl(&mut env, &startup_files);
+            teardown(&mut env, &mut ShellTeardown);
+            result
+        }
+        InputMode::NonInteractive => {
+            // Read a byte at a time so that the commands that are
+            // run can read the input that follows them
+            let stdin = std::io::stdin();
+            let result = run_non_interactive(&mut env, BufReader::with_capacity(1, stdin.lock()));
+            run_exit_trap(&TRAPS, &mut env.cwd, &mut env.env, &env.funcs);
+            result
+        }
+    }
+}
+
+/// Read and run commands from input without prompting, as when
+/// commands are piped into the shell.  As in the interactive repl,
+/// incomplete input, such as an unterminated quoted string, is
+/// continued on the following line.
+fn run_non_interactive<R: BufRead>(env: &mut EnvBits, mut input_reader: R) -> Fallible<()> {
+    let mut input = String::new();
+    loop {
+        run_pending_traps(&TRAPS, &mut env.cwd, &mut env.env, &env.funcs);
+
+        let mut line = String::new();
+        if input_reader.read_line(&mut line)? == 0 {
+            if !input.is_empty() {
+                if let Err(e) = compile_and_run(&input, env) {
+                    print_error(&e, &input);
+                }
+            }
+            break;
+        }
+
+        if let Some(status) = run_input_line(&mut input, line.trim_end_matches('\n'), env) {
+            run_err_trap(&TRAPS, &status, &mut env.cwd, &mut env.env, &env.funcs);
+        }
+    }
+    Ok(())
+}
+
+/// Append line to the pending input, then compile and run the input
+/// if it forms a complete command.  Returns None if the input was
+/// incomplete, in which case it is retained so that it can be
+/// continued on the next line, or if it had an error, which is
+/// reported.  Otherwise the pending input is cleared.
+fn run_input_line(input: &mut String, line: &str, env: &mut EnvBits) -> Option<Status> {
+    input.push_str(line);
+    match compile_and_run(input, env) {
+        Err(e) => {
+            if is_incomplete_input(&e) {
+                input.push('\n');
+            } else {
+                print_error(&e, input);
+                input.clear();
+            }
+            None
+        }
+        Ok(status) => {
+            input.clear();
+            Some(status)
+        }
+    }
+}
+
+fn run_repl(env: &mut EnvBits, startup_files: &[PathBuf]) -> Fallible<()> {
     #[cfg(unix)]
-    init_job_control()?;
+    {
+        init_job_control()?;
+        install_sigchld_handler()?;
+        install_sigint_handler()?;
+    }
+
+    for path in startup_files {
+        source_startup_file(path, env);
+    }
 
-    let mut editor = line_editor()?;
-    let mut host = EditHost::default();
+    // The startup files may have changed $HISTFILE
+    if let Err(err) = HISTORY.lock().unwrap().load(&history_file(&env.env)) {
+        print_error(&err, "");
+    }
+
+    if let Err(err) = apply_edit_mode(&mut env.env) {
+        eprintln!("wzsh: {}", err);
+    }
+    let edit_mode = Rc::new(Cell::new(EditMode::from_options(env.env.options())));
+    let mut reader = line_reader(Rc::clone(&edit_mode));
 
     let mut input = String::new();
 
     loop {
-        let prompt = match input.is_empty() {
-            true => "$ ",
-            false => "..> ",
+        run_pending_traps(&TRAPS, &mut env.cwd, &mut env.env, &env.funcs);
+
+        if env.env.options().histappend {
+            let path = history_file(&env.env);
+            if let Err(err) = HISTORY.lock().unwrap().sync(&path) {
+                print_error(&err, "");
+            }
+        }
+
+        let (prompt_var, default_prompt) = match input.is_empty() {
+            true => ("PS1", "$ "),
+            false => ("PS2", "..> "),
+        };
+        let prompt = env
+            .env
+            .get(prompt_var)
+            .and_then(|prompt| prompt.to_str())
+            .unwrap_or(default_prompt);
+        // The line editor only deals with a single line of prompt,
+        // so any preceding lines are printed directly
+        let context = PromptContext {
+            last_duration: env.last_duration,
+        };
+        let (leading_prompt, prompt) = split_last_line(parse_prompt(prompt, &context));
+        let right_prompt = match input.is_empty() {
+            true => env
+                .env
+                .get("RPROMPT")
+                .and_then(|prompt| prompt.to_str())
+                .map(|prompt| parse_prompt(prompt, &context))
+                .unwrap_or_default(),
+            false => vec![],
         };
-        editor.set_prompt(prompt);
 
+        // Poll every job; this is the fallback for systems without
+        // SIGCHLD, so the changed flag is moot at this point.
+        take_jobs_changed();
         JOB_LIST.check_and_print_status();
 
-        match editor.read_line(&mut host) {
+        if !leading_prompt.is_empty() {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(leading_prompt.as_bytes())?;
+            stdout.flush()?;
+        }
+
+        // SIGCHLD and trapped signals would otherwise interrupt the
+        // line editor; trapped signals are handled once it returns
+        #[cfg(unix)]
+        {
+            block_sigchld(true);
+            block_trapped_signals(&TRAPS.lock().unwrap(), true);
+        }
+        // `set -o vi` and `set -o emacs` take effect at the next line
+        edit_mode.set(EditMode::from_options(env.env.options()));
+        let line = match &mut reader {
+            LineReader::Editor(editor) => editor.read_line(&mut EditHost {
+                history: HISTORY.lock().unwrap(),
+                cwd: &env.cwd,
+                env: &env.env,
+                funcs: &env.funcs,
+                prompt,
+                right_prompt,
+                terminal_width: terminal_width(),
+                ls_colors: LsColors::from_env(&env.env),
+            }),
+            LineReader::Plain(plain) => plain.read_line(&prompt_text(&prompt)),
+        };
+        #[cfg(unix)]
+        {
+            block_sigchld(false);
+            block_trapped_signals(&TRAPS.lock().unwrap(), false);
+        }
+
+        // With `set -o notify`, promptly report any background jobs
+        // that finished while we were waiting for input
+        if should_report_jobs(env.env.options().notify, false, take_jobs_changed()) {
+            JOB_LIST.check_and_print_status();
+        }
+
+        match line {
             Ok(Some(line)) => {
-                host.history().add(&line);
-
-                input.push_str(&line);
-
-                let _status = match compile_and_run(&input, &mut env) {
-                    Err(e) => {
-                        if !is_recoverable_parse_error(&e) {
-                            print_error(&e, &input);
-                            input.clear();
-                        } else {
-                            input.push('\n');
-                        }
-                        continue;
-                    }
-                    Ok(command) => {
-                        input.clear();
-                        command
-                    }
+                HISTORY
+                    .lock()
+                    .unwrap()
+                    .add_history_entry(&line, HistoryControl::from_env(&env.env));
+
+                let status = match run_input_line(&mut input, &line, env) {
+                    Some(status) => status,
+                    None => continue,
                 };
 
                 put_shell_in_foreground();
+                JOB_LIST.remove_completed(env.last_job);
+                if should_report_jobs(env.env.options().notify, false, take_jobs_changed()) {
+                    JOB_LIST.check_and_print_status();
+                }
+
+                run_err_trap(&TRAPS, &status, &mut env.cwd, &mut env.env, &env.funcs);
             }
             Ok(None) => {
                 input.clear();
@@ -191,3 +725,206 @@ pub fn repl(cwd: PathBuf, env: Environment, funcs: &Arc<FunctionRegistry>) -> Fa
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prompt::format_elapsed;
+
+    fn test_env_bits() -> EnvBits {
+        EnvBits {
+            cwd: std::env::current_dir().unwrap(),
+            env: Environment::new_empty(),
+            funcs: Arc::new(FunctionRegistry::new()),
+            last_duration: None,
+            last_job: 0,
+            job_control: false,
+        }
+    }
+
+    #[test]
+    fn input_mode() {
+        assert_eq!(InputMode::detect(|| true), InputMode::Interactive);
+        assert_eq!(InputMode::detect(|| false), InputMode::NonInteractive);
+    }
+
+    #[test]
+    fn non_interactive() -> Fallible<()> {
+        let mut env = test_env_bits();
+        run_non_interactive(&mut env, &b"x=1\ny='a\nb'\nz=$x"[..])?;
+        assert_eq!(env.env.get_str("x")?, Some("1"));
+        assert_eq!(env.env.get_str("y")?, Some("a\nb"));
+        assert_eq!(env.env.get_str("z")?, Some("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn recoverable_errors() {
+        let parse = |text: &str| Parser::new("test", text.as_bytes()).parse().unwrap_err();
+        assert!(is_incomplete_input(&parse("true &&\n")));
+        assert!(is_incomplete_input(&parse("false ||")));
+        assert!(is_incomplete_input(&parse("if true; then\n")));
+        assert!(is_incomplete_input(&parse("while true; do echo | ")));
+        assert!(is_incomplete_input(&parse("echo 'a")));
+        assert!(is_incomplete_input(&parse("echo $'a\\'")));
+        assert!(!is_incomplete_input(&parse("&& true")));
+        assert!(!is_incomplete_input(&parse("true && ;")));
+
+        let mut env = test_env_bits();
+        let mut input = String::new();
+        assert!(run_input_line(&mut input, "x=1 &&", &mut env).is_none());
+        assert_eq!(input, "x=1 &&\n");
+        assert!(run_input_line(&mut input, "y=2", &mut env).is_some());
+        assert_eq!(env.env.get_str("y").unwrap(), Some("2"));
+    }
+
+    #[test]
+    fn plain_reader_continuation() -> Fallible<()> {
+        let mut env = test_env_bits();
+        let mut reader = PlainLineReader {
+            reader: &b"x='a\nb'\ny=$x\n"[..],
+            output: vec![],
+        };
+        let mut input = String::new();
+        for _ in 0..3 {
+            let prompt = if input.is_empty() { "$ " } else { "..> " };
+            let line = reader.read_line(prompt)?.unwrap();
+            run_input_line(&mut input, &line, &mut env);
+        }
+        assert!(input.is_empty());
+        assert_eq!(String::from_utf8(reader.output.clone())?, "$ ..> $ ");
+        assert_eq!(env.env.get_str("x")?, Some("a\nb"));
+        assert_eq!(env.env.get_str("y")?, Some("a\nb"));
+        assert!(reader.read_line("$ ").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn edit_mode_variable() {
+        let mut env = Environment::new_empty();
+        assert!(apply_edit_mode(&mut env).is_ok());
+        assert_eq!(EditMode::from_options(env.options()), EditMode::Emacs);
+
+        env.set(EDIT_MODE_VARIABLE, "vi");
+        assert!(apply_edit_mode(&mut env).is_ok());
+        assert_eq!(EditMode::from_options(env.options()), EditMode::Vi);
+        env.set(EDIT_MODE_VARIABLE, "emacs");
+        assert!(apply_edit_mode(&mut env).is_ok());
+        assert_eq!(EditMode::from_options(env.options()), EditMode::Emacs);
+
+        env.set(EDIT_MODE_VARIABLE, "bogus");
+        assert!(apply_edit_mode(&mut env).is_err());
+    }
+
+    #[test]
+    fn last_duration() {
+        let mut env_bits = test_env_bits();
+        compile_and_run("true", &mut env_bits).unwrap();
+        let duration = env_bits.last_duration.expect("duration to be recorded");
+        assert!(duration < Duration::from_secs(5));
+
+        let context = PromptContext {
+            last_duration: env_bits.last_duration,
+        };
+        assert_eq!(
+            parse_prompt("\\T", &context),
+            vec![PromptSegment::Text(format_elapsed(duration))]
+        );
+    }
+
+    #[test]
+    fn teardown_order() {
+        struct Recorder(Vec<&'static str>);
+
+        impl Teardown for Recorder {
+            fn run_exit_trap(&mut self, _env: &mut EnvBits) {
+                self.0.push("exit trap");
+            }
+
+            fn hangup_jobs(&mut self) {
+                self.0.push("hangup jobs");
+            }
+
+            fn save_history(&mut self, _env: &EnvBits) -> Fallible<()> {
+                self.0.push("save history");
+                Ok(())
+            }
+        }
+
+        let mut env_bits = test_env_bits();
+        let mut recorder = Recorder(vec![]);
+        teardown(&mut env_bits, &mut recorder);
+        assert_eq!(recorder.0, vec!["exit trap", "hangup jobs", "save history"]);
+    }
+
+    #[test]
+    fn rc_file_sourcing() -> Fallible<()> {
+        let root = tempdir::TempDir::new("wzsh")?;
+        let rc = root.path().join("wzshrc");
+        std::fs::write(&rc, "GREETING=hello\ngreet() { echo $GREETING; }\n")?;
+
+        let mut env_bits = test_env_bits();
+        source_startup_file(&rc, &mut env_bits);
+
+        assert_eq!(
+            env_bits.env.get("GREETING").and_then(|v| v.to_str()),
+            Some("hello")
+        );
+        assert!(env_bits.funcs.lookup_function("greet").is_some());
+
+        // A missing file is silently skipped
+        source_startup_file(&root.path().join("missing"), &mut env_bits);
+
+        let mut env = Environment::new_empty();
+        env.set("WZSH_RCFILE", "/some/rcfile");
+        assert_eq!(rc_file(&env), PathBuf::from("/some/rcfile"));
+        Ok(())
+    }
+
+    #[test]
+    fn login_shell() {
+        assert!(is_login_shell("-wzsh", false));
+        assert!(is_login_shell("wzsh", true));
+        assert!(!is_login_shell("wzsh", false));
+        assert!(!is_login_shell("/usr/bin/wzsh", false));
+
+        let home = dirs::home_dir().unwrap();
+        let mut env = Environment::new_empty();
+        env.set("WZSH_RCFILE", "/some/rcfile");
+        assert_eq!(
+            startup_files(&env, true, false),
+            vec![
+                PathBuf::from("/etc/profile"),
+                home.join(".wzsh_profile"),
+                PathBuf::from("/some/rcfile"),
+            ]
+        );
+        assert_eq!(
+            startup_files(&env, true, true),
+            vec![PathBuf::from("/etc/profile"), home.join(".wzsh_profile")]
+        );
+        assert_eq!(
+            startup_files(&env, false, false),
+            vec![PathBuf::from("/some/rcfile")]
+        );
+        assert!(startup_files(&env, false, true).is_empty());
+    }
+
+    #[test]
+    fn shlvl() {
+        let level = |initial: Option<&str>| {
+            let mut env = Environment::new_empty();
+            if let Some(initial) = initial {
+                env.set("SHLVL", initial);
+            }
+            increment_shlvl(&mut env);
+            assert!(env.is_exported("SHLVL"));
+            env.get("SHLVL").unwrap().to_str().unwrap().to_owned()
+        };
+        assert_eq!(level(Some("2")), "3");
+        assert_eq!(level(None), "1");
+        assert_eq!(level(Some("bogus")), "1");
+        assert_eq!(level(Some("-5")), "1");
+        assert_eq!(level(Some("999")), "1");
+    }
+}
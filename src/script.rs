@@ -1,29 +1,155 @@
 use crate::job::Job;
 use crate::shellhost::{FunctionRegistry, Host};
-use failure::Fallible;
+use failure::{err_msg, Fallible};
+use filedescriptor::{FileDescriptor, Pipe};
 use shell_compiler::Compiler;
 use shell_parser::Parser;
-use shell_vm::{Environment, Machine, Program, Status};
+use shell_vm::{Environment, IoEnvironment, Machine, Program, Status};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+/// Parse and run the script at `path`.
+/// The entire script is parsed before any of it is run, so the first
+/// syntax error is fatal: none of the script is run, and the error,
+/// which names the script and the line on which it occurred, is
+/// returned.  This differs from the interactive repl, which reports
+/// the error and discards only the input in which it occurred.
 pub fn compile_and_run_script_file(
     path: &Path,
     cwd: &mut PathBuf,
     env: &mut Environment,
     funcs: &Arc<FunctionRegistry>,
 ) -> Fallible<Status> {
-    let job = Job::new_empty(path.to_string_lossy().to_string());
     let file = std::fs::File::open(path)?;
-    let mut parser = Parser::new(file);
+    let source = path.to_string_lossy().to_string();
+    compile_and_run(file, &source, source.clone(), cwd, env, funcs)
+}
+
+/// Parse and run `text` in the context of the specified environment,
+/// which is updated with the effects of running it.
+pub fn compile_and_run_string(
+    text: &str,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) -> Fallible<Status> {
+    compile_and_run(text.as_bytes(), "string", text.to_owned(), cwd, env, funcs)
+}
+
+/// As `compile_and_run_string`, but the commands use `io_env` for their
+/// input and output rather than the stdio streams of the shell.  This is
+/// used by builtins such as `eval` whose output may be redirected.
+pub fn compile_and_run_string_with_io(
+    text: &str,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+    io_env: &IoEnvironment,
+) -> Fallible<Status> {
+    compile_and_run_with_io(
+        text.as_bytes(),
+        "string",
+        text.to_owned(),
+        cwd,
+        env,
+        funcs,
+        |io| *io = io_env.clone(),
+    )
+}
+
+/// Parse and run `source` in a copy of `env`, returning its exit status
+/// along with everything that it wrote to stdout and stderr.
+/// `env` is updated with the effects of running it.
+// Only the tests call this until wzsh grows a library target to embed it
+#[allow(dead_code)]
+pub fn run_capture(source: &str, env: &mut Environment) -> Fallible<(Status, String, String)> {
+    let mut cwd = std::env::current_dir()?;
+    let funcs = Arc::new(FunctionRegistry::new());
+    run_capture_in(source, &mut cwd, env, &funcs)
+}
+
+/// As `run_capture`, but `source` runs in `cwd` and may call the
+/// shell functions defined in `funcs`
+pub fn run_capture_in(
+    source: &str,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) -> Fallible<(Status, String, String)> {
+    let Pipe {
+        read: stdout_read,
+        write: stdout_write,
+    } = Pipe::new()?;
+    let Pipe {
+        read: stderr_read,
+        write: stderr_write,
+    } = Pipe::new()?;
+
+    // Drain the pipes concurrently so that a command producing more
+    // output than fits in the pipe buffer cannot block forever
+    let stdout_reader = std::thread::spawn(move || consume_pipe(stdout_read));
+    let stderr_reader = std::thread::spawn(move || consume_pipe(stderr_read));
+
+    let status = compile_and_run_with_io(
+        source.as_bytes(),
+        "string",
+        source.to_owned(),
+        cwd,
+        env,
+        funcs,
+        move |io_env| {
+            io_env.assign_fd(1, stdout_write);
+            io_env.assign_fd(2, stderr_write);
+        },
+    );
+
+    let stdout = stdout_reader
+        .join()
+        .map_err(|_| err_msg("stdout reader panicked"))??;
+    let stderr = stderr_reader
+        .join()
+        .map_err(|_| err_msg("stderr reader panicked"))??;
+    Ok((status?, stdout, stderr))
+}
+
+fn consume_pipe(mut fd: FileDescriptor) -> Fallible<String> {
+    let mut res = String::new();
+    fd.read_to_string(&mut res)?;
+    Ok(res)
+}
+
+fn compile_and_run<R: Read>(
+    stream: R,
+    source: &str,
+    label: String,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) -> Fallible<Status> {
+    compile_and_run_with_io(stream, source, label, cwd, env, funcs, |_| {})
+}
+
+fn compile_and_run_with_io<R: Read, F: FnOnce(&mut IoEnvironment)>(
+    stream: R,
+    source: &str,
+    label: String,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+    setup_io: F,
+) -> Fallible<Status> {
+    let job = Job::new_empty(label);
+    let mut parser = Parser::new(source, stream);
 
     let command = parser.parse()?;
     let mut compiler = Compiler::new();
     compiler.compile_command(&command)?;
     let prog = compiler.finish()?;
 
-    let mut machine = Machine::new(&Program::new(prog), Some(env.clone()), &cwd)?;
+    let mut machine = Machine::new(&Program::new(prog), Some(env.clone()), cwd)?;
     machine.set_host(Arc::new(Host::new(job, funcs)));
+    setup_io(machine.io_env_mut()?);
     let status = machine.run();
 
     let (new_cwd, new_env) = machine.top_environment();
@@ -32,3 +158,116 @@ pub fn compile_and_run_script_file(
 
     status
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errorprint::ErrorKind;
+    use shell_parser::ParseErrorKind;
+
+    #[test]
+    fn syntax_error_is_fatal() -> Fallible<()> {
+        let root = tempdir::TempDir::new("wzsh")?;
+        let path = root.path().join("syntax.sh");
+        std::fs::write(&path, "x=1\n&& echo\n")?;
+
+        let mut cwd = std::env::current_dir()?;
+        let mut env = Environment::new_empty();
+        let funcs = Arc::new(FunctionRegistry::new());
+        let result = compile_and_run_script_file(&path, &mut cwd, &mut env, &funcs);
+
+        let err = result.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Syntax);
+        match err.downcast_ref::<ParseErrorKind>() {
+            Some(ParseErrorKind::UnexpectedToken(token, _, source)) => {
+                assert_eq!(source, &path.to_string_lossy());
+                assert_eq!(token.span().start.line, 1);
+            }
+            None => panic!("expected a parse error, got {}", err),
+        }
+        // The assignment on the line before the error was not run
+        assert_eq!(env.get("x"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn capture() -> Fallible<()> {
+        let mut env = Environment::from_system();
+        let (status, stdout, stderr) = run_capture("echo hi", &mut env)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "hi\n");
+        assert_eq!(stderr, "");
+        Ok(())
+    }
+
+    #[test]
+    fn ifs_changes_between_commands() -> Fallible<()> {
+        let mut env = Environment::new_empty();
+        let (status, stdout, stderr) = run_capture(
+            "v='a:b c'\n\
+             printf '<%s>' $v; printf '\\n'\n\
+             IFS=:\n\
+             printf '<%s>' $v; printf '\\n'\n\
+             IFS=\n\
+             printf '<%s>' $v; printf '\\n'\n\
+             unset IFS\n\
+             printf '<%s>' $v; printf '\\n'",
+            &mut env,
+        )?;
+        assert_eq!(stderr, "");
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "<a:b><c>\n<a><b c>\n<a:b c>\n<a:b><c>\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn child_inherits_redirected_descriptors() -> Fallible<()> {
+        let mut env = Environment::from_system();
+        let (status, stdout, stderr) = run_capture(
+            "command sh -c 'echo three >&3; echo err >&2' 3>&1 2>&3",
+            &mut env,
+        )?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "three\nerr\n");
+        assert_eq!(stderr, "");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn redirect_order() -> Fallible<()> {
+        let root = tempdir::TempDir::new("wzsh")?;
+        let path = root.path().join("redirect");
+        let script = "command sh -c 'echo out; echo err >&2'";
+
+        // stderr goes to the original stdout, then stdout to the file
+        let mut env = Environment::from_system();
+        let (status, stdout, stderr) =
+            run_capture(&format!("{} 2>&1 >'{}'", script, path.display()), &mut env)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "err\n");
+        assert_eq!(stderr, "");
+        assert_eq!(std::fs::read_to_string(&path)?, "out\n");
+
+        // stdout goes to the file, then stderr to the same place
+        let (status, stdout, stderr) =
+            run_capture(&format!("{} >'{}' 2>&1", script, path.display()), &mut env)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        assert_eq!(stdout, "");
+        assert_eq!(stderr, "");
+        assert_eq!(std::fs::read_to_string(&path)?, "out\nerr\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn background_pid() -> Fallible<()> {
+        let mut env = Environment::from_system();
+        let (status, stdout, _) = run_capture("echo \"[$!]\"\ncommand true &\necho $!", &mut env)?;
+        assert_eq!(status, Status::Complete(0.into()));
+        let pid = env.background_pid().expect("background pid was recorded");
+        assert_eq!(stdout, format!("[]\n{}\n", pid));
+        Ok(())
+    }
+}
@@ -1,10 +1,12 @@
-use crate::builtins::lookup_builtin;
+use crate::builtins::{is_special_builtin, lookup_builtin, BuiltinFunc};
 use crate::exitstatus::ChildProcess;
 #[cfg(unix)]
 use crate::job::{add_to_process_group, make_foreground_process_group};
 use crate::job::{Job, JOB_LIST};
+use crate::trap::{run_debug_trap, TRAPS};
 use cancel::Token;
 use failure::{bail, err_msg, format_err, Fallible, ResultExt};
+use lazy_static::lazy_static;
 use pathsearch::PathSearcher;
 use shell_vm::{
     Environment, IoEnvironment, Machine, Program, ShellHost, Status, Value, WaitableStatus,
@@ -15,6 +17,90 @@ use std::io::Write;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+lazy_static! {
+    static ref HOME_DIRS: HomeDirCache = HomeDirCache::default();
+}
+
+/// Remembers the home directory of each user so that repeated
+/// `~user` expansions don't need to consult the password database.
+/// The entries are retained for the lifetime of the shell session.
+/// The `None` key holds the home directory of the current user.
+#[derive(Debug, Default)]
+struct HomeDirCache {
+    dirs: Mutex<HashMap<Option<String>, OsString>>,
+}
+
+impl HomeDirCache {
+    fn lookup<F>(&self, user: Option<&str>, lookup: F) -> Fallible<OsString>
+    where
+        F: FnOnce(Option<&str>) -> Fallible<OsString>,
+    {
+        let key = user.map(str::to_owned);
+        if let Some(dir) = self.dirs.lock().unwrap().get(&key) {
+            return Ok(dir.clone());
+        }
+
+        let dir = lookup(user)?;
+        self.dirs.lock().unwrap().insert(key, dir.clone());
+        Ok(dir)
+    }
+}
+
+fn lookup_homedir_uncached(user: Option<&str>) -> Fallible<OsString> {
+    match user {
+        None => dirs::home_dir()
+            .map(PathBuf::into_os_string)
+            .ok_or_else(|| err_msg("unable to determine the home directory")),
+        Some(user) => lookup_user_homedir(user),
+    }
+}
+
+#[cfg(unix)]
+fn lookup_user_homedir(user: &str) -> Fallible<OsString> {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = CString::new(user)?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    loop {
+        let rc = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match rc {
+            0 => break,
+            libc::ERANGE => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            _ => bail!(
+                "looking up user {}: {}",
+                user,
+                std::io::Error::from_raw_os_error(rc)
+            ),
+        }
+    }
+
+    if result.is_null() {
+        bail!("no such user {}", user);
+    }
+
+    let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+    Ok(OsStr::from_bytes(dir.to_bytes()).to_os_string())
+}
+
+#[cfg(windows)]
+fn lookup_user_homedir(user: &str) -> Fallible<OsString> {
+    bail!("unable to look up the home directory of {}", user);
+}
+
 #[derive(Debug)]
 pub struct FunctionRegistry {
     functions: Mutex<HashMap<String, Arc<Program>>>,
@@ -38,6 +124,76 @@ impl FunctionRegistry {
     }
 }
 
+/// Restricts the kinds of command that `resolve_command` considers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandSearch {
+    /// Builtins, functions and the PATH
+    All,
+    /// Only executables in the PATH, as for `command NAME`
+    PathOnly,
+    /// Only builtins, as for `builtin NAME`
+    BuiltinsOnly,
+}
+
+/// What a command name resolves to
+pub enum CommandResolution {
+    /// A special builtin, which takes precedence over functions
+    SpecialBuiltin(BuiltinFunc),
+    Function(Arc<Program>),
+    /// A regular builtin, which a function of the same name overrides
+    Builtin(BuiltinFunc),
+    /// An executable found by searching the PATH
+    External(PathBuf),
+    NotFound,
+}
+
+/// Resolve the command `name`, using the POSIX search order: special
+/// builtins, then shell functions, then regular builtins and finally
+/// executables in the PATH.
+pub fn resolve_command(
+    name: &Value,
+    search: CommandSearch,
+    environment: &Environment,
+    functions: &FunctionRegistry,
+) -> Fallible<CommandResolution> {
+    let search_builtin = search != CommandSearch::PathOnly;
+    let search_path = search != CommandSearch::BuiltinsOnly;
+
+    if let Some(s) = name.as_str() {
+        if search_builtin && is_special_builtin(s) {
+            if let Some(builtin) = lookup_builtin(name) {
+                return Ok(CommandResolution::SpecialBuiltin(builtin));
+            }
+        }
+        if search == CommandSearch::All {
+            if let Some(prog) = functions.lookup_function(s) {
+                return Ok(CommandResolution::Function(prog));
+            }
+        }
+    }
+
+    if search_builtin {
+        if let Some(builtin) = lookup_builtin(name) {
+            return Ok(CommandResolution::Builtin(builtin));
+        }
+    }
+
+    if search_path {
+        if let Some(exe) = PathSearcher::new(
+            name.as_os_str()
+                .ok_or_else(|| err_msg("argv0 is not convertible to OsStr"))?,
+            environment.get("PATH"),
+            environment.get("PATHEXT"),
+        )
+        .next()
+        {
+            return Ok(CommandResolution::External(exe));
+        }
+    }
+
+    Ok(CommandResolution::NotFound)
+}
+
 #[derive(Debug)]
 pub struct Host {
     job: Mutex<Job>,
@@ -64,13 +220,21 @@ impl Host {
 }
 
 impl ShellHost for Host {
-    fn lookup_homedir(&self, _user: Option<&str>) -> Fallible<OsString> {
-        bail!("lookup_homedir not implemented");
+    fn lookup_homedir(&self, user: Option<&str>) -> Fallible<OsString> {
+        HOME_DIRS.lookup(user, lookup_homedir_uncached)
+    }
+
+    fn interrupted(&self) -> bool {
+        crate::job::interrupted()
+    }
+
+    fn job_control_enabled(&self) -> bool {
+        self.job_control_enabled
     }
 
     fn spawn_command(
         &self,
-        argv: &Vec<Value>,
+        argv: &[Value],
         environment: &mut Environment,
         current_directory: &mut PathBuf,
         io_env: &IoEnvironment,
@@ -79,37 +243,42 @@ impl ShellHost for Host {
             return Ok(Status::Complete(0.into()).into());
         }
 
-        let (search_builtin, search_path, argv) = if argv[0].as_str() == Some("command") {
-            (false, true, &argv[1..])
+        run_debug_trap(&TRAPS, argv, current_directory, environment, &self.funcs);
+
+        let (search, argv) = if argv[0].as_str() == Some("command") {
+            (CommandSearch::PathOnly, &argv[1..])
         } else if argv[0].as_str() == Some("builtin") {
-            (true, false, &argv[1..])
+            (CommandSearch::BuiltinsOnly, &argv[1..])
         } else {
-            (true, true, &argv[..])
+            (CommandSearch::All, argv)
         };
 
-        if let Some(name) = argv[0].as_str() {
-            if let Some(prog) = self.funcs.lookup_function(name) {
+        match resolve_command(&argv[0], search, environment, &self.funcs)? {
+            CommandResolution::Function(prog) => {
+                let name = argv[0].as_str().unwrap_or_default();
                 // Execute the function.
                 // This is blocking and not subjectable to job control.
                 let job = Job::new_empty(name.to_string());
-                let mut machine =
-                    Machine::new(&prog, Some(environment.clone()), &current_directory)?;
+                let mut func_env = environment.clone();
+                func_env.push_scope();
+                let mut machine = Machine::new(&prog, Some(func_env), current_directory)?;
                 machine.set_host(Arc::new(Host::new(job, &self.funcs)));
+                // The function's output goes wherever the caller's does
+                *machine.io_env_mut()? = io_env.clone();
 
                 machine.set_positional(argv.to_vec());
 
                 let status = machine.run();
 
-                let (new_cwd, new_env) = machine.top_environment();
+                let (new_cwd, mut new_env) = machine.top_environment();
+                // Restore any variables that the function declared local
+                new_env.pop_scope();
                 *current_directory = new_cwd;
                 *environment = new_env;
 
                 return status.map(Into::into);
             }
-        }
-
-        if search_builtin {
-            if let Some(builtin) = lookup_builtin(&argv[0]) {
+            CommandResolution::SpecialBuiltin(builtin) | CommandResolution::Builtin(builtin) => {
                 // Create a token for cancellation.
                 // This is currently useless; I just wanted to establish this in
                 // the builtins interface.
@@ -117,7 +286,7 @@ impl ShellHost for Host {
                 // signal handler.
                 let token = Arc::new(Token::new());
                 return builtin(
-                    &argv[..],
+                    argv,
                     environment,
                     current_directory,
                     io_env,
@@ -125,18 +294,7 @@ impl ShellHost for Host {
                     &self.funcs,
                 );
             }
-        }
-
-        if search_path {
-            if let Some(exe) = PathSearcher::new(
-                argv[0]
-                    .as_os_str()
-                    .ok_or_else(|| err_msg("argv0 is not convertible to OsStr"))?,
-                environment.get("PATH"),
-                environment.get("PATHEXT"),
-            )
-            .next()
-            {
+            CommandResolution::External(exe) => {
                 let mut child_cmd = std::process::Command::new(&exe);
                 for (i, arg) in argv.iter().enumerate().skip(1) {
                     child_cmd.arg(
@@ -145,7 +303,7 @@ impl ShellHost for Host {
                     );
                 }
                 child_cmd.env_clear();
-                child_cmd.envs(environment.iter());
+                child_cmd.envs(environment.iter_exported());
                 child_cmd.current_dir(&current_directory);
 
                 child_cmd.stdin(io_env.fd_as_stdio(0)?);
@@ -179,6 +337,9 @@ impl ShellHost for Host {
                             libc::SIGTTIN,
                             libc::SIGTTOU,
                             libc::SIGCHLD,
+                            // The Rust runtime ignores SIGPIPE, but children
+                            // writing to a closed pipe should be terminated
+                            libc::SIGPIPE,
                         ] {
                             libc::signal(*s, libc::SIG_DFL);
                         }
@@ -187,6 +348,11 @@ impl ShellHost for Host {
                     });
                 }
 
+                // Also pass any redirected descriptors other than stdio.
+                // On unix this is applied in the child after the job
+                // control setup above.
+                io_env.fd_table()?.apply(&mut child_cmd)?;
+
                 let child = child_cmd.spawn().context(format!("spawning {:?}", argv))?;
                 let child = ChildProcess::new(child);
 
@@ -220,6 +386,7 @@ impl ShellHost for Host {
 
                 return Ok(WaitableStatus::new(Arc::new(child)));
             }
+            CommandResolution::NotFound => {}
         }
 
         if let Some(s) = argv[0].as_str() {
@@ -235,3 +402,73 @@ impl ShellHost for Host {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    fn resolve(name: &str, search: CommandSearch, funcs: &FunctionRegistry) -> &'static str {
+        let env = Environment::from_system();
+        match resolve_command(&name.into(), search, &env, funcs).unwrap() {
+            CommandResolution::SpecialBuiltin(_) => "special",
+            CommandResolution::Function(_) => "function",
+            CommandResolution::Builtin(_) => "builtin",
+            CommandResolution::External(_) => "external",
+            CommandResolution::NotFound => "not found",
+        }
+    }
+
+    #[test]
+    fn resolve_precedence() {
+        let funcs = FunctionRegistry::new();
+        assert_eq!(resolve("sh", CommandSearch::All, &funcs), "external");
+        assert_eq!(resolve("pwd", CommandSearch::All, &funcs), "builtin");
+        assert_eq!(resolve("set", CommandSearch::All, &funcs), "special");
+        assert_eq!(
+            resolve("no-such-command", CommandSearch::All, &funcs),
+            "not found"
+        );
+
+        let prog = Program::new(vec![]);
+        for name in &["sh", "pwd", "set"] {
+            funcs.define_function(name, &prog);
+        }
+        // A function overrides externals and regular builtins...
+        assert_eq!(resolve("sh", CommandSearch::All, &funcs), "function");
+        assert_eq!(resolve("pwd", CommandSearch::All, &funcs), "function");
+        // ...but not special builtins
+        assert_eq!(resolve("set", CommandSearch::All, &funcs), "special");
+
+        // `command` and `builtin` bypass functions
+        assert_eq!(resolve("sh", CommandSearch::PathOnly, &funcs), "external");
+        assert_eq!(
+            resolve("pwd", CommandSearch::BuiltinsOnly, &funcs),
+            "builtin"
+        );
+        assert_eq!(
+            resolve("sh", CommandSearch::BuiltinsOnly, &funcs),
+            "not found"
+        );
+    }
+
+    #[test]
+    fn homedir_cache() {
+        let cache = HomeDirCache::default();
+        let calls = Cell::new(0);
+        let lookup = |user: Option<&str>| -> Fallible<OsString> {
+            calls.set(calls.get() + 1);
+            Ok(format!("/home/{}", user.unwrap_or("me")).into())
+        };
+
+        for _ in 0..3 {
+            assert_eq!(cache.lookup(Some("wez"), lookup).unwrap(), "/home/wez");
+        }
+        assert_eq!(calls.get(), 1);
+
+        for _ in 0..3 {
+            assert_eq!(cache.lookup(None, lookup).unwrap(), "/home/me");
+        }
+        assert_eq!(calls.get(), 2);
+    }
+}
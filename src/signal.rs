@@ -0,0 +1,113 @@
+//! Conversion between signal names and numbers, as used by the
+//! `trap` and `kill` builtins and when reporting the status of jobs.
+
+/// The signals known to the shell, in numeric order.
+/// The names are the canonical ones, without the `SIG` prefix.
+#[cfg(unix)]
+pub const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+#[cfg(windows)]
+pub const SIGNALS: &[(&str, i32)] = &[];
+
+/// Alternative names that some systems and scripts use for signals,
+/// mapped to the canonical name
+const ALIASES: &[(&str, &str)] = &[("IOT", "ABRT"), ("CLD", "CHLD"), ("POLL", "IO")];
+
+/// Look up a signal by name.  The `SIG` prefix is optional and the
+/// name is case insensitive, so `TERM`, `SIGTERM` and `sigterm` are
+/// equivalent.  Returns None if there is no such signal.
+pub fn signal_from_name(name: &str) -> Option<i32> {
+    let upper = name.to_ascii_uppercase();
+    let upper = upper.strip_prefix("SIG").unwrap_or(&upper);
+    let canonical = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == upper)
+        .map_or(upper, |(_, canonical)| *canonical);
+    SIGNALS
+        .iter()
+        .find(|(signame, _)| *signame == canonical)
+        .map(|(_, signo)| *signo)
+}
+
+/// Returns the canonical name of the signal numbered `signo`,
+/// without the `SIG` prefix, or None if it is not a known signal.
+pub fn signal_name(signo: i32) -> Option<&'static str> {
+    SIGNALS
+        .iter()
+        .find(|(_, num)| *num == signo)
+        .map(|(signame, _)| *signame)
+}
+
+/// Format the signals as listed by `trap -l` and `kill -l`
+pub fn list_signals() -> String {
+    SIGNALS
+        .iter()
+        .map(|(name, signo)| format!("{:>2}) SIG{}\n", signo, name))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn round_trip() {
+        for &signo in &[
+            libc::SIGHUP,
+            libc::SIGINT,
+            libc::SIGKILL,
+            libc::SIGTERM,
+            libc::SIGCHLD,
+            libc::SIGWINCH,
+        ] {
+            let name = signal_name(signo).unwrap();
+            assert_eq!(signal_from_name(name), Some(signo));
+            assert_eq!(signal_from_name(&format!("SIG{}", name)), Some(signo));
+        }
+        assert_eq!(signal_name(libc::SIGTERM), Some("TERM"));
+        assert_eq!(signal_from_name("sigint"), Some(libc::SIGINT));
+        assert_eq!(signal_from_name("CLD"), Some(libc::SIGCHLD));
+        assert_eq!(signal_from_name("SIGIOT"), Some(libc::SIGABRT));
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(signal_from_name("BOGUS"), None);
+        assert_eq!(signal_from_name("SIG"), None);
+        assert_eq!(signal_from_name(""), None);
+        assert_eq!(signal_from_name("15"), None);
+        assert_eq!(signal_name(0), None);
+        assert_eq!(signal_name(-1), None);
+    }
+}
@@ -0,0 +1,459 @@
+//! Commands registered by the `trap` builtin, and the machinery
+//! that runs them.  Signal handlers only record that a signal
+//! arrived; the associated commands are run by the repl at points
+//! where it is safe to execute more shell code.
+use crate::errorprint::print_error;
+use crate::script::compile_and_run_string;
+use crate::shellhost::FunctionRegistry;
+use crate::signal::{signal_from_name, signal_name};
+use failure::{bail, Fallible};
+use lazy_static::lazy_static;
+use shell_vm::{shell_quote, Environment, Status, Value};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    pub static ref TRAPS: Mutex<Traps> = Mutex::new(Traps::default());
+}
+
+thread_local! {
+    /// Set while the DEBUG trap is running, so that the commands
+    /// that it runs do not themselves trigger it
+    static IN_DEBUG_TRAP: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A bitmask of the signals that have arrived but whose trap
+/// commands have yet to run.  As with the SIGCHLD flag in the job
+/// module, this is a plain static so that the signal handler can
+/// safely update it.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// The event that causes a trap command to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TrapCondition {
+    /// The shell is exiting
+    Exit,
+    /// A command completed with a non-zero status
+    Err,
+    /// A simple command is about to run
+    Debug,
+    Signal(i32),
+}
+
+impl TrapCondition {
+    /// Parse a condition name as accepted by `trap`: `EXIT` (or `0`),
+    /// `ERR`, `DEBUG`, or a signal given by number or by name with or without
+    /// the `SIG` prefix.  Names are case insensitive.
+    pub fn parse(name: &str) -> Option<TrapCondition> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "EXIT" | "0" => return Some(TrapCondition::Exit),
+            "ERR" => return Some(TrapCondition::Err),
+            "DEBUG" => return Some(TrapCondition::Debug),
+            _ => {}
+        }
+        if let Ok(num) = upper.parse::<i32>() {
+            return signal_name(num).map(|_| TrapCondition::Signal(num));
+        }
+        signal_from_name(&upper).map(TrapCondition::Signal)
+    }
+
+    /// The name used when printing the trap with `trap -p`
+    pub fn name(self) -> String {
+        match self {
+            TrapCondition::Exit => "EXIT".to_string(),
+            TrapCondition::Err => "ERR".to_string(),
+            TrapCondition::Debug => "DEBUG".to_string(),
+            TrapCondition::Signal(signo) => signal_name(signo)
+                .map(|signame| format!("SIG{}", signame))
+                .unwrap_or_else(|| signo.to_string()),
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn trap_signal_handler(signo: libc::c_int) {
+    if (0..64).contains(&signo) {
+        PENDING.fetch_or(1 << signo, Ordering::SeqCst);
+    }
+}
+
+/// The registered trap commands
+#[derive(Default)]
+pub struct Traps {
+    actions: BTreeMap<TrapCondition, String>,
+    /// The disposition of each trapped signal prior to the trap
+    /// being set, so that it can be restored by `trap - SIG`
+    #[cfg(unix)]
+    saved: HashMap<i32, libc::sigaction>,
+    #[cfg(windows)]
+    saved: HashMap<i32, ()>,
+}
+
+impl Traps {
+    /// Register `action` to run when `condition` occurs.  An empty
+    /// action causes the signal to be ignored, while `None` restores
+    /// the default behavior.
+    pub fn set(&mut self, condition: TrapCondition, action: Option<&str>) -> Fallible<()> {
+        if let TrapCondition::Signal(signo) = condition {
+            self.set_disposition(signo, action)?;
+        }
+        match action {
+            Some(action) => self.actions.insert(condition, action.to_owned()),
+            None => self.actions.remove(&condition),
+        };
+        Ok(())
+    }
+
+    /// Returns the command registered for `condition`
+    pub fn get(&self, condition: TrapCondition) -> Option<&str> {
+        self.actions.get(&condition).map(String::as_str)
+    }
+
+    /// Iterate over the registered conditions and their commands
+    pub fn iter(&self) -> impl Iterator<Item = (TrapCondition, &str)> {
+        self.actions
+            .iter()
+            .map(|(condition, action)| (*condition, action.as_str()))
+    }
+
+    #[cfg(unix)]
+    fn set_disposition(&mut self, signo: i32, action: Option<&str>) -> Fallible<()> {
+        unsafe {
+            let mut new_action: libc::sigaction = std::mem::zeroed();
+            libc::sigemptyset(&mut new_action.sa_mask);
+            new_action.sa_flags = libc::SA_RESTART;
+            match action {
+                None => match self.saved.remove(&signo) {
+                    Some(saved) => new_action = saved,
+                    None => return Ok(()),
+                },
+                Some("") => new_action.sa_sigaction = libc::SIG_IGN,
+                Some(_) => {
+                    new_action.sa_sigaction = trap_signal_handler as *const () as usize;
+                }
+            }
+            let mut old_action: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signo, &new_action, &mut old_action) != 0 {
+                bail!(
+                    "{}: {}",
+                    TrapCondition::Signal(signo).name(),
+                    std::io::Error::last_os_error()
+                );
+            }
+            if action.is_some() {
+                self.saved.entry(signo).or_insert(old_action);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn set_disposition(&mut self, signo: i32, _action: Option<&str>) -> Fallible<()> {
+        bail!("{}: signals cannot be trapped on this system", signo);
+    }
+}
+
+/// Format a trap as the command that would register it
+pub fn trap_as_command(condition: TrapCondition, action: &str) -> String {
+    format!(
+        "trap -- '{}' {}\n",
+        action.replace('\'', "'\\''"),
+        condition.name()
+    )
+}
+
+/// Format the registered traps as the commands that would
+/// recreate them, as printed by `trap -p`
+pub fn traps_as_commands(traps: &Traps) -> String {
+    traps
+        .iter()
+        .map(|(condition, action)| trap_as_command(condition, action))
+        .collect()
+}
+
+/// Block or unblock delivery of the signals that have trap commands.
+/// As with SIGCHLD, this defers the signals while the line editor is
+/// waiting for input, as poll(2) is not restarted after a signal.
+#[cfg(unix)]
+pub fn block_trapped_signals(traps: &Traps, block: bool) {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for (condition, action) in traps.iter() {
+            if let TrapCondition::Signal(signo) = condition {
+                if !action.is_empty() {
+                    libc::sigaddset(&mut set, signo);
+                }
+            }
+        }
+        libc::pthread_sigmask(
+            if block {
+                libc::SIG_BLOCK
+            } else {
+                libc::SIG_UNBLOCK
+            },
+            &set,
+            std::ptr::null_mut(),
+        );
+    }
+}
+
+/// Run the command registered with `traps` for `condition`, if any.
+/// The lock is not held while the command runs, so that it may
+/// itself use the `trap` builtin.
+pub fn run_trap(
+    traps: &Mutex<Traps>,
+    condition: TrapCondition,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) -> Option<Status> {
+    let action = traps.lock().unwrap().get(condition)?.to_owned();
+    run_action(&action, cwd, env, funcs)
+}
+
+fn run_action(
+    action: &str,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) -> Option<Status> {
+    if action.is_empty() {
+        return None;
+    }
+    match compile_and_run_string(action, cwd, env, funcs) {
+        Ok(status) => Some(status),
+        Err(err) => {
+            print_error(&err, action);
+            None
+        }
+    }
+}
+
+/// Run the traps for any signals that have arrived since the
+/// last call
+pub fn run_pending_traps(
+    traps: &Mutex<Traps>,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) {
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    if pending == 0 {
+        return;
+    }
+    for signo in 0..64 {
+        if pending & (1 << signo) != 0 {
+            run_trap(traps, TrapCondition::Signal(signo), cwd, env, funcs);
+        }
+    }
+}
+
+/// Run the ERR trap if `status` indicates that a command failed
+pub fn run_err_trap(
+    traps: &Mutex<Traps>,
+    status: &Status,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) {
+    if let Status::Complete(value) = status {
+        // A non-zero exit status is truthy
+        if value.truthy() {
+            run_trap(traps, TrapCondition::Err, cwd, env, funcs);
+        }
+    }
+}
+
+/// Run the DEBUG trap ahead of the simple command `argv`.
+/// The command is made available to the trap in `BASH_COMMAND`.
+/// The commands run by the trap do not trigger it again.
+pub fn run_debug_trap(
+    traps: &Mutex<Traps>,
+    argv: &[Value],
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) {
+    if IN_DEBUG_TRAP.with(Cell::get) {
+        return;
+    }
+    let action = match traps.lock().unwrap().get(TrapCondition::Debug) {
+        Some(action) => action.to_owned(),
+        None => return,
+    };
+
+    let command: Vec<String> = argv
+        .iter()
+        .filter_map(Value::as_os_str)
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect();
+    env.set("BASH_COMMAND", command.join(" "));
+
+    IN_DEBUG_TRAP.with(|running| running.set(true));
+    run_action(&action, cwd, env, funcs);
+    IN_DEBUG_TRAP.with(|running| running.set(false));
+}
+
+/// Run the EXIT trap.  It is removed first so that it runs only once,
+/// even if the trap command itself causes the shell to exit.
+pub fn run_exit_trap(
+    traps: &Mutex<Traps>,
+    cwd: &mut PathBuf,
+    env: &mut Environment,
+    funcs: &Arc<FunctionRegistry>,
+) {
+    let action = traps.lock().unwrap().actions.remove(&TrapCondition::Exit);
+    if let Some(action) = action {
+        run_action(&action, cwd, env, funcs);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn shell_state() -> Fallible<(PathBuf, Environment, Arc<FunctionRegistry>)> {
+        Ok((
+            std::env::current_dir()?,
+            Environment::new_empty(),
+            Arc::new(FunctionRegistry::new()),
+        ))
+    }
+
+    #[test]
+    fn parse_conditions() {
+        assert_eq!(TrapCondition::parse("EXIT"), Some(TrapCondition::Exit));
+        assert_eq!(TrapCondition::parse("0"), Some(TrapCondition::Exit));
+        assert_eq!(TrapCondition::parse("err"), Some(TrapCondition::Err));
+        assert_eq!(TrapCondition::parse("DEBUG"), Some(TrapCondition::Debug));
+        assert_eq!(TrapCondition::parse("bogus"), None);
+        #[cfg(unix)]
+        {
+            let int = TrapCondition::Signal(libc::SIGINT);
+            assert_eq!(TrapCondition::parse("INT"), Some(int));
+            assert_eq!(TrapCondition::parse("sigint"), Some(int));
+            assert_eq!(TrapCondition::parse(&libc::SIGINT.to_string()), Some(int));
+            assert_eq!(int.name(), "SIGINT");
+        }
+    }
+
+    #[test]
+    fn registering() -> Fallible<()> {
+        let mut traps = Traps::default();
+        traps.set(TrapCondition::Exit, Some("echo 'bye'"))?;
+        traps.set(TrapCondition::Err, Some("echo failed"))?;
+        assert_eq!(traps.get(TrapCondition::Exit), Some("echo 'bye'"));
+        assert_eq!(
+            traps_as_commands(&traps),
+            "trap -- 'echo '\\''bye'\\''' EXIT\ntrap -- 'echo failed' ERR\n"
+        );
+
+        traps.set(TrapCondition::Err, None)?;
+        assert_eq!(traps.get(TrapCondition::Err), None);
+        assert_eq!(
+            traps_as_commands(&traps),
+            "trap -- 'echo '\\''bye'\\''' EXIT\n"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_trap() -> Fallible<()> {
+        let traps = Mutex::new(Traps::default());
+        traps
+            .lock()
+            .unwrap()
+            .set(TrapCondition::Signal(libc::SIGUSR2), Some("GOT=usr2"))?;
+
+        let (mut cwd, mut env, funcs) = shell_state()?;
+
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+        }
+        run_pending_traps(&traps, &mut cwd, &mut env, &funcs);
+        assert_eq!(env.get("GOT").and_then(|v| v.to_str()), Some("usr2"));
+
+        traps
+            .lock()
+            .unwrap()
+            .set(TrapCondition::Signal(libc::SIGUSR2), None)?;
+        assert!(traps.lock().unwrap().saved.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn exit_trap_fires_once() -> Fallible<()> {
+        let traps = Mutex::new(Traps::default());
+        traps
+            .lock()
+            .unwrap()
+            .set(TrapCondition::Exit, Some("COUNT=x$COUNT"))?;
+
+        let (mut cwd, mut env, funcs) = shell_state()?;
+
+        run_exit_trap(&traps, &mut cwd, &mut env, &funcs);
+        assert_eq!(env.get("COUNT").and_then(|v| v.to_str()), Some("x"));
+        run_exit_trap(&traps, &mut cwd, &mut env, &funcs);
+        assert_eq!(env.get("COUNT").and_then(|v| v.to_str()), Some("x"));
+        Ok(())
+    }
+
+    #[test]
+    fn err_trap() -> Fallible<()> {
+        let traps = Mutex::new(Traps::default());
+        traps
+            .lock()
+            .unwrap()
+            .set(TrapCondition::Err, Some("FAILED=1"))?;
+
+        let (mut cwd, mut env, funcs) = shell_state()?;
+
+        run_err_trap(
+            &traps,
+            &Status::Complete(0.into()),
+            &mut cwd,
+            &mut env,
+            &funcs,
+        );
+        assert_eq!(env.get("FAILED"), None);
+        run_err_trap(
+            &traps,
+            &Status::Complete(1.into()),
+            &mut cwd,
+            &mut env,
+            &funcs,
+        );
+        assert!(env.get("FAILED").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn debug_trap_fires_per_command() -> Fallible<()> {
+        // The trap runs a command to show that it does not trigger
+        // itself recursively.
+        let traps = Mutex::new(Traps::default());
+        traps
+            .lock()
+            .unwrap()
+            .set(TrapCondition::Debug, Some(": ; COUNT=x$COUNT"))?;
+
+        let (mut cwd, mut env, funcs) = shell_state()?;
+        for command in &[":", "true"] {
+            let argv = vec![Value::String(command.to_string())];
+            run_debug_trap(&traps, &argv, &mut cwd, &mut env, &funcs);
+        }
+
+        assert_eq!(env.get("COUNT").and_then(|v| v.to_str()), Some("xx"));
+        assert_eq!(
+            env.get("BASH_COMMAND").and_then(|v| v.to_str()),
+            Some("true")
+        );
+        Ok(())
+    }
+}